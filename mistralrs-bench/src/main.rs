@@ -66,6 +66,16 @@ fn run_bench(
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        xtc: None,
+        dynatemp: None,
+        repetition_penalty: None,
+        penalty_range: None,
+        seed: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -84,6 +94,8 @@ fn run_bench(
         tool_choice: None,
         logits_processors: None,
         return_raw_logits: false,
+        priority: 0,
+        tenant: None,
     });
 
     let mut usages = Vec::new();
@@ -116,6 +128,7 @@ fn run_bench(
                     }
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
                     Response::Raw { .. } => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
@@ -233,6 +246,16 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        xtc: None,
+        dynatemp: None,
+        repetition_penalty: None,
+        penalty_range: None,
+        seed: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -255,6 +278,8 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         tool_choice: None,
         logits_processors: None,
         return_raw_logits: false,
+        priority: 0,
+        tenant: None,
     });
 
     sender
@@ -0,0 +1,1025 @@
+//! Compile a JSON schema into a constraint the `aici` recognizers understand.
+//!
+//! Schemas with structure (properties, items, enums, ...) are lowered to a regex for `RecRx`.
+//! Free-form JSON (`{}`, `true` or an object without `properties`) cannot be expressed as a
+//! regex, so it is handled by a yacc grammar for `CfgParser` instead. Schemas with keywords
+//! which are not implemented are rejected.
+
+use anyhow::{bail, Context, Result};
+use regex_syntax::{
+    hir::{Class, ClassBytes, ClassBytesRange, Hir, HirKind, Literal},
+    ParserBuilder,
+};
+use serde_json::{Map, Value};
+
+use crate::Constraint;
+
+const WS: &str = "[ ]?";
+/// One character of a JSON string. Lone UTF-16 surrogate escapes are rejected by most parsers,
+/// so `\u` escapes in the surrogate range are not allowed.
+const STRING_CHAR: &str = r#"([^"\\\x00-\x1f]|\\["\\/bfnrt]|\\u([0-9a-ce-fA-CE-F][0-9a-fA-F]{3}|[dD][0-7][0-9a-fA-F]{2}))"#;
+const INTEGER: &str = "-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(true|false)";
+const NULL: &str = "null";
+/// Matches nothing. Used for `false` schemas.
+const NEVER: &str = r"[^\x00-\xff]";
+
+/// How many times a `$ref` may be followed on one path. Deeper optional branches (optional
+/// properties, `anyOf` alternatives and items of arrays which may be empty) are left out, and a
+/// schema which requires going deeper is rejected.
+const MAX_REF_DEPTH: usize = 3;
+/// Nesting depth of free-form values inside an otherwise structured schema.
+const MAX_ANY_DEPTH: usize = 2;
+
+/// Keywords which the compiler implements. Schemas using any other keyword which restricts the
+/// accepted values are rejected, rather than producing values which do not validate.
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "type",
+    "properties",
+    "additionalProperties",
+    "required",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "format",
+    "enum",
+    "const",
+    "$ref",
+    "anyOf",
+    "oneOf",
+    "allOf",
+];
+/// Keywords which do not restrict the values a schema accepts. A schema with only these is
+/// free-form.
+const ANNOTATION_KEYWORDS: &[&str] = &[
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+    "contentEncoding",
+    "contentMediaType",
+    "$schema",
+    "$id",
+    "$comment",
+    "$defs",
+    "definitions",
+];
+
+/// Lower a JSON schema to a `Constraint::Regex`, or to a `Constraint::Yacc` for free-form JSON.
+pub(crate) fn json_schema_constraint(schema: &Value) -> Result<Constraint> {
+    if is_free_form(schema) {
+        return Ok(Constraint::Yacc(json_yacc("value")));
+    }
+    if let Value::Object(obj) = schema {
+        if is_free_form_object(obj) {
+            return Ok(Constraint::Yacc(json_yacc("object")));
+        }
+    }
+    let rx = SchemaCompiler {
+        root: schema,
+        ref_depth: 0,
+    }
+    .compile(schema)?;
+    Ok(Constraint::Regex(rx))
+}
+
+/// Regex for a tool call object: `{"name": ..., "<arguments_key>": ...}`, where the arguments
+/// match the parameters schema of the named function. `tools` holds the name and parameters
+/// schema of each function which may be called.
+pub(crate) fn tool_call_regex(
+    tools: &[(&str, Option<Value>)],
+    arguments_key: &str,
+) -> Result<String> {
+    if tools.is_empty() {
+        bail!("No tools to call");
+    }
+    let mut alts = Vec::with_capacity(tools.len());
+    for (name, parameters) in tools {
+        // Functions without parameters still take an object, which may be empty.
+        let parameters = parameters
+            .clone()
+            .unwrap_or_else(|| serde_json::json!({"type": "object"}));
+        let arguments = SchemaCompiler {
+            root: &parameters,
+            ref_depth: 0,
+        }
+        .compile(&parameters)
+        .with_context(|| format!("Invalid parameters schema for tool `{name}`"))?;
+        alts.push(format!(
+            r#"\{{{WS}"name"{WS}:{WS}{}{WS},{WS}{}{WS}:{WS}{arguments}{WS}\}}"#,
+            literal(&Value::String(name.to_string()))?,
+            literal(&Value::String(arguments_key.to_string()))?,
+        ));
+    }
+    Ok(alternation(alts))
+}
+
+/// A yacc grammar for arbitrary JSON, starting at either `value` or `object`.
+fn json_yacc(start: &str) -> String {
+    format!(
+        r#"%start {start}
+%%
+
+SKIP: "/[ \t\r\n]+/" ;
+
+STRING: '/"{STRING_CHAR}*"/' ;
+
+NUMBER: "/{NUMBER}/" ;
+
+value
+    : object
+    | array
+    | STRING
+    | NUMBER
+    | "true"
+    | "false"
+    | "null"
+    ;
+
+object
+    : "{{" "}}"
+    | "{{" members "}}"
+    ;
+
+members
+    : member
+    | members "," member
+    ;
+
+member: STRING ":" value ;
+
+array
+    : "[" "]"
+    | "[" elements "]"
+    ;
+
+elements
+    : value
+    | elements "," value
+    ;
+"#
+    )
+}
+
+fn is_free_form(schema: &Value) -> bool {
+    match schema {
+        Value::Bool(b) => *b,
+        Value::Object(obj) => obj
+            .keys()
+            .all(|k| ANNOTATION_KEYWORDS.contains(&k.as_str())),
+        _ => false,
+    }
+}
+
+fn is_free_form_object(obj: &Map<String, Value>) -> bool {
+    obj.get("type").and_then(Value::as_str) == Some("object")
+        && obj.get("additionalProperties").map_or(true, is_free_form)
+        && obj.keys().all(|k| {
+            matches!(k.as_str(), "type" | "additionalProperties")
+                || ANNOTATION_KEYWORDS.contains(&k.as_str())
+        })
+}
+
+fn check_keywords(obj: &Map<String, Value>) -> Result<()> {
+    for (keyword, value) in obj {
+        // `uniqueItems: false` is the default, so it does not restrict anything.
+        if SUPPORTED_KEYWORDS.contains(&keyword.as_str())
+            || ANNOTATION_KEYWORDS.contains(&keyword.as_str())
+            || (keyword == "uniqueItems" && value == &Value::Bool(false))
+        {
+            continue;
+        }
+        bail!("Unsupported JSON schema keyword `{keyword}`");
+    }
+    Ok(())
+}
+
+fn string_literal() -> String {
+    format!("\"{STRING_CHAR}*\"")
+}
+
+/// Regex for the contents of a JSON string whose value matches `pattern`. Characters which must
+/// be escaped in JSON are matched by their escape sequence in literals and are left out of
+/// classes, so this accepts a subset of the matching strings.
+fn string_pattern(pattern: &str) -> Result<String> {
+    let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+    let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+    let hir = ParserBuilder::new()
+        .unicode(false)
+        .utf8(false)
+        .build()
+        .parse(pattern)
+        .with_context(|| format!("Invalid JSON schema `pattern` `{pattern}`"))?;
+    Ok(escape_string_hir(&hir)?.to_string())
+}
+
+fn escape_string_hir(hir: &Hir) -> Result<Hir> {
+    Ok(match hir.kind() {
+        HirKind::Empty => Hir::empty(),
+        HirKind::Literal(Literal(bytes)) => Hir::literal(
+            bytes
+                .iter()
+                .flat_map(|b| escape_byte(*b))
+                .collect::<Vec<_>>(),
+        ),
+        HirKind::Class(class) => {
+            let mut class = match class {
+                Class::Bytes(class) => class.clone(),
+                Class::Unicode(class) => class
+                    .to_byte_class()
+                    .context("Only ASCII classes are supported in JSON schema `pattern`")?,
+            };
+            class.difference(&ClassBytes::new([
+                ClassBytesRange::new(0x00, 0x1f),
+                ClassBytesRange::new(b'"', b'"'),
+                ClassBytesRange::new(b'\\', b'\\'),
+            ]));
+            Hir::class(Class::Bytes(class))
+        }
+        HirKind::Look(_) => {
+            bail!("JSON schema `pattern` may only have anchors at its start and end")
+        }
+        HirKind::Repetition(rep) => {
+            let mut rep = rep.clone();
+            rep.sub = Box::new(escape_string_hir(&rep.sub)?);
+            Hir::repetition(rep)
+        }
+        HirKind::Capture(capture) => escape_string_hir(&capture.sub)?,
+        HirKind::Concat(hirs) => Hir::concat(
+            hirs.iter()
+                .map(escape_string_hir)
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        HirKind::Alternation(hirs) => Hir::alternation(
+            hirs.iter()
+                .map(escape_string_hir)
+                .collect::<Result<Vec<_>>>()?,
+        ),
+    })
+}
+
+/// The bytes of `b` in a JSON string.
+fn escape_byte(b: u8) -> Vec<u8> {
+    match b {
+        b'"' => br#"\""#.to_vec(),
+        b'\\' => br"\\".to_vec(),
+        b'\n' => br"\n".to_vec(),
+        b'\r' => br"\r".to_vec(),
+        b'\t' => br"\t".to_vec(),
+        0x08 => br"\b".to_vec(),
+        0x0c => br"\f".to_vec(),
+        0x00..=0x1f => format!("\\u{b:04x}").into_bytes(),
+        b => vec![b],
+    }
+}
+
+/// Regex for the serialized form of a JSON literal, as used by `const` and `enum`.
+fn literal(value: &Value) -> Result<String> {
+    Ok(regex::escape(&serde_json::to_string(value)?))
+}
+
+fn alternation(alts: Vec<String>) -> String {
+    format!("({})", alts.join("|"))
+}
+
+/// Regex for any JSON value nested at most `depth` levels deep.
+fn any_value(depth: usize) -> String {
+    let scalars = format!("{}|{NUMBER}|{BOOLEAN}|{NULL}", string_literal());
+    if depth == 0 {
+        return format!("({scalars})");
+    }
+    let inner = any_value(depth - 1);
+    format!(
+        "({scalars}|{}|{})",
+        array_of(&inner, 0, None),
+        map_of(&inner)
+    )
+}
+
+fn array_of(item: &str, min_items: usize, max_items: Option<usize>) -> String {
+    let sep_item = format!("{WS},{WS}{item}");
+    let body = match (min_items, max_items) {
+        (_, Some(0)) => String::new(),
+        (0, None) => format!("({item}({sep_item})*)?"),
+        (0, Some(max)) => format!("({item}({sep_item}){{0,{}}})?", max - 1),
+        (min, None) => format!("{item}({sep_item}){{{},}}", min - 1),
+        (min, Some(max)) => format!("{item}({sep_item}){{{},{}}}", min - 1, max - 1),
+    };
+    format!(r"\[{WS}{body}{WS}\]")
+}
+
+fn map_of(value: &str) -> String {
+    let member = format!("{}{WS}:{WS}{value}", string_literal());
+    format!(r"\{{{WS}({member}({WS},{WS}{member})*)?{WS}\}}")
+}
+
+/// A `$ref` which would be followed more than `MAX_REF_DEPTH` times. Optional branches which
+/// hit this are left out; anywhere else it is an error.
+#[derive(Debug)]
+struct RefDepthExceeded(String);
+
+impl std::fmt::Display for RefDepthExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "JSON schema reference `{}` is required more than {MAX_REF_DEPTH} levels deep",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for RefDepthExceeded {}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+    ref_depth: usize,
+}
+
+impl SchemaCompiler<'_> {
+    fn compile(&mut self, schema: &Value) -> Result<String> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok(any_value(MAX_ANY_DEPTH)),
+            Value::Bool(false) => return Ok(NEVER.to_string()),
+            Value::Object(obj) => obj,
+            other => bail!("JSON schema must be an object or a boolean, got `{other}`"),
+        };
+        check_keywords(obj)?;
+
+        if let Some(reference) = obj.get("$ref") {
+            return self.compile_ref(reference);
+        }
+        if let Some(value) = obj.get("const") {
+            return literal(value);
+        }
+        if let Some(values) = obj.get("enum") {
+            let Value::Array(values) = values else {
+                bail!("JSON schema `enum` must be an array");
+            };
+            return Ok(alternation(
+                values.iter().map(literal).collect::<Result<Vec<_>>>()?,
+            ));
+        }
+        // `oneOf` is compiled like `anyOf`, so its schemas should not overlap.
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = obj.get(keyword) {
+                let Value::Array(schemas) = schemas else {
+                    bail!("JSON schema `{keyword}` must be an array");
+                };
+                let mut alts = Vec::with_capacity(schemas.len());
+                let mut cut = None;
+                for schema in schemas {
+                    match self.compile(schema) {
+                        Ok(alt) => alts.push(alt),
+                        Err(e) if e.is::<RefDepthExceeded>() => cut = Some(e),
+                        Err(e) => return Err(e),
+                    }
+                }
+                if let (true, Some(e)) = (alts.is_empty(), cut) {
+                    return Err(e);
+                }
+                return Ok(alternation(alts));
+            }
+        }
+        if let Some(schemas) = obj.get("allOf") {
+            match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => return self.compile(schema),
+                _ => bail!("JSON schema `allOf` is only supported with a single schema"),
+            }
+        }
+
+        match obj.get("type") {
+            Some(Value::String(ty)) => self.compile_type(ty, obj),
+            Some(Value::Array(types)) => {
+                let mut alts = Vec::new();
+                for ty in types {
+                    let ty = ty.as_str().context("JSON schema `type` must be a string")?;
+                    alts.push(self.compile_type(ty, obj)?);
+                }
+                Ok(alternation(alts))
+            }
+            Some(other) => bail!("JSON schema `type` must be a string or an array, got `{other}`"),
+            None if obj.contains_key("properties") => self.compile_type("object", obj),
+            None if obj.contains_key("items") => self.compile_type("array", obj),
+            None => Ok(any_value(MAX_ANY_DEPTH)),
+        }
+    }
+
+    fn compile_ref(&mut self, reference: &Value) -> Result<String> {
+        let reference = reference
+            .as_str()
+            .context("JSON schema `$ref` must be a string")?;
+        let Some(pointer) = reference.strip_prefix('#') else {
+            bail!("Only local JSON schema references are supported, got `{reference}`");
+        };
+        let target = self
+            .root
+            .pointer(pointer)
+            .with_context(|| format!("Unresolved JSON schema reference `{reference}`"))?;
+
+        if self.ref_depth >= MAX_REF_DEPTH {
+            bail!(RefDepthExceeded(reference.to_string()));
+        }
+        self.ref_depth += 1;
+        let res = self.compile(target);
+        self.ref_depth -= 1;
+        res
+    }
+
+    /// Compile a schema which may be left out, or `None` if it recurses too deeply.
+    fn compile_optional(&mut self, schema: &Value) -> Result<Option<String>> {
+        match self.compile(schema) {
+            Ok(rx) => Ok(Some(rx)),
+            Err(e) if e.is::<RefDepthExceeded>() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn compile_type(&mut self, ty: &str, obj: &Map<String, Value>) -> Result<String> {
+        let usize_field = |name: &str| -> Result<Option<usize>> {
+            obj.get(name)
+                .map(|v| {
+                    v.as_u64()
+                        .and_then(|v| usize::try_from(v).ok())
+                        .with_context(|| {
+                            format!("JSON schema `{name}` must be a non-negative integer")
+                        })
+                })
+                .transpose()
+        };
+
+        match ty {
+            "string" => {
+                let min = usize_field("minLength")?;
+                let max = usize_field("maxLength")?;
+                let has_length = min.is_some() || max.is_some();
+                if let Some(pattern) = obj.get("pattern") {
+                    if has_length {
+                        bail!("JSON schema `minLength` and `maxLength` are not supported together with `pattern`");
+                    }
+                    let pattern = pattern
+                        .as_str()
+                        .context("JSON schema `pattern` must be a string")?;
+                    return Ok(format!("\"{}\"", string_pattern(pattern)?));
+                }
+                if let Some(rx) = obj
+                    .get("format")
+                    .and_then(Value::as_str)
+                    .and_then(string_format)
+                {
+                    if has_length {
+                        bail!("JSON schema `minLength` and `maxLength` are not supported together with `format`");
+                    }
+                    return Ok(format!("\"{rx}\""));
+                }
+                let min = min.unwrap_or(0);
+                let repeat = match max {
+                    Some(max) => format!("{{{min},{max}}}"),
+                    None if min == 0 => "*".to_string(),
+                    None => format!("{{{min},}}"),
+                };
+                Ok(format!("\"{STRING_CHAR}{repeat}\""))
+            }
+            "integer" => Ok(INTEGER.to_string()),
+            "number" => Ok(NUMBER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "array" => {
+                let min = usize_field("minItems")?.unwrap_or(0);
+                let max = usize_field("maxItems")?;
+                if max.is_some_and(|max| max < min) {
+                    bail!("JSON schema `maxItems` is smaller than `minItems`");
+                }
+                let item = match obj.get("items") {
+                    Some(items) if min == 0 => match self.compile_optional(items)? {
+                        Some(item) => item,
+                        // Too deep for any items, so only the empty array is left.
+                        None => return Ok(array_of("", 0, Some(0))),
+                    },
+                    Some(items) => self.compile(items)?,
+                    None => any_value(MAX_ANY_DEPTH),
+                };
+                Ok(array_of(&item, min, max))
+            }
+            "object" => self.compile_object(obj),
+            other => bail!("Unsupported JSON schema type `{other}`"),
+        }
+    }
+
+    fn compile_object(&mut self, obj: &Map<String, Value>) -> Result<String> {
+        let required = match obj.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .map(|r| {
+                    r.as_str()
+                        .context("JSON schema `required` must hold strings")
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Some(_) => bail!("JSON schema `required` must be an array"),
+            None => Vec::new(),
+        };
+        let any = Value::Bool(true);
+        let additional = obj.get("additionalProperties").unwrap_or(&any);
+        let empty = Map::new();
+        let properties = match obj.get("properties") {
+            Some(properties) => properties
+                .as_object()
+                .context("JSON schema `properties` must be an object")?,
+            None if required.is_empty() => {
+                return Ok(match self.compile_optional(additional)? {
+                    Some(value) => map_of(&value),
+                    None => format!(r"\{{{WS}\}}"),
+                })
+            }
+            None => &empty,
+        };
+        // Required properties which are not declared are emitted after the declared ones.
+        let properties = properties
+            .iter()
+            .map(|(name, schema)| (name.as_str(), schema))
+            .chain(
+                required
+                    .iter()
+                    .filter(|name| !properties.contains_key(**name))
+                    .map(|name| (*name, additional)),
+            )
+            .collect::<Vec<_>>();
+
+        // Properties are emitted in declaration order. Optional ones may be left out, which
+        // moves the separating comma around, so enumerate which property comes first.
+        let mut members = Vec::with_capacity(properties.len());
+        for (name, schema) in properties {
+            let is_required = required.contains(&name);
+            let value = if is_required {
+                self.compile(schema)?
+            } else {
+                match self.compile_optional(schema)? {
+                    Some(value) => value,
+                    None => continue,
+                }
+            };
+            let member = format!(
+                "{}{WS}:{WS}{value}",
+                literal(&Value::String(name.to_string()))?
+            );
+            members.push((member, is_required));
+        }
+        let tail = |start: usize| {
+            members[start..]
+                .iter()
+                .map(|(member, required)| {
+                    let member = format!("{WS},{WS}{member}");
+                    if *required {
+                        member
+                    } else {
+                        format!("({member})?")
+                    }
+                })
+                .collect::<String>()
+        };
+        let mut heads = Vec::new();
+        for (i, (member, required)) in members.iter().enumerate() {
+            heads.push(format!("{member}{}", tail(i + 1)));
+            if *required {
+                break;
+            }
+        }
+        let body = if heads.is_empty() {
+            String::new()
+        } else if members.iter().any(|(_, required)| *required) {
+            alternation(heads)
+        } else {
+            format!("{}?", alternation(heads))
+        };
+        Ok(format!(r"\{{{WS}{body}{WS}\}}"))
+    }
+}
+
+/// Regexes for the string `format`s with a fixed shape.
+fn string_format(format: &str) -> Option<&'static str> {
+    Some(match format {
+        "date" => "[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])",
+        "time" => "([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])?",
+        "date-time" => "[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])T([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])",
+        "uuid" => "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_isaac::Isaac64Rng;
+    use regex::Regex;
+    use serde_json::{json, Map, Value};
+
+    use super::{json_schema_constraint, tool_call_regex, ANNOTATION_KEYWORDS, SUPPORTED_KEYWORDS};
+    use crate::{
+        aici::{
+            cfg::CfgParser,
+            recognizer::FunctionalRecognizer,
+            rx::RecRx,
+            toktree::{Recognizer, SpecialToken},
+        },
+        Constraint,
+    };
+
+    /// Walk the DFA with random bytes until it may stop. Punctuation is favoured so that
+    /// strings and numbers terminate in reasonable time.
+    fn sample(rx: &RecRx, rng: &mut Isaac64Rng) -> Option<String> {
+        let mut state = rx.initial();
+        let mut out = Vec::new();
+        while out.len() < 4096 {
+            let can_stop = rx.special_allowed(state, SpecialToken::EndOfSentence);
+            if can_stop && rng.gen_bool(0.5) {
+                break;
+            }
+            let allowed = (0x20u8..0x7f)
+                .filter_map(|b| rx.try_append(state, b).map(|s| (b, s)))
+                .collect::<Vec<_>>();
+            let punct = allowed
+                .iter()
+                .copied()
+                .filter(|(b, _)| !b.is_ascii_alphanumeric())
+                .collect::<Vec<_>>();
+            let choices = if !punct.is_empty() && rng.gen_bool(0.5) {
+                punct
+            } else {
+                allowed
+            };
+            if choices.is_empty() {
+                assert!(
+                    can_stop,
+                    "dead end after {:?}",
+                    String::from_utf8_lossy(&out)
+                );
+                break;
+            }
+            let (b, next) = choices[rng.gen_range(0..choices.len())];
+            out.push(b);
+            state = next;
+        }
+        rx.special_allowed(state, SpecialToken::EndOfSentence)
+            .then(|| String::from_utf8(out).unwrap())
+    }
+
+    /// Validator for the subset of JSON schema which the compiler supports. Any other keyword
+    /// fails the test, so that samples are never checked against a schema with ignored keywords.
+    fn validate(schema: &Value, root: &Value, value: &Value) -> bool {
+        let Value::Object(obj) = schema else {
+            return schema.as_bool().unwrap_or(false);
+        };
+        for keyword in obj.keys() {
+            assert!(
+                SUPPORTED_KEYWORDS.contains(&keyword.as_str())
+                    || ANNOTATION_KEYWORDS.contains(&keyword.as_str()),
+                "the validator does not implement `{keyword}`"
+            );
+        }
+        if let Some(Value::String(reference)) = obj.get("$ref") {
+            return validate(root.pointer(&reference[1..]).unwrap(), root, value);
+        }
+        if let Some(c) = obj.get("const") {
+            return c == value;
+        }
+        if let Some(Value::Array(values)) = obj.get("enum") {
+            return values.contains(value);
+        }
+        if let Some(Value::Array(schemas)) = obj.get("anyOf") {
+            return schemas.iter().any(|s| validate(s, root, value));
+        }
+        if let Some(Value::Array(schemas)) = obj.get("oneOf") {
+            return schemas.iter().filter(|s| validate(s, root, value)).count() == 1;
+        }
+        if let Some(Value::Array(schemas)) = obj.get("allOf") {
+            return schemas.iter().all(|s| validate(s, root, value));
+        }
+        let types = match obj.get("type") {
+            Some(Value::String(ty)) => vec![ty.as_str()],
+            Some(Value::Array(types)) => types.iter().map(|t| t.as_str().unwrap()).collect(),
+            _ => return true,
+        };
+        types.into_iter().any(|ty| match (ty, value) {
+            ("null", Value::Null) | ("boolean", Value::Bool(_)) | ("number", Value::Number(_)) => {
+                true
+            }
+            ("integer", Value::Number(n)) => n.is_i64() || n.is_u64(),
+            ("string", Value::String(s)) => {
+                let len = s.chars().count() as u64;
+                obj.get("minLength")
+                    .and_then(Value::as_u64)
+                    .map_or(true, |min| len >= min)
+                    && obj
+                        .get("maxLength")
+                        .and_then(Value::as_u64)
+                        .map_or(true, |max| len <= max)
+                    && obj
+                        .get("pattern")
+                        .and_then(Value::as_str)
+                        .map_or(true, |pattern| Regex::new(pattern).unwrap().is_match(s))
+            }
+            ("array", Value::Array(items)) => {
+                let len = items.len() as u64;
+                obj.get("minItems")
+                    .and_then(Value::as_u64)
+                    .map_or(true, |min| len >= min)
+                    && obj
+                        .get("maxItems")
+                        .and_then(Value::as_u64)
+                        .map_or(true, |max| len <= max)
+                    && obj
+                        .get("items")
+                        .map_or(true, |s| items.iter().all(|v| validate(s, root, v)))
+            }
+            ("object", Value::Object(map)) => {
+                let empty = Map::new();
+                let properties = obj
+                    .get("properties")
+                    .and_then(Value::as_object)
+                    .unwrap_or(&empty);
+                let any = Value::Bool(true);
+                let additional = obj.get("additionalProperties").unwrap_or(&any);
+                let required = obj
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .map_or(vec![], |r| r.iter().map(|r| r.as_str().unwrap()).collect());
+                required.iter().all(|r| map.contains_key(*r))
+                    && map
+                        .iter()
+                        .all(|(k, v)| validate(properties.get(k).unwrap_or(additional), root, v))
+            }
+            _ => false,
+        })
+    }
+
+    fn check_samples(schema: Value) {
+        let Constraint::Regex(rx) = json_schema_constraint(&schema).unwrap() else {
+            panic!("expected a regex constraint");
+        };
+        let rx = RecRx::from_rx(&rx, None).unwrap();
+        let mut rng = Isaac64Rng::seed_from_u64(42);
+        let mut n_samples = 0;
+        for _ in 0..200 {
+            let Some(sample) = sample(&rx, &mut rng) else {
+                continue;
+            };
+            let value: Value = match serde_json::from_str(&sample) {
+                Ok(value) => value,
+                // Valid JSON, but the exponent does not fit in an f64.
+                Err(e) if e.to_string().starts_with("number out of range") => continue,
+                Err(e) => panic!("{sample:?} is not valid JSON: {e}"),
+            };
+            assert!(
+                validate(&schema, &schema, &value),
+                "{sample} does not validate against {schema}"
+            );
+            n_samples += 1;
+        }
+        assert!(n_samples > 100, "only {n_samples} samples terminated");
+    }
+
+    #[test]
+    fn samples_validate_object() {
+        check_samples(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 10 },
+                "nickname": { "type": "string" },
+                "age": { "type": "integer" },
+                "score": { "type": ["number", "null"] },
+                "tags": {
+                    "type": "array",
+                    "items": { "enum": ["a", "b", 3, null] },
+                    "maxItems": 3
+                },
+                "kind": { "const": "person" }
+            },
+            "required": ["age", "kind"]
+        }));
+    }
+
+    #[test]
+    fn samples_validate_all_optional() {
+        check_samples(json!({
+            "type": "object",
+            "properties": {
+                "a": { "type": "boolean" },
+                "b": { "anyOf": [{ "type": "integer" }, { "type": "string", "maxLength": 2 }] },
+                "c": { "type": "array", "items": { "type": "integer" }, "minItems": 2 }
+            }
+        }));
+    }
+
+    #[test]
+    fn samples_validate_recursive_ref() {
+        check_samples(json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": "number" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    },
+                    "required": ["value"]
+                }
+            },
+            "$ref": "#/$defs/node"
+        }));
+    }
+
+    #[test]
+    fn samples_validate_optional_recursion() {
+        // Linked list whose tail is optional, and a tree whose children are alternatives.
+        check_samples(json!({
+            "$defs": {
+                "list": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": "integer" },
+                        "next": { "$ref": "#/$defs/list" }
+                    },
+                    "required": ["value"]
+                }
+            },
+            "$ref": "#/$defs/list"
+        }));
+        check_samples(json!({
+            "$defs": {
+                "tree": {
+                    "anyOf": [
+                        { "type": "integer" },
+                        { "type": "array", "items": { "$ref": "#/$defs/tree" }, "minItems": 2 }
+                    ]
+                }
+            },
+            "$ref": "#/$defs/tree"
+        }));
+    }
+
+    #[test]
+    fn required_recursion_is_rejected() {
+        for schema in [
+            json!({
+                "$defs": {
+                    "list": {
+                        "type": "object",
+                        "properties": {
+                            "value": { "type": "integer" },
+                            "next": { "$ref": "#/$defs/list" }
+                        },
+                        "required": ["value", "next"]
+                    }
+                },
+                "$ref": "#/$defs/list"
+            }),
+            json!({
+                "$defs": {
+                    "tree": { "type": "array", "items": { "$ref": "#/$defs/tree" }, "minItems": 1 }
+                },
+                "$ref": "#/$defs/tree"
+            }),
+            json!({
+                "$defs": {
+                    "a": { "anyOf": [{ "$ref": "#/$defs/a" }, { "$ref": "#/$defs/a" }] }
+                },
+                "$ref": "#/$defs/a"
+            }),
+        ] {
+            let err = json_schema_constraint(&schema).unwrap_err();
+            assert!(
+                err.to_string().contains("levels deep"),
+                "{schema} should be rejected for its depth, got `{err}`"
+            );
+        }
+    }
+
+    #[test]
+    fn samples_validate_pattern() {
+        check_samples(json!({
+            "type": "object",
+            "properties": {
+                "quoted": { "type": "string", "pattern": "^a(\\.|\"|b)*.$" },
+                "code": { "type": "string", "pattern": "[A-Z]{2}-[0-9]+" }
+            },
+            "required": ["quoted", "code"]
+        }));
+    }
+
+    #[test]
+    fn samples_validate_required_without_properties() {
+        check_samples(json!({
+            "type": "object",
+            "properties": { "a": { "type": "integer" } },
+            "additionalProperties": { "type": "boolean" },
+            "required": ["b"]
+        }));
+        check_samples(json!({
+            "type": "object",
+            "additionalProperties": false,
+            "description": "Always empty."
+        }));
+    }
+
+    #[test]
+    fn unsupported_keywords_are_rejected() {
+        for schema in [
+            json!({ "type": "integer", "minimum": 0 }),
+            json!({ "type": "number", "exclusiveMaximum": 1 }),
+            json!({ "type": "integer", "multipleOf": 2 }),
+            json!({ "type": "array", "uniqueItems": true }),
+            json!({ "type": "object", "minProperties": 1 }),
+            json!({ "not": { "type": "string" } }),
+            json!({ "if": { "type": "string" }, "then": { "minLength": 1 } }),
+            json!({ "type": "string", "pattern": "a+", "maxLength": 3 }),
+            json!({ "type": "string", "pattern": "\\bword\\b" }),
+            json!({
+                "type": "object",
+                "properties": { "n": { "type": "number", "maximum": 10 } }
+            }),
+        ] {
+            assert!(
+                json_schema_constraint(&schema).is_err(),
+                "{schema} should be rejected"
+            );
+        }
+        // Annotations and the default of `uniqueItems` do not restrict the values.
+        assert!(json_schema_constraint(&json!({
+            "type": "array",
+            "title": "Tags",
+            "uniqueItems": false,
+            "items": { "type": "string", "description": "A tag.", "examples": ["a"] }
+        }))
+        .is_ok());
+    }
+
+    fn rx_accepts(rx: &RecRx, input: &str) -> bool {
+        let mut state = rx.initial();
+        for b in input.bytes() {
+            match rx.try_append(state, b) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        rx.special_allowed(state, SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn tool_call_regex_pairs_names_and_arguments() {
+        let tools = [
+            (
+                "get_weather",
+                Some(json!({
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"]
+                })),
+            ),
+            ("get_time", None),
+        ];
+        let rx = RecRx::from_rx(&tool_call_regex(&tools, "arguments").unwrap(), None).unwrap();
+        assert!(rx_accepts(
+            &rx,
+            r#"{"name": "get_weather", "arguments": {"city": "Paris"}}"#
+        ));
+        assert!(rx_accepts(
+            &rx,
+            r#"{"name": "get_time", "arguments": {"zone": "UTC"}}"#
+        ));
+        assert!(!rx_accepts(
+            &rx,
+            r#"{"name": "get_weather", "arguments": {"zone": "UTC"}}"#
+        ));
+        assert!(!rx_accepts(&rx, r#"{"name": "get_date", "arguments": {}}"#));
+        assert!(!rx_accepts(
+            &rx,
+            r#"{"name": "get_time", "parameters": {}}"#
+        ));
+    }
+
+    fn cfg_accepts(yacc: &str, input: &str) -> bool {
+        let mut parser = CfgParser::from_yacc(yacc).unwrap();
+        input.bytes().all(|b| parser.try_push_byte(b))
+            && parser.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn free_form_object_uses_cfg() {
+        let Constraint::Yacc(yacc) = json_schema_constraint(&json!({ "type": "object" })).unwrap()
+        else {
+            panic!("expected a yacc constraint");
+        };
+        assert!(cfg_accepts(
+            &yacc,
+            r#"{"a": [1, -2.5e3, {"b": {"c": [null]}}], "d": "x\"y"}"#
+        ));
+        assert!(cfg_accepts(&yacc, "{}"));
+        assert!(!cfg_accepts(&yacc, "[1]"));
+        assert!(!cfg_accepts(&yacc, r#"{"a": 1,}"#));
+    }
+}
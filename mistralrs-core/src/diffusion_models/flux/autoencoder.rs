@@ -403,6 +403,12 @@ impl AutoEncoder {
         let z = xs.apply(&self.encoder)?.apply(&self.reg)?;
         (z - self.shift_factor)? * self.scale_factor
     }
+    /// Like [`AutoEncoder::encode`], but using the mean of the latent distribution instead of
+    /// sampling from it, so that the latents are deterministic.
+    pub fn encode_mean(&self, xs: &Tensor) -> Result<Tensor> {
+        let z = xs.apply(&self.encoder)?.chunk(2, 1)?[0].clone();
+        (z - self.shift_factor)? * self.scale_factor
+    }
     pub fn decode(&self, xs: &Tensor) -> Result<Tensor> {
         let xs = ((xs / self.scale_factor)? + self.shift_factor)?;
         xs.apply(&self.decoder)
@@ -1,16 +1,36 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{Device, Result, Tensor};
+use rand::{Rng, SeedableRng};
+use rand_isaac::Isaac64Rng;
 
+/// Initial noise of the latents. With a seed, sample `i` is seeded with `seed + i`, so the
+/// samples differ and each one can be reproduced on its own.
 pub fn get_noise(
     num_samples: usize,
     height: usize,
     width: usize,
+    seed: Option<u64>,
     device: &Device,
 ) -> Result<Tensor> {
     let height = (height + 15) / 16 * 2;
     let width = (width + 15) / 16 * 2;
-    Tensor::randn(0f32, 1., (num_samples, 16, height, width), device)
+    let Some(seed) = seed else {
+        return Tensor::randn(0f32, 1., (num_samples, 16, height, width), device);
+    };
+    // Sampled on the CPU, so that the noise does not depend on the device.
+    let noise = (0..num_samples as u64)
+        .flat_map(|i| {
+            let mut rng = Isaac64Rng::seed_from_u64(seed.wrapping_add(i));
+            (0..16 * height * width).map(move |_| {
+                // Box-Muller transform
+                let u1 = 1.0 - rng.gen::<f64>();
+                let u2 = rng.gen::<f64>();
+                ((-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()) as f32
+            })
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(noise, (num_samples, 16, height, width), &Device::Cpu)?.to_device(device)
 }
 
 #[derive(Debug, Clone)]
@@ -22,14 +42,20 @@ pub struct State {
     pub vec: Tensor,
 }
 
+/// Patchify latents of shape `(b, c, h, w)` into a sequence of shape `(b, h / 2 * w / 2, c * 4)`.
+pub fn pack(xs: &Tensor) -> Result<Tensor> {
+    let (bs, c, h, w) = xs.dims4()?;
+    xs.reshape((bs, c, h / 2, 2, w / 2, 2))? // (b, c, h, ph, w, pw)
+        .permute((0, 2, 4, 1, 3, 5))? // (b, h, w, c, ph, pw)
+        .reshape((bs, h / 2 * w / 2, c * 4))
+}
+
 impl State {
     pub fn new(t5_emb: &Tensor, clip_emb: &Tensor, img: &Tensor) -> Result<Self> {
         let dtype = img.dtype();
-        let (bs, c, h, w) = img.dims4()?;
+        let (bs, _c, h, w) = img.dims4()?;
         let dev = img.device();
-        let img = img.reshape((bs, c, h / 2, 2, w / 2, 2))?; // (b, c, h, ph, w, pw)
-        let img = img.permute((0, 2, 4, 1, 3, 5))?; // (b, h, w, c, ph, pw)
-        let img = img.reshape((bs, h / 2 * w / 2, c * 4))?;
+        let img = pack(img)?;
         let img_ids = Tensor::stack(
             &[
                 Tensor::full(0u32, (h / 2, w / 2), dev)?,
@@ -58,6 +84,24 @@ impl State {
     }
 }
 
+/// Packed latents of an image being inpainted, which are kept outside of the mask.
+pub struct Inpainting {
+    /// Latents of the original image.
+    pub image: Tensor,
+    /// Noise which the image latents started from.
+    pub noise: Tensor,
+    /// A u8 mask, which is 1 in the areas to change and 0 elsewhere.
+    pub mask: Tensor,
+}
+
+impl Inpainting {
+    /// Replace the latents outside of the mask with the original image, noised to timestep `t`.
+    pub fn keep_unmasked(&self, img: &Tensor, t: f64) -> Result<Tensor> {
+        let kept = ((&self.image * (1. - t))? + (&self.noise * t)?)?;
+        self.mask.where_cond(img, &kept)
+    }
+}
+
 fn time_shift(mu: f64, sigma: f64, t: f64) -> f64 {
     let e = mu.exp();
     e / (e + (1. / t - 1.).powf(sigma))
@@ -84,6 +128,14 @@ pub fn get_schedule(num_steps: usize, shift: Option<(usize, f64, f64)>) -> Vec<f
     }
 }
 
+/// The end of the schedule, with the steps which an edit of the given strength makes. With a
+/// strength of 1, this is the whole schedule.
+pub fn skip_steps(timesteps: &[f64], strength: f64) -> &[f64] {
+    let num_steps = timesteps.len() - 1;
+    let steps = ((num_steps as f64 * strength) as usize).min(num_steps);
+    &timesteps[num_steps - steps..]
+}
+
 pub fn unpack(xs: &Tensor, height: usize, width: usize) -> Result<Tensor> {
     let (b, _h_w, c_ph_pw) = xs.dims3()?;
     let height = (height + 15) / 16;
@@ -103,6 +155,8 @@ fn denoise_inner(
     vec_: &Tensor,
     timesteps: &[f64],
     guidance: Option<f64>,
+    negative: Option<(&State, f64)>,
+    inpainting: Option<&Inpainting>,
 ) -> Result<Tensor> {
     let b_sz = img.dim(0)?;
     let dev = img.device();
@@ -119,7 +173,27 @@ fn denoise_inner(
         };
         let t_vec = Tensor::full(*t_curr as f32, b_sz, dev)?;
         let pred = model.forward(&img, img_ids, txt, txt_ids, &t_vec, vec_, guidance.as_ref())?;
-        img = (img + pred * (t_prev - t_curr))?
+        // Classifier-free guidance away from the negative prompt
+        let pred = match negative {
+            Some((negative, scale)) => {
+                let negative_pred = model.forward(
+                    &img,
+                    img_ids,
+                    &negative.txt,
+                    &negative.txt_ids,
+                    &t_vec,
+                    &negative.vec,
+                    guidance.as_ref(),
+                )?;
+                (&negative_pred + ((pred - &negative_pred)? * scale)?)?
+            }
+            None => pred,
+        };
+        img = (img + pred * (t_prev - t_curr))?;
+        if let Some(inpainting) = inpainting {
+            // Outside of the mask, the latents follow the original image.
+            img = inpainting.keep_unmasked(&img, *t_prev)?;
+        }
     }
     Ok(img)
 }
@@ -134,6 +208,8 @@ pub fn denoise(
     vec_: &Tensor,
     timesteps: &[f64],
     guidance: f64,
+    negative: Option<(&State, f64)>,
+    inpainting: Option<&Inpainting>,
 ) -> Result<Tensor> {
     denoise_inner(
         model,
@@ -144,6 +220,8 @@ pub fn denoise(
         vec_,
         timesteps,
         Some(guidance),
+        negative,
+        inpainting,
     )
 }
 
@@ -156,6 +234,69 @@ pub fn denoise_no_guidance(
     txt_ids: &Tensor,
     vec_: &Tensor,
     timesteps: &[f64],
+    negative: Option<(&State, f64)>,
+    inpainting: Option<&Inpainting>,
 ) -> Result<Tensor> {
-    denoise_inner(model, img, img_ids, txt, txt_ids, vec_, timesteps, None)
+    denoise_inner(
+        model, img, img_ids, txt, txt_ids, vec_, timesteps, None, negative, inpainting,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{get_noise, get_schedule, skip_steps, Inpainting};
+
+    #[test]
+    fn test_seeded_noise() {
+        let noise = |seed| {
+            let noise = get_noise(2, 64, 64, Some(seed), &Device::Cpu).unwrap();
+            assert_eq!(noise.dims(), [2, 16, 8, 8]);
+            noise.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        };
+        let a = noise(0);
+        let n = a.len() / 2;
+        // The second sample is seeded with `seed + 1`, so it is the first sample of the next seed.
+        assert_ne!(a[..n], a[n..]);
+        assert_eq!(a[n..], noise(1)[..n]);
+        // The noise is standard normal.
+        let mean = a.iter().sum::<f32>() / a.len() as f32;
+        let var = a.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / a.len() as f32;
+        assert!(mean.abs() < 0.15, "{mean}");
+        assert!((var - 1.0).abs() < 0.2, "{var}");
+
+        assert_eq!(noise(0), noise(0));
+        assert_ne!(noise(0), noise(1));
+    }
+
+    #[test]
+    fn test_skip_steps() {
+        let timesteps = get_schedule(4, None);
+        assert_eq!(skip_steps(&timesteps, 1.0), [1.0, 0.75, 0.5, 0.25, 0.0]);
+        assert_eq!(skip_steps(&timesteps, 0.6), [0.5, 0.25, 0.0]);
+        assert_eq!(skip_steps(&timesteps, 0.0), [0.0]);
+    }
+
+    #[test]
+    fn test_inpainting_keeps_unmasked_latents() {
+        let dev = &Device::Cpu;
+        let inpainting = Inpainting {
+            image: Tensor::new(&[[1f32, 2., 3., 4.]], dev).unwrap(),
+            noise: Tensor::new(&[[-1f32, -1., -1., -1.]], dev).unwrap(),
+            mask: Tensor::new(&[[1u8, 0, 1, 0]], dev).unwrap(),
+        };
+        let img = Tensor::new(&[[10f32, 20., 30., 40.]], dev).unwrap();
+        let keep = |t| {
+            inpainting
+                .keep_unmasked(&img, t)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        };
+        // The masked latents are denoised, and the others are the image noised to the timestep.
+        assert_eq!(keep(0.5), [[10., 0.5, 30., 1.5]]);
+        // At the end of the schedule, the unmasked latents are exactly those of the image.
+        assert_eq!(keep(0.0), [[10., 2., 30., 4.]]);
+    }
 }
@@ -3,6 +3,7 @@ use std::{cmp::Ordering, fs::File};
 use candle_core::{DType, Device, Result, Tensor, D};
 use candle_nn::{Module, VarBuilder};
 use hf_hub::api::sync::{Api, ApiError};
+use image::{imageops::FilterType, DynamicImage};
 use tokenizers::Tokenizer;
 use tracing::info;
 
@@ -11,7 +12,7 @@ use crate::{
         clip::text::{ClipConfig, ClipTextTransformer},
         flux,
         t5::{self, T5EncoderModel},
-        DiffusionGenerationParams,
+        DiffusionGenerationParams, DiffusionInitImage,
     },
     pipeline::DiffusionModel,
     utils::varbuilder_utils::from_mmaped_safetensors,
@@ -22,6 +23,13 @@ use super::{autoencoder::AutoEncoder, model::Flux};
 const T5_XXL_SAFETENSOR_FILES: &[&str] =
     &["t5_xxl-shard-0.safetensors", "t5_xxl-shard-1.safetensors"];
 
+/// Guidance scale used when a request does not set one.
+const DEFAULT_GUIDANCE_SCALE: f64 = 4.0;
+/// Scale of the guidance away from a negative prompt, when the request does not set one.
+const DEFAULT_NEGATIVE_GUIDANCE_SCALE: f64 = 4.0;
+/// Strength of an edit without a mask, when the request does not set one.
+const DEFAULT_STRENGTH: f64 = 0.6;
+
 #[derive(Clone, Copy, Debug)]
 pub struct FluxStepperShift {
     pub base_shift: f64,
@@ -44,7 +52,7 @@ impl FluxStepperConfig {
                 guidance_config: Some(FluxStepperShift {
                     base_shift: 0.5,
                     max_shift: 1.15,
-                    guidance_scale: 4.0,
+                    guidance_scale: DEFAULT_GUIDANCE_SCALE,
                 }),
                 is_guidance: true,
             }
@@ -150,6 +158,50 @@ fn get_tokenization(tok: &Tokenizer, prompts: Vec<String>, device: &Device) -> R
     )
 }
 
+/// The image as a tensor of shape `(1, 3, height, width)`, with values in [-1, 1].
+#[allow(clippy::cast_possible_truncation)]
+fn image_to_tensor(
+    image: &DynamicImage,
+    height: usize,
+    width: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let image = image
+        .resize_exact(width as u32, height as u32, FilterType::Lanczos3)
+        .to_rgb8();
+    let image = Tensor::from_vec(image.into_raw(), (height, width, 3), device)?
+        .permute((2, 0, 1))?
+        .unsqueeze(0)?
+        .to_dtype(DType::F32)?;
+    (image / 127.5)? - 1.
+}
+
+/// The mask as a u8 tensor of shape `(1, 1, height, width)`, which is 1 in the areas to change.
+/// These are the transparent areas of the mask, or its white areas if it is opaque.
+#[allow(clippy::cast_possible_truncation)]
+fn mask_to_tensor(
+    mask: &DynamicImage,
+    height: usize,
+    width: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let mask = mask
+        .resize_exact(width as u32, height as u32, FilterType::Triangle)
+        .to_luma_alpha8();
+    let transparent = mask.pixels().any(|p| p.0[1] < u8::MAX);
+    let values = mask
+        .pixels()
+        .map(|p| {
+            if transparent {
+                u8::from(p.0[1] < 128)
+            } else {
+                u8::from(p.0[0] >= 128)
+            }
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(values, (1, 1, height, width), device)
+}
+
 impl FluxStepper {
     pub fn new(
         cfg: FluxStepperConfig,
@@ -182,14 +234,13 @@ impl FluxStepper {
             offloaded,
         })
     }
-}
 
-impl DiffusionModel for FluxStepper {
-    fn forward(
-        &mut self,
+    /// The T5 and CLIP embeddings of the prompts.
+    fn encode(
+        &self,
+        t5_encoder: &mut T5EncoderModel,
         prompts: Vec<String>,
-        params: DiffusionGenerationParams,
-    ) -> Result<Tensor> {
+    ) -> Result<(Tensor, Tensor)> {
         let mut t5_input_ids = get_tokenization(&self.t5_tok, prompts.clone(), &self.device)?;
         if !self.is_guidance {
             match t5_input_ids.dim(1)?.cmp(&256) {
@@ -203,7 +254,27 @@ impl DiffusionModel for FluxStepper {
             }
         }
 
-        let t5_embed = {
+        let t5_embed = t5_encoder.forward(&t5_input_ids)?;
+
+        let clip_input_ids = get_tokenization(&self.clip_tok, prompts, &self.device)?;
+        let clip_embed = self
+            .clip_text
+            .forward(&clip_input_ids)?
+            .to_dtype(self.dtype)?;
+
+        Ok((t5_embed, clip_embed))
+    }
+}
+
+impl DiffusionModel for FluxStepper {
+    fn forward(
+        &mut self,
+        prompts: Vec<String>,
+        params: DiffusionGenerationParams,
+        init_image: Option<DiffusionInitImage>,
+    ) -> Result<Tensor> {
+        let n_prompts = prompts.len();
+        let ((t5_embed, clip_embed), negative_embeds) = {
             info!("Hotloading T5 XXL model.");
             let mut t5_encoder = get_t5_model(
                 &self.api,
@@ -212,32 +283,89 @@ impl DiffusionModel for FluxStepper {
                 self.silent,
                 self.offloaded,
             )?;
-            t5_encoder.forward(&t5_input_ids)?
+            let embeds = self.encode(&mut t5_encoder, prompts)?;
+            let negative_embeds = params
+                .negative_prompt
+                .as_ref()
+                .map(|negative| self.encode(&mut t5_encoder, vec![negative.clone(); n_prompts]))
+                .transpose()?;
+            (embeds, negative_embeds)
         };
 
-        let clip_input_ids = get_tokenization(&self.clip_tok, prompts, &self.device)?;
-        let clip_embed = self
-            .clip_text
-            .forward(&clip_input_ids)?
-            .to_dtype(self.dtype)?;
-
-        let img = flux::sampling::get_noise(
+        let noise = flux::sampling::get_noise(
             t5_embed.dim(0)?,
             params.height,
             params.width,
+            params.seed,
             self.device(),
         )?
         .to_dtype(self.dtype)?;
+        let (_, _, latent_h, latent_w) = noise.dims4()?;
 
-        let state = flux::sampling::State::new(&t5_embed, &clip_embed, &img)?;
         let timesteps = flux::sampling::get_schedule(
-            self.cfg.num_steps,
+            params.num_steps.unwrap_or(self.cfg.num_steps),
             self.cfg
                 .guidance_config
-                .map(|s| (state.img.dims()[1], s.base_shift, s.max_shift)),
+                .map(|s| (latent_h / 2 * latent_w / 2, s.base_shift, s.max_shift)),
         );
 
-        let img = if let Some(guidance_cfg) = &self.cfg.guidance_config {
+        let (img, timesteps, inpainting) = match init_image {
+            None => (noise, timesteps.as_slice(), None),
+            Some(DiffusionInitImage { image, mask }) => {
+                // The image is encoded at the size of the output.
+                let image = image_to_tensor(&image, latent_h * 8, latent_w * 8, &self.device)?
+                    .to_dtype(self.dtype)?;
+                let image = self
+                    .flux_vae
+                    .encode_mean(&image)?
+                    .repeat((noise.dim(0)?, 1, 1, 1))?;
+
+                let strength = params.strength.unwrap_or(if mask.is_some() {
+                    1.0
+                } else {
+                    DEFAULT_STRENGTH
+                });
+                let timesteps = flux::sampling::skip_steps(&timesteps, strength);
+                // Start from the image, noised to the first timestep.
+                let t = timesteps[0];
+                let img = ((&image * (1. - t))? + (&noise * t)?)?;
+
+                let inpainting = match mask {
+                    Some(mask) => {
+                        let mask = mask_to_tensor(&mask, latent_h, latent_w, &self.device)?
+                            .repeat((noise.dim(0)?, noise.dim(1)?, 1, 1))?;
+                        Some(flux::sampling::Inpainting {
+                            image: flux::sampling::pack(&image)?,
+                            noise: flux::sampling::pack(&noise)?,
+                            mask: flux::sampling::pack(&mask)?,
+                        })
+                    }
+                    None => None,
+                };
+                (img, timesteps, inpainting)
+            }
+        };
+
+        let state = flux::sampling::State::new(&t5_embed, &clip_embed, &img)?;
+        let negative_state = negative_embeds
+            .map(|(t5_embed, clip_embed)| flux::sampling::State::new(&t5_embed, &clip_embed, &img))
+            .transpose()?;
+
+        let guidance_scale = params.guidance_scale.unwrap_or(
+            self.cfg
+                .guidance_config
+                .map_or(DEFAULT_GUIDANCE_SCALE, |s| s.guidance_scale),
+        );
+        let negative = negative_state.as_ref().map(|s| {
+            (
+                s,
+                params
+                    .negative_guidance_scale
+                    .unwrap_or(DEFAULT_NEGATIVE_GUIDANCE_SCALE),
+            )
+        });
+
+        let img = if self.cfg.guidance_config.is_some() {
             flux::sampling::denoise(
                 &mut self.flux_model,
                 &state.img,
@@ -245,8 +373,10 @@ impl DiffusionModel for FluxStepper {
                 &state.txt,
                 &state.txt_ids,
                 &state.vec,
-                &timesteps,
-                guidance_cfg.guidance_scale,
+                timesteps,
+                guidance_scale,
+                negative,
+                inpainting.as_ref(),
             )?
         } else {
             flux::sampling::denoise_no_guidance(
@@ -256,7 +386,9 @@ impl DiffusionModel for FluxStepper {
                 &state.txt,
                 &state.txt_ids,
                 &state.vec,
-                &timesteps,
+                timesteps,
+                negative,
+                inpainting.as_ref(),
             )?
         };
 
@@ -281,3 +413,43 @@ impl DiffusionModel for FluxStepper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use super::mask_to_tensor;
+
+    /// An 8x8 mask, with `left` in the 4 left columns and `right` in the others.
+    fn mask(left: [u8; 4], right: [u8; 4]) -> Vec<Vec<u8>> {
+        let image = RgbaImage::from_fn(8, 8, |x, _| Rgba(if x < 4 { left } else { right }));
+        mask_to_tensor(&DynamicImage::ImageRgba8(image), 8, 8, &Device::Cpu)
+            .unwrap()
+            .squeeze(0)
+            .unwrap()
+            .squeeze(0)
+            .unwrap()
+            .to_vec2::<u8>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_opaque_mask_changes_white_areas() {
+        let mask = mask([255, 255, 255, 255], [0, 0, 0, 255]);
+        for row in mask {
+            assert_eq!(row[0], 1);
+            assert_eq!(row[7], 0);
+        }
+    }
+
+    #[test]
+    fn test_transparent_mask_changes_transparent_areas() {
+        // The alpha decides, whatever the color.
+        let mask = mask([0, 0, 0, 0], [255, 255, 255, 255]);
+        for row in mask {
+            assert_eq!(row[0], 1);
+            assert_eq!(row[7], 0);
+        }
+    }
+}
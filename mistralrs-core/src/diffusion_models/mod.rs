@@ -3,6 +3,8 @@ pub(crate) mod flux;
 pub(crate) mod processor;
 pub(crate) mod t5;
 
+use image::DynamicImage;
+
 macro_rules! generate_repr {
     ($t:ident) => {
         #[cfg(feature = "pyo3_macros")]
@@ -15,22 +17,87 @@ macro_rules! generate_repr {
     };
 }
 
+/// The largest height or width of a generated image.
+pub const MAX_DIFFUSION_IMAGE_SIZE: usize = 2048;
+
 #[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DiffusionGenerationParams {
     pub height: usize,
     pub width: usize,
+    /// Number of denoising steps. Defaults to 50 for models with guidance distillation (FLUX.1-dev)
+    /// and 4 otherwise (FLUX.1-schnell).
+    pub num_steps: Option<usize>,
+    /// Strength of the guidance, 4.0 by default. Models with guidance distillation are conditioned
+    /// on it.
+    pub guidance_scale: Option<f64>,
+    /// Seed of the initial noise. Images generated with the same seed and parameters are the same.
+    pub seed: Option<u64>,
+    /// What the image should not contain. Each denoising step also runs the model with this prompt,
+    /// so generation is about twice as slow.
+    pub negative_prompt: Option<String>,
+    /// Scale of the classifier-free guidance away from the negative prompt, 4.0 by default.
+    pub negative_guidance_scale: Option<f64>,
+    /// How much an edited image is changed, from 0 (not at all) to 1 (completely). Defaults to 0.6,
+    /// or 1 when editing only the areas in a mask.
+    pub strength: Option<f64>,
 }
 
 generate_repr!(DiffusionGenerationParams);
 
 impl Default for DiffusionGenerationParams {
-    /// Image dimensions will be 720x1280.
+    /// Image dimensions will be 720x1280, with the default steps and guidance of the model.
     fn default() -> Self {
         Self {
             height: 720,
             width: 1280,
+            num_steps: None,
+            guidance_scale: None,
+            seed: None,
+            negative_prompt: None,
+            negative_guidance_scale: None,
+            strength: None,
         }
     }
 }
+
+impl DiffusionGenerationParams {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.height == 0 || self.width == 0 {
+            anyhow::bail!("Image dimensions must be positive.");
+        }
+        if self.height > MAX_DIFFUSION_IMAGE_SIZE || self.width > MAX_DIFFUSION_IMAGE_SIZE {
+            anyhow::bail!("Image dimensions must be at most {MAX_DIFFUSION_IMAGE_SIZE}.");
+        }
+        if self.num_steps == Some(0) {
+            anyhow::bail!("The number of steps must be positive.");
+        }
+        if self
+            .guidance_scale
+            .is_some_and(|scale| !scale.is_finite() || scale <= 0.0)
+        {
+            anyhow::bail!("The guidance scale must be positive.");
+        }
+        if self
+            .negative_guidance_scale
+            .is_some_and(|scale| !scale.is_finite() || scale <= 0.0)
+        {
+            anyhow::bail!("The negative guidance scale must be positive.");
+        }
+        if self
+            .strength
+            .is_some_and(|strength| !(0.0..=1.0).contains(&strength))
+        {
+            anyhow::bail!("The strength must be between 0 and 1.");
+        }
+        Ok(())
+    }
+}
+
+/// The image which an edit starts from, and the mask of the areas to change.
+#[derive(Debug, Clone)]
+pub struct DiffusionInitImage {
+    pub image: DynamicImage,
+    pub mask: Option<DynamicImage>,
+}
@@ -14,7 +14,7 @@ use crate::{
     MessageContent, Pipeline,
 };
 
-use super::DiffusionGenerationParams;
+use super::{DiffusionGenerationParams, DiffusionInitImage};
 
 pub struct DiffusionProcessor;
 
@@ -49,6 +49,7 @@ pub struct DiffusionInputsProcessor;
 pub struct ModelInputs {
     pub(crate) prompts: Vec<String>,
     pub(crate) params: DiffusionGenerationParams,
+    pub(crate) init_image: Option<DiffusionInitImage>,
 }
 
 impl InputsProcessor for DiffusionInputsProcessor {
@@ -70,27 +71,60 @@ impl InputsProcessor for DiffusionInputsProcessor {
         _paged_attn_metadata: Option<PagedAttentionMeta<'_>>,
         prompt_batchsize: Option<NonZeroUsize>,
     ) -> Box<dyn Iterator<Item = Result<InputProcessorOutput>>> {
-        let mut make_value = if prompt_batchsize.is_some() {
+        if prompt_batchsize.is_some() {
             return Box::new(std::iter::once(Err(anyhow::Error::msg(
                 "Prompt batching is unsupported for diffusion models",
             ))));
-        } else {
-            || {
+        }
+
+        // Sequences are generated together only if they have the same parameters. Edits each start
+        // from their own image, so they are generated alone.
+        let mut groups: Vec<(
+            DiffusionGenerationParams,
+            Option<DiffusionInitImage>,
+            Vec<usize>,
+        )> = Vec::new();
+        for (i, seq) in input_seqs.iter().enumerate() {
+            let params = match seq
+                .get_diffusion_diffusion_params()
+                .context("Diffusion model params must be present")
+            {
+                Ok(params) => params,
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            };
+            // The images of an edit are the image to edit, followed by the mask if there is one.
+            let init_image = seq.images().and_then(|images| {
+                Some(DiffusionInitImage {
+                    image: images.first()?.clone(),
+                    mask: images.get(1).cloned(),
+                })
+            });
+            match groups
+                .iter_mut()
+                .find(|(p, init, _)| *p == params && init.is_none() && init_image.is_none())
+            {
+                Some((_, _, seq_indices)) => seq_indices.push(i),
+                None => groups.push((params, init_image, vec![i])),
+            }
+        }
+
+        let outputs = groups
+            .into_iter()
+            .map(|(params, init_image, seq_indices)| {
                 let inputs = ModelInputs {
-                    prompts: input_seqs
-                        .iter_mut()
-                        .map(|seq| seq.get_initial_prompt().to_string())
+                    prompts: seq_indices
+                        .iter()
+                        .map(|i| input_seqs[*i].get_initial_prompt().to_string())
                         .collect::<Vec<_>>(),
-                    params: input_seqs[0]
-                        .get_diffusion_diffusion_params()
-                        .context("Diffusion model params must be present")?,
+                    params,
+                    init_image,
                 };
                 Ok(InputProcessorOutput {
                     inputs: Box::new(inputs),
-                    seq_indices: (0..input_seqs.len()).collect::<Vec<_>>(),
+                    seq_indices,
                 })
-            }
-        };
-        Box::new(std::iter::once(make_value()))
+            })
+            .collect::<Vec<_>>();
+        Box::new(outputs.into_iter())
     }
 }
@@ -0,0 +1,268 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// BERT-style encoder-only model, used to compute embeddings.
+/// https://huggingface.co/docs/transformers/model_doc/bert
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn::{embedding, layer_norm, linear, Embedding, LayerNorm, Linear, VarBuilder};
+use serde::Deserialize;
+
+use crate::{layers::Activation, pipeline::EmbeddingModel, serde_default_fn};
+
+serde_default_fn!(usize, type_vocab_size_default, 2);
+serde_default_fn!(f64, layer_norm_eps_default, 1e-12);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) hidden_act: Activation,
+    pub(crate) max_position_embeddings: usize,
+    #[serde(default = "type_vocab_size_default")]
+    pub(crate) type_vocab_size: usize,
+    #[serde(default = "layer_norm_eps_default")]
+    pub(crate) layer_norm_eps: f64,
+}
+
+struct Embeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl Embeddings {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("word_embeddings"))?,
+            position_embeddings: embedding(
+                cfg.max_position_embeddings,
+                cfg.hidden_size,
+                vb.pp("position_embeddings"),
+            )?,
+            token_type_embeddings: embedding(
+                cfg.type_vocab_size,
+                cfg.hidden_size,
+                vb.pp("token_type_embeddings"),
+            )?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("LayerNorm"))?,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let (_bs, seq_len) = input_ids.dims2()?;
+        let position_ids = Tensor::arange(0u32, seq_len as u32, input_ids.device())?;
+        // Only single segment inputs are supported, so the token type is always 0.
+        let token_type_ids = input_ids.zeros_like()?;
+        let xs = self
+            .word_embeddings
+            .forward(input_ids)?
+            .broadcast_add(&self.position_embeddings.forward(&position_ids)?)?
+            .add(&self.token_type_embeddings.forward(&token_type_ids)?)?;
+        self.layer_norm.forward(&xs)
+    }
+}
+
+struct Attention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    dense: Linear,
+    layer_norm: LayerNorm,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_self = vb.pp("self");
+        let vb_output = vb.pp("output");
+        Ok(Self {
+            query: linear(cfg.hidden_size, cfg.hidden_size, vb_self.pp("query"))?,
+            key: linear(cfg.hidden_size, cfg.hidden_size, vb_self.pp("key"))?,
+            value: linear(cfg.hidden_size, cfg.hidden_size, vb_self.pp("value"))?,
+            dense: linear(cfg.hidden_size, cfg.hidden_size, vb_output.pp("dense"))?,
+            layer_norm: layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_eps,
+                vb_output.pp("LayerNorm"),
+            )?,
+            num_heads: cfg.num_attention_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (bs, seq_len, hidden_size) = xs.dims3()?;
+        let heads = |t: Tensor| {
+            t.reshape((bs, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let q = heads(self.query.forward(xs)?)?;
+        let k = heads(self.key.forward(xs)?)?;
+        let v = heads(self.value.forward(xs)?)?;
+
+        // The attention is bidirectional and there is no padding, so no mask is needed.
+        let scale = 1. / (self.head_dim as f64).sqrt();
+        let attn_weights = (q.matmul(&k.t()?)? * scale)?;
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let attn_output = attn_weights.matmul(&v)?.transpose(1, 2)?;
+        let attn_output = attn_output.reshape((bs, seq_len, hidden_size))?;
+        self.layer_norm
+            .forward(&(self.dense.forward(&attn_output)? + xs)?)
+    }
+}
+
+struct Layer {
+    attention: Attention,
+    intermediate: Linear,
+    output: Linear,
+    output_layer_norm: LayerNorm,
+    act: Activation,
+}
+
+impl Layer {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            attention: Attention::new(cfg, vb.pp("attention"))?,
+            intermediate: linear(
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                vb.pp("intermediate").pp("dense"),
+            )?,
+            output: linear(
+                cfg.intermediate_size,
+                cfg.hidden_size,
+                vb.pp("output").pp("dense"),
+            )?,
+            output_layer_norm: layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_eps,
+                vb.pp("output").pp("LayerNorm"),
+            )?,
+            act: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.attention.forward(xs)?;
+        let hidden = self
+            .intermediate
+            .forward(&xs)?
+            .apply(&self.act)?
+            .apply(&self.output)?;
+        self.output_layer_norm.forward(&(hidden + xs)?)
+    }
+}
+
+pub struct Model {
+    embeddings: Embeddings,
+    layers: Vec<Layer>,
+    device: Device,
+    max_seq_len: usize,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        // Checkpoints exported from `BertForMaskedLM` and similar nest the encoder under `bert`.
+        let vb = if vb.contains_tensor("bert.embeddings.word_embeddings.weight") {
+            vb.pp("bert")
+        } else {
+            vb
+        };
+        let embeddings = Embeddings::new(cfg, vb.pp("embeddings"))?;
+        let vb_l = vb.pp("encoder").pp("layer");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| Layer::new(cfg, vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            embeddings,
+            layers,
+            device: vb.device().clone(),
+            max_seq_len: cfg.max_position_embeddings,
+        })
+    }
+}
+
+impl EmbeddingModel for Model {
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let mut xs = self.embeddings.forward(input_ids)?;
+        for layer in &self.layers {
+            xs = layer.forward(&xs)?;
+        }
+        Ok(xs)
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+
+    use super::{Config, Model};
+    use crate::{layers::Activation, pipeline::EmbeddingModel};
+
+    fn tiny_config() -> Config {
+        Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            intermediate_size: 16,
+            hidden_act: Activation::Gelu,
+            max_position_embeddings: 12,
+            type_vocab_size: 2,
+            layer_norm_eps: 1e-12,
+        }
+    }
+
+    #[test]
+    fn forward_shape() {
+        let cfg = tiny_config();
+        let var_map = VarMap::new();
+        let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+        let model = Model::new(&cfg, vb).unwrap();
+        assert_eq!(model.max_seq_len(), 12);
+
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 2, 0]], &Device::Cpu).unwrap();
+        let hidden_states = model.forward(&input_ids).unwrap();
+        assert_eq!(hidden_states.dims(), &[1, 5, 8]);
+        // Every layer ends with a layer norm with unit weight and zero bias.
+        let means = hidden_states.mean(2).unwrap().flatten_all().unwrap();
+        for mean in means.to_vec1::<f32>().unwrap() {
+            assert!(mean.abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn bert_prefix() {
+        let cfg = tiny_config();
+        let var_map = VarMap::new();
+        let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+        let prefixed = Model::new(&cfg, vb.pp("bert")).unwrap();
+        // The weights now live under `bert.`, which must be detected from the root.
+        let unprefixed = Model::new(&cfg, vb).unwrap();
+
+        let input_ids = Tensor::new(&[[3u32, 4, 9]], &Device::Cpu).unwrap();
+        let a = prefixed.forward(&input_ids).unwrap();
+        let b = unprefixed.forward(&input_ids).unwrap();
+        let diff = (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert_eq!(diff, 0.);
+    }
+}
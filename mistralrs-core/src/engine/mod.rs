@@ -9,20 +9,27 @@ use std::{
     },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Mutex,
+};
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    aici::{
+        cfg::CfgParser, json_schema::json_schema_constraint, recognizer::StackRecognizer, rx::RecRx,
+    },
     pipeline::{
-        text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
-        CacheInstruction, EitherCache, NormalCache,
+        text_models_inputs_processor::PagedAttentionMeta, AdapterActivationMixin,
+        AdapterInstruction, BeamSearchState, CacheBackendMetadata, CacheInstruction, EitherCache,
+        KvCacheDType, NormalCache,
     },
     request::{DetokenizationRequest, NormalRequest, TokenizationRequest},
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{SeqStepType, StopReason},
-    tools::{ToolCallingMatcher, ToolChoice},
-    CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
+    tools::{ToolCallParser, ToolCallingMatcher, ToolChoice},
+    CompletionResponse, EmbeddingData, EmbeddingPooling, EmbeddingResponse, EmbeddingUsage,
+    ModelCategory, RequestMessage, Response, SchedulerConfig, DEBUG,
 };
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
@@ -31,10 +38,10 @@ use tracing::{info, warn};
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
     pipeline::Pipeline,
-    prefix_cacher::PrefixCacheManager,
+    prefix_cacher::{DiskPrefixCache, ModelKey, PrefixCacheDiskConfig, PrefixCacheManager},
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
+    sampler::{Sampler, SamplingParams},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
     Constraint, StopTokens,
 };
@@ -62,6 +69,99 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    adapters: AdapterState,
+    tool_call_parser: Option<ToolCallParser>,
+}
+
+/// Tracks the active adapters, so that they are only swapped when the scheduled sequences need
+/// different ones. Sequences which do not select adapters run with the default adapters, so the
+/// adapters of one request do not stay active for later requests.
+struct AdapterState {
+    default: Option<Vec<String>>,
+    // `None` if unknown, such as after a step which activated adapters failed.
+    active: Option<Vec<String>>,
+}
+
+impl AdapterState {
+    fn for_seq(&self, seq: &Sequence) -> Option<Vec<String>> {
+        seq.get_adapters().or_else(|| self.default.clone())
+    }
+
+    /// The adapters of the sequences, if they all use the same ones.
+    fn uniform(&self, seqs: &[&mut Sequence]) -> Option<Option<Vec<String>>> {
+        let first = self.for_seq(seqs.first()?);
+        seqs[1..]
+            .iter()
+            .all(|seq| self.for_seq(seq) == first)
+            .then_some(first)
+    }
+
+    /// The adapter instruction for a step running `seqs`. The adapters are only known to be active
+    /// once the step succeeds, see [`AdapterState::step_succeeded`]. Batches which mix adapters
+    /// are only scheduled if the pipeline supports them.
+    fn instruction(&mut self, seqs: &[&mut Sequence]) -> AdapterInstruction {
+        match self.uniform(seqs) {
+            Some(Some(adapters)) if self.active.as_ref() != Some(&adapters) => {
+                self.active = None;
+                AdapterInstruction::Activate(adapters)
+            }
+            Some(_) => AdapterInstruction::None,
+            None => {
+                self.active = None;
+                AdapterInstruction::ActivatePerSeq(
+                    seqs.iter()
+                        .map(|seq| self.for_seq(seq).unwrap_or_default())
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn step_succeeded(&mut self, seqs: &[&mut Sequence]) {
+        if let Some(Some(adapters)) = self.uniform(seqs) {
+            self.active = Some(adapters);
+        }
+    }
+
+    /// Activate adapters, which become the default adapters of sequences which select none.
+    fn activate(
+        &mut self,
+        pipeline: &mut (impl AdapterActivationMixin + ?Sized),
+        adapters: Vec<String>,
+    ) -> anyhow::Result<usize> {
+        match pipeline.activate_adapters(adapters.clone()) {
+            Ok(n) => {
+                self.default = Some(adapters.clone());
+                self.active = Some(adapters);
+                Ok(n)
+            }
+            Err(e) => {
+                self.active = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Unload an adapter, unless it is a default adapter or `in_use` by a running or waiting
+    /// sequence.
+    fn unload(
+        &self,
+        pipeline: &mut (impl AdapterActivationMixin + ?Sized),
+        name: &str,
+        in_use: bool,
+    ) -> anyhow::Result<usize> {
+        if self
+            .default
+            .as_ref()
+            .is_some_and(|adapters| adapters.iter().any(|adapter| adapter == name))
+        {
+            anyhow::bail!("Adapter `{name}` is active, activate other adapters first.");
+        }
+        if in_use {
+            anyhow::bail!("Adapter `{name}` is used by a running or waiting request.");
+        }
+        pipeline.unload_adapter(name)
+    }
 }
 
 impl Engine {
@@ -74,8 +174,10 @@ impl Engine {
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_disk: Option<PrefixCacheDiskConfig>,
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
+        tool_call_parser: Option<ToolCallParser>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
@@ -84,15 +186,57 @@ impl Engine {
             // Diffusion models...
             assert_eq!(has_no_kv_cache, no_kv_cache);
         }
-        // Prefix caching is always disabled if using PagedAttention for now.
-        // TODO
-        let no_prefix_cache = matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
-            || no_prefix_cache
-            || has_no_kv_cache;
+        let is_text = get_mut_arcmutex!(pipeline).category() == ModelCategory::Text;
+        let supports_prefix_cacher =
+            Self::supports_prefix_cacher(&*get_mut_arcmutex!(pipeline), &config);
+        let mut scheduler = config.into_scheduler();
+        // Blocks are identified by their tokens, which do not identify the images of a vision model.
+        if let Some(block_engine) = scheduler.block_engine() {
+            block_engine
+                .set_prefix_caching(!no_prefix_cache && !has_no_kv_cache && !is_xlora && is_text);
+        }
+        let no_prefix_cache = no_prefix_cache || !supports_prefix_cacher;
+        let default_adapters = get_mut_arcmutex!(pipeline).initial_adapters();
+        scheduler.set_mixed_adapters(get_mut_arcmutex!(pipeline).supports_mixed_adapters());
+        let disk_cache = prefix_cache_disk
+            .filter(|_| !no_prefix_cache)
+            .and_then(|config| {
+                let pipeline = get_mut_arcmutex!(pipeline);
+                let kv_dtype = match pipeline.cache() {
+                    EitherCache::Normal(cache) => cache
+                        .lock()
+                        .unwrap()
+                        .0
+                        .first()
+                        .map_or(KvCacheDType::Auto, |layer| layer.kv_dtype()),
+                    EitherCache::Full(_) => KvCacheDType::Auto,
+                };
+                let metadata = pipeline.get_metadata();
+                let model_id = pipeline.name();
+                let model_key = ModelKey {
+                    revision: ModelKey::resolve_revision(&model_id, config.revision.as_deref()),
+                    model_id,
+                    kind: metadata.kind.to_string(),
+                    isq: config.isq,
+                    dtype: metadata.activation_dtype,
+                    kv_dtype,
+                };
+                let dir = config.dir.clone();
+                match DiskPrefixCache::new(config, model_key) {
+                    Ok(disk_cache) => Some(disk_cache),
+                    Err(e) => {
+                        warn!(
+                            "Disabling the disk prefix cache at `{}`: {e}",
+                            dir.display()
+                        );
+                        None
+                    }
+                }
+            });
         Self {
             rx,
             pipeline,
-            scheduler: config.into_scheduler(),
+            scheduler,
             id: 0,
             truncate_sequence,
             no_kv_cache: no_kv_cache & !has_no_kv_cache,
@@ -101,13 +245,31 @@ impl Engine {
                 prefix_cache_n,
                 is_xlora,
                 no_prefix_cache,
+                disk_cache,
             ),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
+            adapters: AdapterState {
+                active: default_adapters.clone(),
+                default: default_adapters,
+            },
+            tool_call_parser,
         }
     }
 
+    /// Whether the prefix cacher can be used with a pipeline. With PagedAttention, prefixes are
+    /// shared at the block level by the block engine instead.
+    pub(crate) fn supports_prefix_cacher(
+        pipeline: &dyn Pipeline,
+        config: &SchedulerConfig,
+    ) -> bool {
+        !matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
+            && !pipeline.get_metadata().has_no_kv_cache
+            && pipeline.category() == ModelCategory::Text
+            && pipeline.supports_prefix_cache()
+    }
+
     pub async fn run(&mut self) {
         let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)));
         let mut last_completion_ids: Vec<usize> = vec![];
@@ -143,22 +305,15 @@ impl Engine {
                             scheduled.completion.iter().map(|seq| *seq.id()).collect();
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                            let adapter_inst = self.adapters.instruction(&scheduled.completion);
+                            // Beam search replaces the KV caches of the beams while sampling.
                             let pre_op = if !self.no_kv_cache
-                                && last_completion_ids != current_completion_ids
+                                && (last_completion_ids != current_completion_ids
+                                    || scheduled.completion.iter().any(|seq| seq.is_beam()))
                             {
-                                CacheInstruction::In(
-                                    scheduled.completion[0]
-                                        .get_adapters()
-                                        .map(AdapterInstruction::Activate)
-                                        .unwrap_or(AdapterInstruction::None),
-                                )
+                                CacheInstruction::In(adapter_inst)
                             } else {
-                                CacheInstruction::Nothing(
-                                    scheduled.completion[0]
-                                        .get_adapters()
-                                        .map(AdapterInstruction::Activate)
-                                        .unwrap_or(AdapterInstruction::None),
-                                )
+                                CacheInstruction::Nothing(adapter_inst)
                             };
                             let post_op = if !self.no_kv_cache {
                                 CacheInstruction::Out
@@ -200,6 +355,7 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        self.adapters.step_succeeded(&scheduled.completion);
 
                         let throughput_end = Instant::now();
                         #[allow(clippy::cast_precision_loss)]
@@ -229,10 +385,7 @@ impl Engine {
                                     adapter_inst: AdapterInstruction::None,
                                 }
                             };
-                            let adapter_inst = scheduled.prompt[0]
-                                .get_adapters()
-                                .map(AdapterInstruction::Activate)
-                                .unwrap_or(AdapterInstruction::None);
+                            let adapter_inst = self.adapters.instruction(&scheduled.prompt);
 
                             let return_raw_logits = scheduled.prompt[0].return_raw_logits;
                             assert!(
@@ -243,8 +396,18 @@ impl Engine {
                                 "All sequences must either return raw logits, or not."
                             );
 
-                            // Reset non granular state because the old sequence must be dead.
+                            // Prefilled prompts continue from the cache of their prefix. Otherwise,
+                            // reset non granular state because the old sequence must be dead.
                             // Technically we don't need to do this but it is better to be safe.
+                            let pre_op = if scheduled.prompt[0].prefix_len() > 0 {
+                                CacheInstruction::In(adapter_inst)
+                            } else {
+                                CacheInstruction::Reset {
+                                    load_preallocated_cache: true,
+                                    reset_non_granular: false,
+                                    adapter_inst,
+                                }
+                            };
                             pipeline
                                 .step(
                                     &mut scheduled.prompt,
@@ -253,14 +416,7 @@ impl Engine {
                                     &mut self.prefix_cacher,
                                     self.disable_eos_stop,
                                     rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions {
-                                        pre_op: CacheInstruction::Reset {
-                                            load_preallocated_cache: true,
-                                            reset_non_granular: false,
-                                            adapter_inst,
-                                        },
-                                        post_op,
-                                    },
+                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
                                 )
                                 .await
                         };
@@ -273,6 +429,7 @@ impl Engine {
                             'lp,
                             self.prefix_cacher
                         );
+                        self.adapters.step_succeeded(&scheduled.prompt);
 
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
@@ -424,6 +581,20 @@ impl Engine {
                             self.prefix_cacher
                         );
 
+                        // Beams which continued from another beam share its blocks from now on.
+                        let forks = guards_mut
+                            .iter_mut()
+                            .filter_map(|seq| {
+                                seq.take_beam_fork().map(|parent| (*seq.id(), parent))
+                            })
+                            .collect::<Vec<_>>();
+                        if !forks.is_empty() {
+                            self.scheduler
+                                .block_engine()
+                                .unwrap()
+                                .fork_sequences(&forks);
+                        }
+
                         if self.is_debug {
                             let ms_from_last_run = run_start.elapsed().as_secs_f64();
                             let total_len = guards.len();
@@ -483,6 +654,10 @@ impl Engine {
 
             self.scheduler.free_finished_sequence_groups();
         }
+
+        if let Err(e) = self.prefix_cacher.flush_to_disk() {
+            warn!("Failed to write the prefix caches to disk: {e}");
+        }
     }
 
     fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
@@ -491,6 +666,9 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::JsonSchema(schema) => {
+                Self::build_sequence_recognizer(&json_schema_constraint(schema)?)?
+            }
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
@@ -499,24 +677,68 @@ impl Engine {
     async fn handle_request(&mut self, request: Request) {
         match request {
             Request::ActivateAdapters(adapters) => {
-                match get_mut_arcmutex!(self.pipeline).activate_adapters(adapters) {
+                let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                match self.adapters.activate(&mut *pipeline, adapters) {
                     Ok(n) => info!("Swapped adapters in {n} LoRA layers."),
                     Err(e) => warn!("Adapter activation failed: {e:?}"),
                 }
             }
+            Request::LoadAdapter(req) => {
+                let res = get_mut_arcmutex!(self.pipeline).load_adapter(req.name, req.path);
+                req.response.send(res).await.expect("Expected receiver.");
+            }
+            Request::UnloadAdapter(req) => {
+                let in_use = self.scheduler.uses_adapter(&req.name);
+                let res = {
+                    let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                    self.adapters.unload(&mut *pipeline, &req.name, in_use)
+                };
+                req.response.send(res).await.expect("Expected receiver.");
+            }
             Request::Normal(request) => self.add_request(request).await,
             Request::ReIsq(level) => {
                 if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
                     warn!("ISQ requantization failed: {e:?}");
+                } else {
+                    self.prefix_cacher.set_isq(level);
                 }
             }
             Request::Tokenize(req) => self.tokenize_text(req).await,
             Request::Detokenize(req) => self.detokenize_text(req).await,
+            Request::Cancel(id) => {
+                let n_canceled = self.scheduler.cancel_request(id);
+                if n_canceled > 0 {
+                    info!("Canceled {n_canceled} sequence(s) of request {id}.");
+                }
+            }
             Request::Terminate => panic!("This is unreachable in `handle_request`. Termination is handled in the `run` loop."),
         }
     }
 
     async fn add_request(&mut self, request: NormalRequest) {
+        if let RequestMessage::Embedding { inputs, pooling } = request.messages {
+            // Embeddings do not decode, so they are computed off the engine loop. The pipeline is
+            // locked for one input at a time, so that the loop keeps stepping the sequences.
+            tokio::spawn(Self::embed(
+                self.pipeline.clone(),
+                request.id,
+                inputs,
+                pooling,
+                request.response,
+            ));
+            return;
+        }
+        if get_mut_arcmutex!(self.pipeline).category() == ModelCategory::Embedding {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Embedding models can only be used for embedding requests.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -534,7 +756,9 @@ impl Engine {
             RequestMessage::Chat(_)
             | RequestMessage::CompletionTokens(_)
             | RequestMessage::VisionChat { .. }
-            | RequestMessage::ImageGeneration { .. } => 1,
+            | RequestMessage::ImageGeneration { .. }
+            | RequestMessage::ImageEdit { .. }
+            | RequestMessage::Embedding { .. } => 1,
         };
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
@@ -550,44 +774,126 @@ impl Engine {
             return;
         }
 
-        let images = match request.messages {
+        let (images, videos) = match request.messages {
             RequestMessage::VisionChat {
                 ref images,
+                ref videos,
                 messages: _,
-            } => Some(images.clone()),
-            _ => None,
+            } => (Some(images.clone()), Some(videos.clone())),
+            // The diffusion model gets the image to edit, followed by the mask if there is one.
+            RequestMessage::ImageEdit {
+                ref image,
+                ref mask,
+                ..
+            } => (
+                Some(std::iter::once(image).chain(mask).cloned().collect()),
+                None,
+            ),
+            _ => (None, None),
         };
+        if videos.as_ref().is_some_and(|videos| !videos.is_empty())
+            && !get_mut_arcmutex!(self.pipeline)
+                .get_processor()
+                .supports_videos()
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Received videos for a model which does not support video input.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
 
         let matcher = if request.tools.is_some() {
+            let parser = self.tool_call_parser.unwrap_or_else(|| {
+                // Detect the format of the tool calls from the chat template
+                let chat_template = get_mut_arcmutex!(self.pipeline).get_chat_template();
+                let template = chat_template
+                    .as_ref()
+                    .and_then(|ch_t| ch_t.chat_template.as_ref())
+                    .map(|template| match &template.0 {
+                        Either::Left(template) => template.clone(),
+                        Either::Right(templates) => templates
+                            .iter()
+                            .flat_map(|template| template.values())
+                            .map(String::as_str)
+                            .collect(),
+                    });
+                template
+                    .as_deref()
+                    .map(ToolCallParser::from_chat_template)
+                    .unwrap_or_default()
+            });
             Some(Arc::new(handle_seq_error!(
-                ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto),),
+                ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto), parser),
                 request.response
             )))
         } else {
             None
         };
 
+        // A required tool call is enforced with a grammar built from the tool schemas.
+        let constraint = match matcher
+            .as_ref()
+            .map(|matcher| matcher.constraint(request.tools.as_deref().unwrap_or_default()))
+        {
+            None | Some(Ok(Constraint::None)) => Ok(request.constraint.clone()),
+            Some(Ok(_)) if !matches!(request.constraint, Constraint::None) => {
+                Err("A grammar cannot be used when a tool call is required.".to_string())
+            }
+            Some(Ok(constraint)) => Ok(constraint),
+            Some(Err(err)) => Err(format!("Invalid tools. {err:#}")),
+        };
+        let constraint = match constraint {
+            Ok(constraint) => constraint,
+            Err(err) => {
+                request
+                    .response
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        };
+
         let image_generation_format = match &request.messages {
-            RequestMessage::ImageGeneration { format, .. } => Some(*format),
+            RequestMessage::ImageGeneration { format, .. }
+            | RequestMessage::ImageEdit { format, .. } => Some(*format),
             _ => None,
         };
 
         let seq_step_type = match &request.messages {
-            RequestMessage::ImageGeneration { .. } => SeqStepType::OneShot,
+            RequestMessage::ImageGeneration { .. } | RequestMessage::ImageEdit { .. } => {
+                SeqStepType::OneShot
+            }
             _ => SeqStepType::PromptAndDecode,
         };
 
         let diffusion_params = match &request.messages {
             RequestMessage::ImageGeneration {
                 generation_params, ..
+            }
+            | RequestMessage::ImageEdit {
+                generation_params, ..
             } => Some(generation_params.clone()),
             _ => None,
         };
+        if let Some(Err(err)) = diffusion_params.as_ref().map(|params| params.validate()) {
+            request
+                .response
+                .send(Response::ValidationError(err.into()))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
 
         let (mut prompt_tokens, prompt_text) = match request.messages {
             RequestMessage::Chat(messages)
             | RequestMessage::VisionChat {
                 images: _,
+                videos: _,
                 messages,
             } => {
                 let pipeline = &*get_mut_arcmutex!(self.pipeline);
@@ -621,7 +927,9 @@ impl Engine {
                     text,
                 )
             }
-            RequestMessage::ImageGeneration { prompt, .. } => (vec![u32::MAX], prompt),
+            RequestMessage::ImageGeneration { prompt, .. }
+            | RequestMessage::ImageEdit { prompt, .. } => (vec![u32::MAX], prompt),
+            RequestMessage::Embedding { .. } => unreachable!("Embeddings are handled above."),
             RequestMessage::CompletionTokens(it) => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
                     request
@@ -675,18 +983,22 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
-            request.response
-        );
+        // Sequences keep the adapters which were the default when they were added, so that the
+        // cached KV of their blocks stays valid. Prefix caches are only keyed by the tokens, so
+        // they are not used with adapters.
+        let adapters = request
+            .adapters
+            .clone()
+            .or_else(|| self.adapters.default.clone());
+        let prefill_cache = if adapters.is_some() {
+            None
+        } else {
+            handle_seq_error!(
+                self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
+                request.response
+            )
+        };
 
-        let topk = request
-            .sampling_params
-            .top_k
-            .map(|x| x as i64)
-            .unwrap_or(-1);
-        let topp = request.sampling_params.top_p.unwrap_or(1.0);
-        let minp = request.sampling_params.min_p.unwrap_or(0.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -760,28 +1072,71 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
+        // Completions generate `best_of` sequences and return the best `n_choices`. With beam
+        // search, `beam_width` beams are run instead.
+        let n_seqs = match &request.sampling_params.beam_search {
+            Some(beam_search) => beam_search.beam_width,
+            None => request.sampling_params.n_choices.max(best_of),
+        };
+        let mut group = SequenceGroup::new(
+            if request.sampling_params.beam_search.is_some() {
+                request.sampling_params.n_choices
+            } else {
+                n_seqs
+            },
             request.is_streaming,
             is_chat,
-            best_of,
-        )));
-
-        let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+            request.sampling_params.n_choices,
+        );
+        if let Some(beam_search) = request.sampling_params.beam_search.clone() {
+            let error = if beam_search.beam_width < request.sampling_params.n_choices {
+                Some("Number of choices must not be greater than the beam width.")
+            } else if beam_search.beam_width > self.scheduler.max_num_seqs() {
+                Some("The beam width must not be greater than the maximum number of running sequences.")
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.")
+            } else if !matches!(constraint, Constraint::None) {
+                Some("Beam search does not support grammars.")
+            } else if self.no_kv_cache {
+                Some("Beam search requires the KV cache.")
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                request
+                    .response
+                    .send(Response::ValidationError(error.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+            group.beam = Some(BeamSearchState::new(
+                beam_search,
+                request.sampling_params.n_choices,
+            ));
+        }
+        let group = Arc::new(tokio::sync::Mutex::new(group));
+
+        let (tokenizer, dry_breakers) = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            (
+                pipeline.tokenizer(),
+                pipeline.get_metadata().dry_breakers.clone(),
+            )
+        };
 
+        // Requests without a temperature are sampled at temperature 1
         let sampler = Sampler::new(
-            Some(request.sampling_params.temperature.unwrap_or(1.0)),
-            request.sampling_params.top_n_logprobs,
+            &SamplingParams {
+                temperature: Some(request.sampling_params.temperature.unwrap_or(1.0)),
+                ..request.sampling_params.clone()
+            },
             tokenizer,
-            request.sampling_params.frequency_penalty,
-            request.sampling_params.presence_penalty,
-            request.sampling_params.dry_params,
-            topk,
-            topp,
-            minp,
+            dry_breakers.as_deref(),
             request.logits_processors.unwrap_or_default(),
         );
-        let sampler = handle_seq_error!(sampler, request.response);
+        let mut sampler = handle_seq_error!(sampler, request.response);
+        sampler.set_prompt_len(prompt_tokens.len());
 
         if request.sampling_params.n_choices == 0 {
             request
@@ -795,8 +1150,8 @@ impl Engine {
         }
 
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
-            let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
+        for response_index in 0..n_seqs {
+            let recognizer = match Self::build_sequence_recognizer(&constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
                     request
@@ -862,6 +1217,11 @@ impl Engine {
                 None
             };
 
+            let mut seq_sampler = sampler.clone();
+            if let Some(seed) = request.sampling_params.seed {
+                seq_sampler.set_choice_seed(seed, response_index);
+            }
+
             let now = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time travel has occurred!");
@@ -869,10 +1229,11 @@ impl Engine {
                 prompt_tokens.clone(),
                 prompt_text.clone(),
                 self.id,
+                request.id,
                 now.as_millis(),
                 num_hidden_layers,
                 request.response.clone(),
-                sampler.clone(),
+                seq_sampler,
                 stop_toks.clone(),
                 stop_strings.clone(),
                 request.sampling_params.max_len,
@@ -888,8 +1249,9 @@ impl Engine {
                 } else {
                     None
                 },
-                request.adapters.clone(),
+                adapters.clone(),
                 images.clone(),
+                videos.clone(),
                 block_size,
                 trie,
                 matcher.clone(),
@@ -898,13 +1260,28 @@ impl Engine {
                 diffusion_params.clone(),
                 seq_preallocated_cache,
                 request.return_raw_logits,
+                request.priority,
+                request.tenant.clone(),
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
-                seq.prefill(
-                    prefill_cache.normal,
-                    prefill_cache.xlora,
-                    prefill_cache.toks,
-                )
+                // Each sequence appends to its own copy of a normal cache.
+                let normal_cache = match get_mut_arcmutex!(self.pipeline).cache() {
+                    EitherCache::Normal(cache) => {
+                        Some(cache.lock().unwrap().prefilled(&prefill_cache.normal))
+                    }
+                    EitherCache::Full(_) => None,
+                };
+                match normal_cache {
+                    Some(normal_cache) => seq.prefill_normal(
+                        handle_seq_error!(normal_cache, request.response),
+                        prefill_cache.toks,
+                    ),
+                    None => seq.prefill(
+                        prefill_cache.normal,
+                        prefill_cache.xlora,
+                        prefill_cache.toks,
+                    ),
+                }
             } else {
                 seq
             };
@@ -913,6 +1290,90 @@ impl Engine {
         }
     }
 
+    async fn embed(
+        pipeline: Arc<Mutex<dyn Pipeline>>,
+        id: usize,
+        inputs: Vec<String>,
+        pooling: EmbeddingPooling,
+        response: Sender<Response>,
+    ) {
+        let (tokenizer, max_seq_len, name) = {
+            let pipeline = pipeline.lock().await;
+            (
+                pipeline.tokenizer(),
+                pipeline.get_metadata().max_seq_len,
+                pipeline.name(),
+            )
+        };
+        let Some(tokenizer) = tokenizer else {
+            response
+                .send(Response::ValidationError(
+                    "Embedding requests require the pipeline to have a tokenizer".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        };
+
+        let mut data = Vec::new();
+        let mut prompt_tokens = 0;
+        for (index, input) in inputs.into_iter().enumerate() {
+            let toks = match tokenizer.encode(input, true) {
+                Ok(encoding) => encoding.get_ids().to_vec(),
+                Err(e) => {
+                    response
+                        .send(Response::ValidationError(e))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            };
+            if toks.is_empty() || toks.len() > max_seq_len {
+                response
+                    .send(Response::ValidationError(
+                        format!(
+                            "Embedding input {index} has {} tokens, expected between 1 and {max_seq_len}.",
+                            toks.len()
+                        )
+                        .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+            prompt_tokens += toks.len();
+            let embedding = pipeline.lock().await.embed(&toks, pooling);
+            match embedding {
+                Ok(embedding) => data.push(EmbeddingData {
+                    object: "embedding".to_string(),
+                    embedding,
+                    index,
+                }),
+                Err(e) => {
+                    warn!("Embedding request {id} failed: {e}");
+                    response
+                        .send(Response::InternalError(e.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            }
+        }
+
+        response
+            .send(Response::Embeddings(EmbeddingResponse {
+                object: "list".to_string(),
+                data,
+                model: name,
+                usage: EmbeddingUsage {
+                    prompt_tokens,
+                    total_tokens: prompt_tokens,
+                },
+            }))
+            .await
+            .expect("Expected receiver.");
+    }
+
     async fn tokenize_text(&self, request: TokenizationRequest) {
         match request.text {
             Either::Left(messages) => {
@@ -1013,3 +1474,130 @@ impl Engine {
             .expect("Sender disconnected unexpectedly!");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, num::NonZeroUsize, path::PathBuf};
+
+    use super::AdapterState;
+    use crate::{
+        pipeline::{AdapterActivationMixin, AdapterInstruction},
+        scheduler::{DefaultScheduler, DefaultSchedulerMethod, Scheduler},
+        sequence::{Sequence, TestSequence},
+    };
+
+    /// The adapters registered with a LoRA model, without the model.
+    #[derive(Default)]
+    struct LoraAdapters {
+        registered: Vec<String>,
+    }
+
+    impl AdapterActivationMixin for LoraAdapters {
+        fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+            if let Some(unknown) = adapters.iter().find(|a| !self.registered.contains(a)) {
+                anyhow::bail!("Cannot load adapter `{unknown}`.");
+            }
+            Ok(adapters.len())
+        }
+        fn load_adapter(&mut self, name: String, _path: PathBuf) -> anyhow::Result<usize> {
+            self.registered.push(name);
+            Ok(1)
+        }
+        fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+            let Some(pos) = self.registered.iter().position(|a| a == name) else {
+                anyhow::bail!("Adapter `{name}` is not loaded.");
+            };
+            self.registered.remove(pos);
+            Ok(1)
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    fn loaded(adapters: &[&str]) -> (AdapterState, LoraAdapters) {
+        let mut pipeline = LoraAdapters::default();
+        for adapter in adapters {
+            pipeline
+                .load_adapter(adapter.to_string(), PathBuf::new())
+                .unwrap();
+        }
+        let state = AdapterState {
+            default: None,
+            active: None,
+        };
+        (state, pipeline)
+    }
+
+    #[test]
+    fn activated_adapters_are_the_default() {
+        let (mut state, mut pipeline) = loaded(&["a", "b"]);
+        state.activate(&mut pipeline, names(&["a"])).unwrap();
+        assert_eq!(state.default, Some(names(&["a"])));
+
+        // Sequences without adapters run with the default adapters, which are already active.
+        let mut seq = TestSequence::default().build();
+        assert!(matches!(
+            state.instruction(&[&mut seq]),
+            AdapterInstruction::None
+        ));
+
+        let mut seq_b = TestSequence {
+            adapters: Some(names(&["b"])),
+            ..Default::default()
+        }
+        .build();
+        assert!(matches!(
+            state.instruction(&[&mut seq_b]),
+            AdapterInstruction::Activate(adapters) if adapters == names(&["b"])
+        ));
+        state.step_succeeded(&[&mut seq_b]);
+        assert!(matches!(
+            state.instruction(&[&mut seq]),
+            AdapterInstruction::Activate(adapters) if adapters == names(&["a"])
+        ));
+
+        // A failed activation keeps the default adapters.
+        assert!(state.activate(&mut pipeline, names(&["c"])).is_err());
+        assert_eq!(state.default, Some(names(&["a"])));
+        assert_eq!(state.active, None);
+    }
+
+    #[test]
+    fn default_adapters_cannot_be_unloaded() {
+        let (mut state, mut pipeline) = loaded(&["a", "b"]);
+        state.activate(&mut pipeline, names(&["a"])).unwrap();
+        assert!(state.unload(&mut pipeline, "a", false).is_err());
+
+        state.activate(&mut pipeline, names(&["b"])).unwrap();
+        state.unload(&mut pipeline, "a", false).unwrap();
+        assert_eq!(pipeline.registered, names(&["b"]));
+        assert!(state.activate(&mut pipeline, names(&["a"])).is_err());
+        assert!(state.unload(&mut pipeline, "a", false).is_err());
+    }
+
+    #[test]
+    fn adapters_of_waiting_sequences_cannot_be_unloaded() {
+        let (state, mut pipeline) = loaded(&["a", "b"]);
+        let mut scheduler: DefaultScheduler<VecDeque<Sequence>> =
+            DefaultScheduler::new(DefaultSchedulerMethod::Fixed(NonZeroUsize::new(2).unwrap()));
+        scheduler.add_seq(
+            TestSequence {
+                adapters: Some(names(&["b"])),
+                ..Default::default()
+            }
+            .build(),
+        );
+        assert!(!scheduler.uses_adapter("a"));
+        assert!(state
+            .unload(&mut pipeline, "b", scheduler.uses_adapter("b"))
+            .is_err());
+
+        assert_eq!(scheduler.cancel_request(0), 1);
+        state
+            .unload(&mut pipeline, "b", scheduler.uses_adapter("b"))
+            .unwrap();
+        assert_eq!(pipeline.registered, names(&["a"]));
+    }
+}
@@ -171,6 +171,58 @@ impl CausalMasker {
         Ok(Some(causal_mask))
     }
 
+    /// 1 where a token of the tree may not attend to a position, 0 elsewhere. Tokens attend to the
+    /// `past_kv_len` cached tokens, to their ancestors in the tree and to themselves.
+    pub(crate) fn make_tree_mask(
+        &self,
+        parents: &[Option<usize>],
+        past_kv_len: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let tgt_len = parents.len();
+        let offset = tgt_len + past_kv_len;
+        let mut mask = vec![1u8; tgt_len * offset];
+        for (i, row) in mask.chunks_mut(offset).enumerate() {
+            row[..past_kv_len].fill(0);
+            let mut node = Some(i);
+            while let Some(j) = node {
+                row[past_kv_len + j] = 0;
+                node = parents[j];
+            }
+        }
+        Tensor::from_vec(mask, (tgt_len, offset), device)
+    }
+
+    /// Make the mask for a tree of tokens which follow the `past_kv_len` cached tokens, as used to
+    /// verify tree-structured speculative candidates in one forward pass. `parents[i]` is the index
+    /// of the parent of token `i` in the tree, or `None` for a root. Parents come before their
+    /// children.
+    pub fn make_tree_mask_matrix(
+        &self,
+        parents: &[Option<usize>],
+        past_kv_len: usize,
+        dtype: DType,
+        n_attn_heads: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let mask = self.make_tree_mask(parents, past_kv_len, device)?;
+        let zero = Tensor::new(0.0f32, device)?;
+        let mut tree_mask = masked_fill(
+            &zero.to_dtype(dtype)?.broadcast_as(mask.shape())?,
+            &mask,
+            f32::NEG_INFINITY,
+        )?;
+
+        // IMPORTANT: this must match the logic in attention.rs.
+        if tree_mask.device().is_cuda()
+            && !get_use_matmul_via_f16()
+            && CUBLASLT_HANDLE.lock().unwrap().is_some()
+        {
+            tree_mask = tree_mask.unsqueeze(0)?.repeat((n_attn_heads, 1, 1))?;
+        }
+        Ok(tree_mask)
+    }
+
     pub fn make_sliding_window_causal_mask_matrix(
         &self,
         input_ids: &Tensor,
@@ -5,6 +5,7 @@ use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
 pub use engine::{EngineInstruction, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP};
 pub use lora::Ordering;
+use pipeline::EitherCache;
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -20,7 +21,7 @@ use std::{
         Arc, Mutex, RwLock,
     },
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{channel, Sender};
 use tracing::info;
@@ -54,6 +55,7 @@ mod paged_attention;
 use dummy_paged_attention as paged_attention;
 mod attention;
 mod diffusion_models;
+mod embedding_models;
 mod pipeline;
 mod prefix_cacher;
 mod request;
@@ -76,34 +78,45 @@ pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
     chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
     DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
-    DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader,
-    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, IsqOrganization,
-    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader,
-    MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
-    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader,
-    VisionLoaderBuilder, VisionLoaderType, VisionPromptPrefixer, VisionSpecificConfig,
+    DiffusionSpecificConfig, DraftKind, DraftLoader, EmbeddingLoader, EmbeddingLoaderBuilder,
+    EmbeddingLoaderType, EmbeddingPooling, EmbeddingSpecificConfig, GGMLLoader, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
+    Idefics2Loader, IsqOrganization, KvCacheDType, LLaVALoader, LLaVANextLoader, LlamaLoader,
+    Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
+    NgramLookupConfig, NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeHeadsConfig,
+    SpeculativeHeadsKind, SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource,
+    VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionPromptPrefixer,
+    VisionSpecificConfig, MAX_DIFFUSION_IMAGE_SIZE,
 };
+pub use prefix_cacher::PrefixCacheDiskConfig;
 pub use request::{
-    Constraint, DetokenizationRequest, ImageGenerationResponseFormat, MessageContent,
-    NormalRequest, Request, RequestMessage, TokenizationRequest,
+    AdapterLoadRequest, AdapterUnloadRequest, Constraint, DetokenizationRequest,
+    ImageGenerationResponseFormat, MessageContent, NormalRequest, Request, RequestMessage,
+    TokenizationRequest,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    BeamSearchParams, CustomLogitsProcessor, DrySamplingParams, DynamicTemperatureParams,
+    ExtendedSamplers, ExtendedSamplingFields, MirostatParams, MirostatVersion, PenaltyRange,
+    SamplingParams, StopTokens, TopLogprob, XtcParams,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
+pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig, SchedulingPolicy};
 use serde::Serialize;
 use tokio::runtime::Runtime;
-use toml_selector::{TomlLoaderArgs, TomlSelector};
+pub use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, CalledFunctionDelta, Function, Tool, ToolCallDelta, ToolCallParser,
+    ToolCallResponse, ToolCallType, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
 pub use utils::memory_usage::MemoryUsage;
 pub use utils::normal::{ModelDType, TryIntoDType};
 pub use utils::paged_attn_supported;
+pub use vision_models::video::{
+    load_video, load_video_from_memory, DEFAULT_MAX_VIDEO_FRAMES, DEFAULT_VIDEO_FPS,
+};
 
 /// `true` if `MISTRALRS_DEBUG=1`
 pub(crate) static DEBUG: AtomicBool = AtomicBool::new(false);
@@ -140,8 +153,10 @@ struct RebootState {
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
+    prefix_cache_disk: Option<PrefixCacheDiskConfig>,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    tool_call_parser: Option<ToolCallParser>,
 }
 
 #[derive(Debug)]
@@ -176,9 +191,12 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    kv_cache_dtype: Option<KvCacheDType>,
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
     throughput_logging_enabled: Option<()>,
+    tool_call_parser: Option<ToolCallParser>,
 }
 
 impl MistralRsBuilder {
@@ -191,9 +209,12 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_disk: None,
+            kv_cache_dtype: None,
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
             throughput_logging_enabled: None,
+            tool_call_parser: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -220,6 +241,16 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Persist prefix caches which are evicted from the device to a directory on disk.
+    pub fn with_prefix_cache_disk(mut self, prefix_cache_disk: PrefixCacheDiskConfig) -> Self {
+        self.prefix_cache_disk = Some(prefix_cache_disk);
+        self
+    }
+    /// Storage dtype of the normal KV cache. Quantized dtypes trade accuracy for a longer context.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KvCacheDType) -> Self {
+        self.kv_cache_dtype = Some(kv_cache_dtype);
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
@@ -232,6 +263,11 @@ impl MistralRsBuilder {
         self.throughput_logging_enabled = Some(());
         self
     }
+    /// Set the format of the tool calls, instead of detecting it from the chat template.
+    pub fn with_tool_call_parser(mut self, tool_call_parser: ToolCallParser) -> Self {
+        self.tool_call_parser = Some(tool_call_parser);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -281,6 +317,18 @@ impl Drop for MistralRs {
             .lock()
             .expect("`ENGINE_INSTRUCTIONS` was poisioned")
             .insert(self.engine_id, Some(EngineInstruction::Terminate));
+        // The engine writes the prefix caches to disk when it stops, so wait for it. It may be
+        // waiting for a request, which also wakes it up.
+        if self.reboot_state.prefix_cache_disk.is_some() {
+            if let Ok(sender) = self.sender.get_mut() {
+                let _ = sender.try_send(Request::Terminate);
+            }
+            if let Ok(engine_handler) = self.engine_handler.get_mut() {
+                while !engine_handler.is_finished() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
     }
 }
 
@@ -294,26 +342,50 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_disk,
+            kv_cache_dtype,
             disable_eos_stop,
             gemm_full_precision_f16,
             throughput_logging_enabled,
+            tool_call_parser,
         } = config;
 
         let category = pipeline.try_lock().unwrap().category();
         let model_supports_reduced_gemm = match category {
             ModelCategory::Text => true,
             ModelCategory::Vision { has_conv2d, .. } => !has_conv2d,
-            ModelCategory::Diffusion => true,
+            ModelCategory::Diffusion | ModelCategory::Embedding => true,
         };
         if !gemm_full_precision_f16.unwrap_or(false) && model_supports_reduced_gemm {
             set_gemm_reduced_precision_f16();
         }
         setup_cublas_lt_wrapper();
 
+        if let Some(kv_cache_dtype) = kv_cache_dtype
+            .filter(KvCacheDType::is_quantized)
+            .filter(|_| matches!(category, ModelCategory::Text | ModelCategory::Vision { .. }))
+        {
+            match pipeline.try_lock().unwrap().cache() {
+                EitherCache::Normal(cache) => {
+                    info!("Using a {kv_cache_dtype} KV cache.");
+                    cache.lock().unwrap().set_kv_dtype(kv_cache_dtype);
+                }
+                EitherCache::Full(_) => {
+                    warn!("The KV cache dtype is only supported for models with a normal cache, ignoring `{kv_cache_dtype}`.")
+                }
+            }
+        }
+
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
         let prefix_cache_n = prefix_cache_n.unwrap_or(16);
+        if prefix_cache_disk.is_some()
+            && (no_prefix_cache
+                || !Engine::supports_prefix_cacher(&*pipeline.try_lock().unwrap(), &method))
+        {
+            panic!("The disk prefix cache requires the prefix cache, which is only supported for text models without PagedAttention or speculative decoding.");
+        }
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
         let throughput_logging_enabled = throughput_logging_enabled.is_some();
 
@@ -324,8 +396,10 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_disk: prefix_cache_disk.clone(),
             disable_eos_stop,
             throughput_logging_enabled,
+            tool_call_parser,
         };
 
         let (tx, rx) = channel(10_000);
@@ -352,8 +426,10 @@ impl MistralRs {
                     no_kv_cache,
                     no_prefix_cache,
                     prefix_cache_n,
+                    prefix_cache_disk,
                     disable_eos_stop,
                     throughput_logging_enabled,
+                    tool_call_parser,
                 );
                 engine.run().await;
             });
@@ -387,6 +463,8 @@ impl MistralRs {
                     tools: None,
                     logits_processors: None,
                     return_raw_logits: false,
+                    priority: 0,
+                    tenant: None,
                 });
                 info!("Beginning dummy run.");
                 let start = Instant::now();
@@ -451,8 +529,10 @@ impl MistralRs {
                         reboot_state.no_kv_cache,
                         reboot_state.no_prefix_cache,
                         reboot_state.prefix_cache_n,
+                        reboot_state.prefix_cache_disk,
                         reboot_state.disable_eos_stop,
                         reboot_state.throughput_logging_enabled,
+                        reboot_state.tool_call_parser,
                     );
                     engine.run().await;
                 });
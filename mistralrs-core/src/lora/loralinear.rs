@@ -18,6 +18,10 @@ pub struct LoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    linear_config: LoraLinearConfig,
+    prefix: String,
+    // Set if each adapter only applies to some rows of the batch, see `AdapterOp::ActivateRows`.
+    adapter_rows: Option<Vec<Tensor>>,
 }
 
 impl LoraLinear {
@@ -109,6 +113,9 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                prefix: vb.prefix(),
+                adapter_rows: None,
             })
         } else {
             Ok(LoraLinear {
@@ -121,13 +128,48 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                prefix: vb.prefix(),
+                adapter_rows: None,
             })
         }
     }
 }
 
+/// Add the output of one adapter to `result` for only the given rows of the batch. The rows are
+/// gathered, passed through the adapter and scattered back, so the cost is proportional to the
+/// number of rows which use the adapter rather than to the batch size.
+pub(super) fn apply_adapter_to_rows(
+    result: &Tensor,
+    input: &Tensor,
+    rows: &Tensor,
+    adapter_a: &Linear,
+    adapter_b: &Linear,
+    scale: f64,
+) -> Result<Tensor> {
+    let rows = rows.to_device(input.device())?;
+    let out = adapter_b
+        .forward(&adapter_a.forward(&input.index_select(&rows, 0)?)?)?
+        .mul(scale)?;
+    result.index_add(&rows, &out.to_dtype(result.dtype())?, 0)
+}
+
+impl LoraLinear {
+    /// Stacked adapters cannot be swapped, so they are unstacked before the adapter set changes.
+    fn unstack_adapters(&mut self) {
+        if let (Either::Right((_, a)), Either::Right((_, b))) = (&self.a_adapters, &self.b_adapters)
+        {
+            let (a, b) = (a.clone(), b.clone());
+            self.a_adapters = Either::Left(a);
+            self.b_adapters = Either::Left(b);
+        }
+    }
+}
+
 impl AdapterSwapper for LoraLinear {
     fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+        self.unstack_adapters();
+        self.adapter_rows = None;
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -151,10 +193,32 @@ impl AdapterSwapper for LoraLinear {
                     s.push(*scale);
                 }
             }
-            _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
+            _ => unreachable!("Adapters were unstacked above."),
         }
         Ok(())
     }
+    fn _activate_adapter_rows(&mut self, adapters: &[String], rows: &[Tensor]) -> Result<()> {
+        self._activate_adapters(adapters)?;
+        self.adapter_rows = Some(rows.to_vec());
+        Ok(())
+    }
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<bool> {
+        if self.adapters.contains_key(name) {
+            bail!("Adapter `{name}` is already loaded.");
+        }
+        let a_vb = vb.set_prefix(format!("{}.lora_A", self.prefix));
+        let b_vb = vb.set_prefix(format!("{}.lora_B", self.prefix));
+        if !a_vb.contains_tensor("weight") {
+            return Ok(false);
+        }
+        let adapter = make_adapter(a_vb, b_vb, cfg, &self.linear_config)?;
+        self.unstack_adapters();
+        self.adapters.insert(name.to_string(), adapter);
+        Ok(true)
+    }
+    fn _unload_adapter(&mut self, name: &str) -> bool {
+        self.adapters.remove(name).is_some()
+    }
     fn can_load(&self) -> bool {
         true
     }
@@ -241,6 +305,17 @@ impl LinearLayerLike for LoraLinear {
                 zip(a_adapters, zip(b_adapters, &self.scale_adapters)).enumerate()
             {
                 let input_new = input.to_dtype(adapter_a.weight().dtype())?;
+                if let Some(adapter_rows) = &self.adapter_rows {
+                    result = apply_adapter_to_rows(
+                        &result,
+                        &input_new,
+                        &adapter_rows[i],
+                        &adapter_a,
+                        &adapter_b,
+                        adapter_scale * global_scaling_weight,
+                    )?;
+                    continue;
+                }
                 let input_new = if let Some(scalings) = &scalings {
                     apply_scalings_to_x(input_new, scalings, i)?
                 } else {
@@ -286,3 +361,158 @@ impl LinearLayerLike for LoraLinear {
         !self.adapters.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use candle_core::{DType, Device, IndexOp, Tensor};
+    use candle_nn::{Linear, VarBuilder};
+
+    use super::LoraLinear;
+    use crate::lora::{
+        adapter_rows, AdapterOp, AdapterSwapper, LinearLayerLike, LoraConfig, LoraLinearConfig,
+    };
+
+    const IN: usize = 6;
+    const OUT: usize = 5;
+    const RANK: usize = 2;
+
+    fn adapter(device: &Device) -> (VarBuilder<'static>, LoraConfig) {
+        let tensors = HashMap::from([
+            (
+                "layer.lora_A.weight".to_string(),
+                Tensor::randn(0f32, 1., (RANK, IN), device).unwrap(),
+            ),
+            (
+                "layer.lora_B.weight".to_string(),
+                Tensor::randn(0f32, 1., (OUT, RANK), device).unwrap(),
+            ),
+        ]);
+        let cfg = LoraConfig {
+            rank: RANK,
+            alpha: 4.,
+            dropout: None,
+            target_modules: HashSet::from(["layer".to_string()]),
+        };
+        (VarBuilder::from_tensors(tensors, DType::F32, device), cfg)
+    }
+
+    #[test]
+    fn mixed_adapter_batch_matches_sequential() {
+        let device = Device::Cpu;
+        let base = Linear::new(Tensor::randn(0f32, 1., (OUT, IN), &device).unwrap(), None);
+        let preload = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| (name.to_string(), adapter(&device)))
+            .collect::<HashMap<_, _>>();
+        let vb = VarBuilder::zeros(DType::F32, &device).pp("layer");
+        let mut layer = LoraLinear::new(
+            &base,
+            &LoraLinearConfig::new(IN, OUT),
+            &[],
+            &vb,
+            0,
+            &Some(preload),
+        )
+        .unwrap();
+
+        let row_adapters = [
+            vec!["a".to_string()],
+            vec!["b".to_string(), "c".to_string()],
+            vec![],
+            vec!["a".to_string(), "c".to_string()],
+        ];
+        let x = Tensor::randn(0f32, 1., (row_adapters.len(), 3, IN), &device).unwrap();
+
+        let (adapters, rows) = adapter_rows(&row_adapters, &device).unwrap();
+        layer._activate_adapter_rows(&adapters, &rows).unwrap();
+        let batched = layer.lora_forward(&x, None, 1., None).unwrap();
+
+        for (i, row_adapters) in row_adapters.iter().enumerate() {
+            layer._activate_adapters(row_adapters).unwrap();
+            let expected = layer
+                .lora_forward(&x.narrow(0, i, 1).unwrap(), None, 1., None)
+                .unwrap();
+            let diff = (batched.i(i..i + 1).unwrap() - expected)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert!(diff < 1e-5, "row {i} differs by {diff}");
+        }
+    }
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn load_activate_and_unload_adapter() {
+        let device = Device::Cpu;
+        let base = Linear::new(Tensor::randn(0f32, 1., (OUT, IN), &device).unwrap(), None);
+        let vb = VarBuilder::zeros(DType::F32, &device).pp("layer");
+        let mut layer = LoraLinear::new(
+            &base,
+            &LoraLinearConfig::new(IN, OUT),
+            &[],
+            &vb,
+            0,
+            &Some(HashMap::new()),
+        )
+        .unwrap();
+        let x = Tensor::randn(0f32, 1., (1, 3, IN), &device).unwrap();
+        let base_out = layer.lora_forward(&x, None, 1., None).unwrap();
+        let a = ["a".to_string()];
+        assert!(layer.activate(&a).is_err());
+
+        let (adapter_vb, cfg) = adapter(&device);
+        let load = AdapterOp::Load {
+            name: "a",
+            vb: &adapter_vb,
+            cfg: &cfg,
+        };
+        assert_eq!(layer.apply(&load).unwrap(), 1);
+        // Loading an adapter does not activate it, and names are unique.
+        assert_eq!(
+            max_abs_diff(&layer.lora_forward(&x, None, 1., None).unwrap(), &base_out),
+            0.
+        );
+        assert!(layer.apply(&load).is_err());
+
+        assert_eq!(layer.activate(&a).unwrap(), 1);
+        assert!(max_abs_diff(&layer.lora_forward(&x, None, 1., None).unwrap(), &base_out) > 1e-3);
+
+        // Adapters without weights for the layer do not apply to it.
+        let other_vb = VarBuilder::from_tensors(HashMap::new(), DType::F32, &device);
+        let other = AdapterOp::Load {
+            name: "other",
+            vb: &other_vb,
+            cfg: &cfg,
+        };
+        assert_eq!(layer.apply(&other).unwrap(), 0);
+
+        layer.activate(&[]).unwrap();
+        assert_eq!(layer.apply(&AdapterOp::Unload("a")).unwrap(), 1);
+        assert_eq!(layer.apply(&AdapterOp::Unload("a")).unwrap(), 0);
+        assert!(layer.activate(&a).is_err());
+        assert_eq!(
+            max_abs_diff(&layer.lora_forward(&x, None, 1., None).unwrap(), &base_out),
+            0.
+        );
+    }
+}
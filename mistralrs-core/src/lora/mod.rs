@@ -2,7 +2,7 @@
 
 use std::{collections::HashSet, fmt::Debug, sync::Arc};
 
-use candle_core::{quantized::QTensor, DType, IndexOp, Result, Tensor, D};
+use candle_core::{quantized::QTensor, DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{init, Linear, Module, VarBuilder};
 use loralinear::LoraLinear;
 use mistralrs_quant::QuantMethod;
@@ -94,6 +94,33 @@ fn make_adapter(
     Ok(Adapter { a, b, scale })
 }
 
+/// Group the rows of a batch by adapter, given the adapters each row uses. Returns the distinct
+/// adapters and, for each, a `u32` tensor of the indices of the rows which use it.
+pub fn adapter_rows(
+    row_adapters: &[Vec<String>],
+    device: &Device,
+) -> Result<(Vec<String>, Vec<Tensor>)> {
+    let mut adapters: Vec<String> = Vec::new();
+    let mut rows: Vec<Vec<u32>> = Vec::new();
+    for (row, names) in row_adapters.iter().enumerate() {
+        let row = u32::try_from(row).map_err(candle_core::Error::wrap)?;
+        for name in names {
+            match adapters.iter().position(|adapter| adapter == name) {
+                Some(i) => rows[i].push(row),
+                None => {
+                    adapters.push(name.clone());
+                    rows.push(vec![row]);
+                }
+            }
+        }
+    }
+    let rows = rows
+        .into_iter()
+        .map(|rows| Tensor::new(rows, device))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((adapters, rows))
+}
+
 /// Any layer that is linear-like.
 pub trait LinearLayerLike: Merge + AdapterSwapper {
     fn quantized_act_type(&self) -> Option<DType>;
@@ -117,16 +144,60 @@ pub trait Merge {
     fn merge_weights(&mut self) -> Result<()>;
 }
 
+/// A change to the adapters of the LoRA layers of a model.
+pub enum AdapterOp<'a> {
+    /// Replace the active adapters.
+    Activate(&'a [String]),
+    /// Replace the active adapters, applying each one only to some rows of the batch.
+    /// `rows[i]` holds the indices of the rows which use `adapters[i]`, see [`adapter_rows`].
+    ActivateRows {
+        adapters: &'a [String],
+        rows: &'a [Tensor],
+    },
+    /// Register a new adapter from the weights of a PEFT adapter. The weights are looked up with
+    /// the same names as the adapters given at load time, without the adapter id.
+    Load {
+        name: &'a str,
+        vb: &'a VarBuilder<'a>,
+        cfg: &'a LoraConfig,
+    },
+    /// Remove a registered adapter.
+    Unload(&'a str),
+}
+
 pub trait AdapterSwapper {
     fn activate(&mut self, adapter_names: &[String]) -> Result<usize> {
-        if self.can_load() {
-            self._activate_adapters(adapter_names)?;
-            Ok(1)
-        } else {
-            Ok(0)
+        self.apply(&AdapterOp::Activate(adapter_names))
+    }
+    /// Apply an adapter operation, returning the number of layers it changed.
+    fn apply(&mut self, op: &AdapterOp) -> Result<usize> {
+        if !self.can_load() {
+            return Ok(0);
         }
+        match op {
+            AdapterOp::Activate(adapter_names) => self._activate_adapters(adapter_names)?,
+            AdapterOp::ActivateRows { adapters, rows } => {
+                self._activate_adapter_rows(adapters, rows)?
+            }
+            AdapterOp::Load { name, vb, cfg } => {
+                if !self._load_adapter(name, vb, cfg)? {
+                    return Ok(0);
+                }
+            }
+            AdapterOp::Unload(name) => {
+                if !self._unload_adapter(name) {
+                    return Ok(0);
+                }
+            }
+        }
+        Ok(1)
     }
     fn _activate_adapters(&mut self, adapters: &[String]) -> Result<()>;
+    fn _activate_adapter_rows(&mut self, adapters: &[String], rows: &[Tensor]) -> Result<()>;
+    /// Returns `false` if the adapter has no weights for this layer.
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<bool>;
+    /// Returns `false` if the adapter was not registered for this layer.
+    fn _unload_adapter(&mut self, name: &str) -> bool;
     fn can_load(&self) -> bool;
 }
 
@@ -143,6 +214,15 @@ impl AdapterSwapper for Linear {
     fn _activate_adapters(&mut self, _adapter: &[String]) -> Result<()> {
         unreachable!()
     }
+    fn _activate_adapter_rows(&mut self, _adapters: &[String], _rows: &[Tensor]) -> Result<()> {
+        unreachable!()
+    }
+    fn _load_adapter(&mut self, _name: &str, _vb: &VarBuilder, _cfg: &LoraConfig) -> Result<bool> {
+        unreachable!()
+    }
+    fn _unload_adapter(&mut self, _name: &str) -> bool {
+        unreachable!()
+    }
     fn can_load(&self) -> bool {
         false
     }
@@ -6,8 +6,8 @@ use either::Either;
 use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig, UnquantLinear};
 
 use super::{
-    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
+    apply_scalings_to_x, get_maybe_topk_scalings, loralinear::apply_adapter_to_rows, make_adapter,
+    Adapter, AdapterSwapper, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
 };
 
 #[derive(Debug)]
@@ -20,6 +20,9 @@ pub struct QLoraLinear {
     merged: bool,
     adapters: HashMap<String, Adapter>,
     linear_config: Option<LoraLinearConfig>,
+    prefix: String,
+    // Set if each adapter only applies to some rows of the batch, see `AdapterOp::ActivateRows`.
+    adapter_rows: Option<Vec<Tensor>>,
 }
 
 /// Specialized QLoRA for no bias
@@ -66,6 +69,8 @@ impl QLoraLinear {
                 merged: false,
                 adapters: HashMap::default(),
                 linear_config: None,
+                prefix,
+                adapter_rows: None,
             });
         }
 
@@ -75,6 +80,7 @@ impl QLoraLinear {
         let mut b_adapters = Vec::with_capacity(config.len());
         let mut scale_adapters = Vec::with_capacity(config.len());
         let vb = vb.pp(prefix.clone());
+        let adapter_prefix = vb.prefix();
         let a_vb = vb.pp("lora_A".to_string());
         let b_vb = vb.pp("lora_B".to_string());
         let mut state = None;
@@ -157,6 +163,8 @@ impl QLoraLinear {
                 merged: false,
                 adapters,
                 linear_config: Some(linear_config.clone()),
+                prefix: adapter_prefix,
+                adapter_rows: None,
             })
         } else {
             Ok(QLoraLinear {
@@ -168,13 +176,29 @@ impl QLoraLinear {
                 merged: false,
                 adapters,
                 linear_config: Some(linear_config.clone()),
+                prefix: adapter_prefix,
+                adapter_rows: None,
             })
         }
     }
 }
 
+impl QLoraLinear {
+    /// Stacked adapters cannot be swapped, so they are unstacked before the adapter set changes.
+    fn unstack_adapters(&mut self) {
+        if let (Either::Right((_, a)), Either::Right((_, b))) = (&self.a_adapters, &self.b_adapters)
+        {
+            let (a, b) = (a.clone(), b.clone());
+            self.a_adapters = Either::Left(a);
+            self.b_adapters = Either::Left(b);
+        }
+    }
+}
+
 impl AdapterSwapper for QLoraLinear {
     fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+        self.unstack_adapters();
+        self.adapter_rows = None;
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -198,10 +222,32 @@ impl AdapterSwapper for QLoraLinear {
                     s.push(*scale);
                 }
             }
-            _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
+            _ => unreachable!("Adapters were unstacked above."),
         }
         Ok(())
     }
+    fn _activate_adapter_rows(&mut self, adapters: &[String], rows: &[Tensor]) -> Result<()> {
+        self._activate_adapters(adapters)?;
+        self.adapter_rows = Some(rows.to_vec());
+        Ok(())
+    }
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<bool> {
+        let Some(linear_config) = &self.linear_config else {
+            return Ok(false);
+        };
+        let a_vb = vb.set_prefix(format!("{}.lora_A", self.prefix));
+        let b_vb = vb.set_prefix(format!("{}.lora_B", self.prefix));
+        if !a_vb.contains_tensor("weight") {
+            return Ok(false);
+        }
+        let adapter = make_adapter(a_vb, b_vb, cfg, linear_config)?;
+        self.unstack_adapters();
+        self.adapters.insert(name.to_string(), adapter);
+        Ok(true)
+    }
+    fn _unload_adapter(&mut self, name: &str) -> bool {
+        self.adapters.remove(name).is_some()
+    }
     fn can_load(&self) -> bool {
         self.linear_config.is_some()
     }
@@ -292,6 +338,17 @@ impl LinearLayerLike for QLoraLinear {
             for (i, (adapter_a, (adapter_b, adapter_scale))) in
                 zip(a_adapters, zip(b_adapters, &self.scale_adapters)).enumerate()
             {
+                if let Some(adapter_rows) = &self.adapter_rows {
+                    result = apply_adapter_to_rows(
+                        &result,
+                        input,
+                        &adapter_rows[i],
+                        &adapter_a,
+                        &adapter_b,
+                        adapter_scale * global_scaling_weight,
+                    )?;
+                    continue;
+                }
                 let input_new = if let Some(scalings) = &scalings {
                     apply_scalings_to_x(input.clone(), scalings, i)?
                 } else {
@@ -6,9 +6,10 @@ use std::{
 use crate::{
     get_toml_selected_model_dtype,
     pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
-    DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, Loader, ModelDType,
-    ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
-    VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
+    DiffusionLoaderBuilder, DiffusionSpecificConfig, EmbeddingLoaderBuilder,
+    EmbeddingSpecificConfig, GGUFSpecificConfig, Loader, ModelDType, ModelSelected,
+    NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology, VisionLoaderBuilder,
+    VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 /// A builder for a loader using the selected model.
@@ -63,7 +64,8 @@ pub fn get_tgt_non_granular_index(model: &ModelSelected) -> Option<usize> {
         | ModelSelected::LoraGGML { .. }
         | ModelSelected::Toml { .. }
         | ModelSelected::VisionPlain { .. }
-        | ModelSelected::DiffusionPlain { .. } => None,
+        | ModelSelected::DiffusionPlain { .. }
+        | ModelSelected::EmbeddingPlain { .. } => None,
         ModelSelected::XLora {
             tgt_non_granular_index,
             ..
@@ -85,7 +87,8 @@ pub fn get_model_dtype(model: &ModelSelected) -> anyhow::Result<ModelDType> {
         | ModelSelected::Lora { dtype, .. }
         | ModelSelected::XLora { dtype, .. }
         | ModelSelected::VisionPlain { dtype, .. }
-        | ModelSelected::DiffusionPlain { dtype, .. } => Ok(*dtype),
+        | ModelSelected::DiffusionPlain { dtype, .. }
+        | ModelSelected::EmbeddingPlain { dtype, .. } => Ok(*dtype),
         ModelSelected::GGUF { .. }
         | ModelSelected::LoraGGUF { .. }
         | ModelSelected::GGML { .. }
@@ -414,6 +417,15 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             DiffusionLoaderBuilder::new(DiffusionSpecificConfig { use_flash_attn }, Some(model_id))
                 .build(arch)
         }
+        ModelSelected::EmbeddingPlain {
+            model_id,
+            tokenizer_json,
+            arch,
+            dtype: _,
+        } => {
+            EmbeddingLoaderBuilder::new(EmbeddingSpecificConfig { tokenizer_json }, Some(model_id))
+                .build(arch)
+        }
     };
     Ok(loader)
 }
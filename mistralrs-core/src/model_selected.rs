@@ -4,7 +4,7 @@ use clap::Subcommand;
 
 use crate::{
     pipeline::{IsqOrganization, NormalLoaderType, VisionLoaderType},
-    DiffusionLoaderType, ModelDType,
+    DiffusionLoaderType, EmbeddingLoaderType, ModelDType,
 };
 
 fn parse_arch(x: &str) -> Result<NormalLoaderType, String> {
@@ -19,6 +19,10 @@ fn parse_diffusion_arch(x: &str) -> Result<DiffusionLoaderType, String> {
     x.parse()
 }
 
+fn parse_embedding_arch(x: &str) -> Result<EmbeddingLoaderType, String> {
+    x.parse()
+}
+
 fn parse_model_dtype(x: &str) -> Result<ModelDType, String> {
     x.parse()
 }
@@ -410,4 +414,23 @@ pub enum ModelSelected {
         #[arg(short, long, default_value_t = ModelDType::Auto, value_parser = parse_model_dtype)]
         dtype: ModelDType,
     },
+
+    /// Select an encoder-only embedding model, without quantization or adapters
+    EmbeddingPlain {
+        /// Model ID to load from. This may be a HF hub repo or a local path.
+        #[arg(short, long)]
+        model_id: String,
+
+        /// Path to local tokenizer.json file. If this is specified it is used over any remote file.
+        #[arg(short, long)]
+        tokenizer_json: Option<String>,
+
+        /// The architecture of the model.
+        #[arg(short, long, value_parser = parse_embedding_arch)]
+        arch: EmbeddingLoaderType,
+
+        /// Model data type. Defaults to `auto`.
+        #[arg(short, long, default_value_t = ModelDType::Auto, value_parser = parse_model_dtype)]
+        dtype: ModelDType,
+    },
 }
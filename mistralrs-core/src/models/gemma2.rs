@@ -337,6 +337,7 @@ impl Attention {
                         block_tables: None,
                         context_lens: None,
                         max_context_len: None,
+                        attend_to_cache: false,
                         slot_mappings: Tensor::new(&[0f32], q.device())?,
                     };
                     paged_attn.forward(
@@ -617,6 +618,37 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.forward_hidden(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            flash_params,
+        )?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+
+        let mut xs = MatMul.qmethod_matmul(&xs, &*self.lm_head)?;
+
+        if let Some(final_logit_softcapping) = self.final_logit_softcapping {
+            xs = (xs / final_logit_softcapping)?;
+            xs = xs.tanh()?;
+            xs = (xs * final_logit_softcapping)?;
+        }
+
+        extract_logits(&xs, context_lens)
+    }
+
+    /// The final normalized hidden states, before the LM head.
+    fn forward_hidden(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -658,20 +690,7 @@ impl Model {
             )?;
         }
         let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            xs = xs.to_dtype(t)?;
-        }
-
-        let mut xs = MatMul.qmethod_matmul(&xs, &*self.lm_head)?;
-
-        if let Some(final_logit_softcapping) = self.final_logit_softcapping {
-            xs = (xs / final_logit_softcapping)?;
-            xs = xs.tanh()?;
-            xs = (xs * final_logit_softcapping)?;
-        }
-
-        extract_logits(&xs, context_lens)
+        xs.apply(&self.norm)
     }
 }
 
@@ -765,6 +784,22 @@ impl NormalModel for Model {
             flash_params,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_hidden(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            None,
+            flash_params,
+        )
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -1,6 +1,6 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor, D};
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
 use serde::{Deserialize, Serialize};
@@ -48,6 +48,33 @@ pub struct Config {
     pub tie_word_embeddings: bool,
 }
 
+/// Positions of the tokens for the rotary embedding.
+enum RopePositions<'a> {
+    /// The tokens of each sequence of the batch are consecutive, starting at its offset.
+    Offsets {
+        offsets: &'a [usize],
+        kernel: Tensor,
+    },
+    /// The position of every token of a single sequence whose tokens need not be consecutive,
+    /// such as a tree of speculative candidates.
+    PerToken {
+        positions: &'a [usize],
+        kernel: Tensor,
+    },
+}
+
+impl<'a> RopePositions<'a> {
+    fn per_token(positions: &'a [usize], device: &Device) -> Result<Self> {
+        // Each token is rotated as a sequence of length 1.
+        let kernel = Tensor::new(
+            positions.iter().map(|p| *p as i64).collect::<Vec<_>>(),
+            device,
+        )?
+        .unsqueeze(1)?;
+        Ok(Self::PerToken { positions, kernel })
+    }
+}
+
 struct CausalSelfAttention {
     q_proj: Arc<dyn QuantMethod>,
     k_proj: Arc<dyn QuantMethod>,
@@ -63,13 +90,11 @@ struct CausalSelfAttention {
 }
 
 impl CausalSelfAttention {
-    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
         attention_mask: &Option<Tensor>,
-        seqlen_offsets: &[usize],
-        start_offsets_kernel: Tensor,
+        positions: &RopePositions,
         kv_cache: &mut KvCache,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -100,8 +125,27 @@ impl CausalSelfAttention {
             v.reshape((b_sz, self.num_key_value_heads, seq_len, self.head_dim))?
         };
 
-        self.rotary_emb
-            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+        match positions {
+            RopePositions::Offsets { offsets, kernel } => {
+                self.rotary_emb
+                    .forward(offsets, kernel, &mut q, &mut k, b_sz)?;
+            }
+            RopePositions::PerToken { positions, kernel } => {
+                if b_sz != 1 || positions.len() != seq_len {
+                    candle_core::bail!(
+                        "Expected one position per token of a single sequence, got {} positions for {b_sz} sequences of {seq_len} tokens.",
+                        positions.len()
+                    );
+                }
+                // Treat the tokens as a batch of sequences of length 1.
+                self.rotary_emb
+                    .forward(positions, kernel, &mut q, &mut k, seq_len)?;
+                if q.rank() == 4 {
+                    q = q.squeeze(2)?;
+                    k = k.squeeze(2)?;
+                }
+            }
+        }
 
         if q.rank() == 3 && seq_len != 1 {
             q = q
@@ -139,6 +183,7 @@ impl CausalSelfAttention {
                         block_tables: None,
                         context_lens: None,
                         max_context_len: None,
+                        attend_to_cache: false,
                         slot_mappings: Tensor::new(&[0f32], q.device())?,
                     };
                     paged_attn.forward(
@@ -346,8 +391,7 @@ impl Block {
         &self,
         x: &Tensor,
         attention_mask: &Option<Tensor>,
-        seqlen_offsets: &[usize],
-        start_offsets_kernel: Tensor,
+        positions: &RopePositions,
         kv_cache: &mut KvCache,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -357,8 +401,7 @@ impl Block {
         let x = (self.attn.forward(
             &x,
             attention_mask,
-            seqlen_offsets,
-            start_offsets_kernel,
+            positions,
             kv_cache,
             metadata,
             flash_params,
@@ -585,6 +628,35 @@ impl Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let x = self.forward_hidden(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            flash_params,
+        )?;
+        let xs = self.apply_lm_head(x)?;
+        extract_logits(&xs, context_lens)
+    }
+
+    fn apply_lm_head(&self, mut x: Tensor) -> Result<Tensor> {
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        MatMul.qmethod_matmul(&x, &*self.lm_head)
+    }
+
+    /// The final normalized hidden states, before the LM head.
+    fn forward_hidden(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -599,13 +671,16 @@ impl Llama {
             x.dtype(),
             self.blocks[0].attn.num_attention_heads,
         )?;
+        let positions = RopePositions::Offsets {
+            offsets: seqlen_offsets,
+            kernel: start_offsets_kernel,
+        };
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = self.mapper.map(x, block_idx)?;
             x = block.forward(
                 &x,
                 &mask.clone().map(|m| m.to_device(x.device()).unwrap()),
-                seqlen_offsets,
-                start_offsets_kernel.clone(),
+                &positions,
                 &mut cache[block_idx],
                 metadata
                     .as_mut()
@@ -614,12 +689,131 @@ impl Llama {
             )?;
         }
         let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            x = x.to_dtype(t)?;
+        self.ln_f.forward(&x)
+    }
+
+    /// The final normalized hidden states of a tree of tokens which follow the cached tokens.
+    fn forward_tree_hidden(&self, input_ids: &Tensor, parents: &[Option<usize>]) -> Result<Tensor> {
+        if self.blocks[0].attn.sdpa_params.use_flash_attn {
+            candle_core::bail!("Tree attention is not supported with flash attention.");
         }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        let cache = &mut self.kv_cache.normal().0;
+        let past_kv_len = cache[0].current_seq_len();
+        // Tokens are positioned by their depth in the tree.
+        let mut positions: Vec<usize> = Vec::with_capacity(parents.len());
+        for parent in parents {
+            let position = parent.map_or(past_kv_len, |p| positions[p] + 1);
+            positions.push(position);
+        }
+        let positions = RopePositions::per_token(&positions, input_ids.device())?;
+        let mut x = self.wte.forward(input_ids)?;
+        let mask = CausalMasker.make_tree_mask_matrix(
+            parents,
+            past_kv_len,
+            x.dtype(),
+            self.blocks[0].attn.num_attention_heads,
+            input_ids.device(),
+        )?;
+        let flash_params = no_flash_params(input_ids.device())?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = self.mapper.map(x, block_idx)?;
+            x = block.forward(
+                &x,
+                &Some(mask.to_device(x.device())?),
+                &positions,
+                &mut cache[block_idx],
+                None,
+                &flash_params,
+            )?;
+        }
+        let x = x.to_device(&self.device)?;
+        self.ln_f.forward(&x)
+    }
+}
+
+/// Flash attention parameters for attention with an explicit mask, where they are not used.
+fn no_flash_params(device: &Device) -> Result<FlashParams> {
+    Ok(FlashParams {
+        max_q: 0,
+        max_k: 0,
+        cumulative_seqlens_q: Tensor::zeros(1, DType::U32, device)?,
+        cumulative_seqlens_k: Tensor::zeros(1, DType::U32, device)?,
+    })
+}
+
+/// The draft layer of EAGLE, which predicts the next final hidden state of the base model from a
+/// hidden state and the embedding of the token sampled from it: <https://arxiv.org/abs/2401.15077>
+///
+/// This is a Llama decoder layer without the input norm, applied to a projection of the
+/// concatenated embedding and hidden state.
+pub(crate) struct EagleLayer {
+    fc: candle_nn::Linear,
+    attn: CausalSelfAttention,
+    rms_2: RmsNorm,
+    mlp: Mlp,
+}
+
+impl EagleLayer {
+    pub(crate) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let fc = candle_nn::linear_b(
+            2 * cfg.hidden_size,
+            cfg.hidden_size,
+            vb.contains_tensor("fc.bias"),
+            vb.pp("fc"),
+        )?;
+        let rope = Arc::new(Llama3RotaryEmbedding::new_llama3(
+            vb.dtype(),
+            cfg,
+            vb.device(),
+            true,
+        )?);
+        let vb_l = vb.pp("layers.0");
+        Ok(Self {
+            fc,
+            attn: CausalSelfAttention::load(vb_l.pp("self_attn"), cfg, rope, None)?,
+            rms_2: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb_l.pp("post_attention_layernorm"),
+            )?,
+            mlp: Mlp::load(vb_l.pp("mlp"), cfg)?,
+        })
+    }
+
+    /// Predict the next hidden states of `(1, seq_len, hidden_size)` hidden states which follow
+    /// the tokens in `kv_cache`, given the embeddings of the tokens sampled from them.
+    pub(crate) fn forward(
+        &self,
+        hidden_states: &Tensor,
+        embeds: &Tensor,
+        kv_cache: &mut KvCache,
+    ) -> Result<Tensor> {
+        let seq_len = hidden_states.dim(1)?;
+        let past_kv_len = kv_cache.current_seq_len();
+        let x = self
+            .fc
+            .forward(&Tensor::cat(&[embeds, hidden_states], D::Minus1)?)?;
+        let parents: Vec<_> = (0..seq_len).map(|i| i.checked_sub(1)).collect();
+        let mask = CausalMasker.make_tree_mask_matrix(
+            &parents,
+            past_kv_len,
+            x.dtype(),
+            self.attn.num_attention_heads,
+            x.device(),
+        )?;
+        let positions: Vec<_> = (past_kv_len..past_kv_len + seq_len).collect();
+        let positions = RopePositions::per_token(&positions, x.device())?;
+        let residual = &x;
+        let x = (self.attn.forward(
+            &x,
+            &Some(mask),
+            &positions,
+            kv_cache,
+            None,
+            &no_flash_params(x.device())?,
+        )? + residual)?;
+        let residual = &x;
+        self.mlp.forward(&self.rms_2.forward(&x)?)? + residual
     }
 }
 
@@ -703,6 +897,32 @@ impl NormalModel for Llama {
             flash_params,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_hidden(
+            input_ids,
+            self.wte.forward(input_ids)?,
+            seqlen_offsets,
+            start_offsets_kernel,
+            None,
+            flash_params,
+        )
+    }
+    fn forward_tree(&self, input_ids: &Tensor, parents: &[Option<usize>]) -> Result<Tensor> {
+        self.forward_tree_hidden(input_ids, parents)
+    }
+    fn logits_from_hidden_states(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        self.apply_lm_head(hidden_states.clone())
+    }
+    fn embed_tokens(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.wte.forward(input_ids)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -861,3 +1081,145 @@ impl AnyMoeBaseModelMixin for Llama {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, IndexOp, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{no_flash_params, Config, Llama};
+    use crate::{
+        paged_attention::AttentionImplementation, pipeline::NormalLoadingMetadata,
+        DeviceMapMetadata,
+    };
+
+    fn config() -> Config {
+        Config {
+            hidden_size: 16,
+            intermediate_size: 32,
+            vocab_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            max_position_embeddings: 64,
+            ..Default::default()
+        }
+    }
+
+    fn random_weights(cfg: &Config) -> Result<HashMap<String, Tensor>> {
+        let (h, i) = (cfg.hidden_size, cfg.intermediate_size);
+        let kv = h / cfg.num_attention_heads * cfg.num_key_value_heads;
+        let mut shapes = vec![
+            ("model.embed_tokens.weight".to_string(), (cfg.vocab_size, h)),
+            ("lm_head.weight".to_string(), (cfg.vocab_size, h)),
+        ];
+        for layer in 0..cfg.num_hidden_layers {
+            let p = format!("model.layers.{layer}");
+            shapes.extend([
+                (format!("{p}.self_attn.q_proj.weight"), (h, h)),
+                (format!("{p}.self_attn.k_proj.weight"), (kv, h)),
+                (format!("{p}.self_attn.v_proj.weight"), (kv, h)),
+                (format!("{p}.self_attn.o_proj.weight"), (h, h)),
+                (format!("{p}.mlp.gate_proj.weight"), (i, h)),
+                (format!("{p}.mlp.up_proj.weight"), (i, h)),
+                (format!("{p}.mlp.down_proj.weight"), (h, i)),
+            ]);
+        }
+        let mut weights = HashMap::new();
+        for (name, shape) in shapes {
+            weights.insert(name, Tensor::randn(0f32, 0.3, shape, &Device::Cpu)?);
+        }
+        let mut norms = vec!["model.norm.weight".to_string()];
+        for layer in 0..cfg.num_hidden_layers {
+            norms.push(format!("model.layers.{layer}.input_layernorm.weight"));
+            norms.push(format!(
+                "model.layers.{layer}.post_attention_layernorm.weight"
+            ));
+        }
+        for name in norms {
+            weights.insert(name, Tensor::ones(h, DType::F32, &Device::Cpu)?);
+        }
+        Ok(weights)
+    }
+
+    fn load(cfg: &Config, weights: &HashMap<String, Tensor>) -> Result<Llama> {
+        let vb = VarBuilder::from_tensors(weights.clone(), DType::F32, &Device::Cpu);
+        let mapper =
+            DeviceMapMetadata::dummy().into_mapper(cfg.num_hidden_layers, &Device::Cpu, None)?;
+        Llama::new(
+            cfg,
+            vb,
+            false,
+            NormalLoadingMetadata {
+                mapper,
+                loading_isq: false,
+                real_device: Device::Cpu,
+            },
+            AttentionImplementation::Eager,
+        )
+    }
+
+    /// Final hidden states of `tokens`, which follow the tokens in the cache.
+    fn forward_sequence(model: &Llama, tokens: &[u32]) -> Result<Tensor> {
+        let past_kv_len = model.kv_cache.normal().0[0].current_seq_len();
+        let input_ids = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let positions_kernel = Tensor::new(
+            (past_kv_len..past_kv_len + tokens.len())
+                .map(|p| p as i64)
+                .collect::<Vec<_>>(),
+            &Device::Cpu,
+        )?
+        .unsqueeze(0)?;
+        model
+            .forward_hidden(
+                &input_ids,
+                model.get_input_embeddings(&input_ids)?,
+                &[past_kv_len],
+                positions_kernel,
+                None,
+                &no_flash_params(&Device::Cpu)?,
+            )?
+            .squeeze(0)
+    }
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn tree_matches_sequential_forward() -> Result<()> {
+        let cfg = config();
+        let weights = random_weights(&cfg)?;
+        let prompt = [1, 2, 3];
+
+        // The root 4 with the two children 5 and 6.
+        let tree = load(&cfg, &weights)?;
+        forward_sequence(&tree, &prompt)?;
+        let input_ids = Tensor::new(&[4u32, 5, 6], &Device::Cpu)?.unsqueeze(0)?;
+        let hidden = tree
+            .forward_tree_hidden(&input_ids, &[None, Some(0), Some(0)])?
+            .squeeze(0)?;
+
+        for (branch, rows) in [([4, 5], [0u32, 1]), ([4, 6], [0, 2])] {
+            let model = load(&cfg, &weights)?;
+            forward_sequence(&model, &prompt)?;
+            let expected = forward_sequence(&model, &branch)?;
+            let actual = hidden.index_select(&Tensor::new(&rows, &Device::Cpu)?, 0)?;
+            assert!(max_abs_diff(&actual, &expected)? < 1e-4);
+        }
+
+        // Accept the branch 4 -> 6 and continue from it.
+        for cache in tree.kv_cache.normal().0.iter_mut() {
+            cache.retain(prompt.len(), &[0, 2])?;
+        }
+        let actual = forward_sequence(&tree, &[7])?;
+        let model = load(&cfg, &weights)?;
+        let expected = forward_sequence(&model, &[1, 2, 3, 4, 6, 7])?.i(5..)?;
+        assert!(max_abs_diff(&actual, &expected)? < 1e-4);
+        Ok(())
+    }
+}
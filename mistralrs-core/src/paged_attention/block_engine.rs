@@ -1,6 +1,6 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    hash::Hash,
+    collections::{hash_map::DefaultHasher, hash_map::Entry, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
     marker::PhantomData,
     ops::Deref,
     sync::{Arc, Mutex, MutexGuard},
@@ -37,9 +37,12 @@ impl LogicalTokenBlock {
         self.num_tokens += 1;
     }
 
+    pub fn token_ids(&self) -> &[usize] {
+        &self.tokens[..self.num_tokens]
+    }
+
     pub fn pop_token(&mut self) {
         assert_ne!(self.num_tokens, 0);
-        self.tokens.pop();
         self.num_tokens -= 1;
     }
 }
@@ -50,6 +53,10 @@ pub struct _PhysicalTokenBlock {
     block_size: usize,
     refcount: usize,
     is_gpu: bool,
+    /// Set if this block is full and registered in the prefix cache.
+    prefix_hash: Option<PrefixHash>,
+    /// Set once a forward pass has written the KV cache of this block.
+    computed: bool,
 }
 
 pub struct PhysicalTokenBlock(pub Mutex<_PhysicalTokenBlock>);
@@ -103,12 +110,39 @@ impl Deref for GPUAllocatorWrapper {
 
 struct Allocator<T> {
     free_blocks: BlockTable,
+    /// Free blocks which are registered in the prefix cache, keyed by the order in which they were
+    /// freed. They are reused after all other free blocks, least recently freed first.
+    free_cached_blocks: BTreeMap<usize, Arc<PhysicalTokenBlock>>,
+    /// The key of each block in `free_cached_blocks`, by block id.
+    free_cached_keys: HashMap<usize, usize>,
+    n_freed: usize,
     _ghost: PhantomData<T>,
 }
 
 impl<T> Allocator<T> {
+    fn from_blocks(free_blocks: BlockTable) -> Self {
+        Allocator {
+            free_blocks,
+            free_cached_blocks: BTreeMap::new(),
+            free_cached_keys: HashMap::new(),
+            n_freed: 0,
+            _ghost: PhantomData,
+        }
+    }
+
+    fn num_free_blocks(&self) -> usize {
+        self.free_blocks.len() + self.free_cached_blocks.len()
+    }
+
     fn allocate(&mut self) -> Arc<PhysicalTokenBlock> {
-        let block = self.free_blocks.pop().unwrap();
+        let block = match self.free_blocks.pop() {
+            Some(block) => block,
+            None => {
+                let (_, block) = self.free_cached_blocks.pop_first().unwrap();
+                self.free_cached_keys.remove(&block.deref_mut().block_id);
+                block
+            }
+        };
         block.deref_mut().refcount = 1;
         block
     }
@@ -122,9 +156,26 @@ impl<T> Allocator<T> {
         }
         block.deref_mut().refcount -= 1;
         if block.deref_mut().refcount == 0 {
-            self.free_blocks.push(block);
+            if block.deref_mut().prefix_hash.is_some() {
+                // Cached blocks are reused last, so that their contents can be shared for as long as possible.
+                let key = self.n_freed;
+                self.n_freed += 1;
+                self.free_cached_keys
+                    .insert(block.deref_mut().block_id, key);
+                self.free_cached_blocks.insert(key, block);
+            } else {
+                self.free_blocks.push(block);
+            }
         }
     }
+
+    /// Take a free cached block with the given id back out of the free list.
+    fn revive(&mut self, block_id: usize) -> Option<Arc<PhysicalTokenBlock>> {
+        let key = self.free_cached_keys.remove(&block_id)?;
+        let block = self.free_cached_blocks.remove(&key)?;
+        block.deref_mut().refcount = 1;
+        Some(block)
+    }
 }
 
 impl Allocator<GPUAllocator> {
@@ -137,17 +188,16 @@ impl Allocator<GPUAllocator> {
                     block_size,
                     refcount: 0,
                     is_gpu: true,
+                    prefix_hash: None,
+                    computed: false,
                 },
             ))))
         }
-        Allocator {
-            free_blocks,
-            _ghost: PhantomData,
-        }
+        Allocator::from_blocks(free_blocks)
     }
 
     fn get_num_free_blocks(&self) -> GPUAllocatorWrapper {
-        GPUAllocatorWrapper(self.free_blocks.len())
+        GPUAllocatorWrapper(self.num_free_blocks())
     }
 }
 
@@ -161,13 +211,12 @@ impl Allocator<CPUAllocator> {
                     block_size,
                     refcount: 0,
                     is_gpu: true,
+                    prefix_hash: None,
+                    computed: false,
                 },
             ))))
         }
-        Allocator {
-            free_blocks,
-            _ghost: PhantomData,
-        }
+        Allocator::from_blocks(free_blocks)
     }
 }
 
@@ -178,16 +227,49 @@ pub enum AllocStatus {
 }
 
 type SeqID = usize;
+type PrefixHash = u64;
+
+/// Hash of a full block, chained with the hash of the block before it so that equal
+/// hashes imply an equal prefix.
+fn hash_block(parent: Option<PrefixHash>, token_ids: &[usize]) -> PrefixHash {
+    let mut hasher = DefaultHasher::new();
+    parent.hash(&mut hasher);
+    token_ids.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hash which the hash of the first block is chained with. Sequences with different adapters
+/// compute different KV caches for the same tokens, so they start from different roots.
+fn root_hash(adapters: Option<&[String]>) -> Option<PrefixHash> {
+    adapters.map(|adapters| {
+        let mut hasher = DefaultHasher::new();
+        adapters.hash(&mut hasher);
+        hasher.finish()
+    })
+}
 
 /// A BlockEngine maps each Sequence (identified by its SeqID), to physical token blocks.
 /// The physical token blocks may not match the logical token blocks because during
 /// scheduling, physical blocks are allocated to accommodate the new tokens generated.
 /// These new tokens will be added to the logical token block for each sequence.
+///
+/// With prefix caching, full GPU blocks are registered by the hash of their prefix, so that
+/// sequences sharing a prompt prefix share the physical blocks (with reference counting).
+/// Freed cached blocks keep their contents until they are reallocated. The prompt step of a
+/// sequence skips the leading shared blocks whose KV cache was already computed, see
+/// [`BlockEngine::computed_prefix_len`].
 pub struct BlockEngine {
+    block_size: usize,
     num_gpu_blocks: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
     pub block_tables: HashMap<SeqID, BlockTable>,
+    prefix_caching: bool,
+    prefix_cache: HashMap<PrefixHash, Arc<PhysicalTokenBlock>>,
+    /// The number of leading full blocks of each sequence which were registered, and the hash of the last one.
+    registered_blocks: HashMap<SeqID, (usize, Option<PrefixHash>)>,
+    /// The number of leading prompt tokens of each sequence which are already computed.
+    computed_prefixes: HashMap<SeqID, usize>,
 }
 
 pub type BlockTables = HashMap<usize, BlockTable>;
@@ -196,15 +278,103 @@ impl BlockEngine {
     #[must_use]
     pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
         Self {
+            block_size,
             num_gpu_blocks,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
             block_tables: HashMap::new(),
+            prefix_caching: true,
+            prefix_cache: HashMap::new(),
+            registered_blocks: HashMap::new(),
+            computed_prefixes: HashMap::new(),
+        }
+    }
+
+    /// The number of leading prompt tokens of a newly allocated sequence whose KV cache was
+    /// already computed for another sequence sharing the blocks. The prompt step does not need
+    /// to recompute them. This is 0 once the sequence is decoding.
+    pub fn computed_prefix_len(&self, seq_id: usize) -> usize {
+        self.computed_prefixes.get(&seq_id).copied().unwrap_or(0)
+    }
+
+    pub fn set_prefix_caching(&mut self, prefix_caching: bool) {
+        self.prefix_caching = prefix_caching;
+        if !prefix_caching {
+            for block in self.prefix_cache.drain().map(|(_, block)| block) {
+                block.deref_mut().prefix_hash = None;
+            }
+        }
+    }
+
+    fn allocate_gpu_block(&mut self) -> Arc<PhysicalTokenBlock> {
+        let block = self.gpu_allocator.allocate();
+        // The contents will be overwritten, so the block can no longer be shared.
+        if let Some(hash) = block.deref_mut().prefix_hash.take() {
+            self.prefix_cache.remove(&hash);
+        }
+        block.deref_mut().computed = false;
+        block
+    }
+
+    /// Get the cached block for this prefix hash, or allocate and register a new one.
+    fn allocate_cached_gpu_block(&mut self, hash: PrefixHash) -> Arc<PhysicalTokenBlock> {
+        if let Some(block) = self.prefix_cache.get(&hash).cloned() {
+            if block.deref_mut().refcount > 0 {
+                block.deref_mut().refcount += 1;
+                return block;
+            }
+            let block_id = block.deref_mut().block_id;
+            if let Some(block) = self.gpu_allocator.revive(block_id) {
+                return block;
+            }
+        }
+        let block = self.allocate_gpu_block();
+        block.deref_mut().prefix_hash = Some(hash);
+        self.prefix_cache.insert(hash, block.clone());
+        block
+    }
+
+    /// Mark the full blocks of a decoding sequence as computed, and register the ones which are not
+    /// yet cached. Only the blocks which became full since the last call are hashed.
+    fn register_full_blocks(&mut self, seq: &impl BlockEngineSequence) {
+        let seq_id = seq.get_id();
+        let Some(table) = self.block_tables.get(&seq_id) else {
+            return;
+        };
+        let (start, mut parent) = self
+            .registered_blocks
+            .get(&seq_id)
+            .copied()
+            .unwrap_or_else(|| (0, root_hash(seq.get_block_adapters())));
+        let full_blocks = seq.get_full_block_token_ids();
+        let end = table.len().min(full_blocks.len());
+        if end <= start {
+            return;
         }
+        for (block, token_ids) in table[start..end].iter().zip(&full_blocks[start..end]) {
+            let hash = hash_block(parent, token_ids);
+            parent = Some(hash);
+            let mut inner = block.deref_mut();
+            if !inner.is_gpu {
+                continue;
+            }
+            inner.computed = true;
+            if self.prefix_caching && inner.prefix_hash.is_none() {
+                if let Entry::Vacant(e) = self.prefix_cache.entry(hash) {
+                    inner.prefix_hash = Some(hash);
+                    e.insert(block.clone());
+                }
+            }
+        }
+        self.registered_blocks.insert(seq_id, (end, parent));
     }
 
     pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
-        let num_required_blocks = seq.get_logical_token_blocks();
+        self.can_allocate_blocks(seq.get_logical_token_blocks())
+    }
+
+    /// Whether `num_required_blocks` GPU blocks can be allocated, such as for the beams of a request.
+    pub fn can_allocate_blocks(&self, num_required_blocks: usize) -> AllocStatus {
         let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
 
         if *num_free_gpu_blocks < num_required_blocks {
@@ -217,17 +387,132 @@ impl BlockEngine {
     }
 
     pub fn allocate(&mut self, seq: &impl BlockEngineSequence) {
+        let mut full_blocks = if self.prefix_caching {
+            seq.get_full_block_token_ids()
+        } else {
+            Vec::new()
+        };
+        // An empty last block means that the prompt ends with a full block. The prompt step
+        // computes at least its last token, so that block is not shared: it will be written.
+        if seq.blocks_to_add_new_tok() == 1 {
+            full_blocks.pop();
+        }
         let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq.get_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+        let mut parent = root_hash(seq.get_block_adapters());
+        let mut n_computed = 0;
+        for logical_idx in 0..seq.get_logical_token_blocks() {
+            let block = match full_blocks.get(logical_idx) {
+                Some(token_ids) => {
+                    let hash = hash_block(parent, token_ids);
+                    parent = Some(hash);
+                    let block = self.allocate_cached_gpu_block(hash);
+                    if n_computed == logical_idx && block.deref_mut().computed {
+                        n_computed += 1;
+                    }
+                    block
+                }
+                None => self.allocate_gpu_block(),
+            };
+            block_table.push(block);
         }
         self.block_tables.insert(seq.get_id(), block_table.clone());
+        self.registered_blocks.remove(&seq.get_id());
+        if n_computed > 0 {
+            self.computed_prefixes
+                .insert(seq.get_id(), n_computed * self.block_size);
+        }
     }
 
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
+        self.can_append_token_to_seqs([seq])
+    }
+
+    /// Whether a token can be appended to all of these sequences, such as the beams of a request.
+    pub fn can_append_token_to_seqs<'a, S: BlockEngineSequence + 'a>(
+        &self,
+        seqs: impl IntoIterator<Item = &'a S>,
+    ) -> bool {
         let free_blocks = self.gpu_allocator.get_num_free_blocks();
-        // Physical blocks = logical blocks
-        seq.blocks_to_add_new_tok() <= *free_blocks
+        let required = seqs
+            .into_iter()
+            .map(|seq| self.num_blocks_to_append(seq))
+            .sum::<usize>();
+        required <= *free_blocks
+    }
+
+    /// A new block is needed to append a token if the last block is full, or if it is shared and
+    /// must be copied on write.
+    fn num_blocks_to_append(&self, seq: &impl BlockEngineSequence) -> usize {
+        if seq.blocks_to_add_new_tok() == 1 {
+            return 1;
+        }
+        let shared = self
+            .block_tables
+            .get(&seq.get_id())
+            .and_then(|table| table.last())
+            .is_some_and(|block| block.deref_mut().refcount > 1);
+        usize::from(shared)
+    }
+
+    /// Replace the block table of each `(child, parent)` sequence by that of its parent, as when
+    /// a beam continues from another one. The blocks are shared, and the last one is copied on
+    /// write when a token is appended. All parents are read before any child is replaced, so
+    /// beams may swap their blocks.
+    pub fn fork_sequences(&mut self, forks: &[(usize, usize)]) {
+        let forked = forks
+            .iter()
+            .filter(|(child, parent)| child != parent)
+            .filter_map(|&(child, parent)| {
+                let table = self.block_tables.get(&parent)?.clone();
+                for block in &table {
+                    block.deref_mut().refcount += 1;
+                }
+                Some((child, table, self.registered_blocks.get(&parent).copied()))
+            })
+            .collect::<Vec<_>>();
+        for (child, table, registered) in forked {
+            self.free_sequence(child);
+            self.block_tables.insert(child, table);
+            if let Some(registered) = registered {
+                self.registered_blocks.insert(child, registered);
+            }
+        }
+    }
+
+    /// Grow the block table of a sequence so that it has slots for `num_tokens` tokens. Speculative
+    /// decoding uses this to write the KV cache of draft tokens before they are accepted.
+    ///
+    /// Returns `false`, without allocating anything, if there are not enough free GPU blocks.
+    pub fn reserve_slots(&mut self, seq_id: usize, num_tokens: usize) -> bool {
+        let Some(num_blocks) = self.block_tables.get(&seq_id).map(Vec::len) else {
+            return false;
+        };
+        let required = num_tokens
+            .div_ceil(self.block_size)
+            .saturating_sub(num_blocks);
+        if required > *self.gpu_allocator.get_num_free_blocks() {
+            return false;
+        }
+        for _ in 0..required {
+            let block = self.allocate_gpu_block();
+            self.block_tables.get_mut(&seq_id).unwrap().push(block);
+        }
+        true
+    }
+
+    /// Free the blocks of a sequence which are not needed for its first `num_tokens` tokens, such
+    /// as those reserved for rejected draft tokens.
+    pub fn truncate_slots(&mut self, seq_id: usize, num_tokens: usize) {
+        let Some(table) = self.block_tables.get_mut(&seq_id) else {
+            return;
+        };
+        let num_blocks = num_tokens.div_ceil(self.block_size);
+        if table.len() <= num_blocks {
+            return;
+        }
+        for block in table.split_off(num_blocks) {
+            self.gpu_allocator.free_block(block);
+        }
     }
 
     pub fn free_sequence(&mut self, id: usize) {
@@ -244,6 +529,8 @@ impl BlockEngine {
 
             self.block_tables.remove(&id);
         }
+        self.registered_blocks.remove(&id);
+        self.computed_prefixes.remove(&id);
     }
 
     #[allow(dead_code)]
@@ -254,7 +541,7 @@ impl BlockEngine {
             .filter(|(id, _)| seq.get_id() == **id)
             .map(|(_, table)| table.len())
             .sum();
-        blocks_required <= self.cpu_allocator.free_blocks.len()
+        blocks_required <= self.cpu_allocator.num_free_blocks()
     }
 
     /// Update the block table so that the sequence does no longer reserve any GPU
@@ -301,25 +588,45 @@ impl BlockEngine {
         &mut self,
         sequence: &impl BlockEngineSequence,
     ) -> Option<(usize, usize)> {
-        let table = self.block_tables.get_mut(&sequence.get_id())?;
+        if !self.block_tables.contains_key(&sequence.get_id()) {
+            return None;
+        }
+        // The sequence is decoding, so the KV cache of its full blocks has been written.
+        self.computed_prefixes.remove(&sequence.get_id());
+        self.register_full_blocks(sequence);
 
         match sequence.blocks_to_add_new_tok() {
             1 => {
-                table.push(self.gpu_allocator.allocate());
+                let new_block = self.allocate_gpu_block();
+                self.block_tables
+                    .get_mut(&sequence.get_id())
+                    .unwrap()
+                    .push(new_block);
                 None
             }
             0 => {
-                let last_block = table.last_mut().unwrap();
+                let last_block = self
+                    .block_tables
+                    .get(&sequence.get_id())
+                    .unwrap()
+                    .last()
+                    .unwrap()
+                    .clone();
                 assert!(last_block.deref_mut().is_gpu);
                 if last_block.deref_mut().refcount == 1 {
                     None
                 } else {
                     // We would be writing into shared, so COW.
-                    let new_block = self.gpu_allocator.allocate();
+                    let new_block = self.allocate_gpu_block();
                     self.gpu_allocator.free_block(last_block.clone());
                     let old_number = last_block.deref_mut().block_id;
                     let new_number = new_block.deref_mut().block_id;
-                    *last_block = new_block;
+                    *self
+                        .block_tables
+                        .get_mut(&sequence.get_id())
+                        .unwrap()
+                        .last_mut()
+                        .unwrap() = new_block;
                     Some((old_number, new_number))
                 }
             }
@@ -336,7 +643,7 @@ impl BlockEngine {
             .filter(|(id, _)| seq.get_id() == **id)
             .map(|(_, table)| table.len())
             .sum();
-        blocks_required <= self.gpu_allocator.free_blocks.len()
+        blocks_required <= self.gpu_allocator.num_free_blocks()
     }
 
     /// Update the block table so that the sequence does no longer reserve any CPU
@@ -376,3 +683,344 @@ impl BlockEngine {
             .collect::<HashMap<_, _>>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockEngine, BlockEngineSequence, LogicalTokenBlock};
+
+    const BLOCK_SIZE: usize = 4;
+
+    struct TestSeq {
+        id: usize,
+        blocks: Vec<LogicalTokenBlock>,
+        adapters: Option<Vec<String>>,
+    }
+
+    impl TestSeq {
+        fn new(id: usize, tokens: &[usize]) -> Self {
+            let mut seq = Self {
+                id,
+                blocks: vec![LogicalTokenBlock::new(BLOCK_SIZE)],
+                adapters: None,
+            };
+            for tok in tokens {
+                seq.push(*tok);
+            }
+            seq
+        }
+
+        /// Mirrors `Sequence`: a new logical block is added as soon as the last one is full.
+        fn push(&mut self, tok: usize) {
+            self.blocks.last_mut().unwrap().append_token_id(tok);
+            if self.blocks.last().unwrap().is_full() {
+                self.blocks.push(LogicalTokenBlock::new(BLOCK_SIZE));
+            }
+        }
+    }
+
+    impl BlockEngineSequence for TestSeq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            let last = self.blocks.last().unwrap();
+            usize::from(last.is_full() || last.is_empty())
+        }
+
+        fn get_id(&self) -> usize {
+            self.id
+        }
+
+        fn get_logical_token_blocks(&self) -> usize {
+            self.blocks.len()
+        }
+
+        fn get_full_block_token_ids(&self) -> Vec<&[usize]> {
+            self.blocks
+                .iter()
+                .take_while(|block| block.is_full())
+                .map(|block| block.token_ids())
+                .collect()
+        }
+
+        fn get_block_adapters(&self) -> Option<&[String]> {
+            self.adapters.as_deref()
+        }
+    }
+
+    fn block_ids(engine: &BlockEngine, id: usize) -> Vec<usize> {
+        engine.block_tables[&id]
+            .iter()
+            .map(|block| block.deref_mut().block_id)
+            .collect()
+    }
+
+    fn refcounts(engine: &BlockEngine, id: usize) -> Vec<usize> {
+        engine.block_tables[&id]
+            .iter()
+            .map(|block| block.deref_mut().refcount)
+            .collect()
+    }
+
+    #[test]
+    fn shared_prefix_reuses_blocks() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 5, 6, 7, 8, 10, 11]);
+        engine.allocate(&a);
+        engine.allocate(&b);
+
+        let (a_ids, b_ids) = (block_ids(&engine, 0), block_ids(&engine, 1));
+        assert_eq!(a_ids[..2], b_ids[..2]);
+        assert_ne!(a_ids[2], b_ids[2]);
+        assert_eq!(refcounts(&engine, 0), vec![2, 2, 1]);
+        // 2 shared blocks and 2 partial blocks.
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 12);
+
+        engine.free_sequence(0);
+        assert_eq!(refcounts(&engine, 1), vec![1, 1, 1]);
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 13);
+    }
+
+    #[test]
+    fn diverging_prefix_only_shares_common_blocks() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 5, 6, 7, 0]);
+        // Same second block, but after a different first block.
+        let c = TestSeq::new(2, &[0, 2, 3, 4, 5, 6, 7, 8]);
+        engine.allocate(&a);
+        engine.allocate(&b);
+        engine.allocate(&c);
+
+        let (a_ids, b_ids, c_ids) = (
+            block_ids(&engine, 0),
+            block_ids(&engine, 1),
+            block_ids(&engine, 2),
+        );
+        assert_eq!(a_ids[0], b_ids[0]);
+        assert_ne!(a_ids[1], b_ids[1]);
+        assert_ne!(a_ids[0], c_ids[0]);
+        assert_ne!(a_ids[1], c_ids[1]);
+    }
+
+    #[test]
+    fn freed_cached_blocks_are_revived() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5]);
+        engine.allocate(&a);
+        let a_ids = block_ids(&engine, 0);
+        engine.free_sequence(0);
+
+        // Unrelated allocations take uncached blocks first.
+        let other = TestSeq::new(1, &[9, 9]);
+        engine.allocate(&other);
+        assert_ne!(block_ids(&engine, 1)[0], a_ids[0]);
+
+        let b = TestSeq::new(2, &[1, 2, 3, 4, 6]);
+        engine.allocate(&b);
+        assert_eq!(block_ids(&engine, 2)[0], a_ids[0]);
+        assert_eq!(refcounts(&engine, 2)[0], 1);
+    }
+
+    #[test]
+    fn decoded_blocks_are_registered() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let mut a = TestSeq::new(0, &[1, 2, 3]);
+        engine.allocate(&a);
+        a.push(4);
+        assert_eq!(engine.append_token_slot_to_seq(&a), None);
+
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 5]);
+        engine.allocate(&b);
+        assert_eq!(block_ids(&engine, 0)[0], block_ids(&engine, 1)[0]);
+    }
+
+    #[test]
+    fn shared_last_block_is_copied_on_write() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let a = TestSeq::new(0, &[1, 2]);
+        engine.allocate(&a);
+        // Fork the sequence, as if it shared its partial block.
+        let table = engine.block_tables[&0].clone();
+        for block in &table {
+            block.deref_mut().refcount += 1;
+        }
+        engine.block_tables.insert(1, table);
+
+        let mut b = TestSeq::new(1, &[1, 2]);
+        b.push(3);
+        let (src, dst) = engine.append_token_slot_to_seq(&b).unwrap();
+        assert_eq!(src, block_ids(&engine, 0)[0]);
+        assert_eq!(dst, block_ids(&engine, 1)[0]);
+        assert_ne!(src, dst);
+        assert_eq!(refcounts(&engine, 0), vec![1]);
+    }
+
+    #[test]
+    fn forked_beams_share_blocks() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let mut a = TestSeq::new(0, &[1, 2, 3, 4, 5]);
+        let mut b = TestSeq::new(1, &[1, 2, 3, 4, 6]);
+        engine.allocate(&a);
+        engine.allocate(&b);
+        let (a_ids, b_ids) = (block_ids(&engine, 0), block_ids(&engine, 1));
+
+        // The beams swap, and a third continues from the first.
+        let c = TestSeq::new(2, &[1, 2, 3, 4, 6]);
+        engine.allocate(&c);
+        engine.fork_sequences(&[(0, 1), (1, 0), (2, 1)]);
+        assert_eq!(block_ids(&engine, 0), b_ids);
+        assert_eq!(block_ids(&engine, 1), a_ids);
+        assert_eq!(block_ids(&engine, 2), b_ids);
+        assert_eq!(refcounts(&engine, 0), vec![3, 2]);
+        assert_eq!(refcounts(&engine, 1), vec![3, 1]);
+
+        // Appending to the shared partial block needs a copy, the other block has room.
+        a.push(7);
+        b.push(8);
+        assert!(engine.can_append_token_to_seqs([&a, &b]));
+        assert_eq!(engine.num_blocks_to_append(&a), 1);
+        assert_eq!(engine.num_blocks_to_append(&b), 0);
+        let (src, dst) = engine.append_token_slot_to_seq(&a).unwrap();
+        assert_eq!(src, b_ids[1]);
+        assert_eq!(dst, block_ids(&engine, 0)[1]);
+        assert_eq!(refcounts(&engine, 2), vec![3, 1]);
+        assert_eq!(engine.append_token_slot_to_seq(&b), None);
+    }
+
+    #[test]
+    fn disabled_prefix_caching_does_not_share() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        engine.set_prefix_caching(false);
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5]);
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 5]);
+        engine.allocate(&a);
+        engine.allocate(&b);
+        assert_ne!(block_ids(&engine, 0)[0], block_ids(&engine, 1)[0]);
+    }
+
+    #[test]
+    fn different_adapters_do_not_share() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let toks = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut a = TestSeq::new(0, &toks);
+        a.adapters = Some(vec!["a".to_string()]);
+        let mut b = TestSeq::new(1, &toks);
+        b.adapters = Some(vec!["b".to_string()]);
+        let c = TestSeq::new(2, &toks);
+        let mut d = TestSeq::new(3, &toks);
+        d.adapters = Some(vec!["a".to_string()]);
+        for seq in [&a, &b, &c, &d] {
+            engine.allocate(seq);
+        }
+
+        let ids = (0..4).map(|id| block_ids(&engine, id)).collect::<Vec<_>>();
+        assert_ne!(ids[0][0], ids[1][0]);
+        assert_ne!(ids[0][0], ids[2][0]);
+        assert_ne!(ids[1][0], ids[2][0]);
+        assert_eq!(ids[0][..2], ids[3][..2]);
+
+        // Decoded blocks are registered under the adapters of their sequence too.
+        a.push(10);
+        engine.append_token_slot_to_seq(&a);
+        let e = TestSeq::new(4, &toks);
+        engine.allocate(&e);
+        assert_eq!(engine.computed_prefix_len(4), 0);
+        assert_ne!(block_ids(&engine, 4)[0], ids[0][0]);
+    }
+
+    #[test]
+    fn reserved_slots_are_truncated() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 4, 0);
+        let mut a = TestSeq::new(0, &[1, 2, 3]);
+        engine.allocate(&a);
+        assert_eq!(block_ids(&engine, 0).len(), 1);
+
+        // Slots for 9 tokens take 3 blocks, slots for 17 would take more than are free.
+        assert!(engine.reserve_slots(0, 9));
+        assert_eq!(block_ids(&engine, 0).len(), 3);
+        assert!(!engine.reserve_slots(0, 17));
+        assert_eq!(block_ids(&engine, 0).len(), 3);
+
+        // Two tokens were accepted.
+        a.push(4);
+        a.push(5);
+        engine.truncate_slots(0, 5);
+        assert_eq!(block_ids(&engine, 0).len(), 2);
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 2);
+        assert_eq!(engine.append_token_slot_to_seq(&a), None);
+        assert_eq!(block_ids(&engine, 0).len(), 2);
+    }
+
+    #[test]
+    fn computed_shared_blocks_are_skipped() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let mut a = TestSeq::new(0, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        engine.allocate(&a);
+        // Nothing was computed before the first prompt step.
+        assert_eq!(engine.computed_prefix_len(0), 0);
+
+        // Sharing blocks which are still being computed does not skip them.
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 5, 6, 7, 8, 10]);
+        engine.allocate(&b);
+        assert_eq!(engine.computed_prefix_len(1), 0);
+
+        // Once `a` decodes, its prompt blocks are computed.
+        a.push(10);
+        assert_eq!(engine.append_token_slot_to_seq(&a), None);
+        let mut c = TestSeq::new(2, &[1, 2, 3, 4, 5, 6, 7, 8, 11]);
+        engine.allocate(&c);
+        assert_eq!(engine.computed_prefix_len(2), 8);
+        assert_eq!(block_ids(&engine, 0)[..2], block_ids(&engine, 2)[..2]);
+
+        // A partially computed prefix is only skipped up to the first block which is not computed.
+        let d = TestSeq::new(3, &[1, 2, 3, 4, 0, 0, 0, 0, 1]);
+        engine.allocate(&d);
+        assert_eq!(engine.computed_prefix_len(3), 4);
+
+        // Decoding clears the computed prefix.
+        c.push(12);
+        engine.append_token_slot_to_seq(&c);
+        assert_eq!(engine.computed_prefix_len(2), 0);
+    }
+
+    #[test]
+    fn prompt_ending_on_a_block_boundary_computes_its_last_block() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+        let mut a = TestSeq::new(0, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        engine.allocate(&a);
+        a.push(10);
+        engine.append_token_slot_to_seq(&a);
+
+        // The last token must be computed, so the last full block is private.
+        let b = TestSeq::new(1, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        engine.allocate(&b);
+        assert_eq!(engine.computed_prefix_len(1), 4);
+        assert_eq!(block_ids(&engine, 0)[0], block_ids(&engine, 1)[0]);
+        assert_ne!(block_ids(&engine, 0)[1], block_ids(&engine, 1)[1]);
+    }
+
+    #[test]
+    fn least_recently_freed_cached_block_is_reused_first() {
+        let mut engine = BlockEngine::new(BLOCK_SIZE, 6, 0);
+        let a = TestSeq::new(0, &[1, 2, 3, 4, 5]);
+        let b = TestSeq::new(1, &[5, 6, 7, 8, 9]);
+        engine.allocate(&a);
+        let a_id = block_ids(&engine, 0)[0];
+        engine.free_sequence(0);
+        engine.allocate(&b);
+        let b_id = block_ids(&engine, 1)[0];
+        engine.free_sequence(1);
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 6);
+
+        // Uncached blocks are used first, then the cached block of `a`, which was freed first.
+        let c = TestSeq::new(2, &[0; 17]);
+        engine.allocate(&c);
+        assert_eq!(block_ids(&engine, 2)[4], a_id);
+        assert!(!block_ids(&engine, 2).contains(&b_id));
+        engine.free_sequence(2);
+
+        // The block of `b` can still be revived.
+        engine.allocate(&b);
+        assert_eq!(block_ids(&engine, 1)[0], b_id);
+        assert_eq!(refcounts(&engine, 1), vec![1, 1]);
+    }
+}
@@ -2,4 +2,10 @@ pub trait BlockEngineSequence {
     fn blocks_to_add_new_tok(&self) -> usize;
     fn get_id(&self) -> usize;
     fn get_logical_token_blocks(&self) -> usize;
+    /// The token ids of each leading logical block which is full, used to share physical
+    /// blocks between sequences with a common prefix.
+    fn get_full_block_token_ids(&self) -> Vec<&[usize]>;
+    /// The adapters the sequence runs with. The KV cache depends on them, so blocks are only
+    /// shared between sequences with the same adapters.
+    fn get_block_adapters(&self) -> Option<&[String]>;
 }
@@ -76,6 +76,8 @@ impl PagedAttention {
         #[allow(clippy::cast_possible_truncation)]
         let att = match attention_mask {
             None => None,
+            // Attention over the cache is computed below, one query token at a time.
+            Some(_) if input_metadata.attend_to_cache => None,
             Some(mask) => Some(Sdpa.run_attention(
                 query,
                 key,
@@ -146,7 +148,7 @@ impl PagedAttention {
         //
         //  alibi_slopes: shape = [num_heads]
         #[allow(clippy::cast_possible_truncation)]
-        paged_attention(
+        let res = paged_attention(
             &query,
             key_cache.as_ref().unwrap(),
             value_cache.as_ref().unwrap(),
@@ -156,6 +158,14 @@ impl PagedAttention {
             input_metadata.max_context_len.unwrap(),
             self.scale,
             softcapping.unwrap_or(1.0f64) as f32,
-        )
+        )?;
+
+        if attention_mask.is_some() {
+            // Callers expect the prefill layout: [batch_size, num_heads, seq_len, head_size]
+            res.reshape((batch_size, seq_len, attention_heads, head_size))?
+                .transpose(1, 2)
+        } else {
+            Ok(res)
+        }
     }
 }
@@ -69,41 +69,45 @@ impl PagedAttentionScheduler {
         if self.swapped_out.is_empty() {
             let mut scheduled = VecDeque::new();
             let mut did_ignore = false;
-            while !self.waiting.is_empty() {
-                let seq = self.waiting.front().unwrap().clone();
-
-                // If adding this seq means we will have too many, stop as no more could be added.
-                if self.config.max_num_seqs == self.running.len() + 1 {
+            // The beams of a beam search request are admitted together, so that they run in the
+            // same steps.
+            while let Some(group) = pop_group(&mut self.waiting, false) {
+                // If adding this group means we will have too many, stop as no more could be added.
+                if self.running.len() + group.len() > self.config.max_num_seqs {
+                    push_front_group(&mut self.waiting, group);
                     break;
                 }
 
-                // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
-                let can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
+                // If we cannot allocate either now or in the future, either do not continue or remove the group.
+                let can_allocate = self
+                    .block_engine
+                    .can_allocate_blocks(num_blocks_to_allocate(&group));
                 match can_allocate {
-                    AllocStatus::Later => break, // If we can only allocate later, do not bother iterating over the rest.
+                    AllocStatus::Later => {
+                        // If we can only allocate later, do not bother iterating over the rest.
+                        push_front_group(&mut self.waiting, group);
+                        break;
+                    }
                     AllocStatus::Impossible => {
-                        let id = *get_mut_arcmutex!(seq).id();
-                        let len = get_mut_arcmutex!(seq).get_toks().len();
-                        warn!(
-                            "Sequence {id} with length of {len} tokens is too long and exceeds capacity of block engine. Sequence will be ignored.",
-                        );
-                        get_mut_arcmutex!(seq).set_state(SequenceState::FinishedIgnored);
+                        for seq in &group {
+                            let seq = get_mut_arcmutex!(seq);
+                            let id = *seq.id();
+                            let len = seq.get_toks().len();
+                            warn!(
+                                "Sequence {id} with length of {len} tokens is too long and exceeds capacity of block engine. Sequence will be ignored.",
+                            );
+                            seq.set_state(SequenceState::FinishedIgnored);
+                        }
                         did_ignore = true;
+                        self.running.extend(group);
+                        continue;
                     }
-                    _ => {}
-                }
-
-                if !did_ignore {
-                    get_mut_arcmutex!(seq).set_state(SequenceState::RunningPrompt);
-                    let seq_handle = get_mut_arcmutex!(seq);
-                    self._allocate(&seq_handle);
+                    AllocStatus::Ok => {}
                 }
 
-                let seq = self.waiting.pop_front().unwrap();
-                self.running.push_back(seq.clone());
-                if !did_ignore {
-                    scheduled.push_back(seq);
-                }
+                self._allocate_group(&group);
+                self.running.extend(group.iter().cloned());
+                scheduled.extend(group);
             }
 
             // If we did schedule, or we ignored sequences.
@@ -130,37 +134,37 @@ impl PagedAttentionScheduler {
         // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
         self.sort_running_by_priority_fcfs();
 
+        // The beams of a beam search request are preempted together.
         let mut running = VecDeque::new();
         let mut did_preempt = false;
-        while !self.running.is_empty() {
-            let seq = self.running.pop_front().unwrap();
+        while let Some(group) = pop_group(&mut self.running, false) {
             let mut finished_with_break = false;
-            while !self
-                .block_engine
-                .can_append_token_to_seq(&*get_mut_arcmutex!(seq))
-            {
+            while !self.can_append_token_to_group(&group) {
                 // If we cannot, now we need to preempt some seqs
-                if !self.running.is_empty() {
+                if let Some(group_to_preempt) = pop_group(&mut self.running, true) {
                     // There is something to preempt.
-                    let seq_to_preempt = self.running.pop_back().unwrap();
-                    self._preempt(seq_to_preempt, &mut blocks_to_swap_out);
+                    for seq in group_to_preempt {
+                        self._preempt(seq, &mut blocks_to_swap_out);
+                    }
                     did_preempt = true;
                 } else {
                     // Nothing to preempt, preempt ourselves. Also, do not bother looking at anything else.
-                    self._preempt(seq.clone(), &mut blocks_to_swap_out);
+                    for seq in &group {
+                        self._preempt(seq.clone(), &mut blocks_to_swap_out);
+                    }
                     did_preempt = true;
                     finished_with_break = true;
                     break;
                 }
             }
             if !finished_with_break {
-                {
+                for seq in &group {
                     // If we need to, append physical blocks for a new token. We do not need to if there is enough space.
                     // If we just got preempted, there is no reason to allocate
                     let seq_handle = get_mut_arcmutex!(seq);
                     self._append_token_slot_to_seq(&seq_handle, &mut blocks_to_copy);
                 }
-                running.push_back(seq);
+                running.extend(group);
             }
         }
         self.running = running;
@@ -230,7 +234,27 @@ impl PagedAttentionScheduler {
 }
 
 impl PagedAttentionScheduler {
-    #[allow(dead_code)]
+    /// Remove all sequences belonging to this request and free their blocks.
+    pub fn cancel_request(&mut self, request_id: usize) -> usize {
+        let seq_ids = self
+            .waiting
+            .iter()
+            .chain(self.running.iter())
+            .chain(self.swapped_out.iter())
+            .filter_map(|seq| {
+                let seq = get_mut_arcmutex!(seq);
+                (seq.request_id() == request_id).then_some(seq.get_id())
+            })
+            .collect::<Vec<_>>();
+
+        for seq_id in &seq_ids {
+            let removed = self.remove_seq(*seq_id);
+            get_mut_arcmutex!(removed).set_state(SequenceState::Done(StopReason::Canceled));
+            self._free(*seq_id);
+        }
+        seq_ids.len()
+    }
+
     fn remove_seq(&mut self, seq_id: usize) -> Arc<Mutex<Sequence>> {
         // Remove it if it is in waiting
         if let Some(idx) = self
@@ -313,8 +337,31 @@ impl PagedAttentionScheduler {
         self.swapped_out.push_back(seq);
     }
 
-    fn _allocate(&mut self, seq: &Sequence) {
-        self.block_engine.allocate(seq)
+    /// Allocate the blocks of a group of sequences. Beams which have not diverged from the first
+    /// one share its blocks.
+    fn _allocate_group(&mut self, group: &[Arc<Mutex<Sequence>>]) {
+        let first = get_mut_arcmutex!(group[0]);
+        first.set_state(SequenceState::RunningPrompt);
+        self.block_engine.allocate(&*first);
+        for seq in &group[1..] {
+            let seq = get_mut_arcmutex!(seq);
+            seq.set_state(SequenceState::RunningPrompt);
+            if seq.get_toks() == first.get_toks() {
+                self.block_engine
+                    .fork_sequences(&[(seq.get_id(), first.get_id())]);
+            } else {
+                self.block_engine.allocate(&*seq);
+            }
+        }
+    }
+
+    fn can_append_token_to_group(&self, group: &[Arc<Mutex<Sequence>>]) -> bool {
+        let seqs = group
+            .iter()
+            .map(|seq| get_mut_arcmutex!(seq))
+            .collect::<Vec<_>>();
+        self.block_engine
+            .can_append_token_to_seqs(seqs.iter().map(|seq| &**seq))
     }
 
     fn _free(&mut self, seq_id: usize) {
@@ -336,6 +383,51 @@ impl PagedAttentionScheduler {
     }
 }
 
+/// Take the sequence at the front (or back) of the queue, with the other beams of its request if
+/// it is a beam.
+fn pop_group(
+    queue: &mut VecDeque<Arc<Mutex<Sequence>>>,
+    back: bool,
+) -> Option<Vec<Arc<Mutex<Sequence>>>> {
+    let first = if back {
+        queue.pop_back()?
+    } else {
+        queue.pop_front()?
+    };
+    let beam_request = {
+        let first = get_mut_arcmutex!(first);
+        first.is_beam().then(|| first.request_id())
+    };
+    let mut group = vec![first];
+    if let Some(request_id) = beam_request {
+        let (beams, rest): (Vec<_>, VecDeque<_>) = std::mem::take(queue)
+            .into_iter()
+            .partition(|seq| get_mut_arcmutex!(seq).request_id() == request_id);
+        *queue = rest;
+        group.extend(beams);
+    }
+    Some(group)
+}
+
+fn push_front_group(queue: &mut VecDeque<Arc<Mutex<Sequence>>>, group: Vec<Arc<Mutex<Sequence>>>) {
+    for seq in group.into_iter().rev() {
+        queue.push_front(seq);
+    }
+}
+
+/// The number of blocks to allocate for a group, see [`PagedAttentionScheduler::_allocate_group`].
+fn num_blocks_to_allocate(group: &[Arc<Mutex<Sequence>>]) -> usize {
+    let first = get_mut_arcmutex!(group[0]);
+    let mut num_blocks = first.get_logical_token_blocks();
+    for seq in &group[1..] {
+        let seq = get_mut_arcmutex!(seq);
+        if seq.get_toks() != first.get_toks() {
+            num_blocks += seq.get_logical_token_blocks();
+        }
+    }
+    num_blocks
+}
+
 impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
@@ -351,6 +443,22 @@ impl Scheduler for PagedAttentionScheduler {
     fn running_len(&self) -> usize {
         self.running.len()
     }
+    fn max_num_seqs(&self) -> usize {
+        self.config.max_num_seqs
+    }
+    fn cancel_request(&mut self, request_id: usize) -> usize {
+        self.cancel_request(request_id)
+    }
+    fn set_mixed_adapters(&mut self, _mixed_adapters: bool) {
+        // Sequences are not bucketed, and adapter models do not support PagedAttention.
+    }
+    fn uses_adapter(&self, adapter: &str) -> bool {
+        self.waiting
+            .iter()
+            .chain(self.running.iter())
+            .chain(self.swapped_out.iter())
+            .any(|seq| get_mut_arcmutex!(seq).uses_adapter(adapter))
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         Some(&self.block_engine.block_tables)
     }
@@ -364,3 +472,148 @@ impl Scheduler for PagedAttentionScheduler {
         Some(&mut self.block_engine)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::{AllocStatus, CacheConfig, PagedAttentionScheduler, PagedAttentionSchedulerConfig};
+    use crate::{
+        get_mut_arcmutex,
+        pipeline::BeamSearchState,
+        sampler::BeamSearchParams,
+        scheduler::Scheduler,
+        sequence::{Sequence, SequenceGroup, SequenceState, StopReason, TestSequence},
+    };
+
+    const NUM_GPU_BLOCKS: usize = 16;
+
+    fn scheduler() -> PagedAttentionScheduler {
+        let mut scheduler = PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig { max_num_seqs: 4 },
+            CacheConfig {
+                block_size: 4,
+                num_gpu_blocks: NUM_GPU_BLOCKS,
+                num_cpu_blocks: 0,
+            },
+        );
+        // The test prompts are all the same, so they would share blocks.
+        scheduler.block_engine.set_prefix_caching(false);
+        scheduler
+    }
+
+    fn seq(id: usize, request_id: usize) -> Sequence {
+        TestSequence {
+            id,
+            request_id,
+            prompt_len: 6,
+            block_size: Some(4),
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn beams(first_id: usize, request_id: usize, beam_width: usize) -> Vec<Sequence> {
+        let mut group = SequenceGroup::new(1, false, false, 1);
+        group.beam = Some(BeamSearchState::new(BeamSearchParams::new(beam_width), 1));
+        let group = Arc::new(Mutex::new(group));
+        (first_id..first_id + beam_width)
+            .map(|id| {
+                TestSequence {
+                    id,
+                    request_id,
+                    prompt_len: 6,
+                    block_size: Some(4),
+                    group: Some(group.clone()),
+                    ..Default::default()
+                }
+                .build()
+            })
+            .collect()
+    }
+
+    fn ids(seqs: &[Arc<std::sync::Mutex<Sequence>>]) -> Vec<usize> {
+        let mut ids = seqs
+            .iter()
+            .map(|seq| *get_mut_arcmutex!(seq).id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn block_ids(scheduler: &PagedAttentionScheduler, id: usize) -> Vec<usize> {
+        scheduler.block_engine.block_tables[&id]
+            .iter()
+            .map(|block| block.deref_mut().block_id)
+            .collect()
+    }
+
+    fn all_blocks_free(scheduler: &PagedAttentionScheduler) -> bool {
+        matches!(
+            scheduler.block_engine.can_allocate_blocks(NUM_GPU_BLOCKS),
+            AllocStatus::Ok
+        )
+    }
+
+    #[test]
+    fn cancel_waiting_and_running() {
+        let mut scheduler = scheduler();
+        scheduler.add_seq(seq(0, 0));
+        assert_eq!(scheduler.schedule().scheduled.len(), 1);
+        assert!(scheduler.block_engine.block_tables.contains_key(&0));
+        assert!(!all_blocks_free(&scheduler));
+        let running = scheduler.running[0].clone();
+
+        scheduler.add_seq(seq(1, 1));
+        scheduler.add_seq(seq(2, 1));
+        let waiting = scheduler.waiting[0].clone();
+        assert_eq!(scheduler.cancel_request(1), 2);
+        assert!(scheduler.waiting.is_empty());
+        assert_eq!(scheduler.running.len(), 1);
+        assert_eq!(
+            get_mut_arcmutex!(waiting).getstate(),
+            SequenceState::Done(StopReason::Canceled)
+        );
+
+        // The blocks of the running sequence are freed.
+        assert_eq!(scheduler.cancel_request(0), 1);
+        assert!(scheduler.running.is_empty());
+        assert!(scheduler.block_engine.block_tables.is_empty());
+        assert!(all_blocks_free(&scheduler));
+        assert_eq!(
+            get_mut_arcmutex!(running).getstate(),
+            SequenceState::Done(StopReason::Canceled)
+        );
+        assert_eq!(scheduler.cancel_request(0), 0);
+    }
+
+    #[test]
+    fn beams_are_admitted_together_and_share_blocks() {
+        let mut scheduler = scheduler();
+        scheduler.add_seq(seq(0, 0));
+        scheduler.add_seq(seq(1, 1));
+        for beam in beams(2, 2, 3) {
+            scheduler.add_seq(beam);
+        }
+        // Only two of the three beams would fit next to sequences 0 and 1.
+        assert_eq!(ids(&scheduler.schedule().scheduled), vec![0, 1]);
+
+        scheduler.cancel_request(1);
+        assert_eq!(ids(&scheduler.schedule().scheduled), vec![2, 3, 4]);
+        assert_eq!(block_ids(&scheduler, 2), block_ids(&scheduler, 3));
+        assert_eq!(block_ids(&scheduler, 2), block_ids(&scheduler, 4));
+
+        // The shared partial block is copied for all beams but one before they write to it.
+        let shared = block_ids(&scheduler, 2)[1];
+        let output = scheduler.schedule();
+        assert_eq!(ids(&output.scheduled), vec![0, 2, 3, 4]);
+        assert_eq!(output.blocks_to_copy[&shared].len(), 2);
+        let last_blocks = (2..5)
+            .map(|id| block_ids(&scheduler, id)[1])
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(last_blocks.len(), 3);
+        assert!(last_blocks.contains(&shared));
+    }
+}
@@ -2,7 +2,7 @@ use std::{
     any::Any,
     fs::{self, File},
     io::Read,
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
@@ -21,7 +21,7 @@ use crate::{
     amoe::{AnyMoeConfig, AnyMoeTrainingInputRow, AnyMoeTrainingInputs, AnyMoeTrainingResult},
     get_mut_arcmutex,
     prefix_cacher::PrefixCacheManager,
-    sampler::Sampler,
+    sampler::{Sampler, SamplingParams},
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
     DeviceMapMetadata, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig,
@@ -182,6 +182,21 @@ impl AdapterActivationMixin for AnyMoePipeline {
     fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         get_mut_arcmutex!(self.target).activate_adapters(adapters)
     }
+    fn activate_adapters_per_seq(&mut self, adapters: Vec<Vec<String>>) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).activate_adapters_per_seq(adapters)
+    }
+    fn supports_mixed_adapters(&self) -> bool {
+        get_mut_arcmutex!(self.target).supports_mixed_adapters()
+    }
+    fn load_adapter(&mut self, name: String, path: PathBuf) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).load_adapter(name, path)
+    }
+    fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).unload_adapter(name)
+    }
+    fn initial_adapters(&self) -> Option<Vec<String>> {
+        get_mut_arcmutex!(self.target).initial_adapters()
+    }
 }
 
 impl CacheManagerMixin for AnyMoePipeline {
@@ -367,15 +382,9 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
         // Create several dummy objects for the sequences. No custom logits processors.
         let (dummy_sender, _) = tokio::sync::mpsc::channel(10000);
         let dummy_sampler = Sampler::new(
-            None,
-            0,
+            &SamplingParams::deterministic(),
             tokenizer.clone(),
             None,
-            None,
-            None,
-            -1,
-            0.0,
-            0.0,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
@@ -564,6 +573,7 @@ fn new_dummy_seq(
         prompt,
         0,
         0,
+        0,
         1,
         dummy_sender,
         dummy_sampler,
@@ -580,6 +590,7 @@ fn new_dummy_seq(
         None,
         None,
         images,
+        None,
         None, // TODO incorrect for PagedAttention
         None,
         None,
@@ -588,5 +599,7 @@ fn new_dummy_seq(
         None,
         None,
         false,
+        0,
+        None,
     )
 }
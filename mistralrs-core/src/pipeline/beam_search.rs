@@ -0,0 +1,250 @@
+use candle_core::{DType, Result, Tensor};
+
+use crate::{
+    prefix_cacher::PrefixCacheManager,
+    sampler::{BeamSearchParams, Logprobs},
+    sequence::{BeamSnapshot, Sequence, SequenceState, StopReason},
+};
+
+use super::{sampling::finish_or_add_toks_to_seq, Pipeline};
+
+/// A finished beam, waiting for the search to end.
+struct BeamHypothesis {
+    score: f32,
+    snapshot: BeamSnapshot,
+    /// The token which finished the beam.
+    logprobs: Logprobs,
+}
+
+/// The beam search of a request, shared by its beams through their sequence group.
+pub(crate) struct BeamSearchState {
+    params: BeamSearchParams,
+    n_choices: usize,
+    // The best finished beams, best first
+    finished: Vec<BeamHypothesis>,
+    // Whether the beams have been extended, before which they are all the prompt
+    started: bool,
+}
+
+impl BeamSearchState {
+    pub(crate) fn new(params: BeamSearchParams, n_choices: usize) -> Self {
+        Self {
+            params,
+            n_choices,
+            finished: Vec::new(),
+            started: false,
+        }
+    }
+
+    fn add_finished(&mut self, hypothesis: BeamHypothesis) {
+        let pos = self
+            .finished
+            .partition_point(|other| other.score >= hypothesis.score);
+        self.finished.insert(pos, hypothesis);
+        self.finished.truncate(self.params.beam_width);
+    }
+
+    /// Whether the search is over, given the best score of the running beams.
+    fn is_done(&self, best_running_score: Option<f32>) -> bool {
+        let Some(best_running_score) = best_running_score else {
+            return true;
+        };
+        if self.finished.len() < self.params.beam_width {
+            return false;
+        }
+        self.params.early_stopping
+            || self
+                .finished
+                .last()
+                .is_some_and(|worst| worst.score >= best_running_score)
+    }
+}
+
+/// Length normalized score of a beam with `len` generated tokens.
+#[allow(clippy::cast_precision_loss)]
+fn score(cumulative_logprob: f32, len: usize, length_penalty: f32) -> f32 {
+    cumulative_logprob / (len as f32).powf(length_penalty)
+}
+
+struct Candidate {
+    parent: usize,
+    cumulative_logprob: f32,
+    logprobs: Logprobs,
+}
+
+/// Extend the beams of the scheduled beam search requests by one token. Every beam is replaced by
+/// one of the best extensions of all beams of its request, which may continue from another beam.
+/// The scheduler runs all beams of a request in the same step. The pipeline must clone the KV
+/// caches of the beams in on the next step.
+pub(crate) async fn step_beams(
+    this: &dyn Pipeline,
+    mut beams: Vec<(&mut Sequence, Tensor)>,
+    prefix_cacher: &mut PrefixCacheManager,
+    disable_eos_stop: bool,
+) -> Result<()> {
+    beams.sort_by_key(|(seq, _)| (seq.request_id(), seq.get_response_index()));
+    for request in beams.chunk_by_mut(|(a, _), (b, _)| a.request_id() == b.request_id()) {
+        step_request(this, request, prefix_cacher, disable_eos_stop).await?;
+    }
+    Ok(())
+}
+
+async fn step_request(
+    this: &dyn Pipeline,
+    beams: &mut [(&mut Sequence, Tensor)],
+    prefix_cacher: &mut PrefixCacheManager,
+    disable_eos_stop: bool,
+) -> Result<()> {
+    let (params, n_choices, started) = {
+        let group = beams[0].0.get_mut_group();
+        let state = group
+            .beam
+            .as_ref()
+            .expect("Beams have a beam search state.");
+        (state.params.clone(), state.n_choices, state.started)
+    };
+    if beams.len() != params.beam_width {
+        candle_core::bail!(
+            "Expected all {} beams of request {} in the batch, got {}.",
+            params.beam_width,
+            beams[0].0.request_id(),
+            beams.len()
+        );
+    }
+    let metadata = this.get_metadata();
+    let eos_tok = if disable_eos_stop {
+        None
+    } else {
+        Some(&metadata.eos_tok[..])
+    };
+
+    // All beams start out as the prompt, so the first step only extends one of them.
+    let n_parents = if started { beams.len() } else { 1 };
+    let mut candidates = Vec::new();
+    for (parent, (seq, logits)) in beams.iter_mut().take(n_parents).enumerate() {
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        let return_logprobs = seq.return_logprobs();
+        let extensions = seq.sampler().beam_candidates(
+            logits,
+            seq.get_toks(),
+            2 * params.beam_width,
+            return_logprobs,
+        )?;
+        candidates.extend(extensions.into_iter().map(|logprobs| Candidate {
+            parent,
+            cumulative_logprob: seq.cumulative_logprob() + logprobs.logprob,
+            logprobs,
+        }));
+    }
+    candidates.sort_by(|a, b| b.cumulative_logprob.total_cmp(&a.cumulative_logprob));
+
+    let mut running = Vec::new();
+    let mut finished = Vec::new();
+    for (rank, candidate) in candidates.into_iter().enumerate() {
+        if running.len() == beams.len() {
+            break;
+        }
+        let parent = &beams[candidate.parent].0;
+        let len = parent.get_toks().len() - parent.prompt_tokens() + 1;
+        let beam_score = score(candidate.cumulative_logprob, len, params.length_penalty);
+        if parent
+            .is_done(candidate.logprobs.token, eos_tok, metadata.max_seq_len)
+            .is_some()
+        {
+            // Like the running beams, only the best extensions can finish.
+            if rank < params.beam_width {
+                finished.push(BeamHypothesis {
+                    score: beam_score,
+                    snapshot: parent.beam_snapshot(false),
+                    logprobs: candidate.logprobs,
+                });
+            }
+        } else {
+            running.push((beam_score, candidate));
+        }
+    }
+
+    let done = {
+        let mut group = beams[0].0.get_mut_group();
+        let state = group
+            .beam
+            .as_mut()
+            .expect("Beams have a beam search state.");
+        for hypothesis in finished {
+            state.add_finished(hypothesis);
+        }
+        state.started = true;
+        state
+            .is_done(running.iter().map(|(score, _)| *score).reduce(f32::max))
+            .then(|| std::mem::take(&mut state.finished))
+    };
+
+    if let Some(finished) = done {
+        // The beams finish with the best sequences, in order, and the rest are dropped.
+        let mut finished = finished.into_iter();
+        for (seq, _) in beams.iter_mut() {
+            match finished.next() {
+                Some(hypothesis) if seq.get_response_index() < n_choices => {
+                    seq.restore_beam(hypothesis.snapshot)?;
+                    finish_or_add_toks_to_seq(
+                        this,
+                        prefix_cacher,
+                        seq,
+                        hypothesis.logprobs,
+                        eos_tok,
+                        false,
+                    )
+                    .await?;
+                }
+                _ => seq.set_state(SequenceState::Done(StopReason::Canceled)),
+            }
+        }
+        return Ok(());
+    }
+
+    let tok_trie = metadata.tok_trie.as_ref().ok_or(candle_core::Error::Msg(
+        "Beam search requires the pipeline to have a token trie".to_string(),
+    ))?;
+    // If too many extensions finished, the best running beams are duplicated.
+    let assigned = (0..beams.len())
+        .map(|slot| &running[slot % running.len()].1)
+        .collect::<Vec<_>>();
+    // Snapshot the beams which are continued elsewhere before any of them are replaced.
+    let snapshots = (0..beams.len())
+        .map(|i| {
+            assigned
+                .iter()
+                .enumerate()
+                .any(|(slot, candidate)| candidate.parent == i && slot != i)
+                .then(|| beams[i].0.beam_snapshot(true))
+        })
+        .collect::<Vec<_>>();
+    for (slot, candidate) in assigned.into_iter().enumerate() {
+        let seq = &mut beams[slot].0;
+        if candidate.parent != slot {
+            let snapshot = snapshots[candidate.parent]
+                .clone()
+                .expect("Continued beams have a snapshot.");
+            seq.restore_beam(snapshot)?;
+        }
+        seq.add_token(
+            candidate.logprobs.clone(),
+            tok_trie.decode(&[candidate.logprobs.token]),
+            &None,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::score;
+
+    #[test]
+    fn length_penalty() {
+        // Without a length penalty, the shorter sequence is more likely.
+        assert!(score(-2.0, 2, 0.0) > score(-3.0, 4, 0.0));
+        // Normalizing by the length prefers the longer one.
+        assert!(score(-2.0, 2, 1.0) < score(-3.0, 4, 1.0));
+    }
+}
@@ -4,7 +4,7 @@ use candle_core::{Result, Tensor, D};
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
-use super::{CacheManagerMixin, MetadataMixin};
+use super::{CacheManagerMixin, KvCacheDType, MetadataMixin};
 
 pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
     fn clone_in_cache(
@@ -59,6 +59,10 @@ pub struct SingleCache {
     pub current_seq_len: usize,
     pub capacity_seq_len: usize,
     pub max_seq_len: usize,
+    // If the cache is quantized, `all_data` holds the quantized data and this holds the scales,
+    // laid out like `all_data` with a last dimension of 1.
+    pub kv_dtype: KvCacheDType,
+    pub scales: Option<Tensor>,
 }
 
 impl SingleCache {
@@ -69,6 +73,8 @@ impl SingleCache {
             current_seq_len: 0,
             max_seq_len,
             capacity_seq_len,
+            kv_dtype: KvCacheDType::Auto,
+            scales: None,
         }
     }
 
@@ -89,9 +95,15 @@ impl SingleCache {
     }
 
     pub fn current_data(&self) -> Result<Option<Tensor>> {
-        let data = match self.all_data.as_ref() {
-            None => None,
-            Some(d) => Some(d.narrow(self.dim, 0, self.current_seq_len)?),
+        let data = match (self.all_data.as_ref(), self.scales.as_ref()) {
+            (None, _) => None,
+            (Some(d), Some(scales)) if self.kv_dtype.is_quantized() => {
+                Some(self.kv_dtype.dequantize(
+                    &d.narrow(self.dim, 0, self.current_seq_len)?,
+                    &scales.narrow(self.dim, 0, self.current_seq_len)?,
+                )?)
+            }
+            (Some(d), _) => Some(d.narrow(self.dim, 0, self.current_seq_len)?),
         };
         Ok(data)
     }
@@ -99,21 +111,70 @@ impl SingleCache {
     pub fn reset(&mut self) {
         self.current_seq_len = 0;
         self.all_data = None;
+        self.scales = None;
+    }
+
+    /// Grow `data` along `dim` to `capacity_seq_len`, or create it if there is none.
+    fn grow(
+        data: Option<&Tensor>,
+        like: &Tensor,
+        dim: usize,
+        capacity_seq_len: usize,
+    ) -> Result<Tensor> {
+        let mut shape = like.dims().to_vec();
+        shape[dim] = capacity_seq_len;
+        let ad = Tensor::zeros(shape, like.dtype(), like.device())?;
+        if let Some(data) = data {
+            ad.slice_set(data, dim, 0)?;
+        }
+        Ok(ad)
     }
 
     pub fn set_len(&mut self, len: usize) {
         self.current_seq_len = len;
     }
 
+    /// Keep the first `start` tokens and the tokens at `positions` (relative to `start`), in that
+    /// order, dropping the rest.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn retain(&mut self, start: usize, positions: &[usize]) -> Result<()> {
+        let index: Vec<u32> = positions.iter().map(|p| (start + p) as u32).collect();
+        for data in [self.all_data.as_ref(), self.scales.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            let index = Tensor::new(index.as_slice(), data.device())?;
+            let kept = data.index_select(&index, self.dim)?;
+            data.slice_set(&kept, self.dim, start)?;
+        }
+        self.current_seq_len = start + positions.len();
+        Ok(())
+    }
+
+    /// Clone with copies of the data, so that appending to either cache does not write into the
+    /// other one.
+    pub fn deep_clone(&self) -> Result<Self> {
+        let mut cache = self.clone();
+        cache.all_data = self.all_data.as_ref().map(Tensor::copy).transpose()?;
+        cache.scales = self.scales.as_ref().map(Tensor::copy).transpose()?;
+        Ok(cache)
+    }
+
     pub fn append(&mut self, src: &Tensor) -> Result<()> {
         let seq_len = src.dim(self.dim)?;
+        let (src, scales) = if self.kv_dtype.is_quantized() {
+            let (src, scales) = self.kv_dtype.quantize(src)?;
+            (src, Some(scales))
+        } else {
+            (src.clone(), None)
+        };
         // This doesn't seem very idiomatic but because the creation can fail, it's tricky to use
         // self.all_data.get_or_insert_with.
         if self.all_data.is_none() {
-            let mut shape = src.dims().to_vec();
-            shape[self.dim] = self.capacity_seq_len;
-            let ad = Tensor::zeros(shape, src.dtype(), src.device())?;
-            self.all_data = Some(ad);
+            self.all_data = Some(Self::grow(None, &src, self.dim, self.capacity_seq_len)?);
+            if let Some(scales) = &scales {
+                self.scales = Some(Self::grow(None, scales, self.dim, self.capacity_seq_len)?);
+            }
         };
         // Expand kv cache
         if self.current_seq_len + seq_len > self.capacity_seq_len {
@@ -127,14 +188,26 @@ impl SingleCache {
                     self.max_seq_len
                 )
             }
-            let mut shape = src.dims().to_vec();
-            shape[self.dim] = self.capacity_seq_len;
-            let ad = Tensor::zeros(shape, src.dtype(), src.device())?;
-            ad.slice_set(self.all_data.as_ref().unwrap(), self.dim, 0)?;
-            self.all_data = Some(ad);
+            self.all_data = Some(Self::grow(
+                self.all_data.as_ref(),
+                &src,
+                self.dim,
+                self.capacity_seq_len,
+            )?);
+            if let Some(scales) = &scales {
+                self.scales = Some(Self::grow(
+                    self.scales.as_ref(),
+                    scales,
+                    self.dim,
+                    self.capacity_seq_len,
+                )?);
+            }
         }
         let ad = self.all_data.as_mut().unwrap();
-        ad.slice_set(src, self.dim, self.current_seq_len)?;
+        ad.slice_set(&src, self.dim, self.current_seq_len)?;
+        if let (Some(all_scales), Some(scales)) = (self.scales.as_mut(), scales) {
+            all_scales.slice_set(&scales, self.dim, self.current_seq_len)?;
+        }
         self.current_seq_len += seq_len;
         Ok(())
     }
@@ -245,6 +318,32 @@ impl KvCache {
         self.k.set_len(len);
         self.v.set_len(len);
     }
+
+    /// Keep the first `start` tokens and the tokens at `positions` (relative to `start`), in that
+    /// order, dropping the rest.
+    pub fn retain(&mut self, start: usize, positions: &[usize]) -> Result<()> {
+        self.k.retain(start, positions)?;
+        self.v.retain(start, positions)
+    }
+
+    /// Clone with copies of the data, see [`SingleCache::deep_clone`].
+    pub fn deep_clone(&self) -> Result<Self> {
+        Ok(Self {
+            k: self.k.deep_clone()?,
+            v: self.v.deep_clone()?,
+        })
+    }
+
+    pub fn kv_dtype(&self) -> KvCacheDType {
+        self.k.kv_dtype
+    }
+
+    /// Set the storage dtype of the cache. This resets the cache.
+    pub fn set_kv_dtype(&mut self, kv_dtype: KvCacheDType) {
+        self.reset();
+        self.k.kv_dtype = kv_dtype;
+        self.v.kv_dtype = kv_dtype;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -264,6 +363,46 @@ impl NormalCache {
             len
         ])))
     }
+
+    /// Set the storage dtype of all layers. This resets the cache.
+    pub fn set_kv_dtype(&mut self, kv_dtype: KvCacheDType) {
+        for layer in &mut self.0 {
+            layer.set_kv_dtype(kv_dtype);
+        }
+    }
+
+    /// The per-sequence caches of a sequence continuing from the given keys and values, such as a
+    /// cached prefix. They are laid out and stored like the layers of this cache.
+    pub fn prefilled(&self, caches: &LayerCaches) -> Result<Vec<Option<KvCache>>> {
+        self.0
+            .iter()
+            .zip(caches)
+            .map(|(template, layer)| {
+                layer
+                    .as_ref()
+                    .map(|(k, v)| {
+                        let mut cache = KvCache::new(
+                            template.k.dim,
+                            template.k.max_seq_len,
+                            Self::CACHE_GROW_SIZE,
+                        );
+                        cache.set_kv_dtype(template.kv_dtype());
+                        cache.append(k, v)?;
+                        Ok(cache)
+                    })
+                    .transpose()
+            })
+            .collect()
+    }
+}
+
+/// Concatenate the per-sequence tensors along the batch dimension.
+fn cat_seqs(xs: &[Tensor]) -> Tensor {
+    if xs.len() > 1 {
+        Tensor::cat(xs, 0).unwrap()
+    } else {
+        xs[0].clone()
+    }
 }
 
 pub struct NormalCacheManager;
@@ -290,10 +429,13 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
             .unwrap()
             .k
             .capacity_seq_len;
+        let template_kv_dtype = seqs[0].normal_cache()[0].as_ref().unwrap().kv_dtype();
 
         'outer: for layer in 0..pipeline.get_metadata().num_hidden_layers {
             let mut k_vec = Vec::new();
             let mut v_vec = Vec::new();
+            let mut k_scales_vec = Vec::new();
+            let mut v_scales_vec = Vec::new();
             for seq in &mut *seqs {
                 let src_cache = seq.normal_cache();
                 let cache = src_cache.get(layer).unwrap();
@@ -308,20 +450,18 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     .expect("Not handling completions in `clone_in_cache`.");
                 k_vec.push(cache.k.all_data.clone().unwrap());
                 v_vec.push(cache.v.all_data.clone().unwrap());
+                k_scales_vec.extend(cache.k.scales.clone());
+                v_scales_vec.extend(cache.v.scales.clone());
             }
-            new_k_cache.push(Some(if k_vec.len() > 1 {
-                Tensor::cat(&k_vec, 0).unwrap()
-            } else {
-                k_vec[0].clone()
-            }));
-            new_v_cache.push(Some(if v_vec.len() > 1 {
-                Tensor::cat(&v_vec, 0).unwrap()
-            } else {
-                v_vec[0].clone()
-            }));
+            let k_scales = (!k_scales_vec.is_empty()).then(|| cat_seqs(&k_scales_vec));
+            let v_scales = (!v_scales_vec.is_empty()).then(|| cat_seqs(&v_scales_vec));
+            new_k_cache.push(Some((cat_seqs(&k_vec), k_scales)));
+            new_v_cache.push(Some((cat_seqs(&v_vec), v_scales)));
         }
         let mut caches = Vec::new();
         for (k_cache, v_cache) in new_k_cache.into_iter().zip(new_v_cache) {
+            let (k_cache, k_scales) = k_cache.unzip();
+            let (v_cache, v_scales) = v_cache.unzip();
             caches.push(KvCache {
                 k: SingleCache {
                     all_data: k_cache.map(|x| x.contiguous().unwrap()),
@@ -329,6 +469,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     current_seq_len: template_cache_csl,
                     max_seq_len: template_cache_msl,
                     capacity_seq_len: template_cache_capsl,
+                    kv_dtype: template_kv_dtype,
+                    scales: k_scales.flatten().map(|x| x.contiguous().unwrap()),
                 },
                 v: SingleCache {
                     all_data: v_cache.map(|x| x.contiguous().unwrap()),
@@ -336,6 +478,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     current_seq_len: template_cache_csl,
                     max_seq_len: template_cache_msl,
                     capacity_seq_len: template_cache_capsl,
+                    kv_dtype: template_kv_dtype,
+                    scales: v_scales.flatten().map(|x| x.contiguous().unwrap()),
                 },
             });
         }
@@ -357,6 +501,16 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
             debug_assert_eq!(k_caches.len(), seqs.len());
             let v_caches = v_cache.chunk(seqs.len(), 0).unwrap();
             debug_assert_eq!(v_caches.len(), seqs.len());
+            let k_scales = cache
+                .k
+                .scales
+                .as_ref()
+                .map(|scales| scales.chunk(seqs.len(), 0).unwrap());
+            let v_scales = cache
+                .v
+                .scales
+                .as_ref()
+                .map(|scales| scales.chunk(seqs.len(), 0).unwrap());
 
             for (seq_i, seq) in seqs.iter_mut().enumerate() {
                 let output_cache = seq.normal_cache();
@@ -370,6 +524,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                         current_seq_len: cache.k.current_seq_len,
                         max_seq_len: cache.k.max_seq_len,
                         capacity_seq_len: cache.k.capacity_seq_len,
+                        kv_dtype: cache.k.kv_dtype,
+                        scales: k_scales.as_ref().map(|scales| scales[seq_i].clone()),
                     },
                     v: SingleCache {
                         all_data: Some(v),
@@ -377,6 +533,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                         current_seq_len: cache.v.current_seq_len,
                         max_seq_len: cache.v.max_seq_len,
                         capacity_seq_len: cache.v.capacity_seq_len,
+                        kv_dtype: cache.v.kv_dtype,
+                        scales: v_scales.as_ref().map(|scales| scales[seq_i].clone()),
                     },
                 });
             }
@@ -394,7 +552,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
         let template_cache_msl = pipeline.cache().normal().0[0].k.max_seq_len;
 
         for layer in pipeline.cache().normal().0.iter_mut() {
-            if !load_preallocated_cache {
+            // The preallocated cache is in the activation dtype, so it is not used for quantized caches.
+            if !load_preallocated_cache || layer.kv_dtype().is_quantized() {
                 layer.reset();
                 continue;
             }
@@ -422,6 +581,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     current_seq_len: 0,
                     max_seq_len: template_cache_msl,
                     capacity_seq_len: k_cache.dims()[template_cache_dim],
+                    kv_dtype: KvCacheDType::Auto,
+                    scales: None,
                 },
                 v: SingleCache {
                     all_data: Some(v_cache.zeros_like().unwrap()),
@@ -429,6 +590,8 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for NormalCa
                     current_seq_len: 0,
                     max_seq_len: template_cache_msl,
                     capacity_seq_len: k_cache.dims()[template_cache_dim],
+                    kv_dtype: KvCacheDType::Auto,
+                    scales: None,
                 },
             };
             *layer = cache;
@@ -753,3 +916,48 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for FullCach
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, IndexOp, Tensor};
+
+    use super::{KvCache, KvCacheDType};
+
+    #[test]
+    fn retain_keeps_prefix_and_selected_tokens() {
+        for kv_dtype in [KvCacheDType::Auto, KvCacheDType::Q8] {
+            let mut cache = KvCache::new(2, 16, 8);
+            cache.set_kv_dtype(kv_dtype);
+            // Token `i` is filled with `i + 1`, so that no head is all zeros.
+            let xs = Tensor::arange(1f32, 7., &Device::Cpu)
+                .unwrap()
+                .reshape((1, 1, 6, 1))
+                .unwrap()
+                .broadcast_as((1, 2, 6, 4))
+                .unwrap()
+                .contiguous()
+                .unwrap();
+            cache.append(&xs, &xs).unwrap();
+
+            // Keep the first 2 tokens, then the tokens at 2 + 3 and 2 + 1.
+            cache.retain(2, &[3, 1]).unwrap();
+            assert_eq!(cache.k_cache().current_seq_len(), 4);
+            for data in [cache.k().unwrap(), cache.v().unwrap()] {
+                let kept = data.unwrap().i((0, 1, .., 0)).unwrap();
+                let kept = kept.to_vec1::<f32>().unwrap();
+                for (kept, expected) in kept.iter().zip([1., 2., 6., 4.]) {
+                    assert!(
+                        (kept - expected).abs() < 1e-2,
+                        "{kv_dtype}: {kept} != {expected}"
+                    );
+                }
+            }
+
+            // Appending continues after the retained tokens.
+            cache
+                .append(&xs.narrow(2, 0, 1).unwrap(), &xs.narrow(2, 0, 1).unwrap())
+                .unwrap();
+            assert_eq!(cache.k_cache().current_seq_len(), 5);
+        }
+    }
+}
@@ -217,6 +217,7 @@ impl Loader for DiffusionLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_trie: None,
+                dry_breakers: None,
                 is_xlora: false,
                 num_hidden_layers: 1, // FIXME(EricLBuehler): we know this is only for caching, so its OK.
                 eos_tok: vec![],
@@ -307,8 +308,15 @@ impl Pipeline for DiffusionPipeline {
     ) -> candle_core::Result<ForwardInputsResult> {
         assert!(!return_raw_logits);
 
-        let ModelInputs { prompts, params } = *inputs.downcast().expect("Downcast failed.");
-        let img = self.model.forward(prompts, params)?.to_dtype(DType::U8)?;
+        let ModelInputs {
+            prompts,
+            params,
+            init_image,
+        } = *inputs.downcast().expect("Downcast failed.");
+        let img = self
+            .model
+            .forward(prompts, params, init_image)?
+            .to_dtype(DType::U8)?;
         let (_b, c, h, w) = img.dims4()?;
         let mut images = Vec::new();
         for b_img in img.chunk(img.dim(0)?, 0)? {
@@ -0,0 +1,353 @@
+use super::loaders::{EmbeddingModelPaths, EmbeddingModelPathsInner};
+use super::{
+    AdapterActivationMixin, AnyMoePipelineMixin, Cache, CacheManagerMixin, EitherCache,
+    EmbeddingLoaderType, EmbeddingModel, EmbeddingModelLoader, ForwardInputsResult,
+    GeneralMetadata, IsqPipelineMixin, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
+    PreProcessingMixin, Processor, TokenSource,
+};
+use crate::pipeline::{BasicProcessor, BertLoader, ChatTemplate};
+use crate::prefix_cacher::PrefixCacheManager;
+use crate::sequence::Sequence;
+use crate::utils::debug::DeviceRepr;
+use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::{DeviceMapMetadata, PagedAttentionConfig, Pipeline, TryIntoDType};
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+/// How the hidden states of each token are reduced to one embedding.
+pub enum EmbeddingPooling {
+    /// Average over all tokens.
+    #[default]
+    #[serde(rename = "mean")]
+    Mean,
+    /// Hidden state of the last token, for causal models.
+    #[serde(rename = "last")]
+    LastToken,
+    /// Hidden state of the first (`[CLS]`) token, for BERT-style models.
+    #[serde(rename = "cls")]
+    Cls,
+}
+
+impl EmbeddingPooling {
+    /// Pool hidden states of shape `(seq_len, hidden_size)` into an L2-normalized embedding.
+    pub(crate) fn pool(&self, hidden_states: &Tensor) -> candle_core::Result<Vec<f32>> {
+        let hidden_states = hidden_states.to_dtype(DType::F32)?;
+        let pooled = match self {
+            Self::Mean => hidden_states.mean(0)?,
+            Self::LastToken => hidden_states.get(hidden_states.dim(0)? - 1)?,
+            Self::Cls => hidden_states.get(0)?,
+        };
+        let norm = pooled.sqr()?.sum_all()?.sqrt()?.clamp(1e-12, f32::MAX)?;
+        pooled.broadcast_div(&norm)?.to_vec1()
+    }
+}
+
+pub struct EmbeddingPipeline {
+    model: Box<dyn EmbeddingModel + Send + Sync>,
+    tokenizer: Arc<Tokenizer>,
+    model_id: String,
+    metadata: Arc<GeneralMetadata>,
+    dummy_cache: EitherCache,
+}
+
+/// A loader for an encoder-only embedding model.
+pub struct EmbeddingLoader {
+    inner: Box<dyn EmbeddingModelLoader>,
+    model_id: String,
+    config: EmbeddingSpecificConfig,
+    kind: ModelKind,
+}
+
+#[derive(Default)]
+/// A builder for a loader for an encoder-only embedding model.
+pub struct EmbeddingLoaderBuilder {
+    model_id: Option<String>,
+    config: EmbeddingSpecificConfig,
+    kind: ModelKind,
+}
+
+#[derive(Clone, Default)]
+/// Config specific to loading an embedding model.
+pub struct EmbeddingSpecificConfig {
+    pub tokenizer_json: Option<String>,
+}
+
+impl EmbeddingLoaderBuilder {
+    pub fn new(config: EmbeddingSpecificConfig, model_id: Option<String>) -> Self {
+        Self {
+            config,
+            model_id,
+            kind: ModelKind::Normal,
+        }
+    }
+
+    pub fn build(self, loader: EmbeddingLoaderType) -> Box<dyn Loader> {
+        let loader: Box<dyn EmbeddingModelLoader> = match loader {
+            EmbeddingLoaderType::Bert => Box::new(BertLoader),
+        };
+        Box::new(EmbeddingLoader {
+            inner: loader,
+            model_id: self.model_id.unwrap(),
+            config: self.config,
+            kind: self.kind,
+        })
+    }
+}
+
+impl Loader for EmbeddingLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths: anyhow::Result<Box<dyn ModelPaths>> = {
+            let api = ApiBuilder::new()
+                .with_progress(!silent)
+                .with_token(get_token(&token_source)?)
+                .build()?;
+            let revision = revision.unwrap_or("main".to_string());
+            let api = api.repo(Repo::with_revision(
+                self.model_id.clone(),
+                RepoType::Model,
+                revision.clone(),
+            ));
+            let model_id = std::path::Path::new(&self.model_id);
+            Ok(Box::new(EmbeddingModelPaths(
+                EmbeddingModelPathsInner::from_hf(
+                    &api,
+                    model_id,
+                    self.config.tokenizer_json.as_deref(),
+                )?,
+            )))
+        };
+        self.load_model_from_path(
+            &paths?,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            paged_attn_config,
+        )
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        let paths = &paths
+            .as_ref()
+            .as_any()
+            .downcast_ref::<EmbeddingModelPaths>()
+            .expect("Path downcast failed.")
+            .0;
+
+        // Otherwise, the device mapper will print it
+        if mapper.is_dummy() {
+            info!(
+                "Loading model `{}` on {}.",
+                self.get_id(),
+                device.device_pretty_repr()
+            );
+        } else {
+            anyhow::bail!("Device mapping is not supported for embedding models.");
+        }
+
+        if in_situ_quant.is_some() {
+            anyhow::bail!("ISQ is not supported for embedding models.");
+        }
+
+        if paged_attn_config.is_some() {
+            warn!("PagedAttention is not supported for embedding models, disabling it.");
+        }
+
+        let config = std::fs::read_to_string(&paths.config_filename)?;
+        let mapper = mapper.into_mapper(usize::MAX, device, None)?;
+        let dtype = mapper.get_min_dtype(dtype)?;
+
+        let model = match self.kind {
+            ModelKind::Normal => {
+                let vb = from_mmaped_safetensors(
+                    paths.filenames.clone(),
+                    Vec::new(),
+                    Some(dtype),
+                    device,
+                    silent,
+                    None,
+                    |_| true,
+                )?;
+                self.inner.load(&config, vb)?
+            }
+            _ => unreachable!(),
+        };
+        let tokenizer = get_tokenizer(&paths.tokenizer_filename, None)?;
+
+        let max_seq_len = model.max_seq_len();
+        Ok(Arc::new(Mutex::new(EmbeddingPipeline {
+            model,
+            tokenizer: Arc::new(tokenizer),
+            model_id: self.model_id.clone(),
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len,
+                tok_trie: None,
+                dry_breakers: None,
+                is_xlora: false,
+                num_hidden_layers: 1, // NOTE: only used for caching, which these models do not do.
+                eos_tok: vec![],
+                kind: self.kind.clone(),
+                has_no_kv_cache: true,
+                activation_dtype: dtype,
+                sliding_window: None,
+                cache_config: None,
+                cache_engine: None,
+                prompt_batchsize: None,
+                model_metadata: None,
+            }),
+            dummy_cache: EitherCache::Full(Cache::new(0, false)),
+        })))
+    }
+
+    fn get_id(&self) -> String {
+        self.model_id.to_string()
+    }
+
+    fn get_kind(&self) -> ModelKind {
+        self.kind.clone()
+    }
+}
+
+impl PreProcessingMixin for EmbeddingPipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        Arc::new(BasicProcessor)
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        None
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for EmbeddingPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("Embedding models do not support ISQ.")
+    }
+}
+
+impl CacheManagerMixin for EmbeddingPipeline {
+    fn clone_in_cache(&self, _seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {}
+    fn clone_out_cache(&self, _seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {}
+    fn set_none_cache(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _reset_non_granular: bool,
+        _modify_draft_cache: bool,
+        _load_preallocated_cache: bool,
+    ) {
+    }
+    fn cache(&self) -> &EitherCache {
+        &self.dummy_cache
+    }
+}
+
+impl AdapterActivationMixin for EmbeddingPipeline {
+    fn activate_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+        anyhow::bail!("Embedding models do not support adapter activation.");
+    }
+}
+
+impl MetadataMixin for EmbeddingPipeline {
+    fn device(&self) -> Device {
+        self.model.device().clone()
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+    fn name(&self) -> String {
+        self.model_id.clone()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        Some(self.tokenizer.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for EmbeddingPipeline {
+    fn forward_inputs(
+        &mut self,
+        _inputs: Box<dyn Any>,
+        _return_raw_logits: bool,
+    ) -> candle_core::Result<ForwardInputsResult> {
+        candle_core::bail!("Embedding models can only be used to compute embeddings.");
+    }
+    fn embed(&mut self, toks: &[u32], pooling: EmbeddingPooling) -> candle_core::Result<Vec<f32>> {
+        let input_ids = Tensor::new(toks, self.model.device())?.unsqueeze(0)?;
+        let hidden_states = self.model.forward(&input_ids)?.squeeze(0)?;
+        pooling.pool(&hidden_states)
+    }
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManager,
+        _disable_eos_stop: bool,
+        _srng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<(), candle_core::Error> {
+        candle_core::bail!("`sample_causal_gen` is incompatible with `EmbeddingPipeline`");
+    }
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Embedding
+    }
+}
+
+impl AnyMoePipelineMixin for EmbeddingPipeline {}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::EmbeddingPooling;
+
+    #[test]
+    fn pooling() {
+        let hidden_states = Tensor::new(&[[3f32, 0.], [0., 4.], [1., 1.]], &Device::Cpu).unwrap();
+        assert_eq!(
+            EmbeddingPooling::Cls.pool(&hidden_states).unwrap(),
+            vec![1., 0.]
+        );
+        let last = EmbeddingPooling::LastToken.pool(&hidden_states).unwrap();
+        assert!((last[0] - 0.5f32.sqrt()).abs() < 1e-6);
+        assert!((last[1] - 0.5f32.sqrt()).abs() < 1e-6);
+        // The mean is (4 / 3, 5 / 3), with a norm of sqrt(41) / 3.
+        let mean = EmbeddingPooling::Mean.pool(&hidden_states).unwrap();
+        assert!((mean[0] - 4. / 41f32.sqrt()).abs() < 1e-6);
+        assert!((mean[1] - 5. / 41f32.sqrt()).abs() < 1e-6);
+    }
+}
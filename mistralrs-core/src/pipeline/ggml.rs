@@ -10,12 +10,13 @@ use super::{
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
-use crate::lora::Ordering;
+use crate::lora::{AdapterOp, Ordering};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::get_chat_template;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::sampler::DryBreakerTable;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::model_config as ModelConfig;
@@ -366,6 +367,7 @@ impl Loader for GGMLLoader {
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
+        let dry_breakers = Arc::new(DryBreakerTable::new(&tokenizer)?);
         let num_hidden_layers = match model {
             Model::Llama(ref model) => model.cache.normal().0.len(),
             Model::XLoraLlama(ref model) => model.cache.full().lock().len(),
@@ -386,6 +388,7 @@ impl Loader for GGMLLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
+                dry_breakers: Some(dry_breakers),
                 has_no_kv_cache: self.no_kv_cache,
                 num_hidden_layers,
                 eos_tok: eos,
@@ -499,7 +502,7 @@ impl AdapterActivationMixin for GGMLPipeline {
 
         match self.model {
             Model::XLoraLlama(ref mut model) => model
-                .activate_adapters(adapter_names)
+                .apply_adapter_op(&AdapterOp::Activate(&adapter_names))
                 .map_err(anyhow::Error::msg),
             _ => unreachable!(),
         }
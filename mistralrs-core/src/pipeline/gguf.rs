@@ -14,7 +14,7 @@ use crate::gguf::{
     get_gguf_chat_template, {convert_gguf_to_hf_tokenizer, GgufTokenizerConversion},
 };
 use crate::gguf::{Content, GGUFArchitecture};
-use crate::lora::Ordering;
+use crate::lora::{AdapterOp, Ordering};
 use crate::paged_attention::{
     calculate_cache_config, AttentionImplementation, CacheEngine, ModelConfigLike,
 };
@@ -23,6 +23,7 @@ use crate::pipeline::get_chat_template;
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::ChatTemplate;
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::sampler::DryBreakerTable;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::model_config as ModelConfig;
@@ -494,6 +495,7 @@ impl Loader for GGUFLoader {
             Model::Qwen2(ref p) => p.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
+        let dry_breakers = Arc::new(DryBreakerTable::new(&tokenizer)?);
         let num_hidden_layers = match model {
             Model::Llama(ref model) => model.cache.normal().0.len(),
             Model::Phi2(ref model) => model.cache.normal().0.len(),
@@ -533,6 +535,7 @@ impl Loader for GGUFLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
+                dry_breakers: Some(dry_breakers),
                 has_no_kv_cache: self.no_kv_cache,
                 num_hidden_layers,
                 eos_tok: eos,
@@ -635,10 +638,10 @@ impl AdapterActivationMixin for GGUFPipeline {
 
         match self.model {
             Model::XLoraLlama(ref mut model) => model
-                .activate_adapters(adapter_names)
+                .apply_adapter_op(&AdapterOp::Activate(&adapter_names))
                 .map_err(anyhow::Error::msg),
             Model::XLoraPhi3(ref mut model) => model
-                .activate_adapters(adapter_names)
+                .apply_adapter_op(&AdapterOp::Activate(&adapter_names))
                 .map_err(anyhow::Error::msg),
             _ => unreachable!(),
         }
@@ -93,6 +93,9 @@ pub mod text_models_inputs_processor {
         pub context_lens: Option<Tensor>,
         pub slot_mappings: Tensor,
         pub max_context_len: Option<usize>,
+        /// Prompt tokens continue a sequence whose KV cache is already populated, so they must
+        /// attend to the cache as well as to each other. Used to verify speculative draft tokens.
+        pub attend_to_cache: bool,
     }
 
     #[derive(Clone, Debug)]
@@ -129,6 +132,28 @@ pub mod text_models_inputs_processor {
         return_raw_logits: bool,
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
     ) -> Result<InputMetadata> {
+        // Leading prompt tokens whose KV cache the block engine already computed for another
+        // sequence are not recomputed. They are attended to in the cache instead.
+        let computed_prefix_lens = seq_ids
+            .iter()
+            .map(|seq_id| match &paged_attn_metadata {
+                Some(meta) if last_n_context_len.is_none() && !return_raw_logits => {
+                    meta.block_engine.computed_prefix_len(*seq_id)
+                }
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        let attend_to_cache = chunk_offset_toks > 0
+            || last_n_context_len.is_some_and(|(_, cached)| cached > 0)
+            || computed_prefix_lens.iter().any(|len| *len > 0);
+        let toks = toks
+            .into_iter()
+            .zip(&computed_prefix_lens)
+            .map(|(mut ctxt, computed)| {
+                ctxt.drain(..*computed);
+                ctxt
+            })
+            .collect::<Vec<_>>();
         let max_len = toks
             .iter()
             .map(|seq| seq.len())
@@ -145,12 +170,12 @@ pub mod text_models_inputs_processor {
         let mut paged_attn_context_lens = Vec::new();
         let mut seqlens_q = vec![0];
         let mut seqlens_k = vec![0];
-        for (seq_id, mut ctxt) in seq_ids.iter().zip(toks) {
+        for ((seq_id, mut ctxt), computed) in seq_ids.iter().zip(toks).zip(computed_prefix_lens) {
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
-            seqlen_offsets.push(offset.1 + chunk_offset_toks);
+            seqlen_offsets.push(offset.1 + chunk_offset_toks + computed);
 
-            position_ids.push(ctxt.len() + chunk_offset_toks);
+            position_ids.push(ctxt.len() + chunk_offset_toks + computed);
             ctxt.extend(repeat(padding_tok).take(max_len.saturating_sub(ctxt.len())));
             // If we are returning raw logits, we want to not trim the logits at all.
             if return_raw_logits {
@@ -160,14 +185,15 @@ pub mod text_models_inputs_processor {
 
                 context_lens.push((0, ctxt.len()));
             } else {
+                // Index from the unpadded length, the sequences in a batch may differ in length.
                 context_lens.push((
-                    ctxt.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
+                    prompt_len - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
                     last_n_context_len.map(|(a, _)| a).unwrap_or(1),
                 ));
             }
 
             seqlens_q.push(ctxt.len() as u32);
-            seqlens_k.push((ctxt.len() + chunk_offset_toks) as u32);
+            seqlens_k.push((ctxt.len() + chunk_offset_toks + computed) as u32);
 
             seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
 
@@ -185,24 +211,27 @@ pub mod text_models_inputs_processor {
                     .map(|block| block.deref_mut().block_id)
                     .collect::<Vec<_>>();
 
+                // The tokens may continue a sequence with cached tokens, see `last_n_context_len`.
+                let tok_offset = offset.1 + chunk_offset_toks + computed;
                 let start_idx = if let Some(sliding_window) = paged_attn_metadata.sliding_window {
                     if prompt_len > sliding_window {
-                        chunk_offset_toks.min(prompt_len - sliding_window)
+                        tok_offset.min(prompt_len - sliding_window)
                     } else {
-                        chunk_offset_toks
+                        tok_offset
                     }
                 } else {
-                    chunk_offset_toks
+                    tok_offset
                 };
 
                 let mut slot_mapping = Vec::new();
                 let mut ctxt_len = Vec::new();
-                for i in chunk_offset_toks..prompt_len + chunk_offset_toks {
+                for i in tok_offset..prompt_len + tok_offset {
                     if i < start_idx {
                         // Pad [0,start_idx) with _PAD_TOKEN_ID
                         slot_mapping.push(_PAD_SLOT_ID);
                     }
-                    ctxt_len.push(i);
+                    // Each token attends to itself and everything before it.
+                    ctxt_len.push(i + 1);
 
                     let block_number = if i / paged_attn_metadata.block_size >= table.len() {
                         panic!(
@@ -219,13 +248,17 @@ pub mod text_models_inputs_processor {
                     slot_mapping.push(slot.try_into().unwrap());
                     block_tables.push(table.clone());
                 }
+                // Padding tokens have a context length of 0, but need a block table row.
+                for _ in prompt_len..max_len {
+                    block_tables.push(table.clone());
+                }
                 slot_mappings.push(slot_mapping);
                 paged_attn_context_lens.push(ctxt_len);
             }
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
+        if last_n_context_len.is_some() || attend_to_cache {
             for pos in (0..seqs_tensors.len())
                 .map(|i| {
                     (*seqlen_offsets.get(i).unwrap() as i64
@@ -283,7 +316,8 @@ pub mod text_models_inputs_processor {
 
             let max_context_len = paged_attn_context_lens
                 .iter()
-                .map(|x| x.len())
+                .flatten()
+                .copied()
                 .max()
                 .unwrap();
 
@@ -292,7 +326,7 @@ pub mod text_models_inputs_processor {
                     .iter()
                     .map(|x| x.iter().map(|x| *x as u32).collect::<Vec<_>>())
                     .collect::<Vec<_>>(),
-                max_context_len,
+                max_slot_mapping_len,
                 0,
                 device,
             )?
@@ -303,6 +337,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(max_context_len),
+                attend_to_cache,
             })
         } else {
             None
@@ -451,6 +486,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(*max_context_len),
+                attend_to_cache: false,
             })
         } else {
             None
@@ -481,6 +517,9 @@ pub mod text_models_inputs_processor {
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
         prompt_batchsize: Option<NonZeroUsize>,
     ) -> Box<dyn Iterator<Item = Result<InnerInputProcessorOutput>>> {
+        // The tokens of prefilled sequences follow their cached prefix. The scheduler only batches
+        // prompts with the same prefix length.
+        let prefix_len = input_seqs.first().map_or(0, |seq| seq.prefix_len());
         if let (Some(prompt_batchsize), true) = (prompt_batchsize, paged_attn_metadata.is_none()) {
             let mut seq_chunks = Vec::new();
             let mut n_chunks = Vec::new();
@@ -508,7 +547,7 @@ pub mod text_models_inputs_processor {
                 .map(|(i, chunk)| {
                     let (toks, seq_ns): (Vec<Vec<T>>, Vec<usize>) = chunk.into_iter().unzip();
                     make_prompt_chunk(
-                        i * prompt_batchsize,
+                        prefix_len + i * prompt_batchsize,
                         toks,
                         &seq_ns
                             .iter()
@@ -535,7 +574,7 @@ pub mod text_models_inputs_processor {
             }
             Box::new(std::iter::once(
                 make_prompt_chunk(
-                    0,
+                    prefix_len,
                     toks,
                     &input_seqs.iter().map(|s| *s.id()).collect::<Vec<_>>(),
                     device,
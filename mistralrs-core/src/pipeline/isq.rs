@@ -13,8 +13,8 @@ use candle_core::{quantized, Context, Device, Tensor};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use mistralrs_quant::{
-    FP8Linear, GgufMatMul, HqqLayer, IsqType, QuantMethod, QuantizedSerde, QuantizedSerdeType,
-    UnquantLinear,
+    AwqLayer, FP8Linear, GgufMatMul, HqqLayer, IsqType, QuantMethod, QuantizedSerde,
+    QuantizedSerdeType, UnquantLinear,
 };
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use regex::Regex;
@@ -698,6 +698,9 @@ pub trait IsqModel {
                             QuantizedSerdeType::Fp8 => {
                                 FP8Linear::deserialize(Cow::from(artifact), &devices[i])?
                             }
+                            QuantizedSerdeType::Awq => {
+                                AwqLayer::deserialize(Cow::from(artifact), &devices[i])?
+                            }
                         };
                         *tensor = deserialized;
                     }
@@ -727,6 +730,9 @@ pub trait IsqModel {
                             QuantizedSerdeType::Fp8 => {
                                 FP8Linear::deserialize(Cow::from(artifact), &devices[i])?
                             }
+                            QuantizedSerdeType::Awq => {
+                                AwqLayer::deserialize(Cow::from(artifact), &devices[i])?
+                            }
                         };
                         *tensor = deserialized;
                     }
@@ -0,0 +1,175 @@
+use std::{fmt::Display, str::FromStr};
+
+use candle_core::{DType, Result, Tensor, D};
+use serde::Deserialize;
+
+/// Storage type of the normal (non-PagedAttention) KV cache.
+///
+/// Quantized caches are quantized when appending and dequantized to the activation dtype when read.
+/// Each head of each token has its own scale, so appending never requantizes existing entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum KvCacheDType {
+    /// Store the cache in the activation dtype.
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// 8-bit integers with a zero point of 128.
+    #[serde(rename = "q8")]
+    Q8,
+    /// FP8 E4M3.
+    #[serde(rename = "fp8")]
+    F8E4M3,
+    /// 4-bit integers with a zero point of 8, two per byte. Requires an even head dimension.
+    #[serde(rename = "q4")]
+    Q4,
+}
+
+impl Display for KvCacheDType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Q8 => write!(f, "q8"),
+            Self::F8E4M3 => write!(f, "fp8"),
+            Self::Q4 => write!(f, "q4"),
+        }
+    }
+}
+
+impl FromStr for KvCacheDType {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "q8" | "int8" => Ok(Self::Q8),
+            "fp8" | "f8e4m3" => Ok(Self::F8E4M3),
+            "q4" | "int4" => Ok(Self::Q4),
+            other => Err(format!("KV cache dtype `{other}` is not supported.")),
+        }
+    }
+}
+
+const F8E4M3_MAX: f64 = 448.;
+
+impl KvCacheDType {
+    pub fn is_quantized(&self) -> bool {
+        !matches!(self, Self::Auto)
+    }
+
+    /// Quantize along the last dimension, returning the quantized data and the scales.
+    /// The scales have a last dimension of 1 and the dtype of `xs`.
+    pub(crate) fn quantize(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let dtype = xs.dtype();
+        let xs = xs.to_dtype(DType::F32)?;
+        let absmax = xs.abs()?.max_keepdim(D::Minus1)?;
+        let max_q = match self {
+            Self::Auto => candle_core::bail!("`quantize` called on an unquantized KV cache."),
+            Self::Q8 => 127.,
+            Self::F8E4M3 => F8E4M3_MAX,
+            Self::Q4 => 7.,
+        };
+        // Avoid dividing by zero for all-zero heads.
+        let scales = (absmax / max_q)?.clamp(f32::MIN_POSITIVE, f32::MAX)?;
+        let scaled = xs.broadcast_div(&scales)?;
+
+        let quantized = match self {
+            Self::Auto => unreachable!(),
+            Self::Q8 => (scaled.round()? + 128.)?
+                .clamp(0., 255.)?
+                .to_dtype(DType::U8)?,
+            Self::F8E4M3 => scaled
+                .clamp(-F8E4M3_MAX, F8E4M3_MAX)?
+                .to_dtype(DType::F8E4M3)?,
+            Self::Q4 => {
+                let q = (scaled.round()? + 8.)?.clamp(0., 15.)?;
+                let mut dims = q.dims().to_vec();
+                let head_dim = dims.pop().unwrap();
+                if head_dim % 2 != 0 {
+                    candle_core::bail!("Q4 KV cache requires an even head dim, got {head_dim}.");
+                }
+                dims.extend([head_dim / 2, 2]);
+                let pairs = q.reshape(dims)?;
+                let hi = pairs.narrow(D::Minus1, 0, 1)?.squeeze(D::Minus1)?;
+                let lo = pairs.narrow(D::Minus1, 1, 1)?.squeeze(D::Minus1)?;
+                ((hi * 16.)? + lo)?.to_dtype(DType::U8)?
+            }
+        };
+        Ok((quantized, scales.to_dtype(dtype)?))
+    }
+
+    /// Inverse of [`Self::quantize`], returning a tensor with the dtype of the scales.
+    pub(crate) fn dequantize(&self, xs: &Tensor, scales: &Tensor) -> Result<Tensor> {
+        let xs = xs.to_dtype(DType::F32)?;
+        let xs = match self {
+            Self::Auto => candle_core::bail!("`dequantize` called on an unquantized KV cache."),
+            Self::Q8 => (xs - 128.)?,
+            Self::F8E4M3 => xs,
+            Self::Q4 => {
+                let hi = (xs.clone() / 16.)?.floor()?;
+                let lo = (xs - (&hi * 16.)?)?;
+                let mut dims = hi.dims().to_vec();
+                *dims.last_mut().unwrap() *= 2;
+                (Tensor::stack(&[hi, lo], D::Minus1)?.reshape(dims)? - 8.)?
+            }
+        };
+        xs.broadcast_mul(&scales.to_dtype(DType::F32)?)?
+            .to_dtype(scales.dtype())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::KvCacheDType;
+
+    fn max_abs_error(dtype: KvCacheDType, xs: &Tensor) -> f32 {
+        let (q, scales) = dtype.quantize(xs).unwrap();
+        assert_eq!(scales.dims(), &[1, 2, 3, 1]);
+        let dequantized = dtype.dequantize(&q, &scales).unwrap();
+        assert_eq!(dequantized.dims(), xs.dims());
+        (dequantized - xs)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn roundtrip_is_close() {
+        let xs = Tensor::randn(0f32, 1., (1, 2, 3, 8), &Device::Cpu).unwrap();
+        let absmax = xs
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        // Half a quantization step, plus some slack for rounding.
+        assert!(max_abs_error(KvCacheDType::Q8, &xs) <= absmax / 127. * 0.51);
+        assert!(max_abs_error(KvCacheDType::Q4, &xs) <= absmax / 7. * 0.51);
+        // E4M3 has 3 mantissa bits.
+        assert!(max_abs_error(KvCacheDType::F8E4M3, &xs) <= absmax / 16. * 1.01);
+    }
+
+    #[test]
+    fn zeros_roundtrip() {
+        let xs = Tensor::zeros((1, 2, 3, 8), DType::F32, &Device::Cpu).unwrap();
+        assert_eq!(max_abs_error(KvCacheDType::Q8, &xs), 0.);
+        assert_eq!(max_abs_error(KvCacheDType::Q4, &xs), 0.);
+    }
+
+    #[test]
+    fn q4_packs_two_values_per_byte() {
+        let xs = Tensor::randn(0f32, 1., (1, 2, 3, 8), &Device::Cpu).unwrap();
+        let (q, _) = KvCacheDType::Q4.quantize(&xs).unwrap();
+        assert_eq!(q.dims(), &[1, 2, 3, 4]);
+        assert_eq!(q.dtype(), DType::U8);
+    }
+}
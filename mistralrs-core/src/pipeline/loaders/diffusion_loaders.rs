@@ -26,7 +26,7 @@ use crate::{
             self,
             stepper::{FluxStepper, FluxStepperConfig},
         },
-        DiffusionGenerationParams,
+        DiffusionGenerationParams, DiffusionInitImage,
     },
     lora::LoraConfig,
     paged_attention::AttentionImplementation,
@@ -35,11 +35,13 @@ use crate::{
 };
 
 pub trait DiffusionModel {
-    /// This returns a tensor of shape (bs, c, h, w), with values in [0, 255].
+    /// This returns a tensor of shape (bs, c, h, w), with values in [0, 255]. Generation starts
+    /// from `init_image` if there is one, instead of noise.
     fn forward(
         &mut self,
         prompts: Vec<String>,
         params: DiffusionGenerationParams,
+        init_image: Option<DiffusionInitImage>,
     ) -> candle_core::Result<Tensor>;
     fn device(&self) -> &Device;
     fn max_seq_len(&self) -> usize;
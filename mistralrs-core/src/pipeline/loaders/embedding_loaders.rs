@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+
+use hf_hub::api::sync::ApiRepo;
+#[cfg(feature = "pyo3_macros")]
+use pyo3::pyclass;
+
+use serde::Deserialize;
+
+use tracing::info;
+
+use super::ModelPaths;
+use crate::{
+    api_dir_list, api_get_file, embedding_models::bert, lora::LoraConfig,
+    xlora_models::XLoraConfig, Ordering,
+};
+
+pub trait EmbeddingModel {
+    /// This returns the final hidden states, of shape (bs, seq_len, hidden_size).
+    fn forward(&self, input_ids: &Tensor) -> candle_core::Result<Tensor>;
+    fn device(&self) -> &Device;
+    fn max_seq_len(&self) -> usize;
+}
+
+pub trait EmbeddingModelLoader {
+    fn load(&self, config: &str, vb: VarBuilder) -> Result<Box<dyn EmbeddingModel + Send + Sync>>;
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+/// The architecture to load the embedding model as.
+pub enum EmbeddingLoaderType {
+    #[serde(rename = "bert")]
+    Bert,
+}
+
+impl FromStr for EmbeddingLoaderType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bert" => Ok(Self::Bert),
+            a => Err(format!(
+                "Unknown architecture `{a}`. Possible architectures: `bert`."
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingModelPathsInner {
+    pub config_filename: PathBuf,
+    pub tokenizer_filename: PathBuf,
+    pub filenames: Vec<PathBuf>,
+}
+
+impl EmbeddingModelPathsInner {
+    pub(crate) fn from_hf(
+        api: &ApiRepo,
+        model_id: &Path,
+        tokenizer_json: Option<&str>,
+    ) -> Result<Self> {
+        let tokenizer_filename = match tokenizer_json {
+            Some(p) => PathBuf::from_str(p)?,
+            None => api_get_file!(api, "tokenizer.json", model_id),
+        };
+        let config_filename = api_get_file!(api, "config.json", model_id);
+        let filenames = api_dir_list!(api, model_id)
+            .filter(|x| x.ends_with(".safetensors"))
+            .map(|x| api_get_file!(api, &x, model_id))
+            .collect::<Vec<_>>();
+        if filenames.is_empty() {
+            anyhow::bail!("Expected at least 1 .safetensors file for the embedding model.");
+        }
+        Ok(Self {
+            config_filename,
+            tokenizer_filename,
+            filenames,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingModelPaths(pub EmbeddingModelPathsInner);
+
+impl ModelPaths for EmbeddingModelPaths {
+    fn get_config_filename(&self) -> &PathBuf {
+        &self.0.config_filename
+    }
+    fn get_tokenizer_filename(&self) -> &PathBuf {
+        &self.0.tokenizer_filename
+    }
+    fn get_weight_filenames(&self) -> &[PathBuf] {
+        &self.0.filenames
+    }
+    fn get_adapter_filenames(&self) -> &Option<Vec<(String, PathBuf)>> {
+        unreachable!("Embedding models do not support adapters.")
+    }
+    fn get_adapter_configs(&self) -> &Option<Vec<((String, String), LoraConfig)>> {
+        unreachable!("Embedding models do not support adapters.")
+    }
+    fn get_classifier_config(&self) -> &Option<XLoraConfig> {
+        unreachable!("Embedding models do not support adapters.")
+    }
+    fn get_classifier_path(&self) -> &Option<PathBuf> {
+        unreachable!("Embedding models do not support adapters.")
+    }
+    fn get_ordering(&self) -> &Option<Ordering> {
+        unreachable!("Embedding models do not support adapters.")
+    }
+    fn get_template_filename(&self) -> &Option<PathBuf> {
+        unreachable!("Embedding models do not have a chat template.")
+    }
+    fn get_gen_conf_filename(&self) -> Option<&PathBuf> {
+        None
+    }
+    fn get_lora_preload_adapter_info(&self) -> &Option<HashMap<String, (PathBuf, LoraConfig)>> {
+        unreachable!("Embedding models do not support adapters.")
+    }
+    fn get_preprocessor_config(&self) -> &Option<PathBuf> {
+        unreachable!("Embedding models do not have a preprocessor.")
+    }
+    fn get_processor_config(&self) -> &Option<PathBuf> {
+        unreachable!("Embedding models do not have a processor.")
+    }
+    fn get_chat_template_json(&self) -> &Option<PathBuf> {
+        unreachable!("Embedding models do not have a chat template.")
+    }
+}
+
+// ======================== BERT loader
+
+/// [`EmbeddingLoader`] for a BERT-style encoder-only model.
+///
+/// [`EmbeddingLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.EmbeddingLoader.html
+pub struct BertLoader;
+
+impl EmbeddingModelLoader for BertLoader {
+    fn load(&self, config: &str, vb: VarBuilder) -> Result<Box<dyn EmbeddingModel + Send + Sync>> {
+        let cfg: bert::Config = serde_json::from_str(config)?;
+        Ok(Box::new(bert::Model::new(&cfg, vb)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+
+    use super::{BertLoader, EmbeddingModelLoader};
+    use crate::pipeline::EmbeddingPooling;
+
+    #[test]
+    fn bert_embeddings_are_normalized() {
+        // `type_vocab_size` and `layer_norm_eps` fall back to their defaults.
+        let config = r#"{
+            "vocab_size": 16,
+            "hidden_size": 8,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "intermediate_size": 16,
+            "hidden_act": "gelu",
+            "max_position_embeddings": 12
+        }"#;
+        let var_map = VarMap::new();
+        let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+        let model = BertLoader.load(config, vb).unwrap();
+
+        let input_ids = Tensor::new(&[[1u32, 5, 7, 2]], &Device::Cpu).unwrap();
+        let hidden_states = model.forward(&input_ids).unwrap().squeeze(0).unwrap();
+        for pooling in [
+            EmbeddingPooling::Mean,
+            EmbeddingPooling::Cls,
+            EmbeddingPooling::LastToken,
+        ] {
+            let embedding = pooling.pool(&hidden_states).unwrap();
+            assert_eq!(embedding.len(), 8);
+            let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+            assert!((norm - 1.).abs() < 1e-5, "{pooling:?}: {norm}");
+        }
+        let cls = EmbeddingPooling::Cls.pool(&hidden_states).unwrap();
+        let mean = EmbeddingPooling::Mean.pool(&hidden_states).unwrap();
+        assert_ne!(cls, mean);
+    }
+}
@@ -1,4 +1,5 @@
 mod diffusion_loaders;
+mod embedding_loaders;
 mod normal_loaders;
 mod vision_loaders;
 
@@ -32,12 +33,17 @@ pub use diffusion_loaders::{
     DiffusionModelPathsInner, FluxLoader,
 };
 
+pub use embedding_loaders::{
+    BertLoader, EmbeddingLoaderType, EmbeddingModel, EmbeddingModelLoader, EmbeddingModelPaths,
+    EmbeddingModelPathsInner,
+};
+
 use crate::{
     lora::LoraConfig, xlora_models::XLoraConfig, DeviceMapMetadata, Ordering, PagedAttentionConfig,
     TryIntoDType,
 };
 
-use super::Pipeline;
+use super::{Pipeline, SpeculativeHeadsKind};
 
 /// `ModelPaths` abstracts the mechanism to get all necessary files for running a model. For
 /// example `LocalModelPaths` implements `ModelPaths` when all files are in the local file system.
@@ -263,13 +269,31 @@ pub enum ModelKind {
     #[strum(to_string = "speculative: target: `{target}`, draft: `{draft}`")]
     Speculative {
         target: Box<ModelKind>,
-        draft: Box<ModelKind>,
+        draft: DraftKind,
     },
 
     #[strum(to_string = "anymoe: target: `{target}`")]
     AnyMoe { target: Box<ModelKind> },
 }
 
+/// What drafts the tokens of a speculative model.
+#[derive(Clone)]
+pub enum DraftKind {
+    /// A separate draft model.
+    Model(Box<ModelKind>),
+    /// Decoding heads shipped alongside the target model.
+    Heads(SpeculativeHeadsKind),
+}
+
+impl fmt::Display for DraftKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DraftKind::Model(kind) => write!(f, "{kind}"),
+            DraftKind::Heads(heads) => write!(f, "{heads} heads"),
+        }
+    }
+}
+
 #[derive(Clone, Copy, strum::Display, strum::EnumIs, strum::EnumMessage)]
 #[strum(serialize_all = "kebab-case")]
 pub enum QuantizationKind {
@@ -321,12 +345,12 @@ impl ModelKind {
         match self {
             Normal | Adapter { .. } => vec![None],
             GgufQuantized { quant } | GgufAdapter { quant, .. } => vec![Some(*quant)],
-            Speculative { target, draft } => {
-                let t = *target.clone();
-                let d = *draft.clone();
-
-                [t.quantized_kind(), d.quantized_kind()].concat()
-            }
+            Speculative { target, draft } => match draft {
+                DraftKind::Model(draft) => {
+                    [target.quantized_kind(), draft.quantized_kind()].concat()
+                }
+                DraftKind::Heads(_) => target.quantized_kind(),
+            },
             AnyMoe { target } => target.quantized_kind(),
         }
     }
@@ -346,12 +370,10 @@ impl ModelKind {
         match self {
             Normal | GgufQuantized { .. } => vec![None],
             Adapter { adapter } | GgufAdapter { adapter, .. } => vec![Some(*adapter)],
-            Speculative { target, draft } => {
-                let t = *target.clone();
-                let d = *draft.clone();
-
-                [t.adapted_kind(), d.adapted_kind()].concat()
-            }
+            Speculative { target, draft } => match draft {
+                DraftKind::Model(draft) => [target.adapted_kind(), draft.adapted_kind()].concat(),
+                DraftKind::Heads(_) => target.adapted_kind(),
+            },
             AnyMoe { target } => target.adapted_kind(),
         }
     }
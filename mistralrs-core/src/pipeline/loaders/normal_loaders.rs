@@ -8,7 +8,7 @@ use crate::{
     amoe::AnyMoeBaseModelMixin,
     device_map::DeviceMapper,
     layers::{Activation, Llama3RopeConfig, PhiRopeScalingConfig},
-    lora::{LoraConfig, Ordering},
+    lora::{AdapterOp, LoraConfig, Ordering},
     paged_attention::{AttentionImplementation, ModelConfigMetadata},
     pipeline::{
         isq::IsqModelLoader,
@@ -47,6 +47,37 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> candle_core::Result<Tensor>;
+    /// Run the model without the LM head, returning the final normalized hidden states
+    /// of shape `(bs, seq_len, hidden_size)`. This does not support PagedAttention.
+    fn forward_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Embeddings are not supported for this model.");
+    }
+    /// Run the model on a tree of tokens which follow the tokens in the KV cache, returning the
+    /// final normalized hidden states of shape `(1, n_tokens, hidden_size)`. Each token attends to
+    /// the cache, its ancestors and itself, and the KV of every token is added to the cache. This
+    /// does not support PagedAttention.
+    fn forward_tree(
+        &self,
+        _input_ids: &Tensor,
+        _parents: &[Option<usize>],
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Speculative decoding heads are not supported for this model.");
+    }
+    /// Apply the LM head to final normalized hidden states.
+    fn logits_from_hidden_states(&self, _hidden_states: &Tensor) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Speculative decoding heads are not supported for this model.");
+    }
+    /// Embed tokens with the input embeddings of the model.
+    fn embed_tokens(&self, _input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Speculative decoding heads are not supported for this model.");
+    }
     #[allow(clippy::too_many_arguments)]
     fn xlora_forward(
         &self,
@@ -68,7 +99,8 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
     fn cache(&self) -> &EitherCache;
     fn cache_mut(&mut self) -> &mut EitherCache;
     fn max_seq_len(&self) -> usize;
-    fn activate_adapters(&mut self, _: Vec<String>) -> candle_core::Result<usize> {
+    /// Activate, load or unload adapters, returning the number of LoRA layers changed.
+    fn apply_adapter_op(&mut self, _: &AdapterOp) -> candle_core::Result<usize> {
         // NOTE: While X-LoRA shares a similar name, it is not equivalent. Its adapter set must remain the same.
         candle_core::bail!(
             "Activating adapters is only supported for models fine-tuned with LoRA."
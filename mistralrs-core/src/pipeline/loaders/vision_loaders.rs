@@ -542,6 +542,15 @@ impl VisionPromptPrefixer for Qwen2VLPrefixer {
             Qwen2VLProcessor::VISION_END
         )
     }
+
+    fn prefix_video(&self, _video_index: usize, prompt: &str) -> String {
+        format!(
+            "{}{}{}{prompt}",
+            Qwen2VLProcessor::VISION_START,
+            Qwen2VLProcessor::VIDEO_PAD,
+            Qwen2VLProcessor::VISION_END
+        )
+    }
 }
 
 impl VisionModelLoader for Qwen2VLLoader {
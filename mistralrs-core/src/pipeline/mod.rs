@@ -1,11 +1,14 @@
 mod amoe;
+mod beam_search;
 mod cache_manager;
 pub mod chat_template;
 mod diffusion;
+mod embedding;
 mod ggml;
 mod gguf;
 mod inputs_processor;
 mod isq;
+mod kv_cache_quant;
 mod loaders;
 mod macros;
 mod normal;
@@ -14,29 +17,36 @@ mod processing;
 mod response;
 mod sampling;
 mod speculative;
+mod speculative_heads;
 mod vision;
 
-pub use super::diffusion_models::DiffusionGenerationParams;
+pub use super::diffusion_models::{DiffusionGenerationParams, MAX_DIFFUSION_IMAGE_SIZE};
 use crate::aici::toktree::TokTrie;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use crate::paged_attention::{CacheConfig, CacheEngine, ModelConfigLike};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::sampler::DryBreakerTable;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
+pub(crate) use beam_search::BeamSearchState;
 use chat_template::ChatTemplate;
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder, DiffusionSpecificConfig};
+pub use embedding::{
+    EmbeddingLoader, EmbeddingLoaderBuilder, EmbeddingPooling, EmbeddingSpecificConfig,
+};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
 use image::DynamicImage;
 pub use inputs_processor::InputProcessorOutput;
 pub use isq::{parse_isq_value, IsqModel, IsqOrganization};
+pub use kv_cache_quant::KvCacheDType;
 pub use loaders::{
-    AdapterKind, AutoLoader, DiffusionLoaderType, DiffusionModel, DiffusionModelLoader, FluxLoader,
-    Gemma2Loader, GemmaLoader, Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader,
-    LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
-    NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader,
-    Phi3Loader, Phi3VLoader, Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader,
-    Qwen2VLLoader, Starcoder2Loader, TokenSource, VLlamaLoader, VisionLoaderType, VisionModel,
-    VisionModelLoader,
+    AdapterKind, AutoLoader, BertLoader, DiffusionLoaderType, DiffusionModel, DiffusionModelLoader,
+    DraftKind, EmbeddingLoaderType, EmbeddingModel, EmbeddingModelLoader, FluxLoader, Gemma2Loader,
+    GemmaLoader, Idefics2Loader, Idefics3Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
+    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoaderType,
+    NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader,
+    Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader, Qwen2VLLoader, Starcoder2Loader,
+    TokenSource, VLlamaLoader, VisionLoaderType, VisionModel, VisionModelLoader,
 };
 use mistralrs_quant::IsqType;
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
@@ -45,10 +55,16 @@ pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub(crate) use speculative::SpeculativeState;
+pub use speculative::{
+    DraftLoader, NgramLookupConfig, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
+};
+pub(crate) use speculative_heads::HeadsState;
+pub use speculative_heads::{CandidateTree, SpeculativeHeadsConfig, SpeculativeHeadsKind};
 use std::any::Any;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokenizers::Tokenizer;
@@ -71,6 +87,8 @@ pub struct GeneralMetadata {
     pub max_seq_len: usize,
     /// Only None if it doesnt make sense for the model
     pub tok_trie: Option<Arc<TokTrie>>,
+    /// How the DRY sequence breakers are tokenized, None if the model has no tokenizer
+    pub dry_breakers: Option<Arc<DryBreakerTable>>,
     pub has_no_kv_cache: bool,
     pub num_hidden_layers: usize,
     pub eos_tok: Vec<u32>,
@@ -88,6 +106,8 @@ pub struct GeneralMetadata {
 
 pub enum AdapterInstruction {
     Activate(Vec<String>),
+    /// Activate adapters per sequence of the batch, so that the batch can mix adapters.
+    ActivatePerSeq(Vec<Vec<String>>),
     None,
 }
 
@@ -117,7 +137,8 @@ pub trait IsqPipelineMixin {
 }
 
 pub trait CacheManagerMixin {
-    /// Clone the cache FROM the sequences' cache TO the model cache. Only called for completion seqs.
+    /// Clone the cache FROM the sequences' cache TO the model cache. Only called for completion seqs,
+    /// and prompt seqs prefilled with a cached prefix.
     /// It is not a guarantee that this will be called for each completion step.
     fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool);
     /// Clone the cache FROM the model cache TO the sequences. Called for prompt and completion seqs.
@@ -134,11 +155,39 @@ pub trait CacheManagerMixin {
         load_preallocated_cache: bool,
     );
     fn cache(&self) -> &EitherCache;
+    /// Whether prompts may be run on top of a cached prefix, see [`Self::clone_in_cache`].
+    fn supports_prefix_cache(&self) -> bool {
+        true
+    }
 }
 
 pub trait AdapterActivationMixin {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize>;
+    /// Activate adapters for each sequence of a batch, given in the order of the sequences.
+    /// Only supported if [`AdapterActivationMixin::supports_mixed_adapters`].
+    fn activate_adapters_per_seq(&mut self, _adapters: Vec<Vec<String>>) -> Result<usize> {
+        anyhow::bail!(
+            "Mixing adapters in a batch is only supported for plain models fine-tuned with LoRA."
+        )
+    }
+    /// Whether the sequences of a batch may use different adapters.
+    fn supports_mixed_adapters(&self) -> bool {
+        false
+    }
+    /// Register the PEFT adapter saved in the directory `path` as `name`, without activating it.
+    /// Returns the number of layers the adapter applies to.
+    fn load_adapter(&mut self, _name: String, _path: PathBuf) -> Result<usize> {
+        anyhow::bail!("Loading adapters is only supported for plain models fine-tuned with LoRA.")
+    }
+    /// Remove a registered adapter, which must not be active. Returns the number of layers changed.
+    fn unload_adapter(&mut self, _name: &str) -> Result<usize> {
+        anyhow::bail!("Unloading adapters is only supported for plain models fine-tuned with LoRA.")
+    }
+    /// The adapters active when the model was loaded, if adapters are activated per request.
+    fn initial_adapters(&self) -> Option<Vec<String>> {
+        None
+    }
 }
 
 pub trait MetadataMixin {
@@ -214,6 +263,7 @@ pub enum ModelCategory {
         prefixer: Arc<dyn VisionPromptPrefixer>,
     },
     Diffusion,
+    Embedding,
 }
 
 impl PartialEq for ModelCategory {
@@ -222,9 +272,11 @@ impl PartialEq for ModelCategory {
             (Self::Text, Self::Text) => true,
             (Self::Vision { .. }, Self::Vision { .. }) => true,
             (Self::Diffusion, Self::Diffusion) => true,
+            (Self::Embedding, Self::Embedding) => true,
             (Self::Text, _) => false,
             (Self::Vision { .. }, _) => false,
             (Self::Diffusion, _) => false,
+            (Self::Embedding, _) => false,
         }
     }
 }
@@ -233,6 +285,10 @@ impl PartialEq for ModelCategory {
 pub trait VisionPromptPrefixer: Send + Sync {
     /// Prefix for inclusion in messages (may do nothing if the chat template handles it).
     fn prefix_image(&self, image_index: usize, prompt: &str) -> String;
+    /// Prefix for a video in messages. Only models which support video input need this.
+    fn prefix_video(&self, _video_index: usize, prompt: &str) -> String {
+        prompt.to_string()
+    }
 }
 
 pub enum CacheBackendMetadata<'a> {
@@ -300,6 +356,45 @@ pub trait Pipeline:
         return_raw_logits: bool,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
+    /// Compute the pooled and L2-normalized embedding of one token sequence.
+    /// This runs a forward pass outside of the scheduler, so any KV cache of the pipeline is reset.
+    fn embed(
+        &mut self,
+        _toks: &[u32],
+        _pooling: EmbeddingPooling,
+    ) -> Result<Vec<f32>, candle_core::Error> {
+        candle_core::bail!("Model `{}` does not support embeddings.", self.name())
+    }
+
+    /// Run the model on a tree of tokens which follow the tokens in the KV cache, returning the
+    /// final normalized hidden states of shape `(n_tokens, hidden_size)`. The KV of every token
+    /// of the tree is added to the cache. Used by speculative decoding heads.
+    fn forward_tree(&mut self, _tree: &CandidateTree) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!(
+            "Model `{}` does not support speculative decoding heads.",
+            self.name()
+        )
+    }
+
+    /// Apply the LM head to final normalized hidden states.
+    fn logits_from_hidden_states(
+        &self,
+        _hidden_states: &Tensor,
+    ) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!(
+            "Model `{}` does not support speculative decoding heads.",
+            self.name()
+        )
+    }
+
+    /// Embed tokens with the input embeddings of the model.
+    fn embed_tokens(&self, _input_ids: &Tensor) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!(
+            "Model `{}` does not support speculative decoding heads.",
+            self.name()
+        )
+    }
+
     /// Returns the total of model execution time.
     #[allow(clippy::too_many_arguments)]
     async fn step(
@@ -360,6 +455,15 @@ pub trait Pipeline:
                                             ))
                                         })?
                                     }
+                                    AdapterInstruction::ActivatePerSeq(adapters) => self
+                                        .activate_adapters_per_seq(adapters.clone())
+                                        .map_err(|e| {
+                                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                                dyn std::error::Error,
+                                            >>::as_ref(
+                                                &e
+                                            ))
+                                        })?,
                                     AdapterInstruction::None => 0,
                                 };
                                 self.clone_in_cache(input_seqs, false)
@@ -375,6 +479,15 @@ pub trait Pipeline:
                                             ))
                                         })?
                                     }
+                                    AdapterInstruction::ActivatePerSeq(adapters) => self
+                                        .activate_adapters_per_seq(adapters.clone())
+                                        .map_err(|e| {
+                                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                                dyn std::error::Error,
+                                            >>::as_ref(
+                                                &e
+                                            ))
+                                        })?,
                                     AdapterInstruction::None => 0,
                                 };
                             }
@@ -393,6 +506,15 @@ pub trait Pipeline:
                                             ))
                                         })?
                                     }
+                                    AdapterInstruction::ActivatePerSeq(adapters) => self
+                                        .activate_adapters_per_seq(adapters.clone())
+                                        .map_err(|e| {
+                                            candle_core::Error::msg(<anyhow::Error as AsRef<
+                                                dyn std::error::Error,
+                                            >>::as_ref(
+                                                &e
+                                            ))
+                                        })?,
                                     AdapterInstruction::None => 0,
                                 };
                                 self.set_none_cache(
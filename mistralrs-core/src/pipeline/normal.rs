@@ -1,4 +1,4 @@
-use super::cache_manager::{FullCacheManager, NormalCacheManager};
+use super::cache_manager::{FullCacheManager, NormalCache, NormalCacheManager};
 use super::isq::ImatrixDataSource;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
@@ -6,9 +6,9 @@ use super::{
     TokenSource, XLoraPaths,
 };
 use super::{
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, EitherCache,
-    ForwardInputsResult, IsqOrganization, IsqPipelineMixin, MetadataMixin, ModelCategory,
-    PreProcessingMixin,
+    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, CandidateTree, EitherCache,
+    EmbeddingPooling, ForwardInputsResult, IsqOrganization, IsqPipelineMixin, MetadataMixin,
+    ModelCategory, PreProcessingMixin,
 };
 use super::{
     AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
@@ -17,19 +17,23 @@ use super::{
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
 use crate::amoe::AnyMoeExpertType;
-use crate::lora::Ordering;
+use crate::lora::{adapter_rows, AdapterOp, LoraConfig, Ordering};
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::get_chat_template;
 use crate::pipeline::isq::UqffFullSer;
 use crate::pipeline::sampling::sample_and_add_toks;
-use crate::pipeline::text_models_inputs_processor::make_prompt_chunk;
+use crate::pipeline::text_models_inputs_processor::{make_prompt_chunk, InputMetadata};
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::sampler::DryBreakerTable;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
-use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::utils::{
+    tokens::get_token,
+    varbuilder_utils::{from_mmaped_safetensors, load_adapter},
+};
 use crate::xlora_models::NonGranularState;
 use crate::{
     api_dir_list, api_get_file, get_mut_arcmutex, get_paths, get_uqff_paths, lora_model_loader,
@@ -69,6 +73,9 @@ pub struct NormalPipeline {
     generation_config: Option<PathBuf>,
     config: String,
     imatrix: Option<PathBuf>,
+    // For LoRA models, the adapters which were active at load time and all registered adapters
+    initial_adapters: Option<Vec<String>>,
+    adapters: Vec<String>,
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -530,6 +537,7 @@ impl Loader for NormalLoader {
 
         let max_seq_len = model.max_seq_len();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
+        let dry_breakers = Arc::new(DryBreakerTable::new(&tokenizer)?);
         let num_hidden_layers = match model.cache() {
             EitherCache::Full(full) => full.lock().len(),
             EitherCache::Normal(normal) => normal.lock().unwrap().0.len(),
@@ -537,6 +545,25 @@ impl Loader for NormalLoader {
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
         let model_metadata = Arc::new(model.config().clone());
+        let (initial_adapters, adapters) = if self.kind.is_adapted_and(|a| a.is_lora()) {
+            let initial_adapters = paths
+                .get_adapter_configs()
+                .iter()
+                .flatten()
+                .map(|((_, name), _)| name.clone())
+                .collect::<Vec<_>>();
+            let mut adapters = initial_adapters.clone();
+            adapters.extend(
+                paths
+                    .get_lora_preload_adapter_info()
+                    .iter()
+                    .flat_map(|x| x.keys().cloned()),
+            );
+            (Some(initial_adapters), adapters)
+        } else {
+            (None, Vec::new())
+        };
+
         Ok(Arc::new(Mutex::new(NormalPipeline {
             model,
             tokenizer: tokenizer.into(),
@@ -552,6 +579,7 @@ impl Loader for NormalLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
+                dry_breakers: Some(dry_breakers),
                 has_no_kv_cache: self.no_kv_cache,
                 num_hidden_layers,
                 eos_tok: eos,
@@ -571,6 +599,8 @@ impl Loader for NormalLoader {
             generation_config: paths.get_gen_conf_filename().cloned(),
             config,
             imatrix: self.config.imatrix.clone(),
+            initial_adapters,
+            adapters,
         })))
     }
 
@@ -664,9 +694,66 @@ impl CacheManagerMixin for NormalPipeline {
 impl AdapterActivationMixin for NormalPipeline {
     fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
         self.model
-            .activate_adapters(adapter_names)
+            .apply_adapter_op(&AdapterOp::Activate(&adapter_names))
             .map_err(anyhow::Error::msg)
     }
+    fn activate_adapters_per_seq(&mut self, adapters: Vec<Vec<String>>) -> anyhow::Result<usize> {
+        let (adapters, rows) = adapter_rows(&adapters, &self.device())?;
+        self.model
+            .apply_adapter_op(&AdapterOp::ActivateRows {
+                adapters: &adapters,
+                rows: &rows,
+            })
+            .map_err(anyhow::Error::msg)
+    }
+    fn supports_mixed_adapters(&self) -> bool {
+        self.initial_adapters.is_some()
+    }
+    fn load_adapter(&mut self, name: String, path: PathBuf) -> anyhow::Result<usize> {
+        if !self.metadata.kind.is_adapted_and(|a| a.is_lora()) {
+            anyhow::bail!("Loading adapters is only supported for models fine-tuned with LoRA.")
+        }
+        if self.adapters.contains(&name) {
+            anyhow::bail!("Adapter `{name}` is already loaded.")
+        }
+        let cfg: LoraConfig =
+            serde_json::from_str(&fs::read_to_string(path.join("adapter_config.json"))?)?;
+        let vb = load_adapter(
+            &path.join("adapter_model.safetensors"),
+            self.metadata.activation_dtype,
+            &self.device(),
+            self.silent,
+        )?;
+        let n_layers = self
+            .model
+            .apply_adapter_op(&AdapterOp::Load {
+                name: &name,
+                vb: &vb,
+                cfg: &cfg,
+            })
+            .map_err(anyhow::Error::msg)?;
+        if n_layers == 0 {
+            anyhow::bail!("Adapter `{name}` has no weights for any LoRA layer of the model.")
+        }
+        info!("Loaded adapter `{name}` for {n_layers} layers.");
+        self.adapters.push(name);
+        Ok(n_layers)
+    }
+    fn unload_adapter(&mut self, name: &str) -> anyhow::Result<usize> {
+        let Some(pos) = self.adapters.iter().position(|adapter| adapter == name) else {
+            anyhow::bail!("Adapter `{name}` is not loaded.")
+        };
+        let n_layers = self
+            .model
+            .apply_adapter_op(&AdapterOp::Unload(name))
+            .map_err(anyhow::Error::msg)?;
+        self.adapters.remove(pos);
+        info!("Unloaded adapter `{name}`.");
+        Ok(n_layers)
+    }
+    fn initial_adapters(&self) -> Option<Vec<String>> {
+        self.initial_adapters.clone()
+    }
 }
 
 impl MetadataMixin for NormalPipeline {
@@ -756,6 +843,80 @@ impl Pipeline for NormalPipeline {
             Ok(ForwardInputsResult::CausalGeneration { logits })
         }
     }
+    fn embed(
+        &mut self,
+        toks: &[u32],
+        pooling: EmbeddingPooling,
+    ) -> Result<Vec<f32>, candle_core::Error> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Embeddings are not supported for X-LoRA models.");
+        }
+        let InputMetadata {
+            input,
+            positions,
+            positions_kernel,
+            position_ids,
+            flash_meta,
+            ..
+        } = make_prompt_chunk(
+            0,
+            vec![toks.to_vec()],
+            &[0],
+            &self.device(),
+            None,
+            false,
+            None,
+        )
+        .map_err(candle_core::Error::msg)?;
+
+        // The KV cache does not belong to any sequence here. Run on an empty one, and keep the
+        // cache of the running sequences for their next step. With PagedAttention, there is no
+        // metadata so the blocks are not used either.
+        let empty_cache = NormalCache(
+            self.model
+                .cache()
+                .normal()
+                .0
+                .iter()
+                .map(|layer| {
+                    let mut layer = layer.clone();
+                    layer.reset();
+                    layer
+                })
+                .collect(),
+        );
+        let running_cache = std::mem::replace(&mut *self.model.cache().normal(), empty_cache);
+        let hidden_states = self.model.forward_hidden_states(
+            &input,
+            &positions,
+            positions_kernel,
+            position_ids,
+            &flash_meta,
+        );
+        *self.model.cache().normal() = running_cache;
+        pooling.pool(&hidden_states?.squeeze(0)?)
+    }
+    fn forward_tree(&mut self, tree: &CandidateTree) -> Result<Tensor, candle_core::Error> {
+        if self.get_metadata().cache_engine.is_some() {
+            candle_core::bail!("Speculative decoding heads are not supported with PagedAttention.");
+        }
+        if self.model.is_xlora() {
+            candle_core::bail!("Speculative decoding heads are not supported for X-LoRA models.");
+        }
+        let input_ids = Tensor::new(tree.tokens.as_slice(), &self.device())?.unsqueeze(0)?;
+        self.model
+            .forward_tree(&input_ids, &tree.parents)?
+            .squeeze(0)
+    }
+    fn logits_from_hidden_states(
+        &self,
+        hidden_states: &Tensor,
+    ) -> Result<Tensor, candle_core::Error> {
+        self.model.logits_from_hidden_states(hidden_states)
+    }
+    fn embed_tokens(&self, input_ids: &Tensor) -> Result<Tensor, candle_core::Error> {
+        self.model.embed_tokens(input_ids)
+    }
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
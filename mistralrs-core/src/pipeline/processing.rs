@@ -58,6 +58,10 @@ pub trait Processor {
     fn inputs_processor(&self) -> Arc<dyn InputsProcessor>;
     fn get_special_tokens(&self) -> &[&'static str];
     fn template_action(&self) -> MessagesAction;
+    /// If the inputs processor handles the videos of a sequence.
+    fn supports_videos(&self) -> bool {
+        false
+    }
 }
 
 pub(crate) fn apply_chat_template(
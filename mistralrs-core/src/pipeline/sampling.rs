@@ -10,7 +10,7 @@ use crate::{
     sequence::{Sequence, SequenceRecognizer},
 };
 
-use super::Pipeline;
+use super::{beam_search, Pipeline};
 
 pub(crate) async fn finish_or_add_toks_to_seq(
     this: &dyn Pipeline,
@@ -43,10 +43,18 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         if rate_limit_allowed {
             if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
                 if seq.get_mut_group().is_chat {
+                    let (content, tool_calls) = match seq.tools.clone() {
+                        Some(matcher) => {
+                            let text = String::from_utf8_lossy(seq.completion_bytes()).to_string();
+                            matcher.get_delta(&mut seq.tool_call_stream, &text, is_done.is_some())
+                        }
+                        None => (delta.clone(), None),
+                    };
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content,
                             role: "assistant".to_string(),
+                            tool_calls,
                         },
                         index: seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
@@ -83,7 +91,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
 
                 if let Some(reason) = is_done {
                     if use_prefix_cacher {
-                        prefix_cacher.add_sequence(seq);
+                        prefix_cacher.add_sequence(seq)?;
                         prefix_cacher.evict_to_cpu()?;
                     }
                     seq.set_state(crate::sequence::SequenceState::Done(reason));
@@ -170,7 +178,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 if let Some(ref matcher) = seq.tools {
                     let calls = matcher.get_call(&text).map_err(candle_core::Error::msg)?;
                     if !calls.is_empty() {
-                        text_new = None;
+                        text_new = matcher.get_content(&text);
                     }
                     tool_calls = calls;
                 }
@@ -196,7 +204,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
             }
 
             if use_prefix_cacher {
-                prefix_cacher.add_sequence(seq);
+                prefix_cacher.add_sequence(seq)?;
                 prefix_cacher.evict_to_cpu()?;
             }
 
@@ -249,11 +257,25 @@ pub async fn sample_and_add_toks(
     disable_eos_stop: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
 ) -> Result<()> {
-    let seqs_len = seqs.len();
-    debug_assert_eq!(logits_seq.len(), seqs_len);
+    debug_assert_eq!(logits_seq.len(), seqs.len());
+
+    // Beams are not sampled, but extended together with the other beams of their request.
+    let mut beams = Vec::new();
+    let mut sampled_seqs = Vec::new();
+    for (logits_per_seq, seq) in std::iter::zip(logits_seq, seqs.iter_mut()) {
+        if seq.is_beam() {
+            beams.push((&mut **seq, logits_per_seq));
+        } else {
+            sampled_seqs.push((logits_per_seq, &mut **seq));
+        }
+    }
+    if !beams.is_empty() {
+        beam_search::step_beams(this, beams, prefix_cacher, disable_eos_stop).await?;
+    }
 
-    let use_async_pool = seqs_len > 1;
+    let use_async_pool = sampled_seqs.len() > 1;
 
+    let (logits_seq, mut seqs): (Vec<_>, Vec<_>) = sampled_seqs.into_iter().unzip();
     let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
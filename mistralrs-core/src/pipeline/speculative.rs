@@ -1,6 +1,6 @@
 use std::{
     any::Any,
-    iter::zip,
+    iter::once,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -9,37 +9,106 @@ use anyhow::Result as anyhowResult;
 use candle_core::{Device, IndexOp, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
+use serde::Deserialize;
 use tokenizers::Tokenizer;
 use tracing::warn;
 
 use crate::{
     get_mut_arcmutex,
+    paged_attention::CacheConfig,
     pipeline::{
         sampling::{
             finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
         },
+        text_models_inputs_processor::PagedAttentionMeta,
         AdapterInstruction,
     },
     prefix_cacher::PrefixCacheManager,
+    sampler::Logprobs,
     sequence::{Sequence, SequenceRecognizer},
-    DeviceMapMetadata, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource,
-    TryIntoDType,
+    DeviceMapMetadata, Loader, MemoryGpuConfig, ModelKind, PagedAttentionConfig, Pipeline,
+    TokenSource, TryIntoDType,
 };
 
 use super::{
-    cache_manager::FullCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
+    cache_manager::FullCacheManager,
+    chat_template::ChatTemplate,
+    speculative_heads::{HeadsState, SpeculativeHeads},
     AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
-    CacheManager, CacheManagerMixin, EitherCache, ForwardInputsResult, GeneralMetadata,
-    IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
+    CacheManager, CacheManagerMixin, CandidateTree, EitherCache, ForwardInputsResult,
+    GeneralMetadata, IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths,
+    PreProcessingMixin, SpeculativeHeadsConfig,
 };
 
-/// A loader for a speculative pipeline using 2 [`Loader`]s.
+/// A loader for a speculative pipeline using a target [`Loader`] and a source of draft tokens.
 pub struct SpeculativeLoader {
     pub target: Box<dyn Loader>,
-    pub draft: Box<dyn Loader>,
+    pub draft: DraftLoader,
     pub config: SpeculativeConfig,
 }
 
+/// Where a [`SpeculativeLoader`] gets its draft tokens from.
+pub enum DraftLoader {
+    /// A draft model, which must have the same tokenizer as the target model.
+    Model(Box<dyn Loader>),
+    /// N-gram lookup in the tokens of the sequence itself, without any draft weights.
+    NgramLookup(NgramLookupConfig),
+    /// Medusa or EAGLE heads, which draft a tree of candidates from the hidden states of the
+    /// target model. The target model must be a normal model which supports them (Llama).
+    Heads(SpeculativeHeadsConfig),
+}
+
+fn default_max_ngram() -> usize {
+    3
+}
+
+fn default_min_ngram() -> usize {
+    1
+}
+
+/// Draft tokens by finding the last `n` tokens of a sequence earlier in its prompt or output, and
+/// proposing the tokens which followed them there (prompt lookup decoding).
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct NgramLookupConfig {
+    /// Length of the longest n-gram to look up, which is tried first.
+    #[serde(default = "default_max_ngram")]
+    pub max_ngram: usize,
+    /// Length of the shortest n-gram to look up.
+    #[serde(default = "default_min_ngram")]
+    pub min_ngram: usize,
+}
+
+impl Default for NgramLookupConfig {
+    fn default() -> Self {
+        Self {
+            max_ngram: default_max_ngram(),
+            min_ngram: default_min_ngram(),
+        }
+    }
+}
+
+impl NgramLookupConfig {
+    /// Propose up to `max_toks` tokens which followed the most recent earlier occurrence of the
+    /// longest matching suffix of `toks`.
+    fn draft(&self, toks: &[u32], max_toks: usize) -> Vec<u32> {
+        for n in (self.min_ngram.max(1)..=self.max_ngram).rev() {
+            if n >= toks.len() {
+                continue;
+            }
+            let suffix = &toks[toks.len() - n..];
+            // Matches must be followed by at least one token, which excludes the suffix itself.
+            if let Some(start) = (0..toks.len() - n)
+                .rev()
+                .find(|&start| &toks[start..start + n] == suffix)
+            {
+                let continuation = &toks[start + n..];
+                return continuation[..continuation.len().min(max_toks)].to_vec();
+            }
+        }
+        Vec::new()
+    }
+}
+
 impl Loader for SpeculativeLoader {
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn load_model_from_hf(
@@ -53,15 +122,7 @@ impl Loader for SpeculativeLoader {
         in_situ_quant: Option<IsqType>,
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
-        let paged_attn_config = if paged_attn_config.is_none() {
-            warn!(
-                "Speculative decoding does not currently support PagedAttention, running without"
-            );
-            None
-        } else {
-            paged_attn_config
-        };
-
+        let paged_attn_config = self.heads_paged_attn_config(paged_attn_config);
         let target = self.target.load_model_from_hf(
             revision.clone(),
             token_source.clone(),
@@ -72,21 +133,36 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
-        let draft = self.draft.load_model_from_hf(
-            revision,
-            token_source,
-            dtype,
-            device,
-            silent,
-            mapper,
-            in_situ_quant,
-            paged_attn_config,
-        )?;
-        Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
-            target,
-            draft,
-            self.config,
-        )?)))
+        let pipeline = match &self.draft {
+            DraftLoader::Model(draft) => {
+                let draft = draft.load_model_from_hf(
+                    revision,
+                    token_source,
+                    dtype,
+                    device,
+                    silent,
+                    mapper,
+                    in_situ_quant,
+                    draft_paged_attn_config(&target, paged_attn_config)?,
+                )?;
+                SpeculativePipeline::new(target, draft, self.config)?
+            }
+            DraftLoader::NgramLookup(lookup) => {
+                SpeculativePipeline::new_ngram_lookup(target, *lookup, self.config)
+            }
+            DraftLoader::Heads(heads) => {
+                let heads = SpeculativeHeads::load(
+                    heads,
+                    &token_source,
+                    revision,
+                    get_mut_arcmutex!(target).get_metadata().activation_dtype,
+                    device,
+                    silent,
+                )?;
+                SpeculativePipeline::new_heads(target, heads, self.config)?
+            }
+        };
+        Ok(Arc::new(tokio::sync::Mutex::new(pipeline)))
     }
 
     #[allow(clippy::type_complexity, clippy::too_many_arguments)]
@@ -100,15 +176,7 @@ impl Loader for SpeculativeLoader {
         in_situ_quant: Option<IsqType>,
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
-        let paged_attn_config = if paged_attn_config.is_none() {
-            warn!(
-                "Speculative decoding does not currently support PagedAttention, running without"
-            );
-            None
-        } else {
-            paged_attn_config
-        };
-
+        let paged_attn_config = self.heads_paged_attn_config(paged_attn_config);
         let target = self.target.load_model_from_path(
             paths,
             dtype,
@@ -118,37 +186,103 @@ impl Loader for SpeculativeLoader {
             in_situ_quant,
             paged_attn_config,
         )?;
-        let draft = self.draft.load_model_from_path(
-            paths,
-            dtype,
-            device,
-            silent,
-            mapper.clone(),
-            in_situ_quant,
-            paged_attn_config,
-        )?;
-        Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
-            target,
-            draft,
-            self.config,
-        )?)))
+        let pipeline = match &self.draft {
+            DraftLoader::Model(draft) => {
+                let draft = draft.load_model_from_path(
+                    paths,
+                    dtype,
+                    device,
+                    silent,
+                    mapper.clone(),
+                    in_situ_quant,
+                    draft_paged_attn_config(&target, paged_attn_config)?,
+                )?;
+                SpeculativePipeline::new(target, draft, self.config)?
+            }
+            DraftLoader::NgramLookup(lookup) => {
+                SpeculativePipeline::new_ngram_lookup(target, *lookup, self.config)
+            }
+            DraftLoader::Heads(heads) => {
+                let heads = SpeculativeHeads::load(
+                    heads,
+                    &TokenSource::None,
+                    None,
+                    get_mut_arcmutex!(target).get_metadata().activation_dtype,
+                    device,
+                    silent,
+                )?;
+                SpeculativePipeline::new_heads(target, heads, self.config)?
+            }
+        };
+        Ok(Arc::new(tokio::sync::Mutex::new(pipeline)))
     }
     fn get_id(&self) -> String {
+        let draft = match &self.draft {
+            DraftLoader::Model(draft) => draft.get_id(),
+            DraftLoader::NgramLookup(_) => "n-gram lookup".to_string(),
+            DraftLoader::Heads(heads) => format!("{} heads `{}`", heads.kind, heads.model_id),
+        };
         format!(
             "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
             self.target.get_id(),
-            self.draft.get_id(),
+            draft,
             self.config.gamma,
         )
     }
     fn get_kind(&self) -> ModelKind {
-        ModelKind::Speculative {
-            target: Box::new(self.target.get_kind()),
-            draft: Box::new(self.draft.get_kind()),
+        match &self.draft {
+            DraftLoader::Model(draft) => ModelKind::Speculative {
+                target: Box::new(self.target.get_kind()),
+                draft: DraftKind::Model(Box::new(draft.get_kind())),
+            },
+            // Without a draft model, this is just the target model
+            DraftLoader::NgramLookup(_) => self.target.get_kind(),
+            DraftLoader::Heads(heads) => ModelKind::Speculative {
+                target: Box::new(self.target.get_kind()),
+                draft: DraftKind::Heads(heads.kind),
+            },
+        }
+    }
+}
+
+impl SpeculativeLoader {
+    fn heads_paged_attn_config(
+        &self,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> Option<PagedAttentionConfig> {
+        if matches!(self.draft, DraftLoader::Heads(_)) && paged_attn_config.is_some() {
+            warn!("Speculative decoding heads do not currently support PagedAttention, running without");
+            return None;
         }
+        paged_attn_config
     }
 }
 
+/// The draft model shares the block tables of the target model, so it gets a PagedAttention cache
+/// with the same block size which holds as many tokens as the target's. The memory settings of
+/// `paged_attn_config` only apply to the target model.
+fn draft_paged_attn_config(
+    target: &Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>,
+    paged_attn_config: Option<PagedAttentionConfig>,
+) -> anyhowResult<Option<PagedAttentionConfig>> {
+    let Some(paged_attn_config) = paged_attn_config else {
+        return Ok(None);
+    };
+    let Some(cache_config) = get_mut_arcmutex!(target)
+        .get_metadata()
+        .cache_config
+        .clone()
+    else {
+        // The target model does not support PagedAttention
+        return Ok(None);
+    };
+    Ok(Some(PagedAttentionConfig::new(
+        Some(cache_config.block_size),
+        paged_attn_config.mem_cpu,
+        MemoryGpuConfig::ContextSize(cache_config.num_gpu_blocks * cache_config.block_size),
+    )?))
+}
+
 /// Speculative decoding pipeline: <https://arxiv.org/pdf/2211.17192>
 ///
 /// # Algorithm
@@ -160,19 +294,100 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+/// Instead of a draft model, the draft tokens may come from an n-gram lookup in the sequence
+/// itself. These are accepted if they match the samples of the target model.
+///
+/// With PagedAttention, both models share the block tables managed by the scheduler. Slots for the
+/// draft tokens are reserved before each step and the blocks holding rejected tokens are freed
+/// afterwards.
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    gamma: usize,
+    draft: Drafter,
+    config: SpeculativeConfig,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
 }
 
+/// Source of the draft tokens of a [`SpeculativePipeline`].
+enum Drafter {
+    Model(Arc<tokio::sync::Mutex<dyn Pipeline>>),
+    NgramLookup(NgramLookupConfig),
+    Heads(SpeculativeHeads),
+}
+
+impl Drafter {
+    fn model(&self) -> Option<&Arc<tokio::sync::Mutex<dyn Pipeline>>> {
+        match self {
+            Self::Model(model) => Some(model),
+            Self::NgramLookup(_) | Self::Heads(_) => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
-    /// γ completions to run of the draft model
+    /// γ completions to run of the draft model. With `adaptive_gamma`, this is the maximum.
     pub gamma: usize,
+    /// Tune γ per sequence from the observed acceptance rate of the draft tokens.
+    pub adaptive_gamma: bool,
+}
+
+/// Decay applied to the acceptance counts on every step, so that γ follows recent behavior.
+const ACCEPTANCE_DECAY: f32 = 0.9;
+
+/// Per sequence state of speculative decoding: acceptance statistics and the adaptive γ.
+#[derive(Clone, Debug, Default)]
+pub struct SpeculativeState {
+    draft_tokens: usize,
+    accepted_draft_tokens: usize,
+    decayed_accepted: f32,
+    decayed_rejected: f32,
+    gamma: Option<usize>,
+    heads: HeadsState,
+}
+
+impl SpeculativeState {
+    /// Number of tokens drafted for this sequence, if speculative decoding ran.
+    pub fn draft_tokens(&self) -> Option<usize> {
+        (self.draft_tokens > 0).then_some(self.draft_tokens)
+    }
+
+    /// Number of draft tokens accepted by the target model, if speculative decoding ran.
+    pub fn accepted_draft_tokens(&self) -> Option<usize> {
+        (self.draft_tokens > 0).then_some(self.accepted_draft_tokens)
+    }
+
+    pub(crate) fn heads(&mut self) -> &mut HeadsState {
+        &mut self.heads
+    }
+
+    /// Number of tokens to draft in the next step.
+    fn gamma(&self, config: &SpeculativeConfig) -> usize {
+        self.gamma.unwrap_or(config.gamma)
+    }
+
+    /// Record that `accepted` of `drafted` draft tokens were accepted.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    fn record(&mut self, config: &SpeculativeConfig, drafted: usize, accepted: usize) {
+        self.draft_tokens += drafted;
+        self.accepted_draft_tokens += accepted;
+        // A step without draft tokens says nothing about the acceptance rate
+        if !config.adaptive_gamma || drafted == 0 {
+            return;
+        }
+        let rejected = usize::from(accepted < drafted);
+        self.decayed_accepted = ACCEPTANCE_DECAY * self.decayed_accepted + accepted as f32;
+        self.decayed_rejected = ACCEPTANCE_DECAY * self.decayed_rejected + rejected as f32;
+        // With acceptance rate α, runs of accepted tokens have an expected length of α / (1 - α).
+        // Draft one token more than that.
+        let gamma = if self.decayed_rejected == 0. {
+            config.gamma
+        } else {
+            (self.decayed_accepted / self.decayed_rejected).ceil() as usize + 1
+        };
+        self.gamma = Some(gamma.clamp(1, config.gamma));
+    }
 }
 
 impl SpeculativePipeline {
@@ -214,17 +429,538 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' input processors do not match. This is required for speculative decoding.");
         }
-        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let target_metadata = get_mut_arcmutex!(target).get_metadata();
+        let draft_metadata = get_mut_arcmutex!(draft).get_metadata();
+        let metadata = match (&target_metadata.cache_config, &draft_metadata.cache_config) {
+            (None, None) => target_metadata,
+            (Some(target_cache), Some(draft_cache)) => {
+                if target_cache.block_size != draft_cache.block_size {
+                    candle_core::bail!("Target and draft models' PagedAttention block sizes do not match. This is required for speculative decoding.");
+                }
+                // The scheduler allocates blocks for both models, so only use as many as both have.
+                Arc::new(GeneralMetadata {
+                    max_seq_len: target_metadata.max_seq_len,
+                    tok_trie: target_metadata.tok_trie.clone(),
+                    dry_breakers: target_metadata.dry_breakers.clone(),
+                    has_no_kv_cache: target_metadata.has_no_kv_cache,
+                    num_hidden_layers: target_metadata.num_hidden_layers,
+                    eos_tok: target_metadata.eos_tok.clone(),
+                    kind: target_metadata.kind.clone(),
+                    is_xlora: target_metadata.is_xlora,
+                    activation_dtype: target_metadata.activation_dtype,
+                    sliding_window: target_metadata.sliding_window,
+                    cache_config: Some(CacheConfig {
+                        block_size: target_cache.block_size,
+                        num_gpu_blocks: target_cache.num_gpu_blocks.min(draft_cache.num_gpu_blocks),
+                        num_cpu_blocks: target_cache.num_cpu_blocks.min(draft_cache.num_cpu_blocks),
+                    }),
+                    cache_engine: None,
+                    prompt_batchsize: target_metadata.prompt_batchsize,
+                    model_metadata: target_metadata.model_metadata.clone(),
+                })
+            }
+            _ => candle_core::bail!("Target and draft models must either both or neither use PagedAttention. This is required for speculative decoding."),
+        };
         let category = get_mut_arcmutex!(target).category();
         // TODO: some checks or relaxation here?
         Ok(Self {
             target,
-            draft,
-            gamma: config.gamma,
+            draft: Drafter::Model(draft),
+            config,
             metadata,
             category,
         })
     }
+
+    /// Create a speculative pipeline which drafts tokens by n-gram lookup instead of a draft model.
+    pub fn new_ngram_lookup(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        lookup: NgramLookupConfig,
+        config: SpeculativeConfig,
+    ) -> Self {
+        let metadata = get_mut_arcmutex!(target).get_metadata();
+        let category = get_mut_arcmutex!(target).category();
+        Self {
+            target,
+            draft: Drafter::NgramLookup(lookup),
+            config,
+            metadata,
+            category,
+        }
+    }
+
+    /// Create a speculative pipeline which drafts a tree of candidates with speculative decoding
+    /// heads on the hidden states of the target model.
+    pub(crate) fn new_heads(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        heads: SpeculativeHeads,
+        config: SpeculativeConfig,
+    ) -> Result<Self> {
+        let metadata = get_mut_arcmutex!(target).get_metadata();
+        if metadata.is_xlora || !matches!(get_mut_arcmutex!(target).cache(), EitherCache::Normal(_))
+        {
+            candle_core::bail!("Speculative decoding heads require a target model with a normal KV cache and without X-LoRA.");
+        }
+        let category = get_mut_arcmutex!(target).category();
+        Ok(Self {
+            target,
+            draft: Drafter::Heads(heads),
+            config,
+            metadata,
+            category,
+        })
+    }
+}
+
+impl SpeculativePipeline {
+    /// Run the draft model `gamma` times on a sequence and return the sampled draft tokens.
+    async fn draft_with_model(
+        &self,
+        draft: &Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        seq: &mut Sequence,
+        is_prompt: bool,
+        gamma: usize,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        paged_attn_meta: &mut Option<&mut PagedAttentionMeta<'_>>,
+    ) -> Result<Vec<u32>> {
+        // ======================= Run draft model gamma times producing tokens ============================
+        // ======================= Sample the `gamma` logits. ============================
+        let mut draft_toks = Vec::new();
+        for i in 0..gamma {
+            let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+            let device = get_mut_arcmutex!(draft).device();
+            let has_no_kv_cache = get_mut_arcmutex!(draft).get_metadata().has_no_kv_cache;
+            let sliding_window = get_mut_arcmutex!(draft).get_metadata().sliding_window;
+            let inputs = self
+                .get_processor()
+                .inputs_processor()
+                .process_inputs(
+                    self.tokenizer(),
+                    &mut [&mut *seq],
+                    is_prompt && i == 0, // Only prompt (no kv cache) if first
+                    is_xlora,
+                    &device,
+                    has_no_kv_cache,
+                    None,
+                    false,
+                    None,
+                    paged_attn_meta.as_mut().map(|meta| PagedAttentionMeta {
+                        sliding_window,
+                        block_size: meta.block_size,
+                        block_engine: &mut *meta.block_engine,
+                    }),
+                    None, // TODO: do we support???
+                )
+                .nth(0)
+                .unwrap()
+                .unwrap();
+            let logits = get_mut_arcmutex!(draft).forward_inputs(Box::new(inputs), false)?;
+            #[allow(irrefutable_let_patterns)]
+            let ForwardInputsResult::CausalGeneration { logits } = logits
+            else {
+                candle_core::bail!(
+                    "Speculative decoding requires `CausalGeneration` forward results"
+                );
+            };
+
+            let sample = sample_sequence(
+                logits.clone(),
+                seq,
+                seq.return_logprobs(),
+                rng.clone(),
+                false, // todo tune
+                false, // do not add to tok trie yet
+                true,
+            )
+            .await?;
+            seq.add_tmp_tok(sample.token);
+            draft_toks.push(sample.token);
+        }
+        seq.remove_tmp_tok(gamma);
+        Ok(draft_toks)
+    }
+
+    /// Draft tokens for one sequence, verify them with the target model and add the accepted
+    /// tokens to the sequence.
+    async fn speculate(
+        &mut self,
+        seq: &mut Sequence,
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        mut paged_attn_meta: Option<&mut PagedAttentionMeta<'_>>,
+    ) -> Result<()> {
+        if let Drafter::Heads(heads) = &self.draft {
+            return self
+                .speculate_with_heads(heads, seq, is_prompt, prefix_cacher, disable_eos_stop, rng)
+                .await;
+        }
+        let n_toks = seq.get_toks().len();
+        let mut gamma = seq.speculative_state().gamma(&self.config);
+        // The target model runs on the last token and the draft tokens, except for the last token
+        // from a draft model: the draft model has not computed its KV, so it cannot be accepted
+        // together with a token sampled from the target model after it.
+        let draft_toks = match &self.draft {
+            Drafter::Model(draft) => {
+                if let Some(paged_attn_meta) = &mut paged_attn_meta {
+                    // Draft fewer tokens if there are not enough free blocks.
+                    while gamma > 1
+                        && !paged_attn_meta
+                            .block_engine
+                            .reserve_slots(*seq.id(), n_toks + gamma - 1)
+                    {
+                        gamma -= 1;
+                    }
+                }
+                let draft = draft.clone();
+                self.draft_with_model(
+                    &draft,
+                    seq,
+                    is_prompt,
+                    gamma,
+                    rng.clone(),
+                    &mut paged_attn_meta,
+                )
+                .await?
+            }
+            Drafter::Heads(_) => unreachable!(),
+            Drafter::NgramLookup(lookup) => {
+                let mut draft_toks = lookup.draft(seq.get_toks(), gamma);
+                if let Some(paged_attn_meta) = &mut paged_attn_meta {
+                    while !draft_toks.is_empty()
+                        && !paged_attn_meta
+                            .block_engine
+                            .reserve_slots(*seq.id(), n_toks + draft_toks.len())
+                    {
+                        draft_toks.pop();
+                    }
+                }
+                draft_toks
+            }
+        };
+        let n_verified = match self.draft {
+            Drafter::Model(_) => draft_toks.len(),
+            Drafter::NgramLookup(_) => draft_toks.len() + 1,
+            Drafter::Heads(_) => unreachable!(),
+        };
+
+        // ======================= Add the verified draft tokens. Add the last from the seq. ============================
+        let mut draft_prefill_tokens = if is_prompt {
+            seq.get_toks().to_vec()
+        } else {
+            vec![*seq.get_toks().last().unwrap()]
+        };
+        draft_prefill_tokens.extend(&draft_toks[..n_verified - 1]);
+        seq.set_prefill_toks(draft_prefill_tokens);
+
+        // ======================= Run the model with all draft tokens. ============================
+
+        let initial_cache_len = if paged_attn_meta.is_some() {
+            // The KV of all tokens but the last one is in the blocks
+            if is_prompt {
+                0
+            } else {
+                n_toks - 1
+            }
+        } else {
+            match get_mut_arcmutex!(self.target).cache() {
+                EitherCache::Full(full) => full.lock()[0]
+                    .as_ref()
+                    .map(|(k, _)| k.dims()[2])
+                    .unwrap_or(0),
+                EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
+            }
+        };
+
+        // ========= Run the model ============
+        let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+        let device = get_mut_arcmutex!(self.target).device();
+        let has_no_kv_cache = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .has_no_kv_cache;
+        let sliding_window = get_mut_arcmutex!(self.target).get_metadata().sliding_window;
+        let inputs = self
+            .get_processor()
+            .inputs_processor()
+            .process_inputs(
+                self.tokenizer(),
+                &mut [&mut *seq],
+                true, // use the "prefill" tokens
+                is_xlora,
+                &device,
+                has_no_kv_cache,
+                Some((n_verified, initial_cache_len)), // Get the last n_verified, see above
+                false,
+                None,
+                paged_attn_meta.as_mut().map(|meta| PagedAttentionMeta {
+                    sliding_window,
+                    block_size: meta.block_size,
+                    block_engine: &mut *meta.block_engine,
+                }),
+                None, // TODO: do we support???
+            )
+            .nth(0)
+            .unwrap()
+            .unwrap();
+
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs), false)?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+
+        // Reset the prefill tokens
+        seq.reset_prefill_toks();
+
+        // ======================= Rejection sampling. ============================
+        // Map from each target sample to corresponding in draft sample
+        let samples = sample_target_sequence_speculative(
+            logits.clone(),
+            seq,
+            seq.return_logprobs(),
+            rng.clone(),
+            n_verified,
+        )
+        .await?;
+
+        let mut accepted_tokens = Vec::new();
+        let mut n_accepted_drafts = 0;
+        for (i, target_sample) in samples.into_iter().enumerate() {
+            let tok = target_sample.sample.token;
+            accepted_tokens.push(target_sample.sample);
+            if draft_toks.get(i) != Some(&tok) {
+                break;
+            }
+            n_accepted_drafts += 1;
+        }
+
+        // ======================= Narrow caches to account for rejections ============================
+        // With PagedAttention, the blocks of rejected tokens are freed below.
+        if paged_attn_meta.is_none() {
+            let n_not_accepted = n_verified - accepted_tokens.len();
+            if let Drafter::Model(draft) = &self.draft {
+                match get_mut_arcmutex!(draft).cache() {
+                    EitherCache::Full(full) => {
+                        for (k, v) in full.lock().iter_mut().flatten() {
+                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                        }
+                    }
+                    EitherCache::Normal(normal) => {
+                        for cache in &mut *normal.lock().unwrap().0 {
+                            cache.set_len(cache.current_seq_len() - n_not_accepted);
+                        }
+                    }
+                }
+                if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                    match get_mut_arcmutex!(draft).cache() {
+                        EitherCache::Full(full) => {
+                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
+                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                            }
+                        }
+                        EitherCache::Normal(_) => {
+                            unreachable!()
+                        }
+                    }
+                }
+            }
+            match get_mut_arcmutex!(self.target).cache() {
+                EitherCache::Full(full) => {
+                    for (k, v) in full.lock().iter_mut().flatten() {
+                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                    }
+                }
+                EitherCache::Normal(normal) => {
+                    for cache in &mut *normal.lock().unwrap().0 {
+                        cache.set_len(cache.current_seq_len() - n_not_accepted);
+                    }
+                }
+            }
+            if get_mut_arcmutex!(self.target).get_metadata().is_xlora {
+                match get_mut_arcmutex!(self.target).cache() {
+                    EitherCache::Full(full) => {
+                        for (k, v) in full.xlora_lock().iter_mut().flatten() {
+                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                        }
+                    }
+                    EitherCache::Normal(_) => {
+                        unreachable!()
+                    }
+                }
+            }
+        }
+
+        self.add_accepted_tokens(seq, accepted_tokens, prefix_cacher, disable_eos_stop)
+            .await?;
+
+        // Trick to improve lower bounds. Sample last token in multinomial
+        /*
+        let sample = sample_sequence(
+            logits.clone(),
+            seq,
+            seq.return_logprobs(),
+            rng.clone(),
+            false, // todo tune
+            true, // do not add to tok trie yet
+            true,
+        )
+        .await?;
+        finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
+        */
+
+        if let Some(paged_attn_meta) = &mut paged_attn_meta {
+            paged_attn_meta
+                .block_engine
+                .truncate_slots(*seq.id(), seq.get_toks().len());
+        }
+        seq.speculative_state()
+            .record(&self.config, draft_toks.len(), n_accepted_drafts);
+
+        Ok(())
+    }
+
+    /// Add the accepted tokens to the sequence and advance its grammar.
+    async fn add_accepted_tokens(
+        &self,
+        seq: &mut Sequence,
+        accepted_tokens: Vec<Logprobs>,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+    ) -> Result<()> {
+        let eos_owned = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .eos_tok
+            .clone();
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(&eos_owned[..])
+        };
+        // Add the tokens to the seq and the trie
+        for accepted in accepted_tokens {
+            // Do not use the prefix cacher
+            finish_or_add_toks_to_seq(self, prefix_cacher, seq, accepted.clone(), eos_tok, false)
+                .await?;
+            match seq.recognizer {
+                SequenceRecognizer::Regex(ref mut rx) => {
+                    get_mut_arcmutex!(self.target)
+                        .get_metadata()
+                        .tok_trie
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`SpeculativePipeline::step` requires a token trie".to_string(),
+                        ))?
+                        .append_token(rx.as_mut(), accepted.token)
+                        .map_err(candle_core::Error::msg)?;
+                }
+                SequenceRecognizer::Cfg(ref mut cfg) => {
+                    get_mut_arcmutex!(self.target)
+                        .get_metadata()
+                        .tok_trie
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`SpeculativePipeline::step` requires a token trie".to_string(),
+                        ))?
+                        .append_token(cfg.as_mut(), accepted.token)
+                        .map_err(candle_core::Error::msg)?;
+                }
+                SequenceRecognizer::None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Draft a tree of candidates for one sequence with the speculative decoding heads, verify it
+    /// in one forward pass of the target model with a tree attention mask and add the accepted
+    /// tokens to the sequence.
+    ///
+    /// The target model samples a token at the root. If a child of the root has that token, it is
+    /// accepted and the target model samples at that child, and so on. The KV cache then only
+    /// keeps the root and the accepted candidates.
+    async fn speculate_with_heads(
+        &self,
+        heads: &SpeculativeHeads,
+        seq: &mut Sequence,
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        let toks = seq.get_toks().to_vec();
+        let depth = seq.speculative_state().gamma(&self.config);
+        // The heads need hidden states of the target model, so the prompt runs without candidates.
+        let tree = if is_prompt {
+            CandidateTree::chain(toks.clone())
+        } else {
+            heads.draft(
+                seq.speculative_state().heads(),
+                &toks,
+                depth,
+                &mut *get_mut_arcmutex!(self.target),
+            )?
+        };
+
+        let initial_cache_len =
+            get_mut_arcmutex!(self.target).cache().normal().0[0].current_seq_len();
+        let hidden_states = get_mut_arcmutex!(self.target).forward_tree(&tree)?;
+
+        // ======================= Follow the candidates the target model agrees with. ============================
+        let mut path = vec![if is_prompt { tree.len() - 1 } else { 0 }];
+        let mut accepted_tokens = Vec::new();
+        loop {
+            let node = *path.last().unwrap();
+            let logits = get_mut_arcmutex!(self.target)
+                .logits_from_hidden_states(&hidden_states.i(node..node + 1)?)?;
+            let sample = sample_sequence(
+                logits,
+                seq,
+                seq.return_logprobs(),
+                rng.clone(),
+                false,
+                false, // do not add to tok trie yet
+                true,
+            )
+            .await?;
+            let child = tree
+                .children(node)
+                .find(|&child| tree.tokens[child] == sample.token);
+            accepted_tokens.push(sample);
+            let Some(child) = child else {
+                break;
+            };
+            seq.add_tmp_tok(tree.tokens[child]);
+            path.push(child);
+        }
+        let n_accepted_drafts = path.len() - 1;
+        seq.remove_tmp_tok(n_accepted_drafts);
+
+        // ======================= Keep the KV and hidden states of the accepted path. ============================
+        let features = if is_prompt {
+            hidden_states
+        } else {
+            for cache in &mut *get_mut_arcmutex!(self.target).cache().normal().0 {
+                cache.retain(initial_cache_len, &path)?;
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            let path = path.iter().map(|&node| node as u32).collect::<Vec<_>>();
+            hidden_states.index_select(&Tensor::new(path, hidden_states.device())?, 0)?
+        };
+        seq.speculative_state().heads().set_features(features);
+
+        self.add_accepted_tokens(seq, accepted_tokens, prefix_cacher, disable_eos_stop)
+            .await?;
+        if !is_prompt {
+            seq.speculative_state()
+                .record(&self.config, tree.depth(), n_accepted_drafts);
+        }
+        Ok(())
+    }
 }
 
 impl PreProcessingMixin for SpeculativePipeline {
@@ -239,19 +975,26 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        if let Some(draft) = self.draft.model() {
+            get_mut_arcmutex!(draft).re_isq_model(dtype)?;
+        }
+        Ok(())
     }
 }
 
-// TODO: correct handling of cloning in and out for normal cache
+// TODO: correct handling of cloning in and out for the normal cache of a draft model
 impl CacheManagerMixin for SpeculativePipeline {
     fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        FullCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.draft), seqs, modify_draft_cache);
-        FullCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+        if let Some(draft) = self.draft.model() {
+            FullCacheManager.clone_in_cache(&*get_mut_arcmutex!(draft), seqs, modify_draft_cache);
+        }
+        get_mut_arcmutex!(self.target).clone_in_cache(seqs, false);
     }
     fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        FullCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.draft), seqs, modify_draft_cache);
-        FullCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+        if let Some(draft) = self.draft.model() {
+            FullCacheManager.clone_out_cache(&*get_mut_arcmutex!(draft), seqs, modify_draft_cache);
+        }
+        get_mut_arcmutex!(self.target).clone_out_cache(seqs, false);
     }
     fn set_none_cache(
         &self,
@@ -260,18 +1003,15 @@ impl CacheManagerMixin for SpeculativePipeline {
         modify_draft_cache: bool,
         load_preallocated_cache: bool,
     ) {
-        FullCacheManager.set_none_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-            load_preallocated_cache,
-        );
-        FullCacheManager.set_none_cache(
-            &*get_mut_arcmutex!(self.target),
-            seqs,
-            false,
-            load_preallocated_cache,
-        );
+        if let Some(draft) = self.draft.model() {
+            FullCacheManager.set_none_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+                load_preallocated_cache,
+            );
+        }
+        get_mut_arcmutex!(self.target).set_none_cache(seqs, false, false, load_preallocated_cache);
         if reset_non_granular {
             self.reset_non_granular_state()
         }
@@ -279,13 +1019,19 @@ impl CacheManagerMixin for SpeculativePipeline {
     fn cache(&self) -> &EitherCache {
         unreachable!()
     }
+    fn supports_prefix_cache(&self) -> bool {
+        // The draft and target models compute their prompt caches separately.
+        false
+    }
 }
 
 impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
+        if let Some(draft) = self.draft.model() {
+            res += get_mut_arcmutex!(draft).activate_adapters(adapters.clone())?;
+        }
         res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
         Ok(res)
     }
@@ -299,16 +1045,23 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
+        let draft = match &self.draft {
+            Drafter::Model(draft) => get_mut_arcmutex!(draft).name(),
+            Drafter::NgramLookup(_) => "n-gram lookup".to_string(),
+            Drafter::Heads(heads) => format!("{} heads", heads.kind()),
+        };
         format!(
             "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
             get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
+            draft,
+            self.config.gamma,
         )
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Some(draft) = self.draft.model() {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> Arc<GeneralMetadata> {
         self.metadata.clone()
@@ -358,6 +1111,15 @@ impl Pipeline for SpeculativePipeline {
                                     ))
                                 })?
                             }
+                            AdapterInstruction::ActivatePerSeq(adapters) => {
+                                self.activate_adapters_per_seq(adapters).map_err(|e| {
+                                    candle_core::Error::msg(<anyhow::Error as AsRef<
+                                        dyn std::error::Error,
+                                    >>::as_ref(
+                                        &e
+                                    ))
+                                })?
+                            }
                             AdapterInstruction::None => 0,
                         };
                         self.clone_in_cache(input_seqs, false)
@@ -373,6 +1135,15 @@ impl Pipeline for SpeculativePipeline {
                                     ))
                                 })?
                             }
+                            AdapterInstruction::ActivatePerSeq(adapters) => {
+                                self.activate_adapters_per_seq(adapters).map_err(|e| {
+                                    candle_core::Error::msg(<anyhow::Error as AsRef<
+                                        dyn std::error::Error,
+                                    >>::as_ref(
+                                        &e
+                                    ))
+                                })?
+                            }
                             AdapterInstruction::None => 0,
                         };
                     }
@@ -391,6 +1162,15 @@ impl Pipeline for SpeculativePipeline {
                                     ))
                                 })?
                             }
+                            AdapterInstruction::ActivatePerSeq(adapters) => {
+                                self.activate_adapters_per_seq(adapters).map_err(|e| {
+                                    candle_core::Error::msg(<anyhow::Error as AsRef<
+                                        dyn std::error::Error,
+                                    >>::as_ref(
+                                        &e
+                                    ))
+                                })?
+                            }
                             AdapterInstruction::None => 0,
                         };
                         self.set_none_cache(
@@ -405,261 +1185,15 @@ impl Pipeline for SpeculativePipeline {
 
                 let start = Instant::now();
                 assert_eq!(input_seqs.len(), 1);
-
-                let seq = &mut input_seqs[0];
-
-                // ======================= Run draft model gamma times producing tokens ============================
-                // ======================= Sample the `gamma` logits. ============================
-                let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let has_no_kv_cache =
-                        get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
-                    let inputs = self
-                        .get_processor()
-                        .inputs_processor()
-                        .process_inputs(
-                            self.tokenizer(),
-                            &mut [seq],
-                            is_prompt && i == 0, // Only prompt (no kv cache) if first
-                            is_xlora,
-                            &device,
-                            has_no_kv_cache,
-                            None,
-                            false,
-                            None,
-                            None, // TODO: get block tables/handle it
-                            None, // TODO: do we support???
-                        )
-                        .nth(0)
-                        .unwrap()
-                        .unwrap();
-                    let logits =
-                        get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs), false)?;
-                    #[allow(irrefutable_let_patterns)]
-                    let ForwardInputsResult::CausalGeneration { logits } = logits
-                    else {
-                        candle_core::bail!(
-                            "Speculative decoding requires `CausalGeneration` forward results"
-                        );
-                    };
-
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        false, // do not add to tok trie yet
-                        true,
-                    )
-                    .await?;
-                    seq.add_tmp_tok(sample.token);
-                    draft_samples.push(SpeculativeSample { sample });
-                }
-                seq.remove_tmp_tok(self.gamma);
-
-                // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
-                let mut draft_prefill_tokens = if is_prompt {
-                    seq.get_toks().to_vec()
-                } else {
-                    vec![*seq.get_toks().last().unwrap()]
-                };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
-                    }
-                    draft_prefill_tokens.push(sample.sample.token);
-                }
-                seq.set_prefill_toks(draft_prefill_tokens);
-
-                // ======================= Run the model with all draft tokens. ============================
-
-                let initial_cache_len = match get_mut_arcmutex!(self.target).cache() {
-                    EitherCache::Full(full) => full.lock()[0]
-                        .as_ref()
-                        .map(|(k, _)| k.dims()[2])
-                        .unwrap_or(0),
-                    EitherCache::Normal(normal) => normal.lock().unwrap().0[0].current_seq_len(),
-                };
-
-                // ========= Run the model ============
-                let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
-                let device = get_mut_arcmutex!(self.target).device();
-                let has_no_kv_cache = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .has_no_kv_cache;
-                let inputs = self
-                    .get_processor()
-                    .inputs_processor()
-                    .process_inputs(
-                        self.tokenizer(),
-                        &mut [seq],
-                        true, // use the "prefill" tokens
-                        is_xlora,
-                        &device,
-                        has_no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
-                        false,
-                        None,
-                        None, // TODO: get block tables/handle it
-                        None, // TODO: do we support???
-                    )
-                    .nth(0)
-                    .unwrap()
-                    .unwrap();
-
-                let logits =
-                    get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs), false)?;
-                #[allow(irrefutable_let_patterns)]
-                let ForwardInputsResult::CausalGeneration { logits } = logits
-                else {
-                    candle_core::bail!(
-                        "Speculative decoding requires `CausalGeneration` forward results"
-                    );
-                };
-
-                // Reset the prefill tokens
-                seq.reset_prefill_toks();
-
-                // ======================= Rejection sampling. ============================
-                // Map from each target sample to corresponding in draft sample
-                let samples = sample_target_sequence_speculative(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    self.gamma,
-                )
-                .await?;
-
-                let mut accepted_tokens = Vec::new();
-                for (target_sample, draft_sample) in zip(samples, draft_samples) {
-                    let tok = target_sample.sample.token;
-                    accepted_tokens.push(target_sample.sample);
-                    if draft_sample.sample.token != tok {
-                        break;
-                    }
-                }
-
-                // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
-                match get_mut_arcmutex!(self.draft).cache() {
-                    EitherCache::Full(full) => {
-                        for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                        }
-                    }
-                    EitherCache::Normal(normal) => {
-                        for cache in &mut *normal.lock().unwrap().0 {
-                            cache.set_len(cache.current_seq_len() - n_not_accepted);
-                        }
-                    }
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    match get_mut_arcmutex!(self.draft).cache() {
-                        EitherCache::Full(full) => {
-                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
-                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                            }
-                        }
-                        EitherCache::Normal(_) => {
-                            unreachable!()
-                        }
-                    }
-                }
-                match get_mut_arcmutex!(self.target).cache() {
-                    EitherCache::Full(full) => {
-                        for (k, v) in full.lock().iter_mut().flatten() {
-                            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                        }
-                    }
-                    EitherCache::Normal(normal) => {
-                        for cache in &mut *normal.lock().unwrap().0 {
-                            cache.set_len(cache.current_seq_len() - n_not_accepted);
-                        }
-                    }
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    match get_mut_arcmutex!(self.target).cache() {
-                        EitherCache::Full(full) => {
-                            for (k, v) in full.xlora_lock().iter_mut().flatten() {
-                                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                            }
-                        }
-                        EitherCache::Normal(_) => {
-                            unreachable!()
-                        }
-                    }
-                }
-
-                let eos_owned = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .eos_tok
-                    .clone();
-                let eos_tok = if disable_eos_stop {
-                    None
-                } else {
-                    Some(&eos_owned[..])
-                };
-                // Add the tokens to the seq and the trie
-                for accepted in accepted_tokens {
-                    // Do not use the prefix cacher
-                    finish_or_add_toks_to_seq(
-                        self,
-                        prefix_cacher,
-                        seq,
-                        accepted.clone(),
-                        eos_tok,
-                        false,
-                    )
-                    .await?;
-                    match seq.recognizer {
-                        SequenceRecognizer::Regex(ref mut rx) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(rx.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
-                        }
-                        SequenceRecognizer::Cfg(ref mut cfg) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(cfg.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
-                        }
-                        SequenceRecognizer::None => {}
-                    }
-                }
-
-                // Trick to improve lower bounds. Sample last token in multinomial
-                /*
-                let sample = sample_sequence(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    false, // todo tune
-                    true, // do not add to tok trie yet
-                    true,
+                self.speculate(
+                    input_seqs[0],
+                    is_prompt,
+                    prefix_cacher,
+                    disable_eos_stop,
+                    rng,
+                    None,
                 )
                 .await?;
-                finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
-                */
                 let end = Instant::now();
                 let exec_duration = end.duration_since(start);
 
@@ -693,11 +1227,39 @@ impl Pipeline for SpeculativePipeline {
                 Ok(exec_duration)
             }
             CacheBackendMetadata::PagedAttention {
-                metadata: _,
-                blocks_to_copy: _,
-                blocks_to_swap_in: _,
-                blocks_to_swap_out: _,
-            } => unreachable!(),
+                mut metadata,
+                blocks_to_copy,
+                blocks_to_swap_in,
+                blocks_to_swap_out,
+            } => {
+                for pipeline in once(&self.target).chain(self.draft.model()) {
+                    get_mut_arcmutex!(pipeline)
+                        .get_metadata()
+                        .cache_engine
+                        .as_ref()
+                        .expect("PagedAttention must have cache engine.")
+                        .execute_scheduler_ops(
+                            blocks_to_swap_in.clone(),
+                            blocks_to_swap_out.clone(),
+                            blocks_to_copy.clone(),
+                        )?;
+                }
+
+                let start = Instant::now();
+                // Sequences accept different numbers of draft tokens, so run them one at a time.
+                for seq in input_seqs.iter_mut() {
+                    self.speculate(
+                        seq,
+                        is_prompt,
+                        prefix_cacher,
+                        disable_eos_stop,
+                        rng.clone(),
+                        Some(&mut metadata),
+                    )
+                    .await?;
+                }
+                Ok(start.elapsed())
+            }
         }
     }
     fn category(&self) -> ModelCategory {
@@ -707,3 +1269,64 @@ impl Pipeline for SpeculativePipeline {
 
 // TODO
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::{NgramLookupConfig, SpeculativeConfig, SpeculativeState};
+
+    #[test]
+    fn ngram_lookup_drafts_continuation() {
+        let lookup = NgramLookupConfig::default();
+        // The longest matching suffix is [1, 2], which was followed by [3, 4, 1, 2].
+        assert_eq!(lookup.draft(&[1, 2, 3, 4, 1, 2], 3), vec![3, 4, 1]);
+        // The most recent match of [7] is used.
+        assert_eq!(lookup.draft(&[7, 8, 7, 9, 5, 7], 2), vec![9, 5]);
+        assert_eq!(lookup.draft(&[1, 2, 3], 4), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn adaptive_gamma_follows_acceptance() {
+        let config = SpeculativeConfig {
+            gamma: 4,
+            adaptive_gamma: true,
+        };
+        let mut state = SpeculativeState::default();
+        assert_eq!(state.gamma(&config), 4);
+        assert_eq!(state.draft_tokens(), None);
+
+        // Every draft is rejected: draft a single token.
+        state.record(&config, 4, 0);
+        assert_eq!(state.gamma(&config), 1);
+        // A step without draft tokens keeps γ.
+        state.record(&config, 0, 0);
+        assert_eq!(state.gamma(&config), 1);
+
+        // About half of the draft tokens are accepted: draft two.
+        state.record(&config, 1, 0);
+        state.record(&config, 2, 1);
+        state.record(&config, 2, 1);
+        assert_eq!(state.gamma(&config), 2);
+
+        // Full acceptance grows γ up to the configured maximum.
+        for _ in 0..3 {
+            state.record(&config, 4, 4);
+        }
+        assert_eq!(state.gamma(&config), 4);
+        assert_eq!(state.draft_tokens(), Some(21));
+        assert_eq!(state.accepted_draft_tokens(), Some(14));
+    }
+
+    #[test]
+    fn fixed_gamma_only_counts_tokens() {
+        let config = SpeculativeConfig {
+            gamma: 3,
+            adaptive_gamma: false,
+        };
+        let mut state = SpeculativeState::default();
+        state.record(&config, 3, 0);
+        state.record(&config, 3, 1);
+        assert_eq!(state.gamma(&config), 3);
+        assert_eq!(state.draft_tokens(), Some(6));
+        assert_eq!(state.accepted_draft_tokens(), Some(1));
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    iter::once,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{safetensors::MmapedSafetensors, DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{Linear, VarBuilder};
+use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    api_dir_list, api_get_file,
+    models::llama::{Config as LlamaConfig, EagleLayer},
+    utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors},
+    Pipeline, TokenSource,
+};
+
+use super::{KvCache, NormalCache};
+
+/// The kind of speculative decoding heads shipped alongside a base model.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SpeculativeHeadsKind {
+    /// Medusa heads, each of which predicts a token further ahead from the same hidden state:
+    /// <https://arxiv.org/abs/2401.10774>
+    Medusa,
+    /// An EAGLE draft layer, which autoregressively predicts the next hidden states of the base
+    /// model: <https://arxiv.org/abs/2401.15077>
+    Eagle,
+}
+
+/// Speculative decoding heads to load alongside a target model.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SpeculativeHeadsConfig {
+    /// Model ID or local path of the heads.
+    pub model_id: String,
+    pub kind: SpeculativeHeadsKind,
+    /// Tree of Medusa candidates as paths of the top-k ranks of the tokens from each head, in the
+    /// format of Medusa's `medusa_choices`. EAGLE drafts a single chain.
+    #[serde(default)]
+    pub tree: Option<Vec<Vec<usize>>>,
+}
+
+/// Default tree of Medusa candidates, which favors the top ranked tokens of the first heads.
+const DEFAULT_MEDUSA_TREE: &[&[usize]] = &[
+    &[0],
+    &[1],
+    &[2],
+    &[3],
+    &[0, 0],
+    &[0, 1],
+    &[0, 2],
+    &[1, 0],
+    &[1, 1],
+    &[2, 0],
+    &[0, 0, 0],
+    &[0, 0, 1],
+    &[0, 1, 0],
+    &[1, 0, 0],
+    &[0, 0, 0, 0],
+    &[0, 0, 0, 1],
+    &[0, 0, 0, 0, 0],
+];
+
+/// Candidate tokens as a tree rooted at the last token of a sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CandidateTree {
+    /// Tokens of the tree, root first.
+    pub tokens: Vec<u32>,
+    /// Index of the parent of each token, `None` for the root. Parents come before their children.
+    pub parents: Vec<Option<usize>>,
+}
+
+impl CandidateTree {
+    /// A chain in which each token is the child of the previous one.
+    pub fn chain(tokens: Vec<u32>) -> Self {
+        let parents = (0..tokens.len()).map(|i| i.checked_sub(1)).collect();
+        Self { tokens, parents }
+    }
+
+    /// Build a tree rooted at `root` from paths of top-k ranks, where `candidates[d]` are the
+    /// candidate tokens at depth `d + 1` ordered by rank. Paths deeper than `candidates`, with a
+    /// rank out of range, or whose prefix is not in the tree are skipped.
+    fn from_paths<'a>(
+        root: u32,
+        paths: impl IntoIterator<Item = &'a [usize]>,
+        candidates: &[Vec<u32>],
+    ) -> Self {
+        let mut paths: Vec<_> = paths.into_iter().filter(|p| !p.is_empty()).collect();
+        paths.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+        paths.dedup();
+
+        let mut tree = Self::chain(vec![root]);
+        let mut nodes: HashMap<&[usize], usize> = HashMap::new();
+        for path in paths {
+            let (&rank, prefix) = path.split_last().unwrap();
+            let parent = if prefix.is_empty() {
+                Some(0)
+            } else {
+                nodes.get(prefix).copied()
+            };
+            let token = candidates.get(path.len() - 1).and_then(|c| c.get(rank));
+            if let (Some(parent), Some(&token)) = (parent, token) {
+                nodes.insert(path, tree.tokens.len());
+                tree.tokens.push(token);
+                tree.parents.push(Some(parent));
+            }
+        }
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Depth of the deepest token, which is the most candidates one step can accept.
+    pub fn depth(&self) -> usize {
+        let mut depths: Vec<usize> = Vec::with_capacity(self.len());
+        for parent in &self.parents {
+            let depth = parent.map_or(0, |p| depths[p] + 1);
+            depths.push(depth);
+        }
+        depths.into_iter().max().unwrap_or(0)
+    }
+
+    pub(crate) fn children(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        self.parents
+            .iter()
+            .enumerate()
+            .filter(move |(_, parent)| **parent == Some(node))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Per sequence state of speculative decoding heads.
+#[derive(Clone, Debug, Default)]
+pub struct HeadsState {
+    /// Final hidden states of the target model from which the last tokens of the sequence were
+    /// sampled, one row per token.
+    features: Option<Tensor>,
+    /// KV cache of the EAGLE draft layer.
+    eagle_cache: Option<KvCache>,
+}
+
+impl HeadsState {
+    pub(crate) fn set_features(&mut self, features: Tensor) {
+        self.features = Some(features);
+    }
+}
+
+/// A residual block of a Medusa head: `x + silu(linear(x))`.
+struct ResBlock(Linear);
+
+impl Module for ResBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs + candle_nn::ops::silu(&self.0.forward(xs)?)?
+    }
+}
+
+pub(crate) struct MedusaHead {
+    blocks: Vec<ResBlock>,
+    lm_head: Linear,
+}
+
+impl Module for MedusaHead {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for block in &self.blocks {
+            xs = block.forward(&xs)?;
+        }
+        self.lm_head.forward(&xs)
+    }
+}
+
+#[derive(Deserialize)]
+struct MedusaConfig {
+    medusa_num_heads: usize,
+    medusa_num_layers: usize,
+}
+
+/// Speculative decoding heads which draft candidates from the hidden states of a target model.
+pub(crate) enum SpeculativeHeads {
+    Medusa {
+        heads: Vec<MedusaHead>,
+        tree: Vec<Vec<usize>>,
+    },
+    Eagle {
+        layer: EagleLayer,
+        max_seq_len: usize,
+    },
+}
+
+impl SpeculativeHeads {
+    /// Load the heads from the Hugging Face Hub or a local path.
+    pub(crate) fn load(
+        config: &SpeculativeHeadsConfig,
+        token: &TokenSource,
+        revision: Option<String>,
+        dtype: DType,
+        device: &Device,
+        silent: bool,
+    ) -> Result<Self> {
+        let model_id_str = &config.model_id;
+        let model_id = Path::new(&config.model_id);
+
+        let api = ApiBuilder::new()
+            .with_progress(!silent)
+            .with_token(get_token(token).map_err(candle_core::Error::msg)?)
+            .build()
+            .map_err(candle_core::Error::msg)?;
+        let revision = revision.unwrap_or("main".to_string());
+        let api = api.repo(Repo::with_revision(
+            model_id_str.clone(),
+            RepoType::Model,
+            revision,
+        ));
+
+        let mut filenames: Vec<PathBuf> = vec![];
+        for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
+            filenames.push(api_get_file!(api, &rfilename, model_id));
+        }
+        if filenames.is_empty() {
+            candle_core::bail!(
+                "Speculative decoding heads `{model_id_str}` have no .safetensors files."
+            );
+        }
+        let heads_config = std::fs::read_to_string(api_get_file!(api, "config.json", model_id))?;
+
+        let heads = match config.kind {
+            SpeculativeHeadsKind::Medusa => {
+                let MedusaConfig {
+                    medusa_num_heads,
+                    medusa_num_layers,
+                } = serde_json::from_str(&heads_config).map_err(candle_core::Error::msg)?;
+                // Medusa does not record the dimensions of the base model, so read them from the
+                // LM head of the first head.
+                let (vocab_size, hidden_size) = {
+                    let safetensors = unsafe { MmapedSafetensors::multi(&filenames)? };
+                    let shape = safetensors
+                        .get(&format!("0.{medusa_num_layers}.weight"))?
+                        .shape()
+                        .to_vec();
+                    match shape[..] {
+                        [vocab_size, hidden_size] => (vocab_size, hidden_size),
+                        _ => candle_core::bail!("Unexpected shape {shape:?} of a Medusa LM head."),
+                    }
+                };
+                let vb = from_mmaped_safetensors(
+                    filenames,
+                    vec![],
+                    Some(dtype),
+                    device,
+                    silent,
+                    None,
+                    |_| true,
+                )?;
+                let heads = (0..medusa_num_heads)
+                    .map(|i| {
+                        let vb = vb.pp(i);
+                        let blocks = (0..medusa_num_layers)
+                            .map(|j| {
+                                candle_nn::linear(hidden_size, hidden_size, vb.pp(j).pp("linear"))
+                                    .map(ResBlock)
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        let lm_head = candle_nn::linear_no_bias(
+                            hidden_size,
+                            vocab_size,
+                            vb.pp(medusa_num_layers),
+                        )?;
+                        Ok(MedusaHead { blocks, lm_head })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let tree = config
+                    .tree
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_MEDUSA_TREE.iter().map(|p| p.to_vec()).collect());
+                Self::Medusa { heads, tree }
+            }
+            SpeculativeHeadsKind::Eagle => {
+                let cfg: LlamaConfig =
+                    serde_json::from_str(&heads_config).map_err(candle_core::Error::msg)?;
+                let vb = from_mmaped_safetensors(
+                    filenames,
+                    vec![],
+                    Some(dtype),
+                    device,
+                    silent,
+                    None,
+                    |_| true,
+                )?;
+                Self::Eagle {
+                    layer: EagleLayer::new(&cfg, vb)?,
+                    max_seq_len: cfg.max_position_embeddings,
+                }
+            }
+        };
+        info!(
+            "Loaded {} speculative decoding heads from `{model_id_str}`.",
+            config.kind
+        );
+        Ok(heads)
+    }
+
+    pub(crate) fn kind(&self) -> SpeculativeHeadsKind {
+        match self {
+            Self::Medusa { .. } => SpeculativeHeadsKind::Medusa,
+            Self::Eagle { .. } => SpeculativeHeadsKind::Eagle,
+        }
+    }
+
+    /// Draft a tree of candidates of at most `depth` which follow the last of `toks`, from the
+    /// hidden states of the `target` model in `state`.
+    pub(crate) fn draft(
+        &self,
+        state: &mut HeadsState,
+        toks: &[u32],
+        depth: usize,
+        target: &mut dyn Pipeline,
+    ) -> Result<CandidateTree> {
+        let features = state.features.take().ok_or(candle_core::Error::Msg(
+            "Speculative decoding heads require the hidden states of the last tokens".to_string(),
+        ))?;
+        let root = *toks.last().unwrap();
+        match self {
+            Self::Medusa { heads, tree } => {
+                let hidden_states = features.i(features.dim(0)? - 1)?.unsqueeze(0)?;
+                // Only take as many candidates from each head as the tree uses.
+                let mut candidates = Vec::new();
+                for (d, head) in heads.iter().take(depth).enumerate() {
+                    let Some(k) = tree
+                        .iter()
+                        .filter(|p| p.len() == d + 1)
+                        .map(|p| p[d] + 1)
+                        .max()
+                    else {
+                        break;
+                    };
+                    candidates.push(top_k(&head.forward(&hidden_states)?.squeeze(0)?, k)?);
+                }
+                Ok(CandidateTree::from_paths(
+                    root,
+                    tree.iter().map(|p| p.as_slice()),
+                    &candidates,
+                ))
+            }
+            Self::Eagle { layer, max_seq_len } => {
+                let cache = state.eagle_cache.get_or_insert_with(|| {
+                    KvCache::new(2, *max_seq_len, NormalCache::CACHE_GROW_SIZE)
+                });
+                // The features are paired with the tokens sampled from them.
+                let n_features = features.dim(0)?;
+                let input_ids = Tensor::new(&toks[toks.len() - n_features..], features.device())?
+                    .unsqueeze(0)?;
+                let mut hidden_states = layer.forward(
+                    &features.unsqueeze(0)?,
+                    &target.embed_tokens(&input_ids)?,
+                    cache,
+                )?;
+                // The predicted hidden states of the drafts are not kept.
+                let cache_len = cache.current_seq_len();
+                let mut drafts = Vec::new();
+                for i in 0..depth {
+                    let last = hidden_states.i((.., hidden_states.dim(1)? - 1.., ..))?;
+                    let tok = target
+                        .logits_from_hidden_states(&last)?
+                        .flatten_all()?
+                        .argmax(0)?
+                        .to_scalar::<u32>()?;
+                    drafts.push(tok);
+                    if i + 1 < depth {
+                        let input_ids = Tensor::new(&[[tok]], features.device())?;
+                        hidden_states =
+                            layer.forward(&last, &target.embed_tokens(&input_ids)?, cache)?;
+                    }
+                }
+                cache.set_len(cache_len);
+                Ok(CandidateTree::chain(once(root).chain(drafts).collect()))
+            }
+        }
+    }
+}
+
+/// The `k` tokens with the highest logits, highest first.
+#[allow(clippy::cast_possible_truncation)]
+fn top_k(logits: &Tensor, k: usize) -> Result<Vec<u32>> {
+    let logits: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+    let k = k.min(logits.len());
+    let mut toks: Vec<u32> = (0..logits.len() as u32).collect();
+    let by_logit = |a: &u32, b: &u32| logits[*b as usize].total_cmp(&logits[*a as usize]);
+    if k < toks.len() {
+        toks.select_nth_unstable_by(k, by_logit);
+    }
+    toks.truncate(k);
+    toks.sort_by(by_logit);
+    Ok(toks)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::CandidateTree;
+    use crate::layers::CausalMasker;
+
+    #[test]
+    fn tree_from_paths_and_mask() {
+        let paths: &[&[usize]] = &[&[0], &[1], &[0, 0], &[0, 1], &[1, 0], &[2], &[0, 0, 0]];
+        // Only two candidates at depth 1, so [2] is skipped. Nothing is proposed at depth 3.
+        let candidates = vec![vec![10, 11], vec![20, 21]];
+        let tree = CandidateTree::from_paths(5, paths.iter().copied(), &candidates);
+        assert_eq!(tree.tokens, vec![5, 10, 11, 20, 21, 20]);
+        assert_eq!(
+            tree.parents,
+            vec![None, Some(0), Some(0), Some(1), Some(1), Some(2)]
+        );
+        assert_eq!(tree.depth(), 2);
+        assert_eq!(tree.children(1).collect::<Vec<_>>(), vec![3, 4]);
+
+        // Tokens attend to the 2 cached tokens, their ancestors and themselves.
+        let mask = CausalMasker
+            .make_tree_mask(&tree.parents, 2, &Device::Cpu)
+            .unwrap()
+            .to_vec2::<u8>()
+            .unwrap();
+        assert_eq!(
+            mask,
+            vec![
+                vec![0, 0, 0, 1, 1, 1, 1, 1],
+                vec![0, 0, 0, 0, 1, 1, 1, 1],
+                vec![0, 0, 0, 1, 0, 1, 1, 1],
+                vec![0, 0, 0, 0, 1, 0, 1, 1],
+                vec![0, 0, 0, 0, 1, 1, 0, 1],
+                vec![0, 0, 0, 1, 0, 1, 1, 0],
+            ]
+        );
+    }
+}
@@ -17,6 +17,7 @@ use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::{get_chat_template, ChatTemplate, IsqOrganization, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::sampler::DryBreakerTable;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
@@ -364,6 +365,7 @@ impl Loader for VisionLoader {
 
         let max_seq_len = model.max_seq_len();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
+        let dry_breakers = Arc::new(DryBreakerTable::new(&tokenizer)?);
         let num_hidden_layers = match model.cache() {
             EitherCache::Full(full) => full.lock().len(),
             EitherCache::Normal(normal) => normal.lock().unwrap().0.len(),
@@ -379,6 +381,7 @@ impl Loader for VisionLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
+                dry_breakers: Some(dry_breakers),
                 is_xlora: false,
                 num_hidden_layers,
                 eos_tok: eos,
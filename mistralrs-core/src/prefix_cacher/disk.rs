@@ -0,0 +1,442 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use candle_core::{safetensors, DType, Device, Result, Tensor};
+use hf_hub::{Cache, Repo, RepoType};
+use mistralrs_quant::IsqType;
+use tracing::warn;
+
+use crate::pipeline::{KvCacheDType, LayerCaches};
+
+/// Configuration for the persistent prefix cache tier. Caches which are evicted from the device,
+/// and all caches when the engine stops, are also written to `dir`, so that they can be reloaded
+/// after a trie miss or a restart.
+///
+/// Only text models without PagedAttention are supported.
+#[derive(Clone, Debug)]
+pub struct PrefixCacheDiskConfig {
+    /// Directory holding the cache files, created if it does not exist.
+    pub dir: PathBuf,
+    /// Maximum total size of the cache files. The least recently used files are deleted first.
+    pub max_bytes: u64,
+    /// Model revision the weights were loaded from, `main` if unset. This is resolved to a commit
+    /// with the Hugging Face cache, so that caches are not reused once the revision moves.
+    pub revision: Option<String>,
+    /// In-situ quantization applied to the model when loading it.
+    pub isq: Option<IsqType>,
+}
+
+/// Everything the cached values depend on besides the tokens. Caches are only shared by
+/// models with the same key.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ModelKey {
+    pub model_id: String,
+    pub revision: String,
+    pub kind: String,
+    pub isq: Option<IsqType>,
+    pub dtype: DType,
+    pub kv_dtype: KvCacheDType,
+}
+
+impl ModelKey {
+    /// Resolve `revision` to the commit it pointed to when the model was downloaded. Models
+    /// which are not in the Hugging Face cache, such as local ones, keep the revision name.
+    pub fn resolve_revision(model_id: &str, revision: Option<&str>) -> String {
+        let revision = revision.unwrap_or("main");
+        let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, revision.to_string());
+        let ref_path = Cache::default()
+            .path()
+            .join(repo.folder_name())
+            .join("refs")
+            .join(revision);
+        fs::read_to_string(ref_path)
+            .map(|commit| commit.trim().to_string())
+            .unwrap_or_else(|_| revision.to_string())
+    }
+}
+
+impl Display for ModelKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}:{}:{:?}:{:?}:{}",
+            self.model_id, self.revision, self.kind, self.isq, self.dtype, self.kv_dtype
+        )
+    }
+}
+
+const EXTENSION: &str = "safetensors";
+const TOKENS: &str = "tokens";
+const NORMAL: &str = "normal";
+const XLORA: &str = "xlora";
+
+struct DiskEntry {
+    bytes: u64,
+    last_used: SystemTime,
+}
+
+pub(crate) struct DiskPrefixCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    model_key: ModelKey,
+    entries: HashMap<u64, DiskEntry>,
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, continuing from `hash`. Unlike `DefaultHasher` this is stable across Rust releases, so
+/// it can name files.
+fn stable_hash(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl DiskPrefixCache {
+    /// Open the cache directory, indexing the files already present from a previous run.
+    pub fn new(config: PrefixCacheDiskConfig, model_key: ModelKey) -> std::io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut entries = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            let Some(hash) = Self::parse_file_name(&path) else {
+                continue;
+            };
+            let metadata = fs::metadata(&path)?;
+            entries.insert(
+                hash,
+                DiskEntry {
+                    bytes: metadata.len(),
+                    last_used: metadata.modified()?,
+                },
+            );
+        }
+        let mut this = Self {
+            dir: config.dir,
+            max_bytes: config.max_bytes,
+            model_key,
+            entries,
+        };
+        this.enforce_budget();
+        Ok(this)
+    }
+
+    /// The model was requantized, so the caches computed before are no longer valid for it.
+    pub fn set_isq(&mut self, isq: IsqType) {
+        self.model_key.isq = Some(isq);
+    }
+
+    /// The hash of the model key, which the hashes of the tokens continue.
+    fn model_hash(&self) -> u64 {
+        stable_hash(
+            FNV_OFFSET_BASIS,
+            self.model_key.to_string().bytes().chain([0]),
+        )
+    }
+
+    fn key(&self, toks: &[u32]) -> u64 {
+        stable_hash(
+            self.model_hash(),
+            toks.iter().flat_map(|tok| tok.to_le_bytes()),
+        )
+    }
+
+    /// The length of the longest prefix of `toks` which may have a cache file. This is only a hint,
+    /// [`Self::load`] still checks the tokens.
+    pub fn longest_prefix(&self, toks: &[u32]) -> Option<usize> {
+        let mut hash = self.model_hash();
+        let mut longest = None;
+        for (i, tok) in toks.iter().enumerate() {
+            hash = stable_hash(hash, tok.to_le_bytes());
+            if self.entries.contains_key(&hash) {
+                longest = Some(i + 1);
+            }
+        }
+        longest
+    }
+
+    fn path(&self, hash: u64) -> PathBuf {
+        self.dir.join(format!("{hash:016x}.{EXTENSION}"))
+    }
+
+    fn parse_file_name(path: &Path) -> Option<u64> {
+        if path.extension()? != EXTENSION {
+            return None;
+        }
+        u64::from_str_radix(path.file_stem()?.to_str()?, 16).ok()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.bytes).sum()
+    }
+
+    /// Write the caches for a token sequence. The caches must already be on the CPU.
+    pub fn store(
+        &mut self,
+        toks: &[u32],
+        cache: &LayerCaches,
+        xlora_cache: Option<&LayerCaches>,
+    ) -> Result<()> {
+        let hash = self.key(toks);
+        if self.entries.contains_key(&hash) {
+            self.touch(hash);
+            return Ok(());
+        }
+
+        let mut tensors = HashMap::new();
+        tensors.insert(TOKENS.to_string(), Tensor::new(toks, &Device::Cpu)?);
+        Self::insert_layers(&mut tensors, NORMAL, cache)?;
+        if let Some(xlora_cache) = xlora_cache {
+            Self::insert_layers(&mut tensors, XLORA, xlora_cache)?;
+        }
+
+        let path = self.path(hash);
+        safetensors::save(&tensors, &path)?;
+        self.entries.insert(
+            hash,
+            DiskEntry {
+                bytes: fs::metadata(&path)?.len(),
+                last_used: SystemTime::now(),
+            },
+        );
+        self.enforce_budget();
+        Ok(())
+    }
+
+    /// Load the caches stored for exactly this token sequence onto the CPU.
+    pub fn load(&mut self, toks: &[u32]) -> Result<Option<(LayerCaches, Option<LayerCaches>)>> {
+        let hash = self.key(toks);
+        if !self.entries.contains_key(&hash) {
+            return Ok(None);
+        }
+
+        let path = self.path(hash);
+        let tensors = match safetensors::load(&path, &Device::Cpu) {
+            Ok(tensors) => tensors,
+            Err(e) => {
+                warn!(
+                    "Removing unreadable prefix cache file `{}`: {e}",
+                    path.display()
+                );
+                self.remove(hash);
+                return Ok(None);
+            }
+        };
+        // Guard against hash collisions.
+        let stored_toks = tensors
+            .get(TOKENS)
+            .map(|toks| toks.to_vec1::<u32>())
+            .transpose()?;
+        if stored_toks.as_deref() != Some(toks) {
+            return Ok(None);
+        }
+
+        let cache = Self::extract_layers(&tensors, NORMAL)?;
+        let xlora_cache = if tensors.contains_key(&format!("{XLORA}.num_layers")) {
+            Some(Self::extract_layers(&tensors, XLORA)?)
+        } else {
+            None
+        };
+        self.touch(hash);
+        Ok(Some((cache, xlora_cache)))
+    }
+
+    fn insert_layers(
+        tensors: &mut HashMap<String, Tensor>,
+        prefix: &str,
+        cache: &LayerCaches,
+    ) -> Result<()> {
+        tensors.insert(
+            format!("{prefix}.num_layers"),
+            Tensor::new(
+                u32::try_from(cache.len()).map_err(candle_core::Error::wrap)?,
+                &Device::Cpu,
+            )?,
+        );
+        for (i, layer) in cache.iter().enumerate() {
+            if let Some((k, v)) = layer {
+                tensors.insert(format!("{prefix}.{i}.k"), k.clone());
+                tensors.insert(format!("{prefix}.{i}.v"), v.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_layers(tensors: &HashMap<String, Tensor>, prefix: &str) -> Result<LayerCaches> {
+        let num_layers = tensors
+            .get(&format!("{prefix}.num_layers"))
+            .ok_or_else(|| candle_core::Error::Msg(format!("Missing `{prefix}.num_layers`.")))?
+            .to_scalar::<u32>()?;
+        Ok((0..num_layers as usize)
+            .map(|i| {
+                let k = tensors.get(&format!("{prefix}.{i}.k"))?;
+                let v = tensors.get(&format!("{prefix}.{i}.v"))?;
+                Some((k.clone(), v.clone()))
+            })
+            .collect())
+    }
+
+    fn touch(&mut self, hash: u64) {
+        let now = SystemTime::now();
+        if let Some(entry) = self.entries.get_mut(&hash) {
+            entry.last_used = now;
+        }
+        // The modification time is the recency after a restart, so this is best effort.
+        let _ = fs::File::options()
+            .write(true)
+            .open(self.path(hash))
+            .and_then(|file| file.set_modified(now));
+    }
+
+    fn remove(&mut self, hash: u64) {
+        self.entries.remove(&hash);
+        if let Err(e) = fs::remove_file(self.path(hash)) {
+            warn!("Failed to remove prefix cache file: {e}");
+        }
+    }
+
+    fn enforce_budget(&mut self) {
+        let mut total = self.total_bytes();
+        while total > self.max_bytes {
+            let Some((&hash, entry)) = self.entries.iter().min_by_key(|(_, entry)| entry.last_used)
+            else {
+                break;
+            };
+            total -= entry.bytes;
+            self.remove(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use candle_core::{DType, Device, Tensor};
+    use mistralrs_quant::IsqType;
+
+    use super::{DiskPrefixCache, ModelKey, PrefixCacheDiskConfig};
+    use crate::pipeline::{KvCacheDType, LayerCaches};
+
+    fn config(dir: &Path, max_bytes: u64) -> PrefixCacheDiskConfig {
+        PrefixCacheDiskConfig {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            revision: None,
+            isq: None,
+        }
+    }
+
+    fn model_key(model_id: &str) -> ModelKey {
+        ModelKey {
+            model_id: model_id.to_string(),
+            revision: "main".to_string(),
+            kind: "normal".to_string(),
+            isq: None,
+            dtype: DType::BF16,
+            kv_dtype: KvCacheDType::Auto,
+        }
+    }
+
+    fn cache(len: usize) -> LayerCaches {
+        let k = Tensor::ones((1, 2, len, 4), candle_core::DType::F32, &Device::Cpu).unwrap();
+        let v = k.clone();
+        vec![Some((k, v)), None]
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("mistralrs-prefix-cache-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn caches_survive_reopening() {
+        let dir = temp_dir();
+        let mut disk = DiskPrefixCache::new(config(&dir, u64::MAX), model_key("model")).unwrap();
+        disk.store(&[1, 2, 3], &cache(3), None).unwrap();
+        drop(disk);
+
+        let mut disk = DiskPrefixCache::new(config(&dir, u64::MAX), model_key("model")).unwrap();
+        let (loaded, xlora) = disk.load(&[1, 2, 3]).unwrap().unwrap();
+        assert!(xlora.is_none());
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].as_ref().unwrap().0.dims(), &[1, 2, 3, 4]);
+        assert!(loaded[1].is_none());
+        assert!(disk.load(&[1, 2]).unwrap().is_none());
+        assert_eq!(disk.longest_prefix(&[1, 2, 3, 4]), Some(3));
+        assert_eq!(disk.longest_prefix(&[1, 2]), None);
+
+        // Caches of another model are not visible.
+        let mut other = DiskPrefixCache::new(config(&dir, u64::MAX), model_key("other")).unwrap();
+        assert!(other.load(&[1, 2, 3]).unwrap().is_none());
+
+        // Nor are those of the same model at another revision or quantization.
+        let mut moved = DiskPrefixCache::new(
+            config(&dir, u64::MAX),
+            ModelKey {
+                revision: "0123abcd".to_string(),
+                ..model_key("model")
+            },
+        )
+        .unwrap();
+        assert!(moved.load(&[1, 2, 3]).unwrap().is_none());
+        disk.set_isq(IsqType::Q4K);
+        assert!(disk.load(&[1, 2, 3]).unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn longest_prefix_is_found() {
+        let dir = temp_dir();
+        let mut disk = DiskPrefixCache::new(config(&dir, u64::MAX), model_key("model")).unwrap();
+        disk.store(&[1, 2], &cache(2), None).unwrap();
+        disk.store(&[1, 2, 3, 4], &cache(4), None).unwrap();
+        disk.store(&[5, 6, 7], &cache(3), None).unwrap();
+
+        assert_eq!(disk.longest_prefix(&[1, 2, 3]), Some(2));
+        assert_eq!(disk.longest_prefix(&[1, 2, 3, 4, 5]), Some(4));
+        assert_eq!(disk.longest_prefix(&[5, 6]), None);
+        assert_eq!(disk.longest_prefix(&[2, 3, 4]), None);
+        let (loaded, _) = disk.load(&[1, 2, 3, 4]).unwrap().unwrap();
+        assert_eq!(loaded[0].as_ref().unwrap().0.dims(), &[1, 2, 4, 4]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_revisions_are_kept() {
+        assert_eq!(
+            ModelKey::resolve_revision("mistralrs/not-downloaded", Some("v1")),
+            "v1"
+        );
+        assert_eq!(
+            ModelKey::resolve_revision("mistralrs/not-downloaded", None),
+            "main"
+        );
+    }
+
+    #[test]
+    fn least_recently_used_is_removed_over_budget() {
+        let dir = temp_dir();
+        let mut disk = DiskPrefixCache::new(config(&dir, u64::MAX), model_key("model")).unwrap();
+        disk.store(&[1], &cache(8), None).unwrap();
+        let one_file = disk.total_bytes();
+        disk.max_bytes = 2 * one_file;
+
+        disk.store(&[2], &cache(8), None).unwrap();
+        assert!(disk.load(&[1]).unwrap().is_some());
+        disk.store(&[3], &cache(8), None).unwrap();
+
+        assert!(disk.total_bytes() <= 2 * one_file);
+        assert!(disk.load(&[1]).unwrap().is_some());
+        assert!(disk.load(&[2]).unwrap().is_none());
+        assert!(disk.load(&[3]).unwrap().is_some());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::sync::{Arc, Mutex};
+
+use candle_core::{Device, Result, Tensor};
+use mistralrs_quant::IsqType;
+use radix_trie::{Trie, TrieCommon, TrieKey};
+use tracing::warn;
+
+use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
+
+mod disk;
+
+pub use disk::PrefixCacheDiskConfig;
+pub(crate) use disk::{DiskPrefixCache, ModelKey};
+
+#[derive(PartialEq, Eq)]
+struct Tokens(Vec<u32>);
+
+impl TrieKey for Tokens {
+    fn encode_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|x| bytemuck::bytes_of(x).to_vec())
+            .collect::<Vec<u8>>()
+    }
+}
+
+impl From<Vec<u32>> for Tokens {
+    fn from(value: Vec<u32>) -> Self {
+        Self(value)
+    }
+}
+
+type EvictionCacheGroup = (
+    Vec<u32>,
+    Arc<Mutex<LayerCaches>>,
+    Option<Arc<Mutex<LayerCaches>>>,
+);
+
+pub struct PrefixCacheManager {
+    caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
+    xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
+    device: Device,
+    pub n_on_device: usize,
+    no_prefix_cache: bool,
+    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    disk_cache: Option<DiskPrefixCache>,
+}
+
+#[derive(Clone)]
+pub struct MatchingCache {
+    pub normal: LayerCaches,
+    pub xlora: Option<LayerCaches>,
+    pub toks: Vec<u32>,
+}
+
+impl PrefixCacheManager {
+    pub fn new(
+        device: Device,
+        n_on_device: usize,
+        is_xlora: bool,
+        no_prefix_cache: bool,
+        disk_cache: Option<DiskPrefixCache>,
+    ) -> Self {
+        PrefixCacheManager {
+            caches: Trie::new(),
+            xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+            device,
+            n_on_device,
+            no_prefix_cache,
+            eviction_cache_ptrs: Vec::new(),
+            disk_cache,
+        }
+    }
+
+    /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
+    /// some caches will be evicted.
+    ///
+    /// The cache is keyed by the tokens it holds the keys and values of, which are all the tokens of
+    /// the finished sequence but the last one.
+    pub fn add_sequence(&mut self, seq: &mut Sequence) -> Result<()> {
+        // The cache also depends on the adapters, which are not part of the key.
+        if self.no_prefix_cache || seq.get_adapters().is_some() {
+            return Ok(());
+        }
+        let cache = if seq.normal_cache().iter().any(Option::is_some) {
+            // Copy the tokens out of the preallocated buffers, which are appended to in place.
+            seq.normal_cache()
+                .iter()
+                .map(|layer| match layer {
+                    Some(layer) => match (layer.k()?, layer.v()?) {
+                        (Some(k), Some(v)) => Ok(Some((k.copy()?, v.copy()?))),
+                        _ => Ok(None),
+                    },
+                    None => Ok(None),
+                })
+                .collect::<Result<LayerCaches>>()?
+        } else {
+            seq.cache().clone()
+        };
+        let Some(cache_len) = Self::cache_len(&cache)? else {
+            return Ok(());
+        };
+        // A sliding window cache may have dropped the leading tokens.
+        if cache_len >= seq.get_toks().len() {
+            return Ok(());
+        }
+        let toks = seq.get_toks()[..cache_len].to_vec();
+        if self.caches.get(&Tokens(toks.clone())).is_some() {
+            return Ok(());
+        }
+
+        let cache = Arc::new(Mutex::new(cache));
+        self.caches.insert(toks.clone().into(), cache.clone());
+        if seq.is_xlora() {
+            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
+            self.xlora_caches
+                .as_mut()
+                .unwrap()
+                .insert(toks.clone().into(), xlora_cache.clone());
+            self.eviction_cache_ptrs
+                .push((toks, cache, Some(xlora_cache)));
+        } else {
+            self.eviction_cache_ptrs.push((toks, cache, None));
+        }
+        Ok(())
+    }
+
+    /// The number of tokens in a cache, `None` if it has no layers.
+    fn cache_len(cache: &LayerCaches) -> Result<Option<usize>> {
+        cache
+            .iter()
+            .flatten()
+            .next()
+            .map(|(k, _)| k.dim(2))
+            .transpose()
+    }
+
+    /// Whether a cache is on the device rather than on the CPU.
+    fn is_on_device(cache: &LayerCaches) -> bool {
+        cache
+            .iter()
+            .flatten()
+            .next()
+            .is_some_and(|(k, _)| !matches!(k.device(), Device::Cpu))
+    }
+
+    /// The model was requantized, so the caches computed before must not be used for it.
+    pub fn set_isq(&mut self, isq: IsqType) {
+        self.caches = Trie::new();
+        if let Some(xlora_caches) = &mut self.xlora_caches {
+            *xlora_caches = Trie::new();
+        }
+        self.eviction_cache_ptrs.clear();
+        if let Some(disk_cache) = &mut self.disk_cache {
+            disk_cache.set_isq(isq);
+        }
+    }
+
+    /// Write caches which were evicted to the CPU to the disk tier, if there is one.
+    /// Failing to do so is not fatal, the caches are still in CPU memory.
+    fn spill_to_disk(
+        disk_cache: &mut Option<DiskPrefixCache>,
+        toks: &[u32],
+        cache: &LayerCaches,
+        xlora_cache: Option<&LayerCaches>,
+    ) {
+        if let Some(disk_cache) = disk_cache {
+            if let Err(e) = disk_cache.store(toks, cache, xlora_cache) {
+                warn!("Failed to write prefix cache to disk: {e}");
+            }
+        }
+    }
+
+    /// Load a cache from the disk tier into the CPU tier.
+    fn load_from_disk(&mut self, toks: &[u32]) -> Result<()> {
+        let Some(disk_cache) = &mut self.disk_cache else {
+            return Ok(());
+        };
+        let Some((cache, xlora_cache)) = disk_cache.load(toks)? else {
+            return Ok(());
+        };
+        if xlora_cache.is_some() != self.xlora_caches.is_some() {
+            return Ok(());
+        }
+
+        let cache = Arc::new(Mutex::new(cache));
+        self.caches.insert(toks.to_vec().into(), cache.clone());
+        let xlora_cache = xlora_cache.map(|xlora_cache| Arc::new(Mutex::new(xlora_cache)));
+        if let Some(ref xlora_cache) = xlora_cache {
+            self.xlora_caches
+                .as_mut()
+                .unwrap()
+                .insert(toks.to_vec().into(), xlora_cache.clone());
+        }
+        self.eviction_cache_ptrs
+            .push((toks.to_vec(), cache, xlora_cache));
+        Ok(())
+    }
+
+    fn cache_to<'a>(
+        cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
+        device: &Device,
+    ) -> Result<()> {
+        for layer in cache {
+            if let Some((ref q, ref k)) = layer {
+                *layer = Some((q.to_device(device)?, k.to_device(device)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
+    /// the maximum allowed. Returns the number of evicted sequences.
+    pub fn evict_to_cpu(&mut self) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        let n_on_device = self
+            .eviction_cache_ptrs
+            .iter()
+            .filter(|(_, cache, _)| Self::is_on_device(&get_mut_arcmutex!(cache.as_ref())))
+            .count();
+        let mut n_evicted = 0;
+        // Intentionally evict the first ones first, as they are the oldest
+        for (toks, cache, xlora_cache) in &self.eviction_cache_ptrs {
+            if n_on_device - n_evicted <= self.n_on_device {
+                break;
+            }
+            let mut cache = get_mut_arcmutex!(cache);
+            if Self::is_on_device(&cache) {
+                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
+
+                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
+                if let Some(ref mut xlora_cache) = xlora_cache {
+                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
+                }
+                Self::spill_to_disk(&mut self.disk_cache, toks, &cache, xlora_cache.as_deref());
+                n_evicted += 1;
+            }
+        }
+        Ok(self.caches.len().saturating_sub(self.n_on_device))
+    }
+
+    /// Evict all the caches to CPU.
+    pub fn evict_all_to_cpu(&mut self) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        // Intentionally evict the first ones first, as they are the oldest
+        for (toks, cache, xlora_cache) in &self.eviction_cache_ptrs {
+            let mut cache = get_mut_arcmutex!(cache);
+            if Self::is_on_device(&cache) {
+                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
+
+                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
+                if let Some(ref mut xlora_cache) = xlora_cache {
+                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
+                }
+                Self::spill_to_disk(&mut self.disk_cache, toks, &cache, xlora_cache.as_deref());
+            }
+        }
+        Ok(self.caches.len())
+    }
+
+    /// Write all the caches to the disk tier, if there is one, so that they outlive the engine.
+    /// Caches on the CPU were already written when they were evicted from the device.
+    pub fn flush_to_disk(&mut self) -> Result<()> {
+        if self.disk_cache.is_none() {
+            return Ok(());
+        }
+        self.evict_all_to_cpu()?;
+        Ok(())
+    }
+
+    /// Search for the cache of the longest prefix of `toks`, in memory or on disk. The last token
+    /// is never matched, as its logits are needed to sample from.
+    pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
+        if self.no_prefix_cache || toks.len() < 2 {
+            return Ok(None);
+        }
+        let prefix = Tokens(toks[..toks.len() - 1].to_vec());
+
+        let in_memory_len = self.longest_prefix_in_memory(&prefix);
+        if let Some(disk_len) = self
+            .disk_cache
+            .as_ref()
+            .and_then(|disk_cache| disk_cache.longest_prefix(&prefix.0))
+        {
+            if disk_len > in_memory_len {
+                self.load_from_disk(&prefix.0[..disk_len])?;
+            }
+        }
+
+        let len = self.longest_prefix_in_memory(&prefix);
+        if len == 0 {
+            return Ok(None);
+        }
+        let key = Tokens(toks[..len].to_vec());
+        let cache = self.caches.get(&key).expect("Prefix is in the trie.");
+        Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
+        let cache = get_mut_arcmutex!(cache.as_ref()).clone();
+        let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
+            let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&key).unwrap().as_ref());
+            Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
+            Some(xlora_cache.clone())
+        } else {
+            None
+        };
+        Ok(Some(MatchingCache {
+            normal: cache,
+            xlora: xlora_cache,
+            toks: toks[len..].to_vec(),
+        }))
+    }
+
+    fn longest_prefix_in_memory(&self, toks: &Tokens) -> usize {
+        self.caches
+            .get_ancestor(toks)
+            .and_then(|ancestor| ancestor.key().map(|key| key.0.len()))
+            .unwrap_or(0)
+    }
+}
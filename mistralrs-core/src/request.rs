@@ -8,16 +8,19 @@ use crate::{
     response::Response,
     sampler::SamplingParams,
     tools::{Tool, ToolChoice},
-    CustomLogitsProcessor, DiffusionGenerationParams,
+    CustomLogitsProcessor, DiffusionGenerationParams, EmbeddingPooling,
 };
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc, or a JSON schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// A JSON schema, which is compiled to a regex or grammar. A free-form schema such as `{}` or
+    /// `{"type": "object"}` accepts any JSON value or object respectively.
+    JsonSchema(Value),
     None,
 }
 
@@ -43,6 +46,8 @@ pub enum RequestMessage {
     CompletionTokens(Vec<u32>),
     VisionChat {
         images: Vec<image::DynamicImage>,
+        /// The frames of each video.
+        videos: Vec<Vec<image::DynamicImage>>,
         messages: Vec<IndexMap<String, MessageContent>>,
     },
     ImageGeneration {
@@ -50,6 +55,21 @@ pub enum RequestMessage {
         format: ImageGenerationResponseFormat,
         generation_params: DiffusionGenerationParams,
     },
+    /// Like [`RequestMessage::ImageGeneration`], but starting from `image` instead of noise. If
+    /// there is a `mask`, only the areas it marks are changed.
+    ImageEdit {
+        prompt: String,
+        image: image::DynamicImage,
+        mask: Option<image::DynamicImage>,
+        format: ImageGenerationResponseFormat,
+        generation_params: DiffusionGenerationParams,
+    },
+    /// Compute one embedding per input. This runs a forward pass without sampling, and is
+    /// handled immediately instead of being scheduled.
+    Embedding {
+        inputs: Vec<String>,
+        pooling: EmbeddingPooling,
+    },
 }
 
 #[derive(Clone)]
@@ -71,6 +91,8 @@ pub enum RequestMessage {
 ///     3) Apply temperature and softmax
 ///     4) Sample the next token (topk, topp, minp, etc)
 /// - `return_raw_logits`: Return raw logits.
+/// - `priority`: Scheduling priority. Higher values are scheduled first by priority-aware policies.
+/// - `tenant`: Tenant this request is accounted to (for example, an API key) for fair scheduling.
 pub struct NormalRequest {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
@@ -85,6 +107,8 @@ pub struct NormalRequest {
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
     pub return_raw_logits: bool,
+    pub priority: i32,
+    pub tenant: Option<String>,
 }
 
 impl NormalRequest {
@@ -110,6 +134,8 @@ impl NormalRequest {
             adapters: None,
             logits_processors: None,
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         }
     }
 }
@@ -133,6 +159,25 @@ pub struct DetokenizationRequest {
     pub response: Sender<anyhow::Result<String>>,
 }
 
+#[derive(Clone)]
+/// Request to register the PEFT adapter saved in the local directory `path` as `name`. The
+/// directory must contain `adapter_config.json` and `adapter_model.safetensors`. The adapter can
+/// then be selected with the `adapters` of a [`NormalRequest`] or activated. The response is the
+/// number of layers the adapter applies to.
+pub struct AdapterLoadRequest {
+    pub name: String,
+    pub path: PathBuf,
+    pub response: Sender<anyhow::Result<usize>>,
+}
+
+#[derive(Clone)]
+/// Request to remove a registered adapter. This fails while the adapter is active or used by a
+/// running or waiting request.
+pub struct AdapterUnloadRequest {
+    pub name: String,
+    pub response: Sender<anyhow::Result<usize>>,
+}
+
 #[derive(Clone)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mpsc` response `Sender` used to return the [`Response`].
@@ -140,8 +185,13 @@ pub enum Request {
     Normal(NormalRequest),
     ReIsq(IsqType),
     ActivateAdapters(Vec<String>),
+    LoadAdapter(AdapterLoadRequest),
+    UnloadAdapter(AdapterUnloadRequest),
     Tokenize(TokenizationRequest),
     Detokenize(DetokenizationRequest),
+    /// Cancel all sequences belonging to the [`NormalRequest`] with this ID. Any KV cache
+    /// (or PagedAttention blocks) held by the sequences is freed and the response channel is closed.
+    Cancel(usize),
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -166,6 +216,17 @@ impl Debug for Request {
             Request::ActivateAdapters(adapters) => {
                 write!(f, "Activate Adapters Request {adapters:?}",)
             }
+            Request::LoadAdapter(req) => {
+                write!(
+                    f,
+                    "Load Adapter Request {} from {}",
+                    req.name,
+                    req.path.display()
+                )
+            }
+            Request::UnloadAdapter(req) => {
+                write!(f, "Unload Adapter Request {}", req.name)
+            }
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
@@ -175,6 +236,7 @@ impl Debug for Request {
             Request::Detokenize(req) => {
                 write!(f, "Tokenization Request {:?}", req.tokens)
             }
+            Request::Cancel(id) => write!(f, "Cancel Request {id}"),
             Request::Terminate => write!(f, "Termination Request"),
         }
     }
@@ -8,7 +8,10 @@ use candle_core::Tensor;
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::TopLogprob,
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -43,6 +46,8 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 generate_repr!(Delta);
@@ -123,6 +128,12 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Draft tokens proposed by speculative decoding, if used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draft_tokens: Option<usize>,
+    /// Draft tokens accepted by the target model, if speculative decoding was used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_draft_tokens: Option<usize>,
 }
 
 generate_repr!(Usage);
@@ -222,6 +233,41 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// One embedding of an embedding request.
+pub struct EmbeddingData {
+    pub object: String,
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+generate_repr!(EmbeddingData);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+generate_repr!(EmbeddingUsage);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// An OpenAI compatible embedding response.
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+generate_repr!(EmbeddingResponse);
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
@@ -239,6 +285,8 @@ pub enum Response {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embeddings
+    Embeddings(EmbeddingResponse),
     // Raw
     Raw {
         logits_chunks: Vec<Tensor>,
@@ -256,6 +304,8 @@ pub enum ResponseOk {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embeddings
+    Embeddings(EmbeddingResponse),
     // Raw
     Raw {
         logits_chunks: Vec<Tensor>,
@@ -323,6 +373,7 @@ impl Response {
                 Err(Box::new(ResponseErr::CompletionModelError(e, x)))
             }
             Self::ImageGeneration(x) => Ok(ResponseOk::ImageGeneration(x)),
+            Self::Embeddings(x) => Ok(ResponseOk::Embeddings(x)),
             Self::Raw {
                 logits_chunks,
                 tokens,
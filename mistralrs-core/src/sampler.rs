@@ -11,11 +11,17 @@ use candle_core::{Device, Error, Result, Tensor, D};
 use pyo3::pyclass;
 
 use once_cell::sync::Lazy;
-use rand::distributions::{Distribution, WeightedIndex};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng, SeedableRng,
+};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
+use tracing::warn;
+
+use crate::aici::bintokens::ByteTokenizer;
 
 static DRY_SEQUENCE_BREAKERS: Lazy<Vec<String>> =
     Lazy::new(|| ["\n", ":", "\"", "*"].map(String::from).to_vec());
@@ -42,6 +48,18 @@ pub struct SamplingParams {
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    pub beam_search: Option<BeamSearchParams>,
+    pub typical_p: Option<f64>,
+    pub top_a: Option<f64>,
+    pub tfs_z: Option<f64>,
+    pub mirostat: Option<MirostatParams>,
+    pub xtc: Option<XtcParams>,
+    pub dynatemp: Option<DynamicTemperatureParams>,
+    pub repetition_penalty: Option<f32>,
+    pub penalty_range: Option<PenaltyRange>,
+    /// Seed the RNG of the sequences, so that their output does not depend on the other
+    /// requests. Sequence `i` of a request is seeded with `seed + i`.
+    pub seed: Option<u64>,
 }
 
 impl SamplingParams {
@@ -63,10 +81,183 @@ impl SamplingParams {
             logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            beam_search: None,
+            typical_p: None,
+            top_a: None,
+            tfs_z: None,
+            mirostat: None,
+            xtc: None,
+            dynatemp: None,
+            repetition_penalty: None,
+            penalty_range: None,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Beam search keeps the `beam_width` most likely sequences at every step instead of sampling.
+/// The best `n_choices` finished sequences are returned, so `n_choices` may be at most
+/// `beam_width`. Temperature, top-k, top-p and min-p do not apply to beam search.
+pub struct BeamSearchParams {
+    pub beam_width: usize,
+    /// Finished sequences are ranked by their cumulative logprob divided by
+    /// `length^length_penalty`. Values above 0 favor longer sequences.
+    pub length_penalty: f32,
+    /// Stop as soon as `beam_width` sequences are finished, instead of when no running beam can
+    /// score better than them.
+    pub early_stopping: bool,
+}
+
+impl BeamSearchParams {
+    pub fn new(beam_width: usize) -> Self {
+        Self {
+            beam_width,
+            length_penalty: 1.0,
+            early_stopping: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MirostatVersion {
+    V1,
+    V2,
+}
+
+#[derive(Clone, Debug)]
+/// [Mirostat](https://arxiv.org/abs/2007.14966) sampling targets a constant surprise `tau`,
+/// learning at rate `eta`. It replaces top-k, top-p, min-p and the other truncation samplers.
+pub struct MirostatParams {
+    pub version: MirostatVersion,
+    pub tau: f32,
+    pub eta: f32,
+}
+
+impl MirostatParams {
+    /// `version` is 1 or 2, as in llama.cpp.
+    pub fn new_with_defaults(
+        version: usize,
+        tau: Option<f32>,
+        eta: Option<f32>,
+    ) -> anyhow::Result<Self> {
+        let version = match version {
+            1 => MirostatVersion::V1,
+            2 => MirostatVersion::V2,
+            other => anyhow::bail!("Mirostat version must be 1 or 2, got {other}."),
+        };
+        Ok(Self {
+            version,
+            tau: tau.unwrap_or(5.0),
+            eta: eta.unwrap_or(0.1),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+/// XTC ("exclude top choices") removes, with probability `probability`, all tokens with a
+/// probability of at least `threshold` except the least likely of them.
+pub struct XtcParams {
+    pub threshold: f32,
+    pub probability: f32,
+}
+
+impl XtcParams {
+    pub fn new_with_defaults(probability: f32, threshold: Option<f32>) -> Self {
+        Self {
+            threshold: threshold.unwrap_or(0.1),
+            probability,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Dynamic temperature scales the temperature within `temperature ± range` by the normalized
+/// entropy of the distribution, raised to `exponent`.
+pub struct DynamicTemperatureParams {
+    pub range: f64,
+    pub exponent: f64,
+}
+
+impl DynamicTemperatureParams {
+    pub fn new_with_defaults(range: f64, exponent: Option<f64>) -> Self {
+        Self {
+            range,
+            exponent: exponent.unwrap_or(1.0),
         }
     }
 }
 
+#[derive(Clone, Debug, Default)]
+/// Restricts the repetition, frequency and presence penalties to part of the context, as
+/// `penalty_last_n` does in llama.cpp.
+pub struct PenaltyRange {
+    /// Only the last `last_n` tokens of the context are penalized. If `None`, all are.
+    pub last_n: Option<usize>,
+    /// Do not penalize the tokens of the prompt.
+    pub exclude_prompt: bool,
+    /// Tokens which are never penalized, by any penalty including DRY.
+    pub whitelist: Vec<u32>,
+}
+
+/// The request fields of the HTTP and Python APIs which select the Mirostat, XTC and dynamic
+/// temperature samplers and the penalty range. Unset values take their defaults.
+#[derive(Clone, Debug, Default)]
+pub struct ExtendedSamplingFields {
+    /// Mirostat version, 1 or 2. 0 disables Mirostat.
+    pub mirostat: Option<usize>,
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+    pub xtc_probability: Option<f32>,
+    pub xtc_threshold: Option<f32>,
+    pub dynatemp_range: Option<f64>,
+    pub dynatemp_exponent: Option<f64>,
+    pub penalty_last_n: Option<usize>,
+    pub penalty_exclude_prompt: bool,
+    pub penalty_whitelist: Option<Vec<u32>>,
+}
+
+/// The samplers selected by [`ExtendedSamplingFields`], as set in [`SamplingParams`].
+#[derive(Clone, Debug, Default)]
+pub struct ExtendedSamplers {
+    pub mirostat: Option<MirostatParams>,
+    pub xtc: Option<XtcParams>,
+    pub dynatemp: Option<DynamicTemperatureParams>,
+    pub penalty_range: Option<PenaltyRange>,
+}
+
+impl ExtendedSamplers {
+    pub fn new(fields: ExtendedSamplingFields) -> anyhow::Result<Self> {
+        let mirostat = fields
+            .mirostat
+            .filter(|version| *version != 0)
+            .map(|version| {
+                MirostatParams::new_with_defaults(version, fields.mirostat_tau, fields.mirostat_eta)
+            })
+            .transpose()?;
+        let xtc = fields
+            .xtc_probability
+            .map(|probability| XtcParams::new_with_defaults(probability, fields.xtc_threshold));
+        let dynatemp = fields.dynatemp_range.map(|range| {
+            DynamicTemperatureParams::new_with_defaults(range, fields.dynatemp_exponent)
+        });
+        let penalty_range = (fields.penalty_last_n.is_some()
+            || fields.penalty_exclude_prompt
+            || fields.penalty_whitelist.is_some())
+        .then(|| PenaltyRange {
+            last_n: fields.penalty_last_n,
+            exclude_prompt: fields.penalty_exclude_prompt,
+            whitelist: fields.penalty_whitelist.unwrap_or_default(),
+        });
+        Ok(Self {
+            mirostat,
+            xtc,
+            dynatemp,
+            penalty_range,
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DrySamplingParams {
     pub sequence_breakers: Vec<String>,
@@ -104,47 +295,167 @@ impl Default for DrySamplingParams {
 
 #[derive(Clone, Debug)]
 struct DrySamplingParamsInner {
-    pub sequence_breakers: HashSet<u32>,
+    /// The sequence breakers, by the token they start with. A breaker may start within the
+    /// token and continue with the listed tokens, which are empty if the token contains it all.
+    pub sequence_breakers: HashMap<u32, Vec<Vec<u32>>>,
     pub multiplier: f32,
     pub base: f32,
     pub allowed_length: usize,
 }
 
 impl DrySamplingParamsInner {
-    pub fn from(other: DrySamplingParams, tokenizer: &Tokenizer) -> anyhow::Result<Self> {
+    pub fn from(other: DrySamplingParams, breakers: &DryBreakerTable) -> anyhow::Result<Self> {
+        let mut sequence_breakers: HashMap<u32, Vec<Vec<u32>>> = HashMap::new();
+        for breaker in other.sequence_breakers.iter().filter(|x| !x.is_empty()) {
+            for (head, tail) in breakers.token_sequences(breaker)?.iter() {
+                let tails = sequence_breakers.entry(*head).or_default();
+                if !tails.contains(tail) {
+                    tails.push(tail.clone());
+                }
+            }
+        }
         Ok(Self {
             base: other.base,
             allowed_length: other.allowed_length,
-            sequence_breakers: HashSet::from_iter(
-                other
-                    .sequence_breakers
-                    .into_iter()
-                    .map(|breaker| {
-                        tokenizer
-                            // Prefix with 'a' to get the correct encoding of the token at the end of a text.
-                            //
-                            // FIXME: This is a hack. See https://github.com/LostRuins/koboldcpp/pull/982
-                            //        for the correct solution which covers multi-token sequence breakers
-                            //        and ambiguous encodings.
-                            .encode(["a", &breaker].concat(), true)
-                            .map_err(anyhow::Error::msg)
-                            .map(|enc| {
-                                let ids = enc.get_ids();
-                                if !ids.is_empty() {
-                                    None
-                                } else {
-                                    Some(ids[ids.len() - 1])
-                                }
-                            })
-                    })
-                    .collect::<anyhow::Result<Vec<_>>>()?
-                    .into_iter()
-                    .flatten()
-                    .collect::<Vec<_>>(),
-            ),
+            sequence_breakers,
             multiplier: other.multiplier,
         })
     }
+
+    /// Whether the token contains a whole sequence breaker.
+    fn is_breaker_token(&self, tok: u32) -> bool {
+        self.sequence_breakers
+            .get(&tok)
+            .is_some_and(|tails| tails.iter().any(Vec::is_empty))
+    }
+
+    /// The number of tokens at the end of the context which follow the last sequence breaker.
+    fn tokens_since_breaker(&self, context: &[u32]) -> usize {
+        for since in 0..context.len() {
+            let pos = context.len() - 1 - since;
+            let Some(tails) = self.sequence_breakers.get(&context[pos]) else {
+                continue;
+            };
+            let longest_tail = tails
+                .iter()
+                .filter(|tail| {
+                    tail.len() <= since && context[pos + 1..=pos + tail.len()] == tail[..]
+                })
+                .map(Vec::len)
+                .max();
+            if let Some(longest_tail) = longest_tail {
+                return since - longest_tail;
+            }
+        }
+        context.len()
+    }
+}
+
+/// How the sequence breakers of DRY are tokenized, built once per pipeline. The default breakers
+/// are looked up when the table is built, other breakers when a request uses them.
+pub struct DryBreakerTable {
+    /// The tokenizer without the prepended space, so the rest of a breaker is tokenized as it
+    /// follows the token it starts in.
+    tokenizer: Tokenizer,
+    /// The bytes of every token, or `None` if the tokenizer does not allow recovering them.
+    token_bytes: Option<Vec<Vec<u8>>>,
+    defaults: HashMap<String, Arc<Vec<(u32, Vec<u32>)>>>,
+}
+
+impl DryBreakerTable {
+    pub fn new(tokenizer: &Tokenizer) -> anyhow::Result<Self> {
+        let (tokenizer, token_bytes) = match ByteTokenizer::from_tokenizer(tokenizer.clone()) {
+            Ok(byte_tokenizer) => {
+                let token_bytes = byte_tokenizer.token_bytes();
+                (byte_tokenizer.hf_tokenizer, Some(token_bytes))
+            }
+            Err(e) => {
+                warn!("DRY sequence breakers only match how they are tokenized on their own: {e}");
+                (tokenizer.clone(), None)
+            }
+        };
+        let mut table = Self {
+            tokenizer,
+            token_bytes,
+            defaults: HashMap::new(),
+        };
+        for breaker in DRY_SEQUENCE_BREAKERS.iter() {
+            let sequences = Arc::new(table.find_token_sequences(breaker)?);
+            table.defaults.insert(breaker.clone(), sequences);
+        }
+        Ok(table)
+    }
+
+    fn token_sequences(&self, breaker: &str) -> anyhow::Result<Arc<Vec<(u32, Vec<u32>)>>> {
+        match self.defaults.get(breaker) {
+            Some(sequences) => Ok(sequences.clone()),
+            None => Ok(Arc::new(self.find_token_sequences(breaker)?)),
+        }
+    }
+
+    /// The tokens a sequence breaker can start in, with the tokens of the rest of it.
+    fn find_token_sequences(&self, breaker: &str) -> anyhow::Result<Vec<(u32, Vec<u32>)>> {
+        match &self.token_bytes {
+            Some(token_bytes) => breaker_token_sequences(breaker, token_bytes, &self.tokenizer),
+            None => {
+                let ids = self
+                    .tokenizer
+                    .encode(breaker, false)
+                    .map_err(anyhow::Error::msg)?
+                    .get_ids()
+                    .to_vec();
+                Ok(ids
+                    .split_first()
+                    .map(|(head, tail)| (*head, tail.to_vec()))
+                    .into_iter()
+                    .collect())
+            }
+        }
+    }
+}
+
+/// All the ways a sequence breaker can be tokenized, as in koboldcpp: the tokens which contain
+/// it, and the tokens ending with a prefix of it, followed by the tokens of the rest of it.
+fn breaker_token_sequences(
+    breaker: &str,
+    token_bytes: &[Vec<u8>],
+    tokenizer: &Tokenizer,
+) -> anyhow::Result<Vec<(u32, Vec<u32>)>> {
+    let breaker = breaker.as_bytes();
+    // The tails only depend on how much of the breaker the token overlaps.
+    let mut tails: HashMap<usize, Vec<u32>> = HashMap::new();
+    let mut sequences = Vec::new();
+    for (tok, bytes) in token_bytes.iter().enumerate() {
+        // Special tokens have no bytes
+        if bytes.is_empty() {
+            continue;
+        }
+        if bytes.windows(breaker.len()).any(|window| window == breaker) {
+            sequences.push((tok as u32, Vec::new()));
+            continue;
+        }
+        let Some(overlap) = (1..breaker.len().min(bytes.len() + 1))
+            .rev()
+            .find(|n| bytes.ends_with(&breaker[..*n]))
+        else {
+            continue;
+        };
+        let tail = match tails.get(&overlap) {
+            Some(tail) => tail.clone(),
+            None => {
+                let rest = String::from_utf8_lossy(&breaker[overlap..]).to_string();
+                let tail = tokenizer
+                    .encode(rest, false)
+                    .map_err(anyhow::Error::msg)?
+                    .get_ids()
+                    .to_vec();
+                tails.insert(overlap, tail.clone());
+                tail
+            }
+        };
+        sequences.push((tok as u32, tail));
+    }
+    Ok(sequences)
 }
 
 /// Customizable logits processor.
@@ -190,6 +501,40 @@ pub struct Sampler {
     top_p: f64,
     min_p: f64,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    typical_p: Option<f32>,
+    top_a: Option<f32>,
+    tfs_z: Option<f32>,
+    mirostat: Option<(MirostatParams, MirostatMu)>,
+    xtc: Option<XtcParams>,
+    dynatemp: Option<DynamicTemperatureParams>,
+    repetition_penalty: Option<f32>,
+    penalty_range: PenaltyRange,
+    prompt_len: usize,
+    rng: Option<SequenceRng>,
+}
+
+/// The running maximum surprise μ of Mirostat. Every sequence has its own copy of the sampler,
+/// so cloning copies μ rather than sharing it.
+struct MirostatMu(Mutex<f32>);
+
+impl Clone for MirostatMu {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(
+            *self.0.lock().expect("could not lock mirostat mutex"),
+        ))
+    }
+}
+
+/// The RNG of a seeded sequence, used instead of the one shared by all sequences. Like μ, it is
+/// copied rather than shared when the sampler is cloned.
+struct SequenceRng(Arc<Mutex<Isaac64Rng>>);
+
+impl Clone for SequenceRng {
+    fn clone(&self) -> Self {
+        Self(Arc::new(Mutex::new(
+            self.0.lock().expect("could not lock rng mutex").clone(),
+        )))
+    }
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -215,47 +560,73 @@ fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
 }
 
 impl Sampler {
-    #[allow(clippy::too_many_arguments)]
+    /// A sampler for `params`. Without a temperature, the most likely token is chosen. The stop
+    /// tokens, maximum length, number of choices, beam search and seed are not used. DRY is only
+    /// applied with the breaker table of the pipeline.
     pub fn new(
-        temperature: Option<f64>,
-        top_n_logprobs: usize,
+        params: &SamplingParams,
         tokenizer: Option<Arc<Tokenizer>>,
-        frequency_penalty: Option<f32>,
-        presence_penalty: Option<f32>,
-        dry_params: Option<DrySamplingParams>,
-        top_k: i64,
-        top_p: f64,
-        min_p: f64,
+        dry_breakers: Option<&DryBreakerTable>,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
-        let temperature = if temperature.map_or(true, |v| v < 1e-7) {
-            None
-        } else {
-            temperature
-        };
-        let dry_params = if let Some(ref tokenizer) = tokenizer {
-            dry_params.map(|params| DrySamplingParamsInner::from(params, tokenizer))
-        } else {
-            None
-        };
-        let dry_params = match dry_params {
-            Some(fallible) => Some(fallible?),
-            None => None,
+        if params
+            .repetition_penalty
+            .is_some_and(|penalty| penalty <= 0.)
+        {
+            anyhow::bail!("Repetition penalty must be positive.");
+        }
+        let temperature = params.temperature.filter(|v| *v >= 1e-7);
+        let dry_params = match (dry_breakers, &params.dry_params) {
+            (Some(dry_breakers), Some(dry_params)) => Some(DrySamplingParamsInner::from(
+                dry_params.clone(),
+                dry_breakers,
+            )?),
+            _ => None,
         };
         Ok(Self {
             temperature,
-            top_n_logprobs,
+            top_n_logprobs: params.top_n_logprobs,
             tokenizer,
-            frequency_penalty,
-            presence_penalty,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
             dry_params,
-            top_k,
-            top_p,
-            min_p,
+            top_k: params.top_k.map_or(-1, |k| k as i64),
+            top_p: params.top_p.unwrap_or(1.0),
+            min_p: params.min_p.unwrap_or(0.0),
             logits_processors,
+            typical_p: params.typical_p.map(|x| x as f32),
+            top_a: params.top_a.map(|x| x as f32),
+            tfs_z: params.tfs_z.map(|x| x as f32),
+            mirostat: params.mirostat.clone().map(|params| {
+                let mu = MirostatMu(Mutex::new(2. * params.tau));
+                (params, mu)
+            }),
+            xtc: params.xtc.clone(),
+            dynatemp: params.dynatemp.clone(),
+            repetition_penalty: params.repetition_penalty,
+            penalty_range: params.penalty_range.clone().unwrap_or_default(),
+            prompt_len: 0,
+            rng: None,
         })
     }
 
+    /// Set the length of the prompt, which is not penalized if the penalty range excludes it.
+    pub(crate) fn set_prompt_len(&mut self, prompt_len: usize) {
+        self.prompt_len = prompt_len;
+    }
+
+    /// Sample from an RNG seeded with `seed` instead of the RNG shared by all sequences.
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.rng = Some(SequenceRng(Arc::new(Mutex::new(
+            Isaac64Rng::seed_from_u64(seed),
+        ))));
+    }
+
+    /// Like [`Sampler::set_seed`], for the choice `response_index` of a request seeded with `seed`.
+    pub(crate) fn set_choice_seed(&mut self, seed: u64, response_index: usize) {
+        self.set_seed(choice_seed(seed, response_index));
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -482,41 +853,53 @@ impl Sampler {
             }
         }
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
+        if top_p > 0.0 && top_p < 1.0 {
+            // TOP P
 
-        // TOP P
+            // top-p sampling (or "nucleus sampling") samples from the smallest set of
+            // tokens that exceed probability top_p. This way we never sample tokens that
+            // have very low probabilities and are less likely to go "off the rails".
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
+            // Clamp smaller probabilities to zero.
+            let mut cumsum = 0.;
+            for index in &argsort_indices {
+                if cumsum >= top_p {
+                    probs[*index] = 0.0;
+                } else {
+                    cumsum += probs[*index];
+                }
             }
         }
 
-        if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
+        if min_p > 0.0 && min_p < 1.0 {
+            let max_p = probs[argsort_indices[0]];
 
-        let max_p = probs[argsort_indices[0]];
+            // MIN P
 
-        // MIN P
+            // min-p sampling samples from the tokens whose prob are greater than
+            // (max prob of token in dist) * min_p
 
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
+            // Clamp smaller probabilities to zero.
+            for index in &argsort_indices {
+                if max_p * min_p >= probs[*index] {
+                    probs[*index] = 0.0;
+                }
+            }
+        }
 
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
-                probs[*index] = 0.0;
+        if let Some(typical_p) = self.typical_p {
+            apply_typical_p(probs, &argsort_indices, typical_p);
+        }
+        if let Some(tfs_z) = self.tfs_z {
+            apply_tail_free(probs, &argsort_indices, tfs_z);
+        }
+        if let Some(top_a) = self.top_a {
+            apply_top_a(probs, &argsort_indices, top_a);
+        }
+        if let Some(xtc) = &self.xtc {
+            let roll: f32 = rng.lock().expect("could not lock rng mutex").gen();
+            if roll < xtc.probability {
+                apply_xtc(probs, &argsort_indices, xtc.threshold);
             }
         }
 
@@ -524,21 +907,131 @@ impl Sampler {
         self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
     }
 
+    /// Mirostat sampling, see [`MirostatParams`]. This updates μ with the surprise of the sampled
+    /// token.
+    fn sample_mirostat(
+        &self,
+        probs: &mut Vec<f32>,
+        params: &MirostatParams,
+        mu: &MirostatMu,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        let mut mu = mu.0.lock().expect("could not lock mirostat mutex");
+        let keep = match params.version {
+            MirostatVersion::V1 => mirostat_v1_k(probs, &argsort_indices, *mu),
+            // Keep the tokens with a surprise of at most μ, and at least the most likely one.
+            MirostatVersion::V2 => argsort_indices
+                .iter()
+                .take_while(|&&i| -probs[i].log2() <= *mu)
+                .count()
+                .max(1),
+        };
+        for index in &argsort_indices[keep..] {
+            probs[*index] = 0.0;
+        }
+        let total: f32 = argsort_indices[..keep].iter().map(|&i| probs[i]).sum();
+
+        let sampled = self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)?;
+        let surprise = -(probs[sampled.token as usize] / total).log2();
+        *mu -= params.eta * (surprise - params.tau);
+        Ok(sampled)
+    }
+
+    /// Scale the temperature by the normalized entropy of the distribution.
+    fn dynamic_temperature(&self, logits: &Tensor, temperature: f64) -> Result<f64> {
+        let Some(params) = &self.dynatemp else {
+            return Ok(temperature);
+        };
+        if params.range <= 0.0 {
+            return Ok(temperature);
+        }
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(logits)?.to_vec1()?;
+        if probs.len() <= 1 {
+            return Ok(temperature);
+        }
+        let entropy: f32 = probs
+            .iter()
+            .filter(|&&p| p > 0.0)
+            .map(|&p| -p * p.ln())
+            .sum();
+        let normalized_entropy = f64::from(entropy) / (probs.len() as f64).ln();
+        let min_temp = (temperature - params.range).max(0.0);
+        let max_temp = temperature + params.range;
+        let temperature =
+            min_temp + (max_temp - min_temp) * normalized_entropy.powf(params.exponent);
+        // A temperature of 0 is greedy
+        Ok(temperature.max(1e-7))
+    }
+
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
         if context.is_empty() {
             candle_core::bail!("Penalty context is empty, this should not happen.");
         }
 
+        let exempt = self
+            .penalty_range
+            .whitelist
+            .iter()
+            .filter(|tok| (**tok as usize) < logits.len())
+            .map(|tok| (*tok, logits[*tok as usize]))
+            .collect::<Vec<_>>();
+
         // Dry penalty
         self.apply_dry_penalty(&mut logits, context)?;
 
+        let penalized = self.penalized_context(context);
+
         // Frequency and Presence penalty
-        self.apply_freq_presc_penalty(&mut logits, context)?;
+        self.apply_freq_presc_penalty(&mut logits, penalized)?;
+
+        // Repetition penalty
+        self.apply_repetition_penalty(&mut logits, penalized)?;
+
+        for (tok, logit) in exempt {
+            logits[tok as usize] = logit;
+        }
 
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    /// The part of the context which the repetition, frequency and presence penalties apply to.
+    fn penalized_context<'a>(&self, context: &'a [u32]) -> &'a [u32] {
+        let mut start = 0;
+        if self.penalty_range.exclude_prompt {
+            start = self.prompt_len.min(context.len());
+        }
+        if let Some(last_n) = self.penalty_range.last_n {
+            start = start.max(context.len().saturating_sub(last_n));
+        }
+        &context[start..]
+    }
+
+    fn apply_repetition_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        if let Some(penalty) = self.repetition_penalty {
+            // Like llama.cpp, every token which occurred is penalized once, whatever its count.
+            let occurred = context.iter().copied().collect::<HashSet<_>>();
+            for tok in occurred {
+                // Llama 3.2 uses a hack triggering this error... we wouldn't want a weight on it anyway
+                if tok as usize >= logits.len() {
+                    continue;
+                }
+                let logit = &mut logits[tok as usize];
+                *logit = if *logit > 0. {
+                    *logit / penalty
+                } else {
+                    *logit * penalty
+                };
+            }
+        }
+        Ok(())
+    }
+
     fn apply_freq_presc_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
         if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
             let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
@@ -567,6 +1060,12 @@ impl Sampler {
 
     fn apply_dry_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
         if let Some(ref params) = self.dry_params {
+            // Repetitions may not extend back past a sequence breaker.
+            let max_match_length = params.tokens_since_breaker(context);
+            if max_match_length == 0 || max_match_length < params.allowed_length {
+                return Ok(());
+            }
+
             let match_indices = context
                 .par_iter()
                 .enumerate()
@@ -580,7 +1079,7 @@ impl Sampler {
             for i in match_indices {
                 let next_token = context[i + 1];
 
-                if params.sequence_breakers.contains(&next_token) {
+                if params.is_breaker_token(next_token) {
                     continue;
                 }
 
@@ -588,6 +1087,11 @@ impl Sampler {
 
                 // Limit match length to avoid quadratic runtime and potential DoS with adversarial inputs.
                 while match_length < 50 {
+                    if match_length >= max_match_length {
+                        // Seq breaker reached
+                        break;
+                    }
+
                     if match_length > i {
                         // Start of input
                         break;
@@ -601,11 +1105,6 @@ impl Sampler {
                         break;
                     }
 
-                    if params.sequence_breakers.contains(&prev_tok) {
-                        // Seq breaking tok reached
-                        break;
-                    }
-
                     match_length += 1;
                 }
 
@@ -633,6 +1132,52 @@ impl Sampler {
         Ok(())
     }
 
+    /// The `n` most likely next tokens for beam search, most likely first. Penalties and logits
+    /// processors are applied, but not the temperature or the top-k/top-p/min-p filters.
+    pub fn beam_candidates(
+        &self,
+        logits: Tensor,
+        context: &[u32],
+        n: usize,
+        return_logprobs: bool,
+    ) -> Result<Vec<Logprobs>> {
+        let logits = logits.to_vec1()?;
+        let mut logits = self.apply_penalties(logits, context)?;
+        for processor in &self.logits_processors {
+            logits = processor.apply(&logits, context)?;
+        }
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        let top_logprobs = if return_logprobs {
+            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+        } else {
+            None
+        };
+        argsort_indices
+            .into_iter()
+            .take(n)
+            .map(|token| {
+                let bytes = match &self.tokenizer {
+                    Some(tokenizer) => Some(
+                        tokenizer
+                            .decode(&[token as u32], false)
+                            .map_err(|x| Error::Msg(x.to_string()))?,
+                    ),
+                    None => None,
+                };
+                Ok(Logprobs {
+                    token: token as u32,
+                    logprob: probs[token].log(10.0),
+                    bytes,
+                    top_logprobs: top_logprobs.clone(),
+                })
+            })
+            .collect()
+    }
+
     /// Sample the provided tokens.
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
@@ -645,6 +1190,10 @@ impl Sampler {
         rng: Arc<Mutex<Isaac64Rng>>,
         sample_speculative: bool,
     ) -> Result<Logprobs> {
+        let rng = match &self.rng {
+            Some(SequenceRng(own_rng)) => own_rng.clone(),
+            None => rng,
+        };
         let logits = logits.to_vec1()?;
         let mut logits = self.apply_penalties(logits, context)?;
         for processor in &self.logits_processors {
@@ -676,18 +1225,24 @@ impl Sampler {
             match self.temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
                 Some(temperature) => {
+                    let temperature = self.dynamic_temperature(&logits, temperature)?;
                     let logits = (&logits / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = probs.to_vec1()?;
 
-                    self.sample_top_kp_min_p(
-                        &mut probs,
-                        self.top_k,
-                        self.top_p as f32,
-                        self.min_p as f32,
-                        return_logprobs,
-                        rng,
-                    )?
+                    match &self.mirostat {
+                        Some((params, mu)) => {
+                            self.sample_mirostat(&mut probs, params, mu, return_logprobs, rng)?
+                        }
+                        None => self.sample_top_kp_min_p(
+                            &mut probs,
+                            self.top_k,
+                            self.top_p as f32,
+                            self.min_p as f32,
+                            return_logprobs,
+                            rng,
+                        )?,
+                    }
                 }
             }
         };
@@ -695,6 +1250,151 @@ impl Sampler {
     }
 }
 
+/// The nonzero probabilities, renormalized, with their token ids, most likely first.
+/// Hash the seed of a request and the index of a choice with SplitMix64. Adding them instead would
+/// give the choices of nearby seeds the same RNGs.
+fn choice_seed(seed: u64, response_index: usize) -> u64 {
+    splitmix64(splitmix64(seed) ^ response_index as u64)
+}
+
+/// The SplitMix64 output for the state `x`.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn remaining(probs: &[f32], argsort_indices: &[usize]) -> Vec<(usize, f32)> {
+    let total: f32 = probs.iter().sum();
+    argsort_indices
+        .iter()
+        .filter(|&&i| probs[i] > 0.0)
+        .map(|&i| (i, probs[i] / total))
+        .collect()
+}
+
+/// [Locally typical sampling](https://arxiv.org/abs/2202.00666): keep the tokens whose surprise
+/// is closest to the entropy, until their probability adds up to `typical_p`.
+fn apply_typical_p(probs: &mut [f32], argsort_indices: &[usize], typical_p: f32) {
+    if typical_p <= 0.0 || typical_p >= 1.0 {
+        return;
+    }
+    let mut remaining = remaining(probs, argsort_indices);
+    let entropy: f32 = remaining.iter().map(|(_, p)| -p * p.ln()).sum();
+    remaining.sort_by(|(_, a), (_, b)| {
+        (-a.ln() - entropy)
+            .abs()
+            .total_cmp(&(-b.ln() - entropy).abs())
+    });
+    let mut cumsum = 0.;
+    for (index, p) in remaining {
+        if cumsum >= typical_p {
+            probs[index] = 0.0;
+        } else {
+            cumsum += p;
+        }
+    }
+}
+
+/// [Tail free sampling](https://www.trentonbricken.com/Tail-Free-Sampling/): cut off the tail
+/// where the second derivative of the sorted probabilities adds up to `z`.
+fn apply_tail_free(probs: &mut [f32], argsort_indices: &[usize], z: f32) {
+    if z <= 0.0 || z >= 1.0 {
+        return;
+    }
+    let remaining = remaining(probs, argsort_indices);
+    if remaining.len() <= 2 {
+        return;
+    }
+    let first_derivatives = remaining
+        .windows(2)
+        .map(|w| w[0].1 - w[1].1)
+        .collect::<Vec<_>>();
+    let second_derivatives = first_derivatives
+        .windows(2)
+        .map(|w| (w[0] - w[1]).abs())
+        .collect::<Vec<_>>();
+    let total: f32 = second_derivatives.iter().sum();
+    if total <= 0.0 {
+        return;
+    }
+    let mut cumsum = 0.;
+    let mut keep = remaining.len();
+    for (i, d) in second_derivatives.iter().enumerate() {
+        cumsum += d / total;
+        if cumsum > z && i >= 1 {
+            keep = i;
+            break;
+        }
+    }
+    for (index, _) in &remaining[keep..] {
+        probs[*index] = 0.0;
+    }
+}
+
+/// Top-a sampling: remove the tokens less likely than `a` times the square of the highest
+/// probability.
+fn apply_top_a(probs: &mut [f32], argsort_indices: &[usize], a: f32) {
+    if a <= 0.0 {
+        return;
+    }
+    let remaining = remaining(probs, argsort_indices);
+    let Some((_, max_p)) = remaining.first() else {
+        return;
+    };
+    let threshold = a * max_p * max_p;
+    for (index, p) in &remaining[1..] {
+        if *p < threshold {
+            probs[*index] = 0.0;
+        }
+    }
+}
+
+/// Remove the tokens with a probability of at least `threshold`, except the least likely of them.
+fn apply_xtc(probs: &mut [f32], argsort_indices: &[usize], threshold: f32) {
+    let remaining = remaining(probs, argsort_indices);
+    let above = remaining
+        .iter()
+        .take_while(|(_, p)| *p >= threshold)
+        .count();
+    if above < 2 {
+        return;
+    }
+    for (index, _) in &remaining[..above - 1] {
+        probs[*index] = 0.0;
+    }
+}
+
+/// The number of tokens Mirostat 1.0 keeps, from the Zipf exponent estimated on the 100 most
+/// likely tokens.
+fn mirostat_v1_k(probs: &[f32], argsort_indices: &[usize], mu: f32) -> usize {
+    const M: usize = 100;
+    let n = argsort_indices.len();
+    let (mut sum_ti_bi, mut sum_ti_sq) = (0f32, 0f32);
+    for i in 0..(M.min(n) - 1) {
+        let (p, p_next) = (probs[argsort_indices[i]], probs[argsort_indices[i + 1]]);
+        if p_next <= 0.0 {
+            break;
+        }
+        let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+        let b_i = (p / p_next).ln();
+        sum_ti_bi += t_i * b_i;
+        sum_ti_sq += t_i * t_i;
+    }
+    if sum_ti_sq <= 0.0 {
+        return 1;
+    }
+    let s_hat = sum_ti_bi / sum_ti_sq;
+    let epsilon_hat = s_hat - 1.;
+    let k = ((epsilon_hat * 2f32.powf(mu)) / (1. - (n as f32).powf(-epsilon_hat))).powf(1. / s_hat);
+    if k.is_finite() {
+        (k.round() as usize).clamp(1, n)
+    } else {
+        n
+    }
+}
+
 mod tests {
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use tokenizers::Tokenizer;
@@ -712,9 +1412,15 @@ mod tests {
         Tokenizer::from_file(tokenizer_filename).unwrap()
     }
 
+    /// Small tokenizers in the style of GPT-2 (byte level) and Llama (byte fallback).
+    fn get_fixture_tokenizer(name: &str) -> Tokenizer {
+        let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+        Tokenizer::from_file(path).unwrap()
+    }
+
     #[test]
     fn test_argmax() {
-        use super::Sampler;
+        use super::{Sampler, SamplingParams};
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
@@ -722,15 +1428,15 @@ mod tests {
         use std::sync::Mutex;
 
         let sampler = Sampler::new(
-            None,
-            10,
+            &SamplingParams {
+                top_n_logprobs: 10,
+                top_k: Some(32),
+                top_p: Some(0.1),
+                min_p: Some(0.05),
+                ..SamplingParams::deterministic()
+            },
             Some(get_tokenizer().into()),
             None,
-            None,
-            None,
-            32,
-            0.1,
-            0.05,
             vec![],
         )
         .unwrap();
@@ -746,7 +1452,7 @@ mod tests {
 
     #[test]
     fn test_gumbel_speculative() {
-        use super::Sampler;
+        use super::{Sampler, SamplingParams};
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
@@ -754,15 +1460,15 @@ mod tests {
         use std::sync::Mutex;
 
         let sampler = Sampler::new(
-            None,
-            10,
+            &SamplingParams {
+                top_n_logprobs: 10,
+                top_k: Some(32),
+                top_p: Some(0.1),
+                min_p: Some(0.05),
+                ..SamplingParams::deterministic()
+            },
             Some(get_tokenizer().into()),
             None,
-            None,
-            None,
-            32,
-            0.1,
-            0.05,
             vec![],
         )
         .unwrap();
@@ -775,4 +1481,289 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_beam_candidates() {
+        use super::{Sampler, SamplingParams};
+        use candle_core::{Device, Tensor};
+
+        let sampler = Sampler::new(&SamplingParams::deterministic(), None, None, vec![]).unwrap();
+        let logits = Tensor::new(&[0f32, 3., 1., 2.], &Device::Cpu).unwrap();
+        let res = sampler.beam_candidates(logits, &[0], 3, false).unwrap();
+        assert_eq!(
+            res.iter().map(|x| x.token).collect::<Vec<_>>(),
+            vec![1, 3, 2]
+        );
+        assert!(res.windows(2).all(|w| w[0].logprob > w[1].logprob));
+        assert!(res[0].logprob < 0.);
+    }
+
+    #[test]
+    fn test_truncation_samplers() {
+        use super::{apply_tail_free, apply_top_a, apply_typical_p, apply_xtc};
+
+        let sorted = [0, 1, 2, 3];
+        let kept = |probs: &[f32]| {
+            probs
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| **p > 0.0)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        apply_top_a(&mut probs, &sorted, 1.0);
+        assert_eq!(kept(&probs), vec![0, 1]);
+
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        apply_xtc(&mut probs, &sorted, 0.2);
+        assert_eq!(kept(&probs), vec![1, 2, 3]);
+
+        let mut probs = vec![0.4, 0.3, 0.2, 0.05, 0.05];
+        apply_tail_free(&mut probs, &[0, 1, 2, 3, 4], 0.5);
+        assert_eq!(kept(&probs), vec![0, 1]);
+
+        // The most likely token is further from the entropy than the second one.
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        apply_typical_p(&mut probs, &sorted, 0.3);
+        assert_eq!(kept(&probs), vec![1]);
+    }
+
+    #[test]
+    fn test_mirostat_updates_mu() {
+        use super::{MirostatParams, Sampler, SamplingParams};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = Sampler::new(
+            &SamplingParams {
+                temperature: Some(1.0),
+                mirostat: Some(MirostatParams::new_with_defaults(2, Some(3.0), None).unwrap()),
+                ..SamplingParams::deterministic()
+            },
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let copy = sampler.clone();
+        let logits = Tensor::arange(0f32, 32f32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        sampler.sample(logits, &[0], false, rng, false).unwrap();
+
+        let mu = |sampler: &Sampler| *sampler.mirostat.as_ref().unwrap().1 .0.lock().unwrap();
+        assert_ne!(mu(&sampler), 6.0);
+        // Each sequence has its own μ
+        assert_eq!(mu(&copy), 6.0);
+    }
+
+    #[test]
+    fn test_repetition_penalty_range() {
+        use super::{PenaltyRange, Sampler, SamplingParams};
+        use candle_core::{Device, Tensor};
+
+        let mut sampler = Sampler::new(
+            &SamplingParams {
+                repetition_penalty: Some(2.0),
+                penalty_range: Some(PenaltyRange {
+                    last_n: Some(3),
+                    exclude_prompt: true,
+                    whitelist: vec![4],
+                }),
+                ..SamplingParams::deterministic()
+            },
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        sampler.set_prompt_len(2);
+        // Token 0 is in the prompt and token 1 is outside of the window.
+        let context = [0, 5, 1, 2, 3, 4];
+        let logits = vec![2f32, 2., 2., -2., 2., 2.];
+        let logits = sampler
+            .apply_penalties(logits, &context)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![2., 2., 1., -4., 2., 2.]);
+    }
+
+    #[test]
+    fn test_dry_multi_token_breakers() {
+        use super::{DryBreakerTable, DrySamplingParams, DrySamplingParamsInner};
+
+        let params = DrySamplingParams::new_with_defaults(
+            0.8,
+            Some(vec!["\n".to_string(), "The:".to_string()]),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let tokenizer = get_fixture_tokenizer("gpt2_tokenizer.json");
+        let id = |tok: &str| tokenizer.token_to_id(tok).unwrap();
+        let breakers = DryBreakerTable::new(&tokenizer).unwrap();
+        let inner = DrySamplingParamsInner::from(params.clone(), &breakers).unwrap();
+        // "\n" and "\n\n" contain a whole breaker
+        assert_eq!(inner.sequence_breakers[&id("Ċ")], vec![Vec::<u32>::new()]);
+        assert_eq!(inner.sequence_breakers[&id("ĊĊ")], vec![Vec::<u32>::new()]);
+        // "The" and " The" are followed by ":", "T" and " T" by "he" and ":"
+        assert_eq!(inner.sequence_breakers[&id("The")], vec![vec![id(":")]]);
+        assert_eq!(inner.sequence_breakers[&id("ĠThe")], vec![vec![id(":")]]);
+        assert_eq!(
+            inner.sequence_breakers[&id("T")],
+            vec![vec![id("he"), id(":")]]
+        );
+        assert_eq!(
+            inner.sequence_breakers[&id("ĠT")],
+            vec![vec![id("he"), id(":")]]
+        );
+        assert!(!inner.sequence_breakers.contains_key(&id(":")));
+        assert!(!inner.sequence_breakers.contains_key(&id("<|endoftext|>")));
+
+        let tokenizer = get_fixture_tokenizer("llama_tokenizer.json");
+        let id = |tok: &str| tokenizer.token_to_id(tok).unwrap();
+        let breakers = DryBreakerTable::new(&tokenizer).unwrap();
+        let inner = DrySamplingParamsInner::from(params, &breakers).unwrap();
+        // The byte fallback token of "\n"
+        assert_eq!(
+            inner.sequence_breakers[&id("<0x0A>")],
+            vec![Vec::<u32>::new()]
+        );
+        // "The" and "▁The" are followed by ":", without a prepended space
+        assert_eq!(inner.sequence_breakers[&id("The")], vec![vec![id(":")]]);
+        assert_eq!(inner.sequence_breakers[&id("▁The")], vec![vec![id(":")]]);
+        assert_eq!(
+            inner.sequence_breakers[&id("<0x54>")],
+            vec![vec![id("he"), id(":")]]
+        );
+        assert!(!inner.sequence_breakers.contains_key(&id("</s>")));
+    }
+
+    #[test]
+    fn test_dry_breakers_without_token_bytes() {
+        use super::{DryBreakerTable, DrySamplingParams, DrySamplingParamsInner};
+        use tokenizers::DecoderWrapper;
+
+        // Without a decoder, the bytes of the tokens cannot be recovered
+        let mut tokenizer = get_fixture_tokenizer("gpt2_tokenizer.json");
+        tokenizer.with_decoder(None::<DecoderWrapper>);
+        let id = |tok: &str| tokenizer.token_to_id(tok).unwrap();
+        let breakers = DryBreakerTable::new(&tokenizer).unwrap();
+        let params = DrySamplingParams::new_with_defaults(
+            0.8,
+            Some(vec!["\n".to_string(), "The:".to_string()]),
+            None,
+            None,
+        )
+        .unwrap();
+        let inner = DrySamplingParamsInner::from(params, &breakers).unwrap();
+        assert_eq!(inner.sequence_breakers.len(), 2);
+        assert_eq!(inner.sequence_breakers[&id("Ċ")], vec![Vec::<u32>::new()]);
+        assert_eq!(inner.sequence_breakers[&id("The")], vec![vec![id(":")]]);
+    }
+
+    #[test]
+    fn test_dry_penalty_stops_at_breaker() {
+        use super::{DryBreakerTable, DrySamplingParams, Sampler, SamplingParams};
+
+        let tokenizer = get_fixture_tokenizer("llama_tokenizer.json");
+        let id = |tok: &str| tokenizer.token_to_id(tok).unwrap();
+        let breakers = DryBreakerTable::new(&tokenizer).unwrap();
+        let sampler = Sampler::new(
+            &SamplingParams {
+                dry_params: Some(
+                    DrySamplingParams::new_with_defaults(
+                        0.8,
+                        Some(vec!["The:".to_string()]),
+                        Some(1.75),
+                        Some(2),
+                    )
+                    .unwrap(),
+                ),
+                ..SamplingParams::deterministic()
+            },
+            None,
+            Some(&breakers),
+            vec![],
+        )
+        .unwrap();
+        // "The: a the The: a the", the repetition of "a the" may not extend back into "The:"
+        let (the, colon, a, b) = (id("▁The"), id(":"), id("▁a"), id("▁the"));
+        let context = [the, colon, a, b, the, colon, a, b];
+        let logits = sampler
+            .apply_penalties(vec![0f32; tokenizer.get_vocab_size(true)], &context)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits[the as usize], -0.8);
+        assert!(logits
+            .iter()
+            .enumerate()
+            .all(|(tok, logit)| tok == the as usize || *logit == 0.));
+    }
+
+    #[test]
+    fn test_choice_seed() {
+        use super::{choice_seed, splitmix64};
+
+        assert_eq!(splitmix64(0), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(choice_seed(7, 1), choice_seed(7, 1));
+        // Nearby seeds do not share the seeds of their choices.
+        assert_ne!(choice_seed(7, 1), choice_seed(8, 0));
+        assert_ne!(choice_seed(7, 0), choice_seed(7, 1));
+    }
+
+    #[test]
+    fn test_seeded_sampling_ignores_batch() {
+        use super::{Sampler, SamplingParams};
+        use candle_core::{DType, Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let new_sampler = |seed: Option<u64>| {
+            let mut sampler = Sampler::new(
+                &SamplingParams {
+                    temperature: Some(1.0),
+                    ..SamplingParams::deterministic()
+                },
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+            if let Some(seed) = seed {
+                sampler.set_seed(seed);
+            }
+            sampler
+        };
+        let logits = Tensor::zeros(64, DType::F32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+        let sample = |sampler: &Sampler| {
+            sampler
+                .sample(logits.clone(), &[0], false, rng.clone(), false)
+                .unwrap()
+                .token
+        };
+
+        let alone = new_sampler(Some(7));
+        let expected = (0..8).map(|_| sample(&alone)).collect::<Vec<_>>();
+
+        // Batched with sequences using the shared RNG and another seed
+        let batched = new_sampler(Some(7));
+        let unseeded = new_sampler(None);
+        let other = new_sampler(Some(8));
+        let mut actual = Vec::new();
+        for _ in 0..8 {
+            sample(&unseeded);
+            actual.push(sample(&batched));
+            sample(&other);
+        }
+        assert_eq!(actual, expected);
+    }
 }
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::Entry, HashMap, VecDeque},
     num::NonZeroUsize,
     sync::atomic::Ordering,
 };
@@ -10,14 +10,17 @@ use crate::{
     sequence::{Sequence, SequenceState, StopReason},
 };
 
-use super::{Scheduler, SchedulerOutput};
+use super::{
+    policy::{SchedulingPolicy, SchedulingPolicyState},
+    Scheduler, SchedulerOutput,
+};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
     fn add(&mut self, item: Sequence);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
+    fn iter(&self) -> impl Iterator<Item = &Sequence>;
     fn len(&self) -> usize;
-    fn sort_ascending_ids(&mut self);
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -30,9 +33,8 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
-    fn sort_ascending_ids(&mut self) {
-        let slice = self.make_contiguous();
-        slice.sort_by_key(|seq| *seq.id());
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        VecDeque::iter(self)
     }
     fn len(&self) -> usize {
         VecDeque::len(self)
@@ -58,23 +60,54 @@ pub struct BucketedSeqs<Backer: FcfsBacker> {
     waiting: Backer,
 }
 
+/// How the bucket of sequences which runs this step is chosen when there are several.
+#[derive(Clone, Copy)]
+pub enum BucketSelection {
+    /// The bucket with the shortest sequences.
+    Shortest,
+    /// The bucket with the highest total [`Sequence::compute_priority`].
+    Urgency,
+    /// The bucket of the first sequence, for inputs which are ordered by a scheduling policy.
+    First,
+}
+
 pub trait BucketingManager<Backer: FcfsBacker> {
     /// Bucket and waitlist running input sequences, returning the newly running sequences.
     fn bucket_and_waitlist_seqs_waiting(
         &mut self,
         running: Vec<Sequence>,
         waiting: Backer,
-        discrete: bool,
+        selection: BucketSelection,
     ) -> BucketedSeqs<Backer>;
+    /// Allow sequences with different adapters to run in the same batch.
+    fn set_mixed_adapters(&mut self, mixed_adapters: bool);
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters, cache length, (has_imgs && is_prompt), prefix length)
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+// Prefilled prompts are run on top of their cached prefix, which must have the same length
+type BucketKey = (Option<Vec<String>>, usize, bool, usize);
 
-struct FixedBucketingManager;
+struct FixedBucketingManager {
+    // If the pipeline can mix adapters in a batch, sequences are not bucketed by adapters.
+    mixed_adapters: bool,
+}
+
+impl FixedBucketingManager {
+    fn adapters_key(&self, seq: &Sequence) -> Option<Vec<String>> {
+        if self.mixed_adapters {
+            None
+        } else {
+            seq.get_adapters()
+        }
+    }
+}
 
 impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
+    fn set_mixed_adapters(&mut self, mixed_adapters: bool) {
+        self.mixed_adapters = mixed_adapters;
+    }
+
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
     /// The others are moved to the waiting list (retaining high priority due to start time),
     /// without a state modification.
@@ -82,51 +115,26 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         &mut self,
         running: Vec<Sequence>,
         mut waiting: Backer,
-        discrete: bool,
+        selection: BucketSelection,
     ) -> BucketedSeqs<Backer> {
         // Now, get the sequences with the smallest sequence lengths, and allow them to catch up.
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
+        let mut first = None;
         for seq in running {
-            let len = seq.len();
-            match seq_buckets.get_mut(&(
-                seq.get_adapters(),
-                len,
+            let key = (
+                self.adapters_key(&seq),
+                seq.len(),
                 seq.images().is_some() && seq.is_prompt(),
-            )) {
-                Some(bucket) => {
-                    if !discrete {
-                        *seq_priorities
-                            .get_mut(&(
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ))
-                            .unwrap() += seq.compute_priority();
-                    }
-                    bucket.push(seq);
-                }
-                None => {
-                    if !discrete {
-                        seq_priorities.insert(
-                            (
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ),
-                            seq.compute_priority(),
-                        );
-                    }
-                    seq_buckets.insert(
-                        (
-                            seq.get_adapters(),
-                            len,
-                            seq.images().is_some() && seq.is_prompt(),
-                        ),
-                        vec![seq],
-                    );
-                }
+                seq.prefix_len(),
+            );
+            if matches!(selection, BucketSelection::Urgency) {
+                *seq_priorities.entry(key.clone()).or_default() += seq.compute_priority();
+            }
+            if first.is_none() {
+                first = Some(key.clone());
             }
+            seq_buckets.entry(key).or_default().push(seq);
         }
         let running = if seq_buckets.len() <= 1 {
             // Full steam ahead or have everything
@@ -143,15 +151,17 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                 .min_by_key(|(_, x, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
-            let len = if !discrete {
-                seq_priorities
-                    .iter()
-                    .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-                    .map(|(a, b)| (a, *b))
-                    .unwrap_or_else(|| (&min, seq_priorities[&min]))
-                    .0
-            } else {
-                &min
+            let len = match selection {
+                BucketSelection::Urgency => {
+                    seq_priorities
+                        .iter()
+                        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+                        .map(|(a, b)| (a, *b))
+                        .unwrap_or_else(|| (&min, seq_priorities[&min]))
+                        .0
+                }
+                BucketSelection::First => first.as_ref().unwrap_or(&min),
+                BucketSelection::Shortest => &min,
             };
             let highest_priority_seqs = seq_buckets
                 .remove(len)
@@ -176,18 +186,30 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    policy: SchedulingPolicyState,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
     pub fn new(method: DefaultSchedulerMethod) -> Self {
+        Self::new_with_policy(method, SchedulingPolicy::Fcfs, 0)
+    }
+
+    pub fn new_with_policy(
+        method: DefaultSchedulerMethod,
+        policy: SchedulingPolicy,
+        starvation_limit: usize,
+    ) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
-            DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
+            DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager {
+                mixed_adapters: false,
+            }),
         };
         Self {
             running: Vec::new(),
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            policy: SchedulingPolicyState::new(policy, starvation_limit),
         }
     }
 
@@ -198,16 +220,30 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         let waiting = std::mem::take(&mut self.waiting);
         let BucketedSeqs { running, waiting } = self
             .bucketing_manager
-            .bucket_and_waitlist_seqs_waiting(running, waiting, true);
+            .bucket_and_waitlist_seqs_waiting(running, waiting, BucketSelection::Shortest);
         self.waiting = waiting;
         running
     }
 
+    /// Age the sequences which were not scheduled this step for starvation protection, and reset
+    /// the age of the ones which were.
+    fn age_unscheduled(&mut self) {
+        let running = std::mem::take(&mut self.running);
+        self.running = running
+            .into_iter()
+            .map(|seq| self.policy.on_scheduled(seq))
+            .collect();
+        let waiting = std::mem::take(&mut self.waiting);
+        for seq in waiting.into_iter() {
+            self.waiting.add(self.policy.on_skip(seq));
+        }
+    }
+
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self) -> DefaultSchedulerOutput {
         // Filter out all done sequences
         let running = std::mem::take(&mut self.running);
-        let mut waiting = std::mem::take(&mut self.waiting);
+        let waiting = std::mem::take(&mut self.waiting);
         let mut running = running
             .into_iter()
             .filter(|seq| seq.is_running())
@@ -221,7 +257,8 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                     completion: vec![].into(),
                 };
             }
-            (_, 0) => {
+            // With a non-FCFS policy, fall through so that waiting sequences are admitted in policy order.
+            (_, 0) if self.policy.is_fcfs() => {
                 for seq in waiting.into_iter() {
                     seq.set_state(SequenceState::RunningPrompt);
                     self.running.push(seq);
@@ -229,6 +266,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                 self.waiting = Backer::new();
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
+                self.age_unscheduled();
                 return DefaultSchedulerOutput {
                     prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
                     completion: vec![].into(),
@@ -236,6 +274,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             }
             (0, _) => {
                 self.running = self.bucket_and_waitlist_seqs(running);
+                self.age_unscheduled();
                 if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
                     self.running
                         .iter_mut()
@@ -250,31 +289,48 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             _ => {}
         }
 
-        // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        // Sort the waiting seqs according to the scheduling policy
+        let waiting = self.policy.order(waiting.into_iter().collect());
 
-        // If the waiting sequence will fit, add it. Otherwise remove it
+        // If the waiting sequences will fit, add them. Otherwise remove them. The beams of a
+        // request are extended together, so they are only admitted together.
         let mut new_waiting = Backer::new();
-        for seq in waiting.into_iter() {
-            if self.sequence_fits(&running, &seq) {
-                if seq.is_waiting() {
-                    seq.set_state(SequenceState::RunningPrompt);
+        for seqs in group_beams(waiting) {
+            if self.sequences_fit(&running, seqs.len()) {
+                for seq in seqs {
+                    if seq.is_waiting() {
+                        seq.set_state(SequenceState::RunningPrompt);
+                        self.policy.on_admit(&seq);
+                    }
+                    running.push(seq);
                 }
-                running.push(seq);
             } else {
-                new_waiting.add(seq);
+                for seq in seqs {
+                    new_waiting.add(seq);
+                }
             }
         }
 
+        // With a non-FCFS policy, run the bucket holding the sequence the policy ranks first so that
+        // bucketing does not undo the admission order.
+        let selection = if self.policy.is_fcfs() {
+            BucketSelection::Urgency
+        } else {
+            running = self.policy.order(running);
+            BucketSelection::First
+        };
         let BucketedSeqs {
             running,
             waiting: new_waiting,
-        } = self
-            .bucketing_manager
-            .bucket_and_waitlist_seqs_waiting(running, new_waiting, false);
+        } = self.bucketing_manager.bucket_and_waitlist_seqs_waiting(
+            running,
+            new_waiting,
+            selection,
+        );
 
         self.running = running;
         self.waiting = new_waiting;
+        self.age_unscheduled();
 
         let mut completion = Vec::new();
         let mut prompt = Vec::new();
@@ -292,13 +348,66 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
+    /// Remove all sequences belonging to this request. Dropping them frees their KV cache.
+    pub fn cancel_request(&mut self, request_id: usize) -> usize {
+        let mut n_canceled = 0;
+        self.running.retain(|seq| {
+            if seq.request_id() == request_id {
+                seq.set_state(SequenceState::Done(StopReason::Canceled));
+                n_canceled += 1;
+                false
+            } else {
+                true
+            }
+        });
+
+        let waiting = std::mem::take(&mut self.waiting);
+        let mut new_waiting = Backer::new();
+        for seq in waiting.into_iter() {
+            if seq.request_id() == request_id {
+                seq.set_state(SequenceState::Done(StopReason::Canceled));
+                n_canceled += 1;
+            } else {
+                new_waiting.add(seq);
+            }
+        }
+        self.waiting = new_waiting;
+
+        n_canceled
+    }
+
+    fn sequences_fit(&self, running: &[Sequence], n_seqs: usize) -> bool {
+        running.len() + n_seqs <= self.max_num_seqs()
+    }
+
+    pub fn max_num_seqs(&self) -> usize {
         match &self.method {
-            DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
+            DefaultSchedulerMethod::Fixed(n) => (*n).into(),
         }
     }
 }
 
+/// Group the sequences so that the beams of a beam search request are together, at the position of
+/// its first beam. Other sequences are on their own.
+fn group_beams(seqs: Vec<Sequence>) -> Vec<Vec<Sequence>> {
+    let mut groups: Vec<Vec<Sequence>> = Vec::new();
+    let mut beam_groups: HashMap<usize, usize> = HashMap::new();
+    for seq in seqs {
+        if !seq.is_beam() {
+            groups.push(vec![seq]);
+            continue;
+        }
+        match beam_groups.entry(seq.request_id()) {
+            Entry::Occupied(group) => groups[*group.get()].push(seq),
+            Entry::Vacant(group) => {
+                group.insert(groups.len());
+                groups.push(vec![seq]);
+            }
+        }
+    }
+    groups
+}
+
 impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn schedule(&mut self) -> SchedulerOutput<'_> {
         SchedulerOutput::DefaultScheduler {
@@ -311,6 +420,9 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn running_len(&self) -> usize {
         self.running.len()
     }
+    fn max_num_seqs(&self) -> usize {
+        self.max_num_seqs()
+    }
     fn add_seq(&mut self, seq: Sequence) {
         if seq.is_running() {
             // prefill case
@@ -319,6 +431,18 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
             self.waiting.add(seq);
         }
     }
+    fn cancel_request(&mut self, request_id: usize) -> usize {
+        self.cancel_request(request_id)
+    }
+    fn set_mixed_adapters(&mut self, mixed_adapters: bool) {
+        self.bucketing_manager.set_mixed_adapters(mixed_adapters);
+    }
+    fn uses_adapter(&self, adapter: &str) -> bool {
+        self.running
+            .iter()
+            .chain(self.waiting.iter())
+            .any(|seq| seq.uses_adapter(adapter))
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         None
     }
@@ -330,3 +454,95 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, num::NonZeroUsize, sync::Arc};
+
+    use tokio::sync::Mutex;
+
+    use super::{DefaultScheduler, DefaultSchedulerMethod};
+    use crate::{
+        pipeline::BeamSearchState,
+        sampler::BeamSearchParams,
+        scheduler::Scheduler,
+        sequence::{Sequence, SequenceGroup, TestSequence},
+    };
+
+    fn seq(id: usize, request_id: usize) -> Sequence {
+        TestSequence {
+            id,
+            request_id,
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn beams(first_id: usize, request_id: usize, beam_width: usize) -> Vec<Sequence> {
+        let mut group = SequenceGroup::new(1, false, false, 1);
+        group.beam = Some(BeamSearchState::new(BeamSearchParams::new(beam_width), 1));
+        let group = Arc::new(Mutex::new(group));
+        (first_id..first_id + beam_width)
+            .map(|id| {
+                TestSequence {
+                    id,
+                    request_id,
+                    group: Some(group.clone()),
+                    ..Default::default()
+                }
+                .build()
+            })
+            .collect()
+    }
+
+    fn scheduled_ids(scheduler: &mut DefaultScheduler<VecDeque<Sequence>>) -> Vec<usize> {
+        let output = scheduler.schedule();
+        let mut ids = output
+            .prompt
+            .iter()
+            .chain(output.completion.iter())
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn beams_are_admitted_together() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(4).unwrap()),
+        );
+        scheduler.add_seq(seq(0, 0));
+        assert_eq!(scheduled_ids(&mut scheduler), vec![0]);
+
+        // Only two of the three beams would fit next to sequences 0 and 1.
+        scheduler.add_seq(seq(1, 1));
+        for beam in beams(2, 2, 3) {
+            scheduler.add_seq(beam);
+        }
+        assert_eq!(scheduled_ids(&mut scheduler), vec![0, 1]);
+
+        scheduler.cancel_request(1);
+        assert_eq!(scheduled_ids(&mut scheduler), vec![0, 2, 3, 4]);
+    }
+
+    #[test]
+    fn cancel_waiting_and_running() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(4).unwrap()),
+        );
+        scheduler.add_seq(seq(0, 0));
+        assert_eq!(scheduled_ids(&mut scheduler), vec![0]);
+        scheduler.add_seq(seq(1, 1));
+        scheduler.add_seq(seq(2, 1));
+        assert_eq!((scheduler.running_len(), scheduler.waiting_len()), (1, 2));
+
+        // Both waiting sequences of request 1 are canceled.
+        assert_eq!(scheduler.cancel_request(1), 2);
+        assert_eq!((scheduler.running_len(), scheduler.waiting_len()), (1, 0));
+
+        assert_eq!(scheduler.cancel_request(0), 1);
+        assert_eq!((scheduler.running_len(), scheduler.waiting_len()), (0, 0));
+        assert_eq!(scheduler.cancel_request(0), 0);
+    }
+}
@@ -1,6 +1,8 @@
 mod default_scheduler;
+mod policy;
 
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
+pub use policy::SchedulingPolicy;
 
 use crate::{
     paged_attention::{
@@ -15,6 +17,14 @@ pub enum SchedulerConfig {
     DefaultScheduler {
         method: DefaultSchedulerMethod,
     },
+    /// The default scheduler, admitting waiting sequences in the order given by `policy`.
+    /// Sequences which have not been scheduled for `starvation_limit` steps are admitted first,
+    /// a limit of 0 disables starvation protection.
+    DefaultSchedulerWithPolicy {
+        method: DefaultSchedulerMethod,
+        policy: SchedulingPolicy,
+        starvation_limit: usize,
+    },
     PagedAttentionMeta {
         max_num_seqs: usize,
         config: CacheConfig,
@@ -25,6 +35,15 @@ impl SchedulerConfig {
     pub fn into_scheduler(self) -> Box<dyn Scheduler> {
         match self {
             Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
+            Self::DefaultSchedulerWithPolicy {
+                method,
+                policy,
+                starvation_limit,
+            } => Box::new(DefaultScheduler::new_with_policy(
+                method,
+                policy,
+                starvation_limit,
+            )),
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
@@ -49,7 +68,17 @@ pub trait Scheduler {
     fn schedule(&mut self) -> SchedulerOutput<'_>;
     fn waiting_len(&self) -> usize;
     fn running_len(&self) -> usize;
+    /// The maximum number of sequences which run in one step.
+    fn max_num_seqs(&self) -> usize;
     fn add_seq(&mut self, seq: Sequence);
+    /// Cancel all waiting or running sequences created by the given request, freeing any
+    /// resources they hold. Returns the number of sequences which were canceled.
+    fn cancel_request(&mut self, request_id: usize) -> usize;
+    /// Allow sequences with different adapters to run in the same batch, if the pipeline
+    /// supports it.
+    fn set_mixed_adapters(&mut self, mixed_adapters: bool);
+    /// Whether any waiting or running sequence selected this adapter.
+    fn uses_adapter(&self, adapter: &str) -> bool;
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
 
@@ -0,0 +1,292 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::sequence::Sequence;
+
+/// The scheduling policy controls the order in which the default scheduler admits waiting
+/// sequences when there is not enough room to run all of them.
+///
+/// Regardless of the policy, a sequence which has not been scheduled for `starvation_limit`
+/// scheduling steps is admitted before all others.
+#[derive(Clone, Debug, Default)]
+pub enum SchedulingPolicy {
+    /// First come, first served.
+    #[default]
+    Fcfs,
+    /// Requests with a higher `priority` are admitted first, ties are broken by arrival order.
+    StrictPriority,
+    /// Weighted fair queuing by tenant. The tenant which has received the least service
+    /// (admitted prompt tokens divided by its weight) is admitted next. Tenants which
+    /// are not listed have a weight of 1, and requests without a tenant share one queue.
+    WeightedFair { weights: HashMap<String, f64> },
+    /// Requests with the smallest `max_len` are admitted first, requests without one are admitted last.
+    ShortestJobFirst,
+}
+
+impl SchedulingPolicy {
+    pub fn is_fcfs(&self) -> bool {
+        matches!(self, Self::Fcfs)
+    }
+}
+
+type TenantKey = Option<String>;
+
+pub(crate) struct SchedulingPolicyState {
+    policy: SchedulingPolicy,
+    starvation_limit: usize,
+    /// Weighted service received per tenant, only used for weighted fair queuing.
+    served: HashMap<TenantKey, f64>,
+}
+
+impl SchedulingPolicyState {
+    pub fn new(policy: SchedulingPolicy, starvation_limit: usize) -> Self {
+        Self {
+            policy,
+            starvation_limit,
+            served: HashMap::new(),
+        }
+    }
+
+    pub fn is_fcfs(&self) -> bool {
+        self.policy.is_fcfs()
+    }
+
+    fn is_starved(&self, seq: &Sequence) -> bool {
+        self.starvation_limit > 0 && seq.unscheduled_steps() >= self.starvation_limit
+    }
+
+    fn weight(&self, tenant: &TenantKey) -> f64 {
+        match (&self.policy, tenant) {
+            (SchedulingPolicy::WeightedFair { weights }, Some(tenant)) => weights
+                .get(tenant)
+                .copied()
+                .filter(|w| *w > 0.)
+                .unwrap_or(1.),
+            _ => 1.,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn cost(&self, seq: &Sequence) -> f64 {
+        seq.len() as f64 / self.weight(&seq.tenant().map(ToString::to_string))
+    }
+
+    /// Order the waiting sequences such that the first should be admitted first.
+    /// Starved sequences are always placed first, in arrival order.
+    pub fn order(&mut self, seqs: Vec<Sequence>) -> Vec<Sequence> {
+        let (mut starved, mut rest): (Vec<_>, Vec<_>) =
+            seqs.into_iter().partition(|seq| self.is_starved(seq));
+        starved.sort_by_key(|seq| *seq.id());
+
+        match self.policy {
+            SchedulingPolicy::Fcfs => rest.sort_by_key(|seq| *seq.id()),
+            SchedulingPolicy::StrictPriority => {
+                rest.sort_by_key(|seq| (Reverse(seq.priority()), *seq.id()))
+            }
+            SchedulingPolicy::ShortestJobFirst => {
+                rest.sort_by_key(|seq| (seq.max_len().unwrap_or(usize::MAX), *seq.id()))
+            }
+            SchedulingPolicy::WeightedFair { .. } => rest = self.order_weighted_fair(rest),
+        }
+
+        starved.extend(rest);
+        starved
+    }
+
+    /// Simulate admitting the tenants' queues in order of least weighted service.
+    fn order_weighted_fair(&mut self, seqs: Vec<Sequence>) -> Vec<Sequence> {
+        // New tenants start at the current minimum so they cannot monopolize the scheduler.
+        let baseline = self
+            .served
+            .values()
+            .copied()
+            .min_by(|a, b| a.total_cmp(b))
+            .unwrap_or(0.);
+
+        let mut queues: HashMap<TenantKey, Vec<Sequence>> = HashMap::new();
+        for seq in seqs {
+            queues
+                .entry(seq.tenant().map(ToString::to_string))
+                .or_default()
+                .push(seq);
+        }
+        let mut virtual_served = HashMap::new();
+        for (tenant, queue) in queues.iter_mut() {
+            // Reverse so that popping yields the earliest arrival.
+            queue.sort_by_key(|seq| Reverse(*seq.id()));
+            let served = *self.served.entry(tenant.clone()).or_insert(baseline);
+            virtual_served.insert(tenant.clone(), served);
+        }
+
+        let mut ordered = Vec::new();
+        while let Some(tenant) = queues
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .min_by(|(a_tenant, a_queue), (b_tenant, b_queue)| {
+                virtual_served[*a_tenant]
+                    .total_cmp(&virtual_served[*b_tenant])
+                    .then_with(|| {
+                        a_queue
+                            .last()
+                            .map(|seq| *seq.id())
+                            .cmp(&b_queue.last().map(|seq| *seq.id()))
+                    })
+            })
+            .map(|(tenant, _)| tenant.clone())
+        {
+            let seq = queues.get_mut(&tenant).unwrap().pop().unwrap();
+            *virtual_served.get_mut(&tenant).unwrap() += self.cost(&seq);
+            ordered.push(seq);
+        }
+        ordered
+    }
+
+    /// Account for a sequence which was admitted.
+    pub fn on_admit(&mut self, seq: &Sequence) {
+        if matches!(self.policy, SchedulingPolicy::WeightedFair { .. }) {
+            let cost = self.cost(seq);
+            *self
+                .served
+                .entry(seq.tenant().map(ToString::to_string))
+                .or_insert(0.) += cost;
+        }
+    }
+
+    /// Handle a sequence which was not scheduled this step, aging it if starvation protection is enabled.
+    pub fn on_skip(&self, seq: Sequence) -> Sequence {
+        if self.starvation_limit > 0 {
+            seq.add_unscheduled_step()
+        } else {
+            seq
+        }
+    }
+
+    /// Handle a sequence which was scheduled this step, resetting its age.
+    pub fn on_scheduled(&self, seq: Sequence) -> Sequence {
+        seq.reset_unscheduled_steps()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{SchedulingPolicy, SchedulingPolicyState};
+    use crate::sequence::{Sequence, TestSequence};
+
+    fn dummy_seq(id: usize, prompt_len: usize, priority: i32, tenant: Option<&str>) -> Sequence {
+        TestSequence {
+            id,
+            request_id: id,
+            prompt_len,
+            priority,
+            tenant: tenant.map(ToString::to_string),
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn ids(seqs: &[Sequence]) -> Vec<usize> {
+        seqs.iter().map(|seq| *seq.id()).collect()
+    }
+
+    #[test]
+    fn fcfs_orders_by_arrival() {
+        let mut state = SchedulingPolicyState::new(SchedulingPolicy::Fcfs, 0);
+        let seqs = vec![
+            dummy_seq(2, 4, 0, None),
+            dummy_seq(0, 4, 5, None),
+            dummy_seq(1, 4, 0, None),
+        ];
+        assert_eq!(ids(&state.order(seqs)), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn strict_priority_orders_by_priority_then_arrival() {
+        let mut state = SchedulingPolicyState::new(SchedulingPolicy::StrictPriority, 0);
+        let seqs = vec![
+            dummy_seq(0, 4, 0, None),
+            dummy_seq(1, 4, 10, None),
+            dummy_seq(2, 4, -1, None),
+            dummy_seq(3, 4, 10, None),
+        ];
+        assert_eq!(ids(&state.order(seqs)), vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn shortest_job_first_orders_by_max_len() {
+        let mut state = SchedulingPolicyState::new(SchedulingPolicy::ShortestJobFirst, 0);
+        let seqs = vec![
+            dummy_seq(0, 64, 0, None),
+            dummy_seq(1, 8, 0, None),
+            dummy_seq(2, 16, 0, None),
+        ];
+        assert_eq!(ids(&state.order(seqs)), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn starved_sequences_are_admitted_first() {
+        let mut state = SchedulingPolicyState::new(SchedulingPolicy::StrictPriority, 3);
+        let mut low = dummy_seq(0, 4, 0, None);
+        for _ in 0..3 {
+            low = state.on_skip(low);
+        }
+        let seqs = vec![low, dummy_seq(1, 4, 10, None), dummy_seq(2, 4, 5, None)];
+        assert_eq!(ids(&state.order(seqs)), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn starvation_age_is_independent_of_urgency() {
+        let mut state = SchedulingPolicyState::new(SchedulingPolicy::StrictPriority, 2);
+        // Bucketing resets the urgency, which must not reset the starvation age.
+        let low = state
+            .on_skip(state.on_skip(dummy_seq(0, 4, 0, None)))
+            .reset_urgency();
+        let seqs = vec![low, dummy_seq(1, 4, 10, None)];
+        let ordered = state.order(seqs);
+        assert_eq!(ids(&ordered), vec![0, 1]);
+
+        let seqs = ordered
+            .into_iter()
+            .map(|seq| state.on_scheduled(seq))
+            .collect();
+        assert_eq!(ids(&state.order(seqs)), vec![1, 0]);
+    }
+
+    #[test]
+    fn weighted_fair_interleaves_tenants() {
+        let mut state = SchedulingPolicyState::new(
+            SchedulingPolicy::WeightedFair {
+                weights: HashMap::new(),
+            },
+            0,
+        );
+        // Tenant `a` floods the queue before `b` sends anything.
+        let mut seqs = (0..4)
+            .map(|id| dummy_seq(id, 8, 0, Some("a")))
+            .collect::<Vec<_>>();
+        seqs.extend((4..6).map(|id| dummy_seq(id, 8, 0, Some("b"))));
+        assert_eq!(ids(&state.order(seqs)), vec![0, 4, 1, 5, 2, 3]);
+    }
+
+    #[test]
+    fn weighted_fair_respects_weights_and_history() {
+        let mut state = SchedulingPolicyState::new(
+            SchedulingPolicy::WeightedFair {
+                weights: HashMap::from([("a".to_string(), 2.0)]),
+            },
+            0,
+        );
+        let mut seqs = (0..4)
+            .map(|id| dummy_seq(id, 8, 0, Some("a")))
+            .collect::<Vec<_>>();
+        seqs.extend((4..6).map(|id| dummy_seq(id, 8, 0, Some("b"))));
+        // `a` has twice the weight, so it is charged half as much per token.
+        let ordered = state.order(seqs);
+        assert_eq!(ids(&ordered), vec![0, 4, 1, 2, 5, 3]);
+
+        // After `b` has been served, `a` goes first.
+        state.on_admit(&ordered[1]);
+        let seqs = vec![dummy_seq(6, 8, 0, Some("b")), dummy_seq(7, 8, 0, Some("a"))];
+        assert_eq!(ids(&state.order(seqs)), vec![7, 6]);
+    }
+}
@@ -11,9 +11,9 @@ use tokio::sync::{
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
-    pipeline::{DiffusionGenerationParams, KvCache},
+    pipeline::{BeamSearchState, DiffusionGenerationParams, KvCache, SpeculativeState},
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    tools::{ToolCallStreamState, ToolCallingMatcher},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
     ImageGenerationResponse, ImageGenerationResponseFormat,
 };
@@ -127,6 +127,13 @@ impl SequenceCustomMetadata {
                 logical_token_blocks,
                 block_size: _,
             } => {
+                // A full block is followed by an empty one, which goes away with its last token.
+                if logical_token_blocks
+                    .last()
+                    .is_some_and(|last| last.is_empty())
+                {
+                    logical_token_blocks.pop();
+                }
                 let last = logical_token_blocks.last_mut().unwrap();
                 last.pop_token();
             }
@@ -147,6 +154,27 @@ impl SequenceCustomMetadata {
     }
 }
 
+/// The decoding state of a beam, which the other beams of a request can continue from.
+#[derive(Clone)]
+pub(crate) struct BeamSnapshot {
+    id: usize,
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    completion_bytes: Vec<u8>,
+    last_completion_bytes_len: usize,
+    last_logprob: f32,
+    caches: Option<BeamCaches>,
+}
+
+#[derive(Clone)]
+struct BeamCaches {
+    normal_cache: Vec<Option<KvCache>>,
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    scaling_cache: Option<Tensor>,
+}
+
 #[derive(Clone, Copy)]
 pub enum SeqStepType {
     PromptAndDecode,
@@ -156,6 +184,7 @@ pub enum SeqStepType {
 pub struct Sequence {
     // Metadata, const
     id: usize,
+    request_id: usize,
     prompt_len: usize,
     max_len: Option<usize>,
     timestamp: u128,
@@ -183,13 +212,22 @@ pub struct Sequence {
 
     // Speculative
     is_tmp: bool,
+    speculative: SpeculativeState,
+
+    // Beam search: the beam whose PagedAttention blocks this one continues from
+    beam_fork: Option<usize>,
 
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefix_len: usize,
 
     // Adapter dynamic config
     adapters: Option<Vec<String>>,
 
+    // Scheduling policy metadata
+    priority: i32,
+    tenant: Option<String>,
+
     // Cache
     normal_cache: Vec<Option<KvCache>>,
     scaling_cache: Option<Tensor>,
@@ -211,7 +249,9 @@ pub struct Sequence {
     stream_idx: usize,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
+    unscheduled_steps: usize,  // Starvation age used by the scheduling policy
     input_images: Option<Vec<image::DynamicImage>>,
+    input_videos: Option<Vec<Vec<image::DynamicImage>>>,
     pub cached_pixel_values: Option<Tensor>,
     pub cached_img_thw: Option<Tensor>,
     pub cached_vid_thw: Option<Tensor>,
@@ -227,6 +267,7 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    pub(crate) tool_call_stream: ToolCallStreamState,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -255,6 +296,24 @@ impl BlockEngineSequence for Sequence {
             SequenceCustomMetadata::None => unreachable!(),
         }
     }
+
+    fn get_full_block_token_ids(&self) -> Vec<&[usize]> {
+        match &self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                logical_token_blocks,
+                block_size: _,
+            } => logical_token_blocks
+                .iter()
+                .take_while(|block| block.is_full())
+                .map(|block| block.token_ids())
+                .collect(),
+            SequenceCustomMetadata::None => unreachable!(),
+        }
+    }
+
+    fn get_block_adapters(&self) -> Option<&[String]> {
+        self.adapters.as_deref()
+    }
 }
 
 impl Sequence {
@@ -263,6 +322,7 @@ impl Sequence {
         tokens: Vec<u32>,
         prompt: String,
         id: usize,
+        request_id: usize,
         timestamp: u128,
         layers: usize,
         responder: Sender<Response>,
@@ -280,6 +340,7 @@ impl Sequence {
         prefix: Option<String>,
         adapters: Option<Vec<String>>,
         input_images: Option<Vec<image::DynamicImage>>,
+        input_videos: Option<Vec<Vec<image::DynamicImage>>>,
         // Paged attention
         block_size: Option<usize>,
         //
@@ -292,6 +353,9 @@ impl Sequence {
         seq_preallocated_cache: Option<Tensor>,
         //
         return_raw_logits: bool,
+        // Scheduling
+        priority: i32,
+        tenant: Option<String>,
     ) -> Self {
         let prompt_len = tokens.len();
         let mut custom_metadata = if let Some(block_size) = block_size {
@@ -310,6 +374,7 @@ impl Sequence {
             logprobs: Vec::new(),
             prompt_len,
             id,
+            request_id,
             timestamp,
             state: RwLock::new(SequenceState::Waiting),
             normal_cache: vec![None; layers],
@@ -335,6 +400,7 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefix_len: 0,
             suffix,
             prefix,
             cumulative_logprob: 0.,
@@ -344,12 +410,17 @@ impl Sequence {
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
+            speculative: SpeculativeState::default(),
+            beam_fork: None,
             scheduling_urgency: 0,
+            unscheduled_steps: 0,
             adapters,
             input_images,
+            input_videos,
             custom_metadata,
             tok_trie,
             tools,
+            tool_call_stream: ToolCallStreamState::default(),
             image_gen_response_format,
             sequence_stepping_type,
             diffusion_params,
@@ -357,6 +428,8 @@ impl Sequence {
             cached_img_thw: None,
             cached_vid_thw: None,
             return_raw_logits,
+            priority,
+            tenant,
         }
     }
 
@@ -370,6 +443,22 @@ impl Sequence {
         self
     }
 
+    pub fn add_unscheduled_step(mut self) -> Self {
+        self.unscheduled_steps += 1;
+        self
+    }
+
+    pub fn reset_unscheduled_steps(mut self) -> Self {
+        self.unscheduled_steps = 0;
+        self
+    }
+
+    /// The number of consecutive scheduling steps in which this sequence was not scheduled.
+    /// Unlike the urgency, this is not reset by bucketing.
+    pub fn unscheduled_steps(&self) -> usize {
+        self.unscheduled_steps
+    }
+
     /// Simple metric: (scheduling urgency) + log2(length)
     /// Takes into account: urgency (scales linear) and length (scales logarithmic)
     /// Scaling urgency is the number of scheduling passes where we have not been scheduled.
@@ -386,11 +475,27 @@ impl Sequence {
     ) -> Self {
         self.cache = cache;
         self.xlora_cache = xlora_cache;
+        self.prefix_len = self.tokens.len() - toks.len();
+        self.prefill_prompt_toks = Some(toks);
+        self.set_state(SequenceState::RunningPrefillPrompt);
+        self
+    }
+
+    /// Like [`Self::prefill`], for models with a normal cache.
+    pub fn prefill_normal(mut self, normal_cache: Vec<Option<KvCache>>, toks: Vec<u32>) -> Self {
+        self.normal_cache = normal_cache;
+        self.prefix_len = self.tokens.len() - toks.len();
         self.prefill_prompt_toks = Some(toks);
         self.set_state(SequenceState::RunningPrefillPrompt);
         self
     }
 
+    /// The number of leading prompt tokens which were prefilled from a prefix cache, and are
+    /// attended to in the KV cache instead of being run.
+    pub fn prefix_len(&self) -> usize {
+        self.prefix_len
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -418,6 +523,11 @@ impl Sequence {
         &self.id
     }
 
+    /// The ID of the [`NormalRequest`](crate::NormalRequest) which created this sequence.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
     pub fn is_running(&self) -> bool {
         matches!(
             *self.state.read().unwrap(),
@@ -549,6 +659,73 @@ impl Sequence {
         self.custom_metadata.remove_tokens_from_blocks(n);
     }
 
+    pub(crate) fn speculative_state(&mut self) -> &mut SpeculativeState {
+        &mut self.speculative
+    }
+
+    /// Whether this sequence is a beam of a beam search request.
+    pub(crate) fn is_beam(&self) -> bool {
+        get_mut_group!(self).beam.is_some()
+    }
+
+    /// Snapshot the decoding state of this beam, with its KV caches if `with_caches`.
+    pub(crate) fn beam_snapshot(&self, with_caches: bool) -> BeamSnapshot {
+        BeamSnapshot {
+            id: self.id,
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            completion_bytes: self.completion_bytes.clone(),
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            last_logprob: self.last_logprob,
+            caches: with_caches.then(|| BeamCaches {
+                normal_cache: self.normal_cache.clone(),
+                cache: self.cache.clone(),
+                xlora_cache: self.xlora_cache.clone(),
+                scaling_cache: self.scaling_cache.clone(),
+            }),
+        }
+    }
+
+    /// Continue from the decoding state of another beam. The normal KV cache is copied because it
+    /// is appended to in place. The PagedAttention blocks are forked by the engine, see
+    /// [`Sequence::take_beam_fork`].
+    pub(crate) fn restore_beam(&mut self, snapshot: BeamSnapshot) -> candle_core::Result<()> {
+        if let Some(caches) = snapshot.caches {
+            self.beam_fork = (snapshot.id != self.id).then_some(snapshot.id);
+            self.normal_cache = caches
+                .normal_cache
+                .iter()
+                .map(|cache| cache.as_ref().map(KvCache::deep_clone).transpose())
+                .collect::<candle_core::Result<_>>()?;
+            self.cache = caches.cache;
+            self.xlora_cache = caches.xlora_cache;
+            self.scaling_cache = caches.scaling_cache;
+        }
+        self.tokens = snapshot.tokens;
+        self.logprobs = snapshot.logprobs;
+        self.cumulative_logprob = snapshot.cumulative_logprob;
+        self.completion_bytes = snapshot.completion_bytes;
+        self.last_completion_bytes_len = snapshot.last_completion_bytes_len;
+        self.last_logprob = snapshot.last_logprob;
+        self.prefill_prompt_toks = None;
+        if let SequenceCustomMetadata::PagedAttention {
+            logical_token_blocks,
+            block_size: _,
+        } = &mut self.custom_metadata
+        {
+            logical_token_blocks.clear();
+        }
+        self.custom_metadata
+            .append_tokens_to_blocks(self.tokens.iter().map(|x| *x as usize).collect());
+        Ok(())
+    }
+
+    /// The beam whose PagedAttention blocks this beam must now share, since it continued from it.
+    pub(crate) fn take_beam_fork(&mut self) -> Option<usize> {
+        self.beam_fork.take()
+    }
+
     pub fn add_token(
         &mut self,
         tok: Logprobs,
@@ -576,6 +753,7 @@ impl Sequence {
         self.tokens.push(tok.token);
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+        self.prefix_len = 0;
     }
 
     pub fn responder(&self) -> Sender<Response> {
@@ -651,6 +829,10 @@ impl Sequence {
         self.prompt_len
     }
 
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
     pub fn stop_strings(&self) -> &[String] {
         &self.stop_strings
     }
@@ -699,6 +881,12 @@ impl Sequence {
 
         get_mut_group!(self).total_prompt_toks += self.prompt_len;
         get_mut_group!(self).total_toks += self.len();
+
+        if let Some(draft_tokens) = self.speculative.draft_tokens() {
+            let mut group = get_mut_group!(self);
+            group.draft_tokens += draft_tokens;
+            group.accepted_draft_tokens += self.speculative.accepted_draft_tokens().unwrap_or(0);
+        }
     }
 
     pub fn add_image_choice_to_group(&self, choice: ImageChoice) {
@@ -751,6 +939,26 @@ impl Sequence {
         self.adapters.clone()
     }
 
+    /// Whether the sequence runs with the adapter, which was selected by its request or was a
+    /// default adapter when the sequence was added.
+    pub fn uses_adapter(&self, adapter: &str) -> bool {
+        self.adapters
+            .as_ref()
+            .is_some_and(|adapters| adapters.iter().any(|a| a == adapter))
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len
+    }
+
     pub fn take_images(&mut self) -> Option<Vec<image::DynamicImage>> {
         self.input_images.take()
     }
@@ -763,6 +971,15 @@ impl Sequence {
         self.input_images.as_deref()
     }
 
+    pub fn clone_videos(&mut self) -> Option<Vec<Vec<image::DynamicImage>>> {
+        self.input_videos.clone()
+    }
+
+    /// The frames of each video.
+    pub fn videos(&self) -> Option<&[Vec<image::DynamicImage>]> {
+        self.input_videos.as_deref()
+    }
+
     pub fn image_gen_response_format(&self) -> Option<ImageGenerationResponseFormat> {
         self.image_gen_response_format
     }
@@ -777,13 +994,15 @@ impl Sequence {
 }
 
 pub struct SequenceGroup {
-    n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
-    best_of: usize,   // Top n seqs based on cumulative logprobs.
+    n_choices: usize, // The target number of choices to collect. Can be decreased if an error is thrown.
+    best_of: usize, // The number of completion choices to return, the best by cumulative logprobs.
     pub total_prompt_toks: usize,
     pub total_toks: usize,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
+    draft_tokens: usize,
+    accepted_draft_tokens: usize,
     choices: Vec<Choice>,
     image_choices: Vec<ImageChoice>,
     raw_choices: Vec<(Vec<Tensor>, Vec<u32>)>,
@@ -792,6 +1011,7 @@ pub struct SequenceGroup {
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    pub(crate) beam: Option<BeamSearchState>,
 }
 
 impl SequenceGroup {
@@ -807,11 +1027,14 @@ impl SequenceGroup {
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
+            draft_tokens: 0,
+            accepted_draft_tokens: 0,
             chat_streaming_chunks: Vec::new(),
             completion_streaming_chunks: Vec::new(),
             is_streaming,
             is_chat,
             best_of,
+            beam: None,
         }
     }
 
@@ -823,12 +1046,18 @@ impl SequenceGroup {
     /// This applies the best_of.
     pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
         let mut choices = self.completion_choices.clone();
-        // Sort by descending logprobs
-        choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+        if self.beam.is_some() {
+            // The beams are finished with their rank as the index
+            choices.sort_by_key(|(_, x)| x.index);
+        } else {
+            // Sort by descending logprobs
+            choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+        }
         choices
             .into_iter()
             .take(self.best_of)
-            .map(|(_, x)| x)
+            .enumerate()
+            .map(|(index, (_, x))| CompletionChoice { index, ..x })
             .collect::<Vec<_>>()
     }
 
@@ -851,6 +1080,8 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            draft_tokens: (self.draft_tokens > 0).then_some(self.draft_tokens),
+            accepted_draft_tokens: (self.draft_tokens > 0).then_some(self.accepted_draft_tokens),
         }
     }
 
@@ -949,3 +1180,80 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// A waiting sequence for tests of the scheduling logic, which is never run.
+#[cfg(test)]
+pub(crate) struct TestSequence {
+    pub id: usize,
+    pub request_id: usize,
+    pub prompt_len: usize,
+    pub priority: i32,
+    pub tenant: Option<String>,
+    pub adapters: Option<Vec<String>>,
+    /// The group shared with the other sequences of the request, or a new one.
+    pub group: Option<Arc<Mutex<SequenceGroup>>>,
+    /// The PagedAttention block size, if the sequence is scheduled with PagedAttention.
+    pub block_size: Option<usize>,
+}
+
+#[cfg(test)]
+impl Default for TestSequence {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            request_id: 0,
+            prompt_len: 4,
+            priority: 0,
+            tenant: None,
+            adapters: None,
+            group: None,
+            block_size: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl TestSequence {
+    pub fn build(self) -> Sequence {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let sampler =
+            Sampler::new(&crate::SamplingParams::deterministic(), None, None, vec![]).unwrap();
+        let group = self
+            .group
+            .unwrap_or_else(|| Arc::new(Mutex::new(SequenceGroup::new(1, false, false, 1))));
+        Sequence::new_waiting(
+            vec![0; self.prompt_len],
+            String::new(),
+            self.id,
+            self.request_id,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            vec![],
+            Some(self.prompt_len),
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            self.adapters,
+            None,
+            None,
+            self.block_size,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+            false,
+            self.priority,
+            self.tenant,
+        )
+    }
+}
@@ -3,11 +3,11 @@ use std::{fs::File, num::NonZeroUsize, path::PathBuf};
 use serde::Deserialize;
 
 use crate::{
-    amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, GGMLLoaderBuilder,
+    amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, DraftLoader, GGMLLoaderBuilder,
     GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, ModelDType,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER,
+    NgramLookupConfig, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    SpeculativeConfig, SpeculativeHeadsConfig, SpeculativeLoader, Topology, VisionLoaderBuilder,
+    VisionLoaderType, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
@@ -304,8 +304,18 @@ pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
-    /// Base model
-    draft_model: TomlModelSelected,
+    /// Tune gamma per sequence from the acceptance rate, with `gamma` as the maximum
+    #[serde(default)]
+    adaptive_gamma: bool,
+
+    /// Draft model
+    draft_model: Option<TomlModelSelected>,
+
+    /// Draft by n-gram lookup in the sequence instead of with a draft model
+    ngram_lookup: Option<NgramLookupConfig>,
+
+    /// Draft with Medusa or EAGLE heads on the hidden states of the target model
+    heads: Option<SpeculativeHeadsConfig>,
 }
 
 #[derive(Deserialize)]
@@ -672,12 +682,26 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
         let loader = if let Some(speculative) = selector.speculative {
-            let draft_loader = loader_from_selected(args, speculative.draft_model)?;
+            let draft = match (
+                speculative.draft_model,
+                speculative.ngram_lookup,
+                speculative.heads,
+            ) {
+                (Some(draft_model), None, None) => {
+                    DraftLoader::Model(loader_from_selected(args, draft_model)?)
+                }
+                (None, Some(ngram_lookup), None) => DraftLoader::NgramLookup(ngram_lookup),
+                (None, None, Some(heads)) => DraftLoader::Heads(heads),
+                _ => anyhow::bail!(
+                    "Speculative decoding requires exactly one of `draft_model`, `ngram_lookup` or `heads`."
+                ),
+            };
             Box::new(SpeculativeLoader {
                 target: loader,
-                draft: draft_loader,
+                draft,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    adaptive_gamma: speculative.adaptive_gamma,
                 },
             })
         } else {
@@ -1,33 +1,33 @@
+mod parser;
 mod request;
 mod response;
 
+pub use parser::ToolCallParser;
 pub use request::*;
 pub use response::*;
-use serde_json::Value;
-use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::{aici::json_schema::tool_call_regex, Constraint};
+
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
+    parser: ToolCallParser,
 }
 
-// Same as CalledFunction, but uses `parameters`
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct CalledFunctionParameters {
-    pub name: String,
-    pub parameters: HashMap<String, Value>,
-}
-
-// Same as CalledFunction, but uses `arguments``
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct CalledFunctionArguments {
-    pub name: String,
-    pub arguments: HashMap<String, Value>,
+/// Tool calls already sent for a streaming sequence.
+#[derive(Debug, Default)]
+pub(crate) struct ToolCallStreamState {
+    content_sent: usize,
+    // Per call, its ID and the length of the arguments sent.
+    calls: Vec<(String, usize)>,
 }
 
 impl ToolCallingMatcher {
-    pub fn new(tool_choice: ToolChoice) -> anyhow::Result<Self> {
-        Ok(Self { tool_choice })
+    pub fn new(tool_choice: ToolChoice, parser: ToolCallParser) -> anyhow::Result<Self> {
+        Ok(Self {
+            tool_choice,
+            parser,
+        })
     }
 
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
@@ -35,61 +35,240 @@ impl ToolCallingMatcher {
             return Ok(Vec::new());
         }
 
-        if let Ok(deser) = serde_json::from_str::<CalledFunctionParameters>(message) {
-            let id = format!("call-{}", Uuid::new_v4());
-            Ok(vec![ToolCallResponse {
-                id,
+        let calls = self
+            .parser
+            .scan(message.trim_start())
+            .calls
+            .into_iter()
+            .filter(|call| call.complete)
+            .map(|call| ToolCallResponse {
+                id: format!("call-{}", Uuid::new_v4()),
                 tp: ToolCallType::Function,
                 function: CalledFunction {
-                    name: deser.name,
-                    arguments: serde_json::to_string(&deser.parameters)?,
+                    name: call.name.unwrap_or_default(),
+                    arguments: call.arguments,
                 },
-            }])
-        } else if let Ok(deser) = serde_json::from_str::<Vec<CalledFunctionParameters>>(message) {
-            Ok(deser
-                .into_iter()
-                .map(|deser| {
-                    let id = format!("call-{}", Uuid::new_v4());
-                    Ok(ToolCallResponse {
-                        id,
-                        tp: ToolCallType::Function,
-                        function: CalledFunction {
-                            name: deser.name,
-                            arguments: serde_json::to_string(&deser.parameters)?,
-                        },
-                    })
-                })
-                .collect::<anyhow::Result<Vec<_>>>()?)
-        } else if let Ok(deser) = serde_json::from_str::<CalledFunctionArguments>(message) {
-            let id = format!("call-{}", Uuid::new_v4());
-            Ok(vec![ToolCallResponse {
-                id,
-                tp: ToolCallType::Function,
-                function: CalledFunction {
-                    name: deser.name,
-                    arguments: serde_json::to_string(&deser.arguments)?,
+            })
+            .collect::<Vec<_>>();
+
+        if calls.is_empty()
+            && matches!(self.tool_choice, ToolChoice::Required | ToolChoice::Tool(_))
+        {
+            anyhow::bail!("Tool choice was required but no tools were called.")
+        }
+        Ok(calls)
+    }
+
+    /// The constraint which makes the model call one of `tools`, if the tool choice requires a
+    /// call. The name of the function and its arguments must match one of the declared tools.
+    pub(crate) fn constraint(&self, tools: &[Tool]) -> anyhow::Result<Constraint> {
+        let tools = match &self.tool_choice {
+            ToolChoice::None | ToolChoice::Auto => return Ok(Constraint::None),
+            ToolChoice::Required => tools.iter().collect::<Vec<_>>(),
+            // The tool choice may only name the function, so use the declared tool.
+            ToolChoice::Tool(tool) => {
+                match tools.iter().find(|t| t.function.name == tool.function.name) {
+                    Some(tool) => vec![tool],
+                    None => anyhow::bail!(
+                        "Tool choice `{}` is not one of the provided tools.",
+                        tool.function.name
+                    ),
+                }
+            }
+        };
+        let tools = tools
+            .into_iter()
+            .map(|tool| {
+                let parameters = tool
+                    .function
+                    .parameters
+                    .as_ref()
+                    .map(serde_json::to_value)
+                    .transpose()?;
+                Ok((tool.function.name.as_str(), parameters))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let call = tool_call_regex(&tools, self.parser.arguments_key())?;
+        Ok(Constraint::Regex(self.parser.call_regex(&call)))
+    }
+
+    /// The text before the tool calls of `message`, if any.
+    pub fn get_content(&self, message: &str) -> Option<String> {
+        let message = message.trim_start();
+        let content = message[..self.parser.scan(message).content_end].trim_end();
+        (!content.is_empty()).then(|| content.to_string())
+    }
+
+    /// The content and tool calls generated since the last delta of a streaming sequence.
+    ///
+    /// Text which may be part of a tool call is held back until it is known. Once the sequence
+    /// is done, text which did not turn out to be tool calls is sent as content.
+    pub(crate) fn get_delta(
+        &self,
+        state: &mut ToolCallStreamState,
+        message: &str,
+        is_done: bool,
+    ) -> (String, Option<Vec<ToolCallDelta>>) {
+        let message = message.trim_start();
+        if matches!(self.tool_choice, ToolChoice::None) {
+            let content = message[state.content_sent..].to_string();
+            state.content_sent = message.len();
+            return (content, None);
+        }
+
+        let scan = self.parser.scan(message);
+        let mut deltas = Vec::new();
+        // Calls are sent in order, once their name and the start of their arguments are known.
+        for (index, call) in scan.calls.iter().enumerate() {
+            let (Some(name), true) = (&call.name, call.has_arguments) else {
+                break;
+            };
+            let mut delta = ToolCallDelta {
+                index,
+                id: None,
+                tp: None,
+                function: CalledFunctionDelta {
+                    name: None,
+                    arguments: String::new(),
                 },
-            }])
-        } else if let Ok(deser) = serde_json::from_str::<Vec<CalledFunctionArguments>>(message) {
-            Ok(deser
-                .into_iter()
-                .map(|deser| {
-                    let id = format!("call-{}", Uuid::new_v4());
-                    Ok(ToolCallResponse {
-                        id,
-                        tp: ToolCallType::Function,
-                        function: CalledFunction {
-                            name: deser.name,
-                            arguments: serde_json::to_string(&deser.arguments)?,
-                        },
-                    })
-                })
-                .collect::<anyhow::Result<Vec<_>>>()?)
+            };
+            if index == state.calls.len() {
+                let id = format!("call-{}", Uuid::new_v4());
+                delta.id = Some(id.clone());
+                delta.tp = Some(ToolCallType::Function);
+                delta.function.name = Some(name.clone());
+                state.calls.push((id, 0));
+            }
+            let sent = &mut state.calls[index].1;
+            if let Some(arguments) = call.arguments.get(*sent..) {
+                delta.function.arguments = arguments.to_string();
+                *sent = call.arguments.len();
+            }
+            if delta.id.is_some() || !delta.function.arguments.is_empty() {
+                deltas.push(delta);
+            }
+        }
+
+        let content_end = if is_done && !scan.calls.iter().any(|call| call.complete) {
+            message.len()
         } else {
-            if matches!(self.tool_choice, ToolChoice::Tool(_)) {
-                anyhow::bail!("Tool choice was required but no tools were called.")
+            scan.content_end
+        };
+        let content = message
+            .get(state.content_sent..content_end)
+            .unwrap_or_default()
+            .to_string();
+        state.content_sent = state.content_sent.max(content_end);
+
+        (content, (!deltas.is_empty()).then_some(deltas))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Tool, ToolCallParser, ToolCallStreamState, ToolCallingMatcher, ToolChoice};
+    use crate::Constraint;
+
+    #[test]
+    fn test_tool_call_formats() {
+        let cases = [
+            (
+                ToolCallParser::Json,
+                r#"{"name": "get_weather", "parameters": {"city": "Paris"}}"#,
+                None,
+            ),
+            (
+                ToolCallParser::Hermes,
+                "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+                Some("Let me check."),
+            ),
+            (
+                ToolCallParser::Mistral,
+                r#"[TOOL_CALLS][{"name": "get_weather", "arguments": {"city": "Paris"}}]"#,
+                None,
+            ),
+            (
+                ToolCallParser::Llama3,
+                r#"<|python_tag|>{"name": "get_weather", "parameters": {"city": "Paris"}}"#,
+                None,
+            ),
+        ];
+        for (parser, message, content) in cases {
+            let matcher = ToolCallingMatcher::new(ToolChoice::Auto, parser).unwrap();
+            let calls = matcher.get_call(message).unwrap();
+            assert_eq!(calls.len(), 1, "{parser}");
+            assert_eq!(calls[0].function.name, "get_weather");
+            assert_eq!(calls[0].function.arguments, r#"{"city": "Paris"}"#);
+            assert_eq!(matcher.get_content(message).as_deref(), content, "{parser}");
+        }
+
+        // Not a tool call
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto, ToolCallParser::Json).unwrap();
+        assert!(matcher.get_call(r#"{"name": "Bob"}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tool_call_streaming_deltas() {
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto, ToolCallParser::Hermes).unwrap();
+        let message = "Sure.<tool_call>{\"name\": \"a\", \"arguments\": {\"x\": [1, \"}\"]}}</tool_call>\n<tool_call>{\"name\": \"b\", \"arguments\": {}}</tool_call>";
+
+        let mut state = ToolCallStreamState::default();
+        let mut content = String::new();
+        let mut names = Vec::new();
+        let mut arguments = Vec::<String>::new();
+        for end in 1..=message.len() {
+            let (delta, tool_calls) =
+                matcher.get_delta(&mut state, &message[..end], end == message.len());
+            content.push_str(&delta);
+            for call in tool_calls.unwrap_or_default() {
+                if let Some(name) = call.function.name {
+                    assert_eq!(call.index, names.len());
+                    names.push(name);
+                    arguments.push(String::new());
+                }
+                arguments[call.index].push_str(&call.function.arguments);
             }
-            Ok(Vec::new())
         }
+        assert_eq!(content, "Sure.");
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(arguments, [r#"{"x": [1, "}"]}"#, "{}"]);
+
+        // Text which only looks like the start of a tool call is sent once done.
+        let mut state = ToolCallStreamState::default();
+        let (delta, _) = matcher.get_delta(&mut state, "a <tool_", false);
+        assert_eq!(delta, "a ");
+        let (delta, _) = matcher.get_delta(&mut state, "a <tool_x", true);
+        assert_eq!(delta, "<tool_x");
+    }
+
+    #[test]
+    fn test_required_tool_call_rejects_unsupported_schemas() {
+        let tools: Vec<Tool> = serde_json::from_value(json!([{
+            "type": "function",
+            "function": {
+                "name": "set_volume",
+                "parameters": {
+                    "type": "object",
+                    "properties": { "level": { "type": "integer", "minimum": 0 } },
+                    "required": ["level"]
+                }
+            }
+        }]))
+        .unwrap();
+
+        let matcher = ToolCallingMatcher::new(ToolChoice::Required, ToolCallParser::Json).unwrap();
+        let Err(err) = matcher.constraint(&tools) else {
+            panic!("expected the schema to be rejected");
+        };
+        assert!(format!("{err:#}").contains("`minimum`"), "{err:#}");
+
+        // Without a required call, the schemas are not compiled.
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto, ToolCallParser::Json).unwrap();
+        assert!(matches!(
+            matcher.constraint(&tools).unwrap(),
+            Constraint::None
+        ));
     }
 }
@@ -0,0 +1,511 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::Deserialize;
+
+/// Format in which a model emits its tool calls.
+///
+/// Each call is a JSON object with a `name` and its `arguments` (or `parameters`). The formats
+/// differ in how the calls are introduced. Markers which are special tokens are not part of the
+/// decoded output, so formats whose marker is a special token also accept calls at the start of
+/// the message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ToolCallParser {
+    /// The message is a JSON object or an array of objects.
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    /// Hermes: each call is wrapped in `<tool_call>` and `</tool_call>`. Qwen 2.5 uses this format.
+    #[serde(rename = "hermes", alias = "qwen")]
+    Hermes,
+    /// Mistral: `[TOOL_CALLS]` followed by an array of calls.
+    #[serde(rename = "mistral")]
+    Mistral,
+    /// Llama 3.1: an optional `<|python_tag|>` followed by calls separated by `;`.
+    #[serde(rename = "llama3")]
+    Llama3,
+}
+
+impl Display for ToolCallParser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Hermes => write!(f, "hermes"),
+            Self::Mistral => write!(f, "mistral"),
+            Self::Llama3 => write!(f, "llama3"),
+        }
+    }
+}
+
+impl FromStr for ToolCallParser {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "hermes" | "qwen" => Ok(Self::Hermes),
+            "mistral" => Ok(Self::Mistral),
+            "llama3" | "llama" => Ok(Self::Llama3),
+            other => Err(format!("Tool call parser `{other}` is not supported.")),
+        }
+    }
+}
+
+/// Strings which may introduce or separate tool calls.
+const SEPARATORS: &[&str] = &[
+    "<tool_call>",
+    "</tool_call>",
+    "[TOOL_CALLS]",
+    "<|python_tag|>",
+    ",",
+    ";",
+    "[",
+    "]",
+];
+
+/// A tool call in the model output, which may still be being generated.
+#[derive(Debug)]
+pub(crate) struct ScannedCall {
+    pub(crate) name: Option<String>,
+    /// If the `arguments` key has been generated.
+    pub(crate) has_arguments: bool,
+    /// The arguments generated so far, as JSON.
+    pub(crate) arguments: String,
+    pub(crate) complete: bool,
+}
+
+/// Tool calls found in the model output.
+#[derive(Debug)]
+pub(crate) struct Scan {
+    /// Byte length of the text before the tool calls. Text which may turn out to be the start of
+    /// a tool call is not included.
+    pub(crate) content_end: usize,
+    /// The calls after the content, in order.
+    pub(crate) calls: Vec<ScannedCall>,
+}
+
+impl ToolCallParser {
+    /// Pick the parser matching the tool call format a chat template renders.
+    pub fn from_chat_template(template: &str) -> Self {
+        if template.contains("<tool_call>") {
+            Self::Hermes
+        } else if template.contains("[TOOL_CALLS]") {
+            Self::Mistral
+        } else if template.contains("<|python_tag|>") || template.contains("ipython") {
+            Self::Llama3
+        } else {
+            Self::Json
+        }
+    }
+
+    fn markers(&self) -> &'static [&'static str] {
+        match self {
+            Self::Json => &[],
+            Self::Hermes => &["<tool_call>"],
+            Self::Mistral => &["[TOOL_CALLS]"],
+            Self::Llama3 => &["<|python_tag|>"],
+        }
+    }
+
+    /// Key of the call arguments which the model is prompted with.
+    pub(crate) fn arguments_key(&self) -> &'static str {
+        match self {
+            Self::Llama3 => "parameters",
+            Self::Json | Self::Hermes | Self::Mistral => "arguments",
+        }
+    }
+
+    /// Regex for a message made of one tool call, given the regex of the call object. Markers
+    /// which are special tokens cannot be generated under a constraint, so they are left out.
+    pub(crate) fn call_regex(&self, call: &str) -> String {
+        match self {
+            Self::Json | Self::Llama3 => call.to_string(),
+            Self::Hermes => format!(
+                "{}\n?{call}\n?{}",
+                regex::escape("<tool_call>"),
+                regex::escape("</tool_call>")
+            ),
+            Self::Mistral => format!("\\[{call}\\]"),
+        }
+    }
+
+    /// If calls may start the message without a marker.
+    fn allows_bare_calls(&self) -> bool {
+        !matches!(self, Self::Hermes)
+    }
+
+    /// Find the tool calls in `text`, which should not start with whitespace.
+    pub(crate) fn scan(&self, text: &str) -> Scan {
+        let start = if self.allows_bare_calls() && text.starts_with(['{', '[']) {
+            Some(0)
+        } else {
+            self.markers().iter().filter_map(|m| text.find(m)).min()
+        };
+        let Some(start) = start else {
+            // The end of the text may be the start of a marker.
+            let held = self
+                .markers()
+                .iter()
+                .map(|m| partial_prefix_len(text, m))
+                .max()
+                .unwrap_or(0);
+            return Scan {
+                content_end: text.len() - held,
+                calls: Vec::new(),
+            };
+        };
+        match scan_calls(text, start) {
+            Some(calls) => Scan {
+                content_end: start,
+                calls,
+            },
+            None => Scan {
+                content_end: text.len(),
+                calls: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Length of the longest suffix of `text` which is a proper prefix of `marker`.
+fn partial_prefix_len(text: &str, marker: &str) -> usize {
+    (1..marker.len())
+        .rev()
+        .find(|n| text.ends_with(&marker[..*n]))
+        .unwrap_or(0)
+}
+
+/// Scan the calls starting at `i`. Returns `None` if the text there is not made of tool calls.
+fn scan_calls(text: &str, mut i: usize) -> Option<Vec<ScannedCall>> {
+    let b = text.as_bytes();
+    let mut calls = Vec::new();
+    loop {
+        i = skip_whitespace(b, i);
+        if let Some(sep) = SEPARATORS.iter().find(|s| text[i..].starts_with(**s)) {
+            i += sep.len();
+            continue;
+        }
+        if i == b.len() || SEPARATORS.iter().any(|s| s.starts_with(&text[i..])) {
+            return Some(calls);
+        }
+        if b[i] != b'{' {
+            return None;
+        }
+        let (call, end) = scan_call(text, i)?;
+        match end {
+            Some(end) if call.name.is_some() && call.has_arguments => {
+                calls.push(call);
+                i = end;
+            }
+            Some(_) => return None,
+            None => {
+                calls.push(call);
+                return Some(calls);
+            }
+        }
+    }
+}
+
+/// Scan the call object starting at `i`, returning the end of the object if it is complete.
+fn scan_call(text: &str, i: usize) -> Option<(ScannedCall, Option<usize>)> {
+    let b = text.as_bytes();
+    let mut call = ScannedCall {
+        name: None,
+        has_arguments: false,
+        arguments: String::new(),
+        complete: false,
+    };
+    let mut j = i + 1;
+    loop {
+        j = skip_whitespace(b, j);
+        if j == b.len() {
+            return Some((call, None));
+        }
+        match b[j] {
+            b'}' => {
+                serde_json::from_str::<serde_json::Value>(&text[i..=j]).ok()?;
+                call.complete = true;
+                return Some((call, Some(j + 1)));
+            }
+            b',' => {
+                j += 1;
+                continue;
+            }
+            b'"' => (),
+            _ => return None,
+        }
+
+        let Some(key_end) = scan_string(b, j) else {
+            return Some((call, None));
+        };
+        let key = serde_json::from_str::<String>(&text[j..key_end]).ok()?;
+        j = skip_whitespace(b, key_end);
+        if j == b.len() {
+            return Some((call, None));
+        }
+        if b[j] != b':' {
+            return None;
+        }
+        j = skip_whitespace(b, j + 1);
+        if j == b.len() {
+            return Some((call, None));
+        }
+
+        let value_start = j;
+        let value_end = scan_value(b, j)?;
+        if matches!(key.as_str(), "arguments" | "parameters") {
+            call.has_arguments = true;
+        }
+        match (key.as_str(), value_end) {
+            ("name", Some(end)) => {
+                call.name = Some(serde_json::from_str::<String>(&text[value_start..end]).ok()?);
+            }
+            ("arguments" | "parameters", Some(end)) if b[value_start] == b'"' => {
+                // Arguments encoded as a JSON string
+                call.arguments = serde_json::from_str::<String>(&text[value_start..end]).ok()?;
+            }
+            ("arguments" | "parameters", end) if b[value_start] != b'"' => {
+                call.arguments = text[value_start..end.unwrap_or(b.len())].to_string();
+            }
+            _ => (),
+        }
+        match value_end {
+            Some(end) => j = end,
+            None => return Some((call, None)),
+        }
+    }
+}
+
+fn skip_whitespace(b: &[u8], mut i: usize) -> usize {
+    while i < b.len() && b[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// End of the JSON string starting at `i`, if it is complete.
+fn scan_string(b: &[u8], i: usize) -> Option<usize> {
+    let mut j = i + 1;
+    while j < b.len() {
+        match b[j] {
+            b'\\' => j += 2,
+            b'"' => return Some(j + 1),
+            _ => j += 1,
+        }
+    }
+    None
+}
+
+/// End of the JSON value starting at `i`: `Some(None)` if it is not complete yet, and `None` if
+/// it is not valid.
+fn scan_value(b: &[u8], i: usize) -> Option<Option<usize>> {
+    match b[i] {
+        b'"' => Some(scan_string(b, i)),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut j = i;
+            while j < b.len() {
+                match b[j] {
+                    b'"' => match scan_string(b, j) {
+                        Some(end) => {
+                            j = end;
+                            continue;
+                        }
+                        None => return Some(None),
+                    },
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(Some(j + 1));
+                        }
+                    }
+                    _ => (),
+                }
+                j += 1;
+            }
+            Some(None)
+        }
+        b'}' | b']' | b',' | b':' => None,
+        _ => {
+            let mut j = i;
+            while j < b.len() && !matches!(b[j], b',' | b'}' | b']') && !b[j].is_ascii_whitespace()
+            {
+                j += 1;
+            }
+            Some((j < b.len()).then_some(j))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{partial_prefix_len, ToolCallParser};
+
+    /// Scan `text` and check the content before the calls, and the `(name, arguments, complete)`
+    /// of each call.
+    fn check(parser: ToolCallParser, text: &str, content: &str, calls: &[(&str, &str, bool)]) {
+        let scan = parser.scan(text);
+        assert_eq!(&text[..scan.content_end], content, "{parser}: {text}");
+        let scanned = scan
+            .calls
+            .iter()
+            .map(|call| {
+                (
+                    call.name.as_deref().unwrap_or_default(),
+                    call.arguments.as_str(),
+                    call.complete,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(scanned, calls, "{parser}: {text}");
+    }
+
+    #[test]
+    fn mistral() {
+        let cases: &[(&str, &str, &[(&str, &str, bool)])] = &[
+            (
+                r#"[TOOL_CALLS][{"name": "a", "arguments": {"x": 1}}, {"name": "b", "arguments": {}}]"#,
+                "",
+                &[("a", r#"{"x": 1}"#, true), ("b", "{}", true)],
+            ),
+            (
+                r#"Sure.[TOOL_CALLS][{"name": "a", "arguments": {"x": 1}}]"#,
+                "Sure.",
+                &[("a", r#"{"x": 1}"#, true)],
+            ),
+            // The marker is a special token, so it may be missing from the decoded output.
+            (
+                r#"[{"name": "a", "arguments": {"x": 1}}]"#,
+                "",
+                &[("a", r#"{"x": 1}"#, true)],
+            ),
+            (
+                r#"[TOOL_CALLS][{"name": "a", "argu"#,
+                "",
+                &[("a", "", false)],
+            ),
+            ("Hi [TOOL_", "Hi ", &[]),
+            ("[TOOL_CALLS] not a call", "[TOOL_CALLS] not a call", &[]),
+        ];
+        for (text, content, calls) in cases {
+            check(ToolCallParser::Mistral, text, content, calls);
+        }
+    }
+
+    #[test]
+    fn llama3() {
+        let cases: &[(&str, &str, &[(&str, &str, bool)])] = &[
+            (
+                r#"<|python_tag|>{"name": "a", "parameters": {"x": 1}}; {"name": "b", "parameters": {"y": "z"}}"#,
+                "",
+                &[("a", r#"{"x": 1}"#, true), ("b", r#"{"y": "z"}"#, true)],
+            ),
+            (
+                r#"{"name": "a", "parameters": {}};{"name": "b", "parameters": {"y": [1, 2]"#,
+                "",
+                &[("a", "{}", true), ("b", r#"{"y": [1, 2]"#, false)],
+            ),
+            ("<|python", "", &[]),
+            (
+                r#"{"name": "a", "parameters": {"x": 1}} and some text"#,
+                r#"{"name": "a", "parameters": {"x": 1}} and some text"#,
+                &[],
+            ),
+        ];
+        for (text, content, calls) in cases {
+            check(ToolCallParser::Llama3, text, content, calls);
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        let cases: &[(&str, &str, &[(&str, &str, bool)])] = &[
+            // Arguments encoded as a JSON string
+            (
+                r#"{"name": "a", "arguments": "{\"x\": 1}"}"#,
+                "",
+                &[("a", r#"{"x": 1}"#, true)],
+            ),
+            // Escaped quotes and braces in strings do not end the arguments.
+            (
+                r#"{"name": "a", "arguments": {"text": "say \"}\" and {"}}"#,
+                "",
+                &[("a", r#"{"text": "say \"}\" and {"}"#, true)],
+            ),
+            (
+                r#"{"arguments": {"s": "]}"}, "name": "a"}"#,
+                "",
+                &[("a", r#"{"s": "]}"}"#, true)],
+            ),
+        ];
+        for (text, content, calls) in cases {
+            check(ToolCallParser::Json, text, content, calls);
+        }
+    }
+
+    #[test]
+    fn partial_and_invalid() {
+        let cases: &[(ToolCallParser, &str, &str, &[(&str, &str, bool)])] = &[
+            (
+                ToolCallParser::Hermes,
+                "<tool_call>\n{\"name\": \"a\", \"arguments\": {\"x\": ",
+                "",
+                &[("a", "{\"x\": ", false)],
+            ),
+            // The end of the text may be the start of a marker, so it is held back.
+            (
+                ToolCallParser::Hermes,
+                "Let me check.<tool_",
+                "Let me check.",
+                &[],
+            ),
+            // Hermes calls need the marker.
+            (
+                ToolCallParser::Hermes,
+                r#"{"name": "a", "arguments": {}}"#,
+                r#"{"name": "a", "arguments": {}}"#,
+                &[],
+            ),
+            (
+                ToolCallParser::Hermes,
+                "<tool_call>not json</tool_call>",
+                "<tool_call>not json</tool_call>",
+                &[],
+            ),
+            // A call needs a name and arguments.
+            (
+                ToolCallParser::Json,
+                r#"{"name": "a"}"#,
+                r#"{"name": "a"}"#,
+                &[],
+            ),
+            (
+                ToolCallParser::Json,
+                r#"{"name": "a", "arguments": {}, "extra" 3}"#,
+                r#"{"name": "a", "arguments": {}, "extra" 3}"#,
+                &[],
+            ),
+            (ToolCallParser::Json, "Hello", "Hello", &[]),
+        ];
+        for (parser, text, content, calls) in cases {
+            check(*parser, text, content, calls);
+        }
+    }
+
+    #[test]
+    fn marker_prefix() {
+        assert_eq!(partial_prefix_len("abc<tool", "<tool_call>"), 5);
+        assert_eq!(partial_prefix_len("abc<", "<tool_call>"), 1);
+        assert_eq!(partial_prefix_len("abc", "<tool_call>"), 0);
+        // A complete marker is not a partial one.
+        assert_eq!(partial_prefix_len("abc<tool_call>", "<tool_call>"), 0);
+    }
+
+    #[test]
+    fn qwen_is_hermes() {
+        assert_eq!("qwen".parse::<ToolCallParser>(), Ok(ToolCallParser::Hermes));
+        assert_eq!(
+            serde_json::from_str::<ToolCallParser>(r#""qwen""#).unwrap(),
+            ToolCallParser::Hermes
+        );
+    }
+}
@@ -16,6 +16,9 @@ pub enum ToolChoice {
     #[serde(rename = "auto")]
     /// Allow automatic selection of any given tool, or none.
     Auto,
+    #[serde(rename = "required")]
+    /// Force selection of one of the given tools.
+    Required,
     #[serde(untagged)]
     /// Force selection of a given tool.
     Tool(Tool),
@@ -31,3 +31,26 @@ pub struct ToolCallResponse {
     pub tp: ToolCallType,
     pub function: CalledFunction,
 }
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CalledFunctionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+/// Part of a tool call in a streaming chunk. The first delta of a call has its `id`, `type` and
+/// function name, and the arguments are split over the following deltas.
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub tp: Option<ToolCallType>,
+    pub function: CalledFunctionDelta,
+}
@@ -146,6 +146,19 @@ pub(crate) fn from_mmaped_safetensors<'a>(
     ))
 }
 
+/// Load the weights of a single LoRA adapter.
+pub(crate) fn load_adapter<'a>(
+    path: &PathBuf,
+    dtype: DType,
+    device: &Device,
+    silent: bool,
+) -> Result<VarBuilder<'a>> {
+    let loader = Common::new();
+    let loaded_tensors =
+        loader.load_tensors_from_path(path, device, Some(dtype), silent, |_| true, |_| false)?;
+    Ok(VarBuilder::from_tensors(loaded_tensors, dtype, device))
+}
+
 pub(crate) fn load_preload_adapters<'a>(
     paths: &Option<HashMap<String, (PathBuf, LoraConfig)>>,
     dtype: DType,
@@ -155,22 +168,9 @@ pub(crate) fn load_preload_adapters<'a>(
     if let Some(paths) = paths {
         let mut map = HashMap::new();
         for (name, (path, config)) in paths {
-            let loader = Common::new();
-            let loaded_tensors = loader.load_tensors_from_path(
-                path,
-                device,
-                Some(dtype),
-                silent,
-                |_| true,
-                |_| false,
-            )?;
-
             map.insert(
                 name.clone(),
-                (
-                    VarBuilder::from_tensors(loaded_tensors, dtype, device),
-                    config.clone(),
-                ),
+                (load_adapter(path, dtype, device, silent)?, config.clone()),
             );
         }
         Ok(Some(map))
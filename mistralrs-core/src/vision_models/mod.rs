@@ -13,6 +13,7 @@ pub(crate) use phi3::phi3_inputs_processor;
 pub(crate) mod preprocessor_config;
 pub(crate) mod processor_config;
 pub(crate) mod qwen2vl;
+pub(crate) mod video;
 pub(crate) use llava::llava15;
 pub(crate) use llava::llava_inputs_processor;
 pub(crate) use llava::llava_next;
@@ -68,6 +68,10 @@ impl Processor for Qwen2VLProcessor {
     fn template_action(&self) -> MessagesAction {
         MessagesAction::FlattenOnlyText
     }
+
+    fn supports_videos(&self) -> bool {
+        true
+    }
 }
 
 fn replace_first_occurrence(text: &str, to_replace: &str, replacement: &str) -> String {
@@ -211,6 +215,14 @@ impl InputsProcessor for Qwen2VLImageProcessor {
         let has_images = input_seqs
             .iter()
             .all(|seq| seq.images().is_some_and(|images| !images.is_empty()));
+        let has_videos = input_seqs
+            .iter()
+            .all(|seq| seq.videos().is_some_and(|videos| !videos.is_empty()));
+        if has_images && has_videos {
+            return Box::new(std::iter::once(Err(anyhow::Error::msg(
+                "Qwen2-VL does not support images and videos in the same request.",
+            ))));
+        }
 
         let (
             new_input,
@@ -222,7 +234,7 @@ impl InputsProcessor for Qwen2VLImageProcessor {
             input_ids_searching,
             image_nums,
             video_nums,
-        ) = if has_images {
+        ) = if has_images || has_videos {
             let mut pixel_values_accum = Vec::new();
             let mut image_grid_thw_accum = Vec::new();
             let mut video_grid_thw_accum = Vec::new();
@@ -260,9 +272,8 @@ impl InputsProcessor for Qwen2VLImageProcessor {
                             cols: _,
                         } = self
                             .preprocess(
-                                seq.clone_images()
-                                    .expect("Need to have images by this point."),
-                                vec![],
+                                seq.clone_images().unwrap_or_default(),
+                                seq.clone_videos().unwrap_or_default(),
                                 config,
                                 device,
                                 (usize::MAX, usize::MAX), // Don't use it here...
@@ -497,6 +508,10 @@ impl InputsProcessor for Qwen2VLImageProcessor {
 }
 
 impl Qwen2VLImageProcessor {
+    /// Pixel budget of a video frame, and of all the frames of a video, as in `qwen-vl-utils`.
+    const VIDEO_MAX_PIXELS: usize = 768 * 28 * 28;
+    const VIDEO_TOTAL_PIXELS: usize = 24576 * 28 * 28;
+
     fn smart_resize(
         &self,
         height: usize,
@@ -542,6 +557,7 @@ impl Qwen2VLImageProcessor {
         config: &PreProcessorConfig,
         device: &Device,
         (mut height, mut width): (u32, u32),
+        max_pixels: usize,
     ) -> candle_core::Result<(Tensor, (u32, u32, u32))> {
         let mut processed_images = Vec::new();
 
@@ -562,7 +578,7 @@ impl Qwen2VLImageProcessor {
                     config.patch_size.context("Require `patch_size`.")?
                         * config.merge_size.context("Require `merge_size`")?,
                     config.min_pixels.context("Require `min_pixels`")?,
-                    config.max_pixels.context("Require `max_pixels`")?,
+                    max_pixels,
                 )?;
                 height = resized_height as u32;
                 width = resized_width as u32;
@@ -601,9 +617,16 @@ impl Qwen2VLImageProcessor {
         let merge_size = config.merge_size.context("Require `merge_size")?;
         // Important to write it!
         *self.merge_size.write().unwrap() = Some(merge_size);
-        // Image
-        if patches.dim(0)? == 1 {
-            patches = patches.repeat((temporal_patch_size, 1, 1, 1))?;
+        // Pad with the last frame to fill the temporal patches. An image is repeated.
+        let num_frames = patches.dim(0)?;
+        if num_frames % temporal_patch_size != 0 {
+            let last = patches.i(num_frames - 1)?.unsqueeze(0)?.repeat((
+                temporal_patch_size - num_frames % temporal_patch_size,
+                1,
+                1,
+                1,
+            ))?;
+            patches = Tensor::cat(&[patches, last], 0)?;
         }
         let channel = patches.dim(1)?;
         let grid_t = patches.dim(0)? / temporal_patch_size;
@@ -665,9 +688,15 @@ impl ImagePreProcessor for Qwen2VLImageProcessor {
                 }
             }
 
+            let max_pixels = config.max_pixels.context("Require `max_pixels`")?;
             for image in images {
-                let (patches, (t, h, w)) =
-                    self.preprocess_inner(vec![image], config, device, (height, width))?;
+                let (patches, (t, h, w)) = self.preprocess_inner(
+                    vec![image],
+                    config,
+                    device,
+                    (height, width),
+                    max_pixels,
+                )?;
                 pixel_values.push(patches);
                 vision_grid_thw.push(Tensor::new(&[t, h, w], &Device::Cpu)?);
             }
@@ -702,8 +731,19 @@ impl ImagePreProcessor for Qwen2VLImageProcessor {
             }
 
             for images in videos {
+                // Limit the pixels of each frame so that the whole video fits in the budget.
+                // Frames are merged into temporal patches, which is what the budget counts.
+                let temporal_patch_size = config
+                    .temporal_patch_size
+                    .context("Require `temporal_patch_size")?;
+                let max_pixels = config
+                    .max_pixels
+                    .context("Require `max_pixels`")?
+                    .min(Self::VIDEO_MAX_PIXELS)
+                    .min(Self::VIDEO_TOTAL_PIXELS * temporal_patch_size / images.len().max(1))
+                    .max(config.min_pixels.context("Require `min_pixels`")?);
                 let (patches, (t, h, w)) =
-                    self.preprocess_inner(images, config, device, (height, width))?;
+                    self.preprocess_inner(images, config, device, (height, width), max_pixels)?;
                 pixel_values.push(patches);
                 vision_grid_thw.push(Tensor::new(&[t, h, w], &Device::Cpu)?);
             }
@@ -318,12 +318,15 @@ impl Qwen2VLModel {
             }
 
             if let Some(pixel_values_videos) = pixel_values_videos {
-                let video_embeds = self.vision.forward(
-                    &pixel_values_videos,
-                    video_grid_thw
-                        .as_ref()
-                        .context("pixel_values_videos require video_grid_thw")?,
-                )?;
+                let video_embeds = self
+                    .vision
+                    .forward(
+                        &pixel_values_videos,
+                        video_grid_thw
+                            .as_ref()
+                            .context("pixel_values_videos require video_grid_thw")?,
+                    )?
+                    .to_dtype(self.text.dtype)?;
 
                 for (batch, batch_ids) in continuous_vid_pad.into_iter().enumerate() {
                     let mut last_end = 0;
@@ -334,7 +337,7 @@ impl Qwen2VLModel {
                                 .i((last_end..last_end + (end - start), ..))?
                                 .unsqueeze(0)?,
                         )?;
-                        last_end = end - start;
+                        last_end += end - start;
                     }
                 }
             }
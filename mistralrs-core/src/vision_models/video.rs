@@ -0,0 +1,329 @@
+//! Decode videos into sampled frames for models with video input.
+
+use std::{
+    fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, DynamicImage, Frames, ImageFormat,
+};
+use uuid::Uuid;
+
+/// Frames per second sampled from a video, as used by Qwen2-VL.
+pub const DEFAULT_VIDEO_FPS: f64 = 2.0;
+/// Maximum number of frames sampled from a video. Longer videos are sampled more sparsely.
+pub const DEFAULT_MAX_VIDEO_FRAMES: usize = 32;
+
+/// Browsers show animation frames without a delay for 100ms, so do the same.
+const DEFAULT_FRAME_DELAY_SECS: f64 = 0.1;
+
+/// The demuxers `ffmpeg` may use: video containers, but no playlists like HLS or concat, which
+/// read other files or URLs.
+const FFMPEG_DEMUXERS: &str = "mov,mp4,m4a,3gp,3g2,mj2,matroska,webm,avi,mpeg,mpegts,flv,ogg,asf";
+
+/// `ffmpeg` and `ffprobe` are killed after this long, such as on a crafted video.
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Decode the video at `path` into frames sampled at `fps`, keeping at most `max_frames` evenly
+/// spaced frames.
+///
+/// Animated GIF, PNG and WebP files are decoded directly, and a still image is a video of one
+/// frame. Other formats are decoded with `ffmpeg`, which must be installed, and which is stopped
+/// after 60 seconds.
+pub fn load_video(
+    path: impl AsRef<Path>,
+    fps: f64,
+    max_frames: usize,
+) -> Result<Vec<DynamicImage>> {
+    let path = path.as_ref();
+    check_sampling(fps, max_frames)?;
+    let bytes =
+        fs::read(path).with_context(|| format!("Could not read video at {}", path.display()))?;
+    match decode_animation(&bytes, fps, max_frames)? {
+        Some(frames) => Ok(frames),
+        None => decode_with_ffmpeg(path, fps, max_frames),
+    }
+}
+
+/// Like [`load_video`], but from the contents of the video file.
+pub fn load_video_from_memory(
+    bytes: &[u8],
+    fps: f64,
+    max_frames: usize,
+) -> Result<Vec<DynamicImage>> {
+    check_sampling(fps, max_frames)?;
+    match decode_animation(bytes, fps, max_frames)? {
+        Some(frames) => Ok(frames),
+        None => {
+            // Most containers must be seekable, so `ffmpeg` reads from a file and not a pipe.
+            let path = temp_path("video");
+            fs::write(&path, bytes)?;
+            let frames = decode_with_ffmpeg(&path, fps, max_frames);
+            fs::remove_file(&path)?;
+            frames
+        }
+    }
+}
+
+fn check_sampling(fps: f64, max_frames: usize) -> Result<()> {
+    if fps.is_nan() || fps <= 0.0 {
+        bail!("Video sampling rate must be positive, got {fps}");
+    }
+    if max_frames == 0 {
+        bail!("At least one video frame must be sampled");
+    }
+    Ok(())
+}
+
+fn temp_path(kind: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mistralrs-{kind}-{}", Uuid::new_v4()))
+}
+
+/// Decode the formats which the `image` crate supports. Returns `None` for other formats.
+fn decode_animation(
+    bytes: &[u8],
+    fps: f64,
+    max_frames: usize,
+) -> Result<Option<Vec<DynamicImage>>> {
+    let frames = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return Ok(Some(vec![image::load_from_memory(bytes)?]));
+            }
+            decoder.apng()?.into_frames()
+        }
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if !decoder.has_animation() {
+                return Ok(Some(vec![image::load_from_memory(bytes)?]));
+            }
+            decoder.into_frames()
+        }
+        Ok(_) => return Ok(Some(vec![image::load_from_memory(bytes)?])),
+        Err(_) => return Ok(None),
+    };
+    sample_frames(frames, fps, max_frames).map(Some)
+}
+
+/// Sample the frame shown every `1 / fps` seconds, starting with the first one, and keep at most
+/// `max_frames` evenly spaced samples. Only the sampled frames are kept while decoding.
+fn sample_frames(frames: Frames<'_>, fps: f64, max_frames: usize) -> Result<Vec<DynamicImage>> {
+    let mut samples = Vec::new();
+    // The time at which the current frame stops being shown
+    let mut end = 0.0;
+    for frame in frames {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        end += match f64::from(numer) / f64::from(denom) / 1000.0 {
+            delay if delay > 0.0 => delay,
+            _ => DEFAULT_FRAME_DELAY_SECS,
+        };
+        // The frame is the sample of every sample time before it stops being shown.
+        let mut image = None;
+        while (samples.len() as f64) / fps < end {
+            let image =
+                image.get_or_insert_with(|| DynamicImage::ImageRgba8(frame.buffer().clone()));
+            samples.push(image.clone());
+        }
+    }
+    if samples.is_empty() {
+        bail!("Video has no frames");
+    }
+    Ok(evenly_spaced(samples.len(), max_frames)
+        .map(|i| samples[i].clone())
+        .collect())
+}
+
+fn decode_with_ffmpeg(path: &Path, fps: f64, max_frames: usize) -> Result<Vec<DynamicImage>> {
+    let dir = temp_path("frames");
+    fs::create_dir(&dir)?;
+    let frames = run_ffmpeg(path, fps, max_frames, &dir);
+    fs::remove_dir_all(&dir)?;
+    frames
+}
+
+fn run_ffmpeg(path: &Path, fps: f64, max_frames: usize, dir: &Path) -> Result<Vec<DynamicImage>> {
+    // Long videos are sampled more sparsely, so that `ffmpeg` writes about `max_frames` frames.
+    let fps = match probe_duration(path) {
+        Some(duration) => fps.min(max_frames as f64 / duration),
+        None => fps,
+    };
+    // Only read the local file, and only with demuxers which do not open other files or URLs.
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-v", "error"])
+        .args(["-protocol_whitelist", "file"])
+        .args(["-format_whitelist", FFMPEG_DEMUXERS])
+        .arg("-i")
+        .arg(path)
+        .args(["-vf", &format!("fps={fps}")])
+        .args(["-frames:v", &max_frames.to_string()])
+        .arg(dir.join("%06d.png"));
+    run_with_timeout(command, "ffmpeg")?;
+
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if paths.is_empty() {
+        bail!("Video has no frames");
+    }
+    // The frames are numbered with a fixed width, so they sort in order.
+    paths.sort();
+    evenly_spaced(paths.len(), max_frames)
+        .map(|i| Ok(image::open(&paths[i])?))
+        .collect()
+}
+
+/// The duration of the video in seconds, if `ffprobe` can tell.
+fn probe_duration(path: &Path) -> Option<f64> {
+    let mut command = Command::new("ffprobe");
+    command
+        .args(["-v", "error"])
+        .args(["-protocol_whitelist", "file"])
+        .args(["-format_whitelist", FFMPEG_DEMUXERS])
+        .args(["-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path);
+    let stdout = run_with_timeout(command, "ffprobe").ok()?;
+    let duration = String::from_utf8_lossy(&stdout)
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    (duration.is_finite() && duration > 0.0).then_some(duration)
+}
+
+/// Run `command`, killing it after [`FFMPEG_TIMEOUT`], and return its standard output.
+fn run_with_timeout(mut command: Command, name: &str) -> Result<Vec<u8>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| {
+            format!("Could not run `{name}`, which is required to decode this video format")
+        })?;
+    // Read the pipes while the command runs, so that it does not block on a full one.
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > FFMPEG_TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            bail!(
+                "`{name}` did not finish decoding the video within {}s",
+                FFMPEG_TIMEOUT.as_secs()
+            );
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        bail!(
+            "`{name}` could not decode the video: {}",
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+    Ok(stdout)
+}
+
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // The output is only used for messages, so a read error leaves what was read.
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+/// The indices of at most `max` evenly spaced items out of `n`.
+fn evenly_spaced(n: usize, max: usize) -> impl Iterator<Item = usize> {
+    let kept = n.min(max);
+    (0..kept).map(move |i| i * n / kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        codecs::gif::GifEncoder, Delay, DynamicImage, Frame, GenericImageView, Rgba, RgbaImage,
+    };
+
+    use super::load_video_from_memory;
+
+    #[test]
+    fn test_load_gif_video() {
+        // 10 frames of 250ms, each with a shade of red from its index.
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for i in 0..10u8 {
+                let image = RgbaImage::from_pixel(4, 4, Rgba([i * 20, 0, 0, 255]));
+                encoder
+                    .encode_frame(Frame::from_parts(
+                        image,
+                        0,
+                        0,
+                        Delay::from_numer_denom_ms(250, 1),
+                    ))
+                    .unwrap();
+            }
+        }
+
+        // GIF colors are quantized, so compare them approximately.
+        let red = |frame: &DynamicImage| i32::from(frame.get_pixel(0, 0).0[0]);
+
+        // At 2 fps, every other frame is sampled.
+        let frames = load_video_from_memory(&bytes, 2.0, 32).unwrap();
+        assert_eq!(frames.len(), 5);
+        for (i, frame) in (0..).step_by(2).zip(&frames) {
+            assert_eq!(frame.dimensions(), (4, 4));
+            assert!((red(frame) - i * 20).abs() <= 8);
+        }
+
+        // Long videos are sampled sparsely.
+        let frames = load_video_from_memory(&bytes, 2.0, 2).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!((red(&frames[1]) - 80).abs() <= 8);
+    }
+
+    #[test]
+    fn test_sample_short_frames() {
+        // 100 frames of 10ms, so only the first and middle ones are sampled at 2 fps.
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for i in 0..100u8 {
+                let image = RgbaImage::from_pixel(4, 4, Rgba([i * 2, 0, 0, 255]));
+                encoder
+                    .encode_frame(Frame::from_parts(
+                        image,
+                        0,
+                        0,
+                        Delay::from_numer_denom_ms(10, 1),
+                    ))
+                    .unwrap();
+            }
+        }
+
+        let red = |frame: &DynamicImage| i32::from(frame.get_pixel(0, 0).0[0]);
+        let frames = load_video_from_memory(&bytes, 2.0, 32).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(red(&frames[0]) <= 8);
+        assert!((red(&frames[1]) - 100).abs() <= 8);
+    }
+}
@@ -4,7 +4,7 @@ use crate::{
     amoe::AnyMoeBaseModelMixin,
     attention::SdpaParams,
     layers::{Llama3RotaryEmbedding, Sdpa},
-    lora::{linear_no_bias as linear, LinearLayerLike, LoraConfig, Ordering},
+    lora::{linear_no_bias as linear, AdapterOp, LinearLayerLike, LoraConfig, Ordering},
     paged_attention::ModelConfigMetadata,
     pipeline::{
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
@@ -827,34 +827,20 @@ impl NormalModel for XLoraLlama {
     fn max_seq_len(&self) -> usize {
         self.blocks[0].attn.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn apply_adapter_op(&mut self, op: &AdapterOp) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
         let mut sum = 0;
         for layer in self.blocks.iter_mut() {
-            sum += Arc::get_mut(&mut layer.attn.k_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.attn.o_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.attn.q_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.attn.v_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
-
-            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.mlp.c_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.k_proj).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj).unwrap().apply(op)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj).unwrap().apply(op)?;
         }
         Ok(sum)
     }
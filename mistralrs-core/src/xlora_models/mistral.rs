@@ -4,7 +4,7 @@ use crate::{
     amoe::AnyMoeBaseModelMixin,
     attention::SdpaParams,
     layers::Sdpa,
-    lora::{linear_no_bias, LinearLayerLike, LoraConfig, Ordering},
+    lora::{linear_no_bias, AdapterOp, LinearLayerLike, LoraConfig, Ordering},
     paged_attention::ModelConfigMetadata,
     pipeline::{
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
@@ -860,7 +860,7 @@ impl NormalModel for XLoraModel {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn apply_adapter_op(&mut self, op: &AdapterOp) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -868,26 +868,20 @@ impl NormalModel for XLoraModel {
         for layer in self.layers.iter_mut() {
             sum += Arc::get_mut(&mut layer.self_attn.k_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .apply(op)?;
             sum += Arc::get_mut(&mut layer.self_attn.o_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .apply(op)?;
             sum += Arc::get_mut(&mut layer.self_attn.q_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .apply(op)?;
             sum += Arc::get_mut(&mut layer.self_attn.v_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .apply(op)?;
 
-            sum += Arc::get_mut(&mut layer.mlp.down_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
-            sum += Arc::get_mut(&mut layer.mlp.up_proj)
-                .unwrap()
-                .activate(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.down_proj).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj).unwrap().apply(op)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj).unwrap().apply(op)?;
         }
         Ok(sum)
     }
@@ -6,7 +6,8 @@ use std::sync::Arc;
 use crate::attention::SdpaParams;
 use crate::gguf::Content;
 use crate::lora::{
-    get_lora_cfg, AdapterSwapper, LinearLayerLike, LoraConfig, Merge, Ordering, QLoraLinear,
+    get_lora_cfg, AdapterOp, AdapterSwapper, LinearLayerLike, LoraConfig, Merge, Ordering,
+    QLoraLinear,
 };
 use crate::pipeline::text_models_inputs_processor::FlashParams;
 use crate::utils::progress::NiceProgressBar;
@@ -779,21 +780,21 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn apply_adapter_op(&mut self, op: &AdapterOp) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
-            sum += layer.attention_wk.activate(&adapter_names)?;
-            sum += layer.attention_wo.activate(&adapter_names)?;
-            sum += layer.attention_wq.activate(&adapter_names)?;
-            sum += layer.attention_wv.activate(&adapter_names)?;
+            sum += layer.attention_wk.apply(op)?;
+            sum += layer.attention_wo.apply(op)?;
+            sum += layer.attention_wq.apply(op)?;
+            sum += layer.attention_wv.apply(op)?;
             match &mut layer.mlp_or_moe {
                 MlpOrMoe::Mlp(ref mut m) => {
-                    sum += m.feed_forward_w1.activate(&adapter_names)?;
-                    sum += m.feed_forward_w2.activate(&adapter_names)?;
-                    sum += m.feed_forward_w3.activate(&adapter_names)?;
+                    sum += m.feed_forward_w1.apply(op)?;
+                    sum += m.feed_forward_w2.apply(op)?;
+                    sum += m.feed_forward_w3.apply(op)?;
                 }
                 MlpOrMoe::MoE {
                     n_expert_used: _,
@@ -801,9 +802,9 @@ impl ModelWeights {
                     experts,
                 } => {
                     for expert in experts {
-                        sum += expert.feed_forward_w1.activate(&adapter_names)?;
-                        sum += expert.feed_forward_w2.activate(&adapter_names)?;
-                        sum += expert.feed_forward_w3.activate(&adapter_names)?;
+                        sum += expert.feed_forward_w1.apply(op)?;
+                        sum += expert.feed_forward_w2.apply(op)?;
+                        sum += expert.feed_forward_w3.apply(op)?;
                     }
                 }
             }
@@ -9,6 +9,7 @@ use crate::layers::CausalMasker;
 use crate::layers::RmsNorm;
 use crate::layers::Sdpa;
 use crate::lora::get_lora_cfg;
+use crate::lora::AdapterOp;
 use crate::lora::AdapterSwapper;
 use crate::lora::LinearLayerLike;
 use crate::lora::LoraConfig;
@@ -388,16 +389,16 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn apply_adapter_op(&mut self, op: &AdapterOp) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
-            sum += layer.attn_qkv.activate(&adapter_names)?;
-            sum += layer.attn_output.activate(&adapter_names)?;
-            sum += layer.mlp.ffn_down.activate(&adapter_names)?;
-            sum += layer.mlp.ffn_up.activate(&adapter_names)?;
+            sum += layer.attn_qkv.apply(op)?;
+            sum += layer.attn_output.apply(op)?;
+            sum += layer.mlp.ffn_down.apply(op)?;
+            sum += layer.mlp.ffn_up.apply(op)?;
         }
         Ok(sum)
     }
@@ -6,6 +6,7 @@ from typing import Iterator
 class ToolChoice(Enum):
     NoTools = "None"
     Auto = "Auto"
+    Required = "Required"
 
 @dataclass
 class ChatCompletionRequest:
@@ -39,6 +40,21 @@ class ChatCompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    tfs_z: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    xtc_probability: float | None = None
+    xtc_threshold: float | None = None
+    dynatemp_range: float | None = None
+    dynatemp_exponent: float | None = None
+    repetition_penalty: float | None = None
+    penalty_last_n: int | None = None
+    penalty_exclude_prompt: bool = False
+    penalty_whitelist: list[int] | None = None
+    seed: int | None = None
 
 @dataclass
 class CompletionRequest:
@@ -67,6 +83,21 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    tfs_z: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    xtc_probability: float | None = None
+    xtc_threshold: float | None = None
+    dynatemp_range: float | None = None
+    dynatemp_exponent: float | None = None
+    repetition_penalty: float | None = None
+    penalty_last_n: int | None = None
+    penalty_exclude_prompt: bool = False
+    penalty_whitelist: list[int] | None = None
+    seed: int | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -241,6 +272,7 @@ class Runner:
         prefix_cache_n: int = 16,
         token_source: str = "cache",
         speculative_gamma: int = 32,
+        speculative_adaptive_gamma: bool = False,
         which_draft: Which | None = None,
         chat_template: str | None = None,
         num_device_layers: list[str] | None = None,
@@ -263,6 +295,8 @@ class Runner:
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
             the target model. If `which_draft` is not specified, this is ignored.
+        - `speculative_adaptive_gamma` tunes the number of draft tokens per sequence from the observed acceptance rate, using
+            `speculative_gamma` as the maximum. If `which_draft` is not specified, this is ignored.
         - `which_draft` specifies which draft model to load. Setting this parameter will cause a speculative decoding model to be loaded,
             with `which` as the target (higher quality) model and `which_draft` as the draft (lower quality) model.
         - `chat_template` specifies an optional JINJA chat template.
@@ -310,9 +344,18 @@ class Runner:
         response_format: ImageGenerationResponseFormat,
         height: int = 720,
         width: int = 1280,
+        num_steps: int | None = None,
+        guidance_scale: float | None = None,
+        seed: int | None = None,
+        negative_prompt: str | None = None,
+        negative_guidance_scale: float | None = None,
     ) -> ImageGenerationResponse:
         """
         Generate an image.
+
+        `num_steps` and `guidance_scale` default to those of the model. Images generated with the
+        same `seed` and parameters are the same. The `negative_prompt` describes what the image
+        should not contain, and `negative_guidance_scale` how strongly it is avoided.
         """
 
     def send_re_isq(self, dtype: str) -> CompletionResponse:
@@ -325,6 +368,17 @@ class Runner:
         Send a request to make the specified adapters the active adapters for the model.
         """
 
+    def load_adapter(self, name: str, path: str) -> int:
+        """
+        Load a PEFT adapter from a local directory containing `adapter_config.json` and `adapter_model.safetensors`,
+        registering it as `name`. Returns the number of layers the adapter applies to.
+        """
+
+    def unload_adapter(self, name: str) -> None:
+        """
+        Unload an adapter which is not active or used by any request.
+        """
+
     def tokenize_text(self, text: str, add_speial_tokens: bool) -> list[int]:
         """
         Tokenize some text, returning raw tokens.
@@ -397,6 +451,8 @@ class Usage:
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
+    draft_tokens: int | None
+    accepted_draft_tokens: int | None
 
 @dataclass
 class ToolCallType(Enum):
@@ -453,10 +509,23 @@ class ChatCompletionResponse:
     object: str
     usage: Usage
 
+@dataclass
+class CalledFunctionDelta:
+    name: str | None
+    arguments: str
+
+@dataclass
+class ToolCallDelta:
+    index: int
+    id: str | None
+    type: ToolCallType | None
+    function: CalledFunctionDelta
+
 @dataclass
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta] | None
 
 @dataclass
 class ChunkChoice:
@@ -10,6 +10,7 @@ use std::{
     cell::RefCell,
     collections::HashMap,
     num::NonZeroUsize,
+    path::PathBuf,
     str::FromStr,
     sync::{Arc, Mutex, OnceLock},
 };
@@ -19,13 +20,15 @@ use util::{PyApiErr, PyApiResult};
 
 use candle_core::{Device, Result};
 use mistralrs_core::{
-    initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader,
-    ChatCompletionResponse, CompletionResponse, Constraint, DefaultSchedulerMethod,
-    DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata, DiffusionGenerationParams,
-    DiffusionLoaderBuilder, DiffusionSpecificConfig, DrySamplingParams, GGMLLoaderBuilder,
+    initialize_logging, paged_attn_supported, parse_isq_value, AdapterLoadRequest,
+    AdapterUnloadRequest, AnyMoeLoader, ChatCompletionResponse, CompletionResponse, Constraint,
+    DefaultSchedulerMethod, DetokenizationRequest, DeviceLayerMapMetadata, DeviceMapMetadata,
+    DiffusionGenerationParams, DiffusionLoaderBuilder, DiffusionSpecificConfig, DraftLoader,
+    DrySamplingParams, EmbeddingLoaderBuilder, EmbeddingPooling, EmbeddingResponse,
+    EmbeddingSpecificConfig, ExtendedSamplers, ExtendedSamplingFields, GGMLLoaderBuilder,
     GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse,
     ImageGenerationResponseFormat, Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder,
-    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
+    ModelCategory, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
     Request as _Request, RequestMessage, Response, ResponseOk, SamplingParams, SchedulerConfig,
     SpeculativeConfig, SpeculativeLoader, StopTokens, TokenSource, TokenizationRequest, Tool,
     Topology, VisionLoaderBuilder, VisionSpecificConfig,
@@ -37,7 +40,9 @@ mod requests;
 mod stream;
 mod util;
 mod which;
-use which::{Architecture, DiffusionArchitecture, VisionArchitecture, Which};
+use which::{
+    Architecture, DiffusionArchitecture, EmbeddingArchitecture, VisionArchitecture, Which,
+};
 
 static DEVICE: OnceLock<Result<Device>> = OnceLock::new();
 
@@ -377,6 +382,15 @@ fn parse_which(
             DiffusionLoaderBuilder::new(DiffusionSpecificConfig { use_flash_attn }, Some(model_id))
                 .build(arch.into())
         }
+        Which::EmbeddingPlain {
+            model_id,
+            arch,
+            tokenizer_json,
+            dtype: _,
+        } => {
+            EmbeddingLoaderBuilder::new(EmbeddingSpecificConfig { tokenizer_json }, Some(model_id))
+                .build(arch.into())
+        }
     })
 }
 
@@ -390,6 +404,7 @@ impl Runner {
         prefix_cache_n = 16,
         token_source = "cache",
         speculative_gamma = 32,
+        speculative_adaptive_gamma = false,
         which_draft = None,
         chat_template = None,
         num_device_layers = None,
@@ -410,6 +425,7 @@ impl Runner {
         prefix_cache_n: usize,
         token_source: &str,
         speculative_gamma: usize,
+        speculative_adaptive_gamma: bool,
         which_draft: Option<Which>,
         chat_template: Option<String>,
         num_device_layers: Option<Vec<String>>,
@@ -431,7 +447,8 @@ impl Runner {
             | Which::GGML { .. }
             | Which::LoraGGML { .. }
             | Which::VisionPlain { .. }
-            | Which::DiffusionPlain { .. } => None,
+            | Which::DiffusionPlain { .. }
+            | Which::EmbeddingPlain { .. } => None,
             Which::XLora {
                 tgt_non_granular_index,
                 ..
@@ -454,6 +471,7 @@ impl Runner {
             | Which::LoraGGML { dtype, .. }
             | Which::VisionPlain { dtype, .. }
             | Which::DiffusionPlain { dtype, .. }
+            | Which::EmbeddingPlain { dtype, .. }
             | Which::XLora { dtype, .. }
             | Which::XLoraGGUF { dtype, .. }
             | Which::XLoraGGML { dtype, .. } => dtype,
@@ -479,9 +497,10 @@ impl Runner {
             let draft = parse_which(draft_which, no_kv_cache, chat_template, prompt_batchsize)?;
             Box::new(SpeculativeLoader {
                 target: loader,
-                draft,
+                draft: DraftLoader::Model(draft),
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    adaptive_gamma: speculative_adaptive_gamma,
                 },
             })
         } else {
@@ -667,14 +686,38 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(serde_json::from_str(request.grammar.as_ref().unwrap())?)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
                 ));
             } else {
                 Constraint::None
             };
 
+            let ExtendedSamplers {
+                mirostat,
+                xtc,
+                dynatemp,
+                penalty_range,
+            } = ExtendedSamplers::new(ExtendedSamplingFields {
+                mirostat: request.mirostat,
+                mirostat_tau: request.mirostat_tau,
+                mirostat_eta: request.mirostat_eta,
+                xtc_probability: request.xtc_probability,
+                xtc_threshold: request.xtc_threshold,
+                dynatemp_range: request.dynatemp_range,
+                dynatemp_exponent: request.dynatemp_exponent,
+                penalty_last_n: request.penalty_last_n,
+                penalty_exclude_prompt: request.penalty_exclude_prompt,
+                penalty_whitelist: request.penalty_whitelist.clone(),
+            })?;
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
                     dry_multiplier,
@@ -690,6 +733,7 @@ impl Runner {
                 Either::Left(ref messages) => {
                     let mut messages_vec = Vec::new();
                     let mut image_urls = Vec::new();
+                    let mut video_urls = Vec::new();
                     for message in messages {
                         match &message["content"] {
                             Either::Left(content) => {
@@ -710,12 +754,12 @@ impl Runner {
                             Either::Right(image_messages) => {
                                 if image_messages.len() != 2 {
                                     return Err(PyApiErr::from(
-                                        "Expected 2 items for the content of a message with an image."
+                                        "Expected 2 items for the content of a message with an image or video."
                                     ));
                                 }
                                 if message["role"].as_ref().left().unwrap() != "user" {
                                     return Err(PyApiErr::from(format!(
-                                        "Role for an image message must be `user`, but it is {}",
+                                        "Role for an image or video message must be `user`, but it is {}",
                                         &message["role"].as_ref().left().unwrap()
                                     )));
                                 }
@@ -723,7 +767,7 @@ impl Runner {
                                 let mut items = Vec::new();
                                 for image_message in image_messages {
                                     if image_message.len() != 2 {
-                                        return Err(PyApiErr::from("Expected 2 items for the sub-content of a message with an image.".to_string()));
+                                        return Err(PyApiErr::from("Expected 2 items for the sub-content of a message with an image or video.".to_string()));
                                     }
                                     if !image_message.contains_key("type") {
                                         return Err(PyApiErr::from(
@@ -742,6 +786,7 @@ impl Runner {
                                 fn get_content_and_url(
                                     text_idx: usize,
                                     url_idx: usize,
+                                    url_key: &str,
                                     image_messages: &[HashMap<
                                         String,
                                         Either<String, HashMap<String, String>>,
@@ -756,15 +801,16 @@ impl Runner {
                                         .as_ref()
                                         .unwrap_left()
                                         .clone();
-                                    if image_messages[url_idx]["image_url"].is_left()
-                                        || !image_messages[url_idx]["image_url"]
+                                    if !image_messages[url_idx].contains_key(url_key)
+                                        || image_messages[url_idx][url_key].is_left()
+                                        || !image_messages[url_idx][url_key]
                                             .as_ref()
                                             .unwrap_right()
                                             .contains_key("url")
                                     {
-                                        return Err(PyApiErr::from("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}".to_string()));
+                                        return Err(PyApiErr::from(format!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `{url_key}`, `{url_key}`: {{`url`: ...}}}}")));
                                     }
-                                    let url = image_messages[url_idx]["image_url"]
+                                    let url = image_messages[url_idx][url_key]
                                         .as_ref()
                                         .unwrap_right()["url"]
                                         .clone();
@@ -778,16 +824,33 @@ impl Runner {
                                     "role".to_string(),
                                     Either::Left(message["role"].as_ref().left().unwrap().clone()),
                                 );
-                                let (content, url) = if items[0] == "text" {
-                                    get_content_and_url(0, 1, image_messages)?
-                                } else {
-                                    get_content_and_url(1, 0, image_messages)?
-                                };
+                                let (text_idx, url_idx) =
+                                    if items[0] == "text" { (0, 1) } else { (1, 0) };
+                                let is_video = items[url_idx] == "video_url";
+                                let url_key = if is_video { "video_url" } else { "image_url" };
+                                let (mut content, url) = get_content_and_url(
+                                    text_idx,
+                                    url_idx,
+                                    url_key,
+                                    image_messages,
+                                )?;
+                                if is_video {
+                                    // The video placeholder is not in the chat template for all models.
+                                    if let ModelCategory::Vision { prefixer, .. } =
+                                        &self.runner.config().category
+                                    {
+                                        content = prefixer.prefix_video(video_urls.len(), &content);
+                                    }
+                                }
 
                                 let mut content_map = Vec::new();
                                 let mut content_image_map = IndexMap::new();
-                                content_image_map
-                                    .insert("type".to_string(), Value::String("image".to_string()));
+                                content_image_map.insert(
+                                    "type".to_string(),
+                                    Value::String(
+                                        if is_video { "video" } else { "image" }.to_string(),
+                                    ),
+                                );
                                 content_map.push(content_image_map);
                                 let mut content_text_map = IndexMap::new();
                                 content_text_map
@@ -798,11 +861,15 @@ impl Runner {
                                 message_map
                                     .insert("content".to_string(), Either::Right(content_map));
                                 messages_vec.push(message_map);
-                                image_urls.push(url);
+                                if is_video {
+                                    video_urls.push(url);
+                                } else {
+                                    image_urls.push(url);
+                                }
                             }
                         }
                     }
-                    if !image_urls.is_empty() {
+                    if !image_urls.is_empty() || !video_urls.is_empty() {
                         let mut images = Vec::new();
                         for url in image_urls {
                             let url_unparsed = url.trim();
@@ -810,9 +877,17 @@ impl Runner {
                             let image = util::parse_image_url(url_unparsed)?;
                             images.push(image);
                         }
+                        let mut videos = Vec::new();
+                        for url in video_urls {
+                            let url_unparsed = url.trim();
+
+                            let frames = util::parse_video_url(url_unparsed)?;
+                            videos.push(frames);
+                        }
                         RequestMessage::VisionChat {
                             messages: messages_vec,
                             images,
+                            videos,
                         }
                     } else {
                         RequestMessage::Chat(messages_vec)
@@ -834,6 +909,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -868,6 +944,16 @@ impl Runner {
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    beam_search: None,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    tfs_z: request.tfs_z,
+                    mirostat,
+                    xtc,
+                    dynatemp,
+                    repetition_penalty: request.repetition_penalty,
+                    penalty_range,
+                    seed: request.seed,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -879,6 +965,8 @@ impl Runner {
                 tools,
                 logits_processors: None,
                 return_raw_logits: false,
+                priority: 0,
+                tenant: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -901,6 +989,7 @@ impl Runner {
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
                     Response::Raw { .. } => unreachable!(),
                 }
             }
@@ -933,9 +1022,16 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(serde_json::from_str(request.grammar.as_ref().unwrap())?)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -944,6 +1040,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -956,6 +1053,23 @@ impl Runner {
                 None
             };
 
+            let ExtendedSamplers {
+                mirostat,
+                xtc,
+                dynatemp,
+                penalty_range,
+            } = ExtendedSamplers::new(ExtendedSamplingFields {
+                mirostat: request.mirostat,
+                mirostat_tau: request.mirostat_tau,
+                mirostat_eta: request.mirostat_eta,
+                xtc_probability: request.xtc_probability,
+                xtc_threshold: request.xtc_threshold,
+                dynatemp_range: request.dynatemp_range,
+                dynatemp_exponent: request.dynatemp_exponent,
+                penalty_last_n: request.penalty_last_n,
+                penalty_exclude_prompt: request.penalty_exclude_prompt,
+                penalty_whitelist: request.penalty_whitelist.clone(),
+            })?;
             let dry_params = if let Some(dry_multiplier) = request.dry_multiplier {
                 Some(DrySamplingParams::new_with_defaults(
                     dry_multiplier,
@@ -993,6 +1107,16 @@ impl Runner {
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    beam_search: None,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    tfs_z: request.tfs_z,
+                    mirostat,
+                    xtc,
+                    dynatemp,
+                    repetition_penalty: request.repetition_penalty,
+                    penalty_range,
+                    seed: request.seed,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -1004,6 +1128,8 @@ impl Runner {
                 tools,
                 logits_processors: None,
                 return_raw_logits: false,
+                priority: 0,
+                tenant: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1022,6 +1148,7 @@ impl Runner {
                 Response::ModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
                 Response::Raw { .. } => unreachable!(),
             }
         })
@@ -1033,6 +1160,11 @@ impl Runner {
         response_format,
         height = 720,
         width = 1280,
+        num_steps = None,
+        guidance_scale = None,
+        seed = None,
+        negative_prompt = None,
+        negative_guidance_scale = None,
     ))]
     fn generate_image(
         &self,
@@ -1040,6 +1172,11 @@ impl Runner {
         response_format: ImageGenerationResponseFormat,
         height: usize,
         width: usize,
+        num_steps: Option<usize>,
+        guidance_scale: Option<f64>,
+        seed: Option<u64>,
+        negative_prompt: Option<String>,
+        negative_guidance_scale: Option<f64>,
     ) -> PyApiResult<ImageGenerationResponse> {
         let (tx, mut rx) = channel(1);
 
@@ -1048,7 +1185,16 @@ impl Runner {
             messages: RequestMessage::ImageGeneration {
                 prompt: prompt.to_string(),
                 format: response_format,
-                generation_params: DiffusionGenerationParams { height, width },
+                generation_params: DiffusionGenerationParams {
+                    height,
+                    width,
+                    num_steps,
+                    guidance_scale,
+                    seed,
+                    negative_prompt,
+                    negative_guidance_scale,
+                    strength: None,
+                },
             },
             sampling_params: SamplingParams::deterministic(),
             response: tx,
@@ -1061,6 +1207,8 @@ impl Runner {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         });
 
         let sender = self.runner.get_sender()?;
@@ -1077,6 +1225,47 @@ impl Runner {
         Ok(response)
     }
 
+    /// Embed each of the inputs, returning one L2-normalized vector per input.
+    #[pyo3(signature = (inputs, pooling = EmbeddingPooling::Mean))]
+    fn embed(
+        &self,
+        inputs: Vec<String>,
+        pooling: EmbeddingPooling,
+    ) -> PyApiResult<EmbeddingResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = _Request::Normal(NormalRequest {
+            id: 0,
+            messages: RequestMessage::Embedding { inputs, pooling },
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            return_raw_logits: false,
+            priority: 0,
+            tenant: None,
+        });
+
+        let sender = self.runner.get_sender()?;
+        sender.blocking_send(request).unwrap();
+
+        let ResponseOk::Embeddings(response) = rx
+            .blocking_recv()
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            return Err(PyApiErr::from("Got unexpected response type."));
+        };
+
+        Ok(response)
+    }
+
     /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
     /// then nothing will happen.
     fn send_re_isq(&self, dtype: String) -> PyApiResult<()> {
@@ -1095,6 +1284,36 @@ impl Runner {
             .unwrap();
     }
 
+    /// Load a PEFT adapter from a local directory as `name`, so that it can be used by requests.
+    /// Returns the number of layers the adapter applies to.
+    fn load_adapter(&self, name: String, path: PathBuf) -> PyApiResult<usize> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::LoadAdapter(AdapterLoadRequest {
+            name,
+            path,
+            response: tx,
+        });
+
+        self.runner.get_sender()?.blocking_send(request).unwrap();
+
+        rx.blocking_recv()
+            .context("Channel was erroneously closed!")?
+            .map_err(PyApiErr::from)
+    }
+
+    /// Unload an adapter which is not active or used by any request.
+    fn unload_adapter(&self, name: String) -> PyApiResult<()> {
+        let (tx, mut rx) = channel(1);
+        let request = _Request::UnloadAdapter(AdapterUnloadRequest { name, response: tx });
+
+        self.runner.get_sender()?.blocking_send(request).unwrap();
+
+        rx.blocking_recv()
+            .context("Channel was erroneously closed!")?
+            .map_err(PyApiErr::from)?;
+        Ok(())
+    }
+
     /// Tokenize some text, returning raw tokens.
     fn tokenize_text(&self, text: String, add_special_tokens: bool) -> PyApiResult<Vec<u32>> {
         let (tx, mut rx) = channel(1);
@@ -1141,6 +1360,7 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Architecture>()?;
     m.add_class::<VisionArchitecture>()?;
     m.add_class::<DiffusionArchitecture>()?;
+    m.add_class::<EmbeddingArchitecture>()?;
     m.add_class::<AnyMoeConfig>()?;
     m.add_class::<AnyMoeExpertType>()?;
     m.add_class::<ToolChoice>()?;
@@ -1159,5 +1379,9 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::TopLogprob>()?;
     m.add_class::<mistralrs_core::ModelDType>()?;
     m.add_class::<mistralrs_core::ImageGenerationResponseFormat>()?;
+    m.add_class::<mistralrs_core::EmbeddingPooling>()?;
+    m.add_class::<mistralrs_core::EmbeddingData>()?;
+    m.add_class::<mistralrs_core::EmbeddingUsage>()?;
+    m.add_class::<mistralrs_core::EmbeddingResponse>()?;
     Ok(())
 }
@@ -13,6 +13,7 @@ use pyo3::{
 pub enum ToolChoice {
     NoTools,
     Auto,
+    Required,
 }
 
 #[pyclass]
@@ -43,6 +44,21 @@ pub struct CompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) tfs_z: Option<f64>,
+    pub(crate) mirostat: Option<usize>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) xtc_probability: Option<f32>,
+    pub(crate) xtc_threshold: Option<f32>,
+    pub(crate) dynatemp_range: Option<f64>,
+    pub(crate) dynatemp_exponent: Option<f64>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) penalty_last_n: Option<usize>,
+    pub(crate) penalty_exclude_prompt: bool,
+    pub(crate) penalty_whitelist: Option<Vec<u32>>,
+    pub(crate) seed: Option<u64>,
 }
 
 #[pymethods]
@@ -73,6 +89,21 @@ impl CompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        typical_p=None,
+        top_a=None,
+        tfs_z=None,
+        mirostat=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        xtc_probability=None,
+        xtc_threshold=None,
+        dynatemp_range=None,
+        dynatemp_exponent=None,
+        repetition_penalty=None,
+        penalty_last_n=None,
+        penalty_exclude_prompt=false,
+        penalty_whitelist=None,
+        seed=None,
     ))]
     fn new(
         prompt: String,
@@ -99,6 +130,21 @@ impl CompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        xtc_probability: Option<f32>,
+        xtc_threshold: Option<f32>,
+        dynatemp_range: Option<f64>,
+        dynatemp_exponent: Option<f64>,
+        repetition_penalty: Option<f32>,
+        penalty_last_n: Option<usize>,
+        penalty_exclude_prompt: bool,
+        penalty_whitelist: Option<Vec<u32>>,
+        seed: Option<u64>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -125,6 +171,21 @@ impl CompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            typical_p,
+            top_a,
+            tfs_z,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            xtc_probability,
+            xtc_threshold,
+            dynatemp_range,
+            dynatemp_exponent,
+            repetition_penalty,
+            penalty_last_n,
+            penalty_exclude_prompt,
+            penalty_whitelist,
+            seed,
         })
     }
 }
@@ -166,6 +227,21 @@ pub struct ChatCompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) tfs_z: Option<f64>,
+    pub(crate) mirostat: Option<usize>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) xtc_probability: Option<f32>,
+    pub(crate) xtc_threshold: Option<f32>,
+    pub(crate) dynatemp_range: Option<f64>,
+    pub(crate) dynatemp_exponent: Option<f64>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) penalty_last_n: Option<usize>,
+    pub(crate) penalty_exclude_prompt: bool,
+    pub(crate) penalty_whitelist: Option<Vec<u32>>,
+    pub(crate) seed: Option<u64>,
 }
 
 #[pymethods]
@@ -196,6 +272,21 @@ impl ChatCompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        typical_p=None,
+        top_a=None,
+        tfs_z=None,
+        mirostat=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        xtc_probability=None,
+        xtc_threshold=None,
+        dynatemp_range=None,
+        dynatemp_exponent=None,
+        repetition_penalty=None,
+        penalty_last_n=None,
+        penalty_exclude_prompt=false,
+        penalty_whitelist=None,
+        seed=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -222,6 +313,21 @@ impl ChatCompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        xtc_probability: Option<f32>,
+        xtc_threshold: Option<f32>,
+        dynatemp_range: Option<f64>,
+        dynatemp_exponent: Option<f64>,
+        repetition_penalty: Option<f32>,
+        penalty_last_n: Option<usize>,
+        penalty_exclude_prompt: bool,
+        penalty_whitelist: Option<Vec<u32>>,
+        seed: Option<u64>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -296,6 +402,21 @@ impl ChatCompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            typical_p,
+            top_a,
+            tfs_z,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            xtc_probability,
+            xtc_threshold,
+            dynatemp_range,
+            dynatemp_exponent,
+            repetition_penalty,
+            penalty_last_n,
+            penalty_exclude_prompt,
+            penalty_whitelist,
+            seed,
         })
     }
 }
@@ -4,7 +4,9 @@ use std::{
 };
 
 use image::DynamicImage;
-use mistralrs_core::ResponseErr;
+use mistralrs_core::{
+    load_video_from_memory, ResponseErr, DEFAULT_MAX_VIDEO_FRAMES, DEFAULT_VIDEO_FPS,
+};
 use pyo3::{exceptions::PyValueError, PyErr};
 
 pub(crate) struct PyApiErr(pub(crate) PyErr);
@@ -85,14 +87,32 @@ impl From<Box<ResponseErr>> for PyApiErr {
 }
 
 pub(crate) fn parse_image_url(url_unparsed: &str) -> PyApiResult<DynamicImage> {
+    let bytes = load_url(url_unparsed)?;
+    image::load_from_memory(&bytes).map_err(|e| PyApiErr::from(format!("{e}")))
+}
+
+pub(crate) fn parse_video_url(url_unparsed: &str) -> PyApiResult<Vec<DynamicImage>> {
+    let bytes = load_url(url_unparsed)?;
+    Ok(load_video_from_memory(
+        &bytes,
+        DEFAULT_VIDEO_FPS,
+        DEFAULT_MAX_VIDEO_FRAMES,
+    )?)
+}
+
+/// Read the contents of a URL, a path to a local file, or base64 encoded data.
+fn load_url(url_unparsed: &str) -> PyApiResult<Vec<u8>> {
     let url = if let Ok(url) = url::Url::parse(url_unparsed) {
         url
     } else if File::open(url_unparsed).is_ok() {
         url::Url::from_file_path(std::path::absolute(url_unparsed)?)
             .map_err(|_| format!("Could not parse file path: {}", url_unparsed))?
     } else {
-        url::Url::parse(&format!("data:image/png;base64,{}", url_unparsed))
-            .map_err(|_| format!("Could not parse as base64 data: {}", url_unparsed))?
+        url::Url::parse(&format!(
+            "data:application/octet-stream;base64,{}",
+            url_unparsed
+        ))
+        .map_err(|_| format!("Could not parse as base64 data: {}", url_unparsed))?
     };
 
     let bytes = if url.scheme() == "http" || url.scheme() == "https" {
@@ -129,5 +149,5 @@ pub(crate) fn parse_image_url(url_unparsed: &str) -> PyApiResult<DynamicImage> {
         )));
     };
 
-    image::load_from_memory(&bytes).map_err(|e| PyApiErr::from(format!("{e}")))
+    Ok(bytes)
 }
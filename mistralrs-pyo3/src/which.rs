@@ -1,7 +1,9 @@
 use std::path::PathBuf;
 
 use either::Either;
-use mistralrs_core::{DiffusionLoaderType, ModelDType, NormalLoaderType, VisionLoaderType};
+use mistralrs_core::{
+    DiffusionLoaderType, EmbeddingLoaderType, ModelDType, NormalLoaderType, VisionLoaderType,
+};
 use pyo3::pyclass;
 
 #[pyclass(eq, eq_int)]
@@ -69,6 +71,20 @@ pub enum DiffusionArchitecture {
     FluxOffloaded,
 }
 
+#[pyclass(eq, eq_int)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmbeddingArchitecture {
+    Bert,
+}
+
+impl From<EmbeddingArchitecture> for EmbeddingLoaderType {
+    fn from(value: EmbeddingArchitecture) -> Self {
+        match value {
+            EmbeddingArchitecture::Bert => EmbeddingLoaderType::Bert,
+        }
+    }
+}
+
 impl From<DiffusionArchitecture> for DiffusionLoaderType {
     fn from(value: DiffusionArchitecture) -> Self {
         match value {
@@ -327,4 +343,17 @@ pub enum Which {
         arch: DiffusionArchitecture,
         dtype: ModelDType,
     },
+
+    #[pyo3(constructor = (
+        model_id,
+        arch,
+        tokenizer_json = None,
+        dtype = ModelDType::Auto,
+    ))]
+    EmbeddingPlain {
+        model_id: String,
+        arch: EmbeddingArchitecture,
+        tokenizer_json: Option<String>,
+        dtype: ModelDType,
+    },
 }
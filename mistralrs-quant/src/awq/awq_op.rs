@@ -0,0 +1,129 @@
+use candle_core::{CpuStorage, CustomOp3, Layout, Result, Shape, WithDType};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use super::AwqVersion;
+
+/// AWQ packs 8 int4 values per i32.
+pub(crate) const AWQ_PACK_FACTOR: usize = 8;
+
+/// Nibble holding each output column in a GEMM packed i32. The inverse of AutoAWQ's pack order
+/// `[0, 2, 4, 6, 1, 3, 5, 7]`.
+const AWQ_REVERSE_ORDER: [usize; AWQ_PACK_FACTOR] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+/// Dequantize AWQ weights to a `(in_dim, out_dim)` matrix.
+///
+/// The inputs are the packed `qweight`, the packed `qzeros` (both i32) and the `scales`:
+/// - GEMM: `(in_dim, out_dim / 8)`, `(groups, out_dim / 8)` and `(groups, out_dim)`.
+/// - GEMV: `(out_dim, in_dim / 8)`, `(out_dim, zeros_width)` and `(out_dim, zeros_width * 8)`.
+///   The zeros and scales are padded along the groups.
+pub(crate) struct DequantAwq {
+    pub(crate) version: AwqVersion,
+    pub(crate) group_size: usize,
+    pub(crate) h: usize,
+    pub(crate) w: usize,
+}
+
+impl DequantAwq {
+    fn dequantize<T: WithDType>(
+        &self,
+        q: &[i32],
+        z: &[i32],
+        z_width: usize,
+        s: &[T],
+        s_width: usize,
+    ) -> Vec<T> {
+        let nibble =
+            |packed: i32, i: usize| T::from_f64((((packed as u32) >> (4 * i)) & 0xF) as f64);
+        (0..self.h * self.w)
+            .into_par_iter()
+            .map(|i| {
+                let (k, n) = (i / self.w, i % self.w);
+                let g = k / self.group_size;
+                let (q, z, s) = match self.version {
+                    AwqVersion::Gemm => {
+                        let col = n / AWQ_PACK_FACTOR;
+                        let pos = AWQ_REVERSE_ORDER[n % AWQ_PACK_FACTOR];
+                        (
+                            nibble(q[k * (self.w / AWQ_PACK_FACTOR) + col], pos),
+                            nibble(z[g * z_width + col], pos),
+                            s[g * s_width + n],
+                        )
+                    }
+                    AwqVersion::Gemv => (
+                        nibble(
+                            q[n * (self.h / AWQ_PACK_FACTOR) + k / AWQ_PACK_FACTOR],
+                            k % AWQ_PACK_FACTOR,
+                        ),
+                        nibble(z[n * z_width + g / AWQ_PACK_FACTOR], g % AWQ_PACK_FACTOR),
+                        s[n * s_width + g],
+                    ),
+                };
+                (q - z) * s
+            })
+            .collect()
+    }
+}
+
+impl CustomOp3 for DequantAwq {
+    fn name(&self) -> &'static str {
+        "dequant-awq"
+    }
+    fn cpu_fwd(
+        &self,
+        q: &CpuStorage,
+        l_q: &Layout,
+        z: &CpuStorage,
+        l_z: &Layout,
+        s: &CpuStorage,
+        l_s: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (CpuStorage::I32(q_slice), CpuStorage::I32(z_slice)) = (q, z) else {
+            candle_core::bail!("Weight and zeros must be i32, AWQ dequant");
+        };
+        let (Some((q_start, q_end)), Some((z_start, z_end)), Some((s_start, s_end))) = (
+            l_q.contiguous_offsets(),
+            l_z.contiguous_offsets(),
+            l_s.contiguous_offsets(),
+        ) else {
+            candle_core::bail!("All inputs must be contiguous");
+        };
+        let q_slice = &q_slice[q_start..q_end];
+        let z_slice = &z_slice[z_start..z_end];
+        let z_width = l_z.dims()[1];
+        let s_width = l_s.dims()[1];
+        let shape = Shape::from_dims(&[self.h, self.w]);
+        match s {
+            CpuStorage::F32(s_slice) => Ok((
+                CpuStorage::F32(self.dequantize(
+                    q_slice,
+                    z_slice,
+                    z_width,
+                    &s_slice[s_start..s_end],
+                    s_width,
+                )),
+                shape,
+            )),
+            CpuStorage::F16(s_slice) => Ok((
+                CpuStorage::F16(self.dequantize(
+                    q_slice,
+                    z_slice,
+                    z_width,
+                    &s_slice[s_start..s_end],
+                    s_width,
+                )),
+                shape,
+            )),
+            CpuStorage::BF16(s_slice) => Ok((
+                CpuStorage::BF16(self.dequantize(
+                    q_slice,
+                    z_slice,
+                    z_width,
+                    &s_slice[s_start..s_end],
+                    s_width,
+                )),
+                shape,
+            )),
+            _ => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
+        }
+    }
+}
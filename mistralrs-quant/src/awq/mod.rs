@@ -0,0 +1,531 @@
+use std::{
+    borrow::Cow,
+    io::Cursor,
+    num::NonZeroUsize,
+    sync::{atomic::AtomicUsize, Arc},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+
+mod awq_op;
+
+use crate::{
+    unquantized::{isq_cpu_threads, requantize},
+    utils::{deserialize_tensor, serialize_tensor, version_is_compatible, HQFF_VERSION},
+    DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizedConfig, QuantizedSerde,
+    QuantizedSerdeType, UnquantLinear,
+};
+use awq_op::{DequantAwq, AWQ_PACK_FACTOR};
+
+/// Packing layout of an AWQ checkpoint, the `version` of its quantization config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AwqVersion {
+    /// `qweight` is `(in_dim, out_dim / 8)`, packed in AutoAWQ's interleaved order.
+    Gemm,
+    /// `qweight` is `(out_dim, in_dim / 8)`, packed in order.
+    Gemv,
+}
+
+impl AwqVersion {
+    fn from_config(version: Option<&str>) -> Result<Self> {
+        match version.map(|v| v.to_lowercase()).as_deref() {
+            None | Some("gemm") => Ok(Self::Gemm),
+            Some("gemv") => Ok(Self::Gemv),
+            Some(other) => {
+                candle_core::bail!("Unsupported AWQ version `{other}`, expected `gemm` or `gemv`.")
+            }
+        }
+    }
+}
+
+/// Width of the padded GEMV zeros, as computed by AutoAWQ.
+fn gemv_zeros_width(in_dim: usize, group_size: usize) -> Result<usize> {
+    let size_multiplier = match group_size {
+        128.. => 1,
+        64 => 2,
+        32 => 4,
+        other => candle_core::bail!("Unsupported AWQ GEMV group size {other}."),
+    };
+    let base_width = (in_dim / group_size).div_ceil(AWQ_PACK_FACTOR);
+    Ok(base_width.div_ceil(size_multiplier) * size_multiplier)
+}
+
+/// 4-bit AWQ layer. On the CPU, the weight is dequantized for each forward pass. See
+/// [`AwqLayer::into_device_layer`] for other devices.
+#[derive(Debug)]
+pub struct AwqLayer {
+    qweight: Tensor,
+    qzeros: Tensor,
+    scales: Tensor,
+    bias: Option<Tensor>,
+    group_size: usize,
+    version: AwqVersion,
+}
+
+impl AwqLayer {
+    /// Dequantize the weight to a `(in_dim, out_dim)` matrix.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let (in_dim, out_dim) = match self.version {
+            AwqVersion::Gemm => {
+                let (in_dim, packed_out) = self.qweight.dims2()?;
+                (in_dim, packed_out * AWQ_PACK_FACTOR)
+            }
+            AwqVersion::Gemv => {
+                let (out_dim, packed_in) = self.qweight.dims2()?;
+                (packed_in * AWQ_PACK_FACTOR, out_dim)
+            }
+        };
+        let op = DequantAwq {
+            version: self.version,
+            group_size: self.group_size,
+            h: in_dim,
+            w: out_dim,
+        };
+        // Only a CPU kernel exists, so other devices dequantize through the CPU.
+        let device = self.scales.device();
+        self.qweight
+            .to_device(&Device::Cpu)?
+            .apply_op3_no_bwd(
+                &self.qzeros.to_device(&Device::Cpu)?,
+                &self.scales.to_device(&Device::Cpu)?,
+                &op,
+            )?
+            .to_device(device)
+    }
+
+    /// The layer to run on the device of the weights. There is no dequantization kernel outside
+    /// of the CPU, so other devices run with the weight dequantized once.
+    pub fn into_device_layer(self) -> Result<Arc<dyn QuantMethod>> {
+        if self.scales.device().is_cpu() {
+            return Ok(Arc::new(self));
+        }
+        Ok(Arc::new(UnquantLinear::from_dequantized(
+            &self.dequantize()?,
+            self.bias,
+        )?))
+    }
+}
+
+impl QuantMethod for AwqLayer {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Awq {
+                qweight,
+                qzeros,
+                scales,
+                bias,
+                group_size,
+                version,
+            } => {
+                let dtype = if qweight.device().is_cpu() {
+                    DType::F32
+                } else {
+                    DType::F16
+                };
+                Ok(Self {
+                    qweight: qweight.contiguous()?,
+                    qzeros: qzeros.contiguous()?,
+                    scales: scales.to_dtype(dtype)?.contiguous()?,
+                    bias: bias.map(|b| b.to_dtype(dtype)).transpose()?,
+                    group_size,
+                    version,
+                })
+            }
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Dummy
+            | QuantMethodConfig::FP8 { .. } => {
+                unreachable!()
+            }
+        }
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        let w = self.dequantize()?;
+        let w = match *a.dims() {
+            [b1, b2, _, _] => w.broadcast_left((b1, b2))?,
+            [bsize, _, _] => w.broadcast_left(bsize)?,
+            _ => w,
+        };
+        let res = a.matmul(&w)?;
+        if let Some(ref bias) = self.bias {
+            res.broadcast_add(bias)
+        } else {
+            Ok(res)
+        }
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        Some(self.scales.dtype())
+    }
+
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("AWQ quantization does not support adding weight delta.")
+    }
+
+    fn dtype_and_device(&self) -> (DType, Device) {
+        (self.scales.dtype(), self.scales.device().clone())
+    }
+
+    fn get_bias_mut(&mut self) -> Option<&mut Tensor> {
+        self.bias.as_mut()
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        let Some(dtype) = dtype else {
+            // Keep the AWQ weights, only move them.
+            return Self {
+                qweight: self.qweight.to_device(&device)?,
+                qzeros: self.qzeros.to_device(&device)?,
+                scales: self.scales.to_device(&device)?,
+                bias: self
+                    .bias
+                    .as_ref()
+                    .map(|b| b.to_device(&device))
+                    .transpose()?,
+                group_size: self.group_size,
+                version: self.version,
+            }
+            .into_device_layer();
+        };
+
+        requantize(
+            &self.dequantize()?,
+            self.bias.clone(),
+            dtype,
+            device,
+            n_quantized,
+            imatrix_weight,
+        )
+    }
+
+    fn get_max_isq_cpu_threads(&self, dtype: IsqType) -> Option<NonZeroUsize> {
+        isq_cpu_threads(dtype)
+    }
+}
+
+// Serialization structure:
+//
+// -----------------------
+// HQFF version, u32, little endian
+// -----------------------
+// ISQ type (4 for awq), u8, little endian
+// -----------------------
+// Whether bias data is included, u8 boolean
+// -----------------------
+// AWQ version (0 for gemm, 1 for gemv), u8, little endian
+// -----------------------
+// Group size, u32, little endian
+// -----------------------
+// Packed weight tensor data generated by `serialize_tensor`. Refer to its docs for layout.
+// -----------------------
+// Packed zeros tensor data generated by `serialize_tensor`. Refer to its docs for layout.
+// -----------------------
+// Scales tensor data generated by `serialize_tensor`. Refer to its docs for layout.
+// -----------------------
+// [OPTIONAL] Bias tensor data generated by `serialize_tensor`. Refer to its docs for layout.
+// -----------------------
+
+impl QuantizedSerde for AwqLayer {
+    fn isq_serde_supported(&self) -> bool {
+        true
+    }
+    fn name(&self) -> &'static str {
+        "awq"
+    }
+    fn serialize(&self) -> Result<Cow<[u8]>> {
+        let mut buffer = Vec::new();
+
+        buffer.extend(&HQFF_VERSION.to_le_bytes());
+
+        // ISQ type for awq is 4
+        buffer.push(QuantizedSerdeType::Awq as u8);
+
+        // Has bias
+        buffer.push(self.bias.is_some() as u8);
+
+        // AWQ version
+        buffer.push(match self.version {
+            AwqVersion::Gemm => 0,
+            AwqVersion::Gemv => 1,
+        });
+
+        // Group size
+        buffer.extend(&(self.group_size as u32).to_le_bytes());
+
+        serialize_tensor(&mut buffer, &self.qweight)?;
+        serialize_tensor(&mut buffer, &self.qzeros)?;
+        serialize_tensor(&mut buffer, &self.scales)?;
+
+        if let Some(bias) = &self.bias {
+            // Bias
+            serialize_tensor(&mut buffer, bias)?;
+        }
+
+        Ok(Cow::from(buffer))
+    }
+
+    fn deserialize(data: Cow<[u8]>, device: &Device) -> Result<Arc<dyn QuantMethod>>
+    where
+        Self: Sized,
+    {
+        let mut buffer = Cursor::new(data.to_vec());
+
+        let version = buffer.read_u32::<LittleEndian>()?;
+        if let Err(e) = version_is_compatible(version) {
+            return Err(candle_core::Error::wrap(e));
+        }
+
+        let isq_type = buffer.read_u8()? as usize;
+        if isq_type != QuantizedSerdeType::Awq as usize {
+            candle_core::bail!(
+                "ISQ type ({isq_type}) doesn't match expected type {}",
+                QuantizedSerdeType::Awq as usize
+            );
+        }
+
+        let has_bias = buffer.read_u8()? != 0;
+
+        let version = match buffer.read_u8()? {
+            0 => AwqVersion::Gemm,
+            1 => AwqVersion::Gemv,
+            other => candle_core::bail!("Unknown AWQ version {other}."),
+        };
+
+        let group_size = buffer.read_u32::<LittleEndian>()? as usize;
+
+        let qweight = deserialize_tensor(&mut buffer, device)?;
+        let qzeros = deserialize_tensor(&mut buffer, device)?;
+        let scales = deserialize_tensor(&mut buffer, device)?;
+
+        let bias = if has_bias {
+            Some(deserialize_tensor(&mut buffer, device)?)
+        } else {
+            None
+        };
+
+        Self {
+            qweight,
+            qzeros,
+            scales,
+            bias,
+            group_size,
+            version,
+        }
+        .into_device_layer()
+    }
+}
+
+pub fn awq_linear(
+    in_dim: usize,
+    out_dim: usize,
+    config: &QuantizedConfig,
+    vb: VarBuilder,
+) -> Result<Arc<dyn QuantMethod>> {
+    // Handle the case where the layer is dummy (no tensors)
+    if !(vb.contains_tensor("qweight")
+        && vb.contains_tensor("qzeros")
+        && vb.contains_tensor("scales"))
+    {
+        let layer = <DummyLayer as QuantMethod>::new(QuantMethodConfig::Dummy)?;
+        return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
+    }
+
+    if config.bits != 4 {
+        candle_core::bail!("AWQ only supports 4 bits, got {}.", config.bits);
+    }
+    let version = AwqVersion::from_config(config.version.as_deref())?;
+    let groups = in_dim / config.group_size;
+
+    let (qweight_shape, qzeros_shape, scales_shape) = match version {
+        AwqVersion::Gemm => (
+            (in_dim, out_dim / AWQ_PACK_FACTOR),
+            (groups, out_dim / AWQ_PACK_FACTOR),
+            (groups, out_dim),
+        ),
+        AwqVersion::Gemv => {
+            let zeros_width = gemv_zeros_width(in_dim, config.group_size)?;
+            (
+                (out_dim, in_dim / AWQ_PACK_FACTOR),
+                (out_dim, zeros_width),
+                (out_dim, zeros_width * AWQ_PACK_FACTOR),
+            )
+        }
+    };
+
+    let qweight =
+        vb.get_with_hints_dtype(qweight_shape, "qweight", Default::default(), DType::I32)?;
+    let qzeros = vb.get_with_hints_dtype(qzeros_shape, "qzeros", Default::default(), DType::I32)?;
+    let scales = vb.get_with_hints_dtype(scales_shape, "scales", Default::default(), DType::F16)?;
+    let bias = if vb.contains_tensor("bias") {
+        Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), DType::F16)?)
+    } else {
+        None
+    };
+
+    let config = QuantMethodConfig::Awq {
+        qweight,
+        qzeros,
+        scales,
+        bias,
+        group_size: config.group_size,
+        version,
+    };
+    AwqLayer::new(config)?.into_device_layer()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, sync::atomic::AtomicUsize};
+
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use crate::{
+        utils::testing::{
+            max_abs_diff, quantized_value, scale, zero_point, GROUP_SIZE, IN_DIM, OUT_DIM,
+        },
+        IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde,
+    };
+
+    use super::{gemv_zeros_width, AwqLayer, AwqVersion};
+
+    const PACK_ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+
+    fn i32_tensor(data: Vec<u32>, shape: (usize, usize), dev: &Device) -> Result<Tensor> {
+        Tensor::from_vec(data.into_iter().map(|x| x as i32).collect(), shape, dev)
+    }
+
+    /// Pack an AWQ layer the way AutoAWQ does and return its `(in_dim, out_dim)` reference.
+    fn awq_layer(version: AwqVersion, dev: &Device) -> Result<(AwqLayer, Vec<f32>)> {
+        let groups = IN_DIM / GROUP_SIZE;
+        let q = |k: usize, n: usize| quantized_value(k, n, 4);
+        let z = |g: usize, n: usize| zero_point(g, n, 16);
+        let s = scale;
+
+        let (qweight, qzeros, scales) = match version {
+            AwqVersion::Gemm => {
+                let mut qweight = vec![0u32; IN_DIM * OUT_DIM / 8];
+                for k in 0..IN_DIM {
+                    for col in 0..OUT_DIM / 8 {
+                        for (i, order) in PACK_ORDER.iter().enumerate() {
+                            qweight[k * OUT_DIM / 8 + col] |= q(k, col * 8 + order) << (4 * i);
+                        }
+                    }
+                }
+                let mut qzeros = vec![0u32; groups * OUT_DIM / 8];
+                let mut scales = Vec::new();
+                for g in 0..groups {
+                    for col in 0..OUT_DIM / 8 {
+                        for (i, order) in PACK_ORDER.iter().enumerate() {
+                            qzeros[g * OUT_DIM / 8 + col] |= z(g, col * 8 + order) << (4 * i);
+                        }
+                    }
+                    scales.extend((0..OUT_DIM).map(|n| s(g, n)));
+                }
+                (
+                    i32_tensor(qweight, (IN_DIM, OUT_DIM / 8), dev)?,
+                    i32_tensor(qzeros, (groups, OUT_DIM / 8), dev)?,
+                    Tensor::from_vec(scales, (groups, OUT_DIM), dev)?,
+                )
+            }
+            AwqVersion::Gemv => {
+                let zeros_width = gemv_zeros_width(IN_DIM, GROUP_SIZE)?;
+                let mut qweight = vec![0u32; OUT_DIM * IN_DIM / 8];
+                let mut qzeros = vec![0u32; OUT_DIM * zeros_width];
+                let mut scales = vec![0f32; OUT_DIM * zeros_width * 8];
+                for n in 0..OUT_DIM {
+                    for k in 0..IN_DIM {
+                        qweight[n * IN_DIM / 8 + k / 8] |= q(k, n) << (4 * (k % 8));
+                    }
+                    for g in 0..groups {
+                        qzeros[n * zeros_width + g / 8] |= z(g, n) << (4 * (g % 8));
+                        scales[n * zeros_width * 8 + g] = s(g, n);
+                    }
+                }
+                (
+                    i32_tensor(qweight, (OUT_DIM, IN_DIM / 8), dev)?,
+                    i32_tensor(qzeros, (OUT_DIM, zeros_width), dev)?,
+                    Tensor::from_vec(scales, (OUT_DIM, zeros_width * 8), dev)?,
+                )
+            }
+        };
+
+        let mut reference = Vec::new();
+        for k in 0..IN_DIM {
+            for n in 0..OUT_DIM {
+                let g = k / GROUP_SIZE;
+                reference.push((q(k, n) as f32 - z(g, n) as f32) * s(g, n));
+            }
+        }
+
+        let layer = AwqLayer::new(QuantMethodConfig::Awq {
+            qweight,
+            qzeros,
+            scales: scales.to_dtype(DType::F16)?,
+            bias: None,
+            group_size: GROUP_SIZE,
+            version,
+        })?;
+        Ok((layer, reference))
+    }
+
+    #[test]
+    fn test_dequantize_awq() -> Result<()> {
+        let dev = Device::Cpu;
+        for version in [AwqVersion::Gemm, AwqVersion::Gemv] {
+            let (layer, reference) = awq_layer(version, &dev)?;
+            let dequant = layer.dequantize()?.flatten_all()?.to_vec1::<f32>()?;
+            assert!(max_abs_diff(&dequant, &reference) < 1e-5, "{version:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_awq_forward_and_serde() -> Result<()> {
+        let dev = Device::Cpu;
+        let xs = Tensor::arange(0f32, (3 * IN_DIM) as f32, &dev)?
+            .affine(1. / (3 * IN_DIM) as f64, -0.5)?
+            .reshape((1, 3, IN_DIM))?;
+        for version in [AwqVersion::Gemm, AwqVersion::Gemv] {
+            let (layer, reference) = awq_layer(version, &dev)?;
+            let expected = xs
+                .matmul(&Tensor::from_vec(reference, (IN_DIM, OUT_DIM), &dev)?.unsqueeze(0)?)?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            let tol = expected.iter().fold(0f32, |m, x| m.max(x.abs()));
+
+            let out = layer.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?;
+            assert!(max_abs_diff(&out, &expected) < 1e-4 * tol, "{version:?}");
+
+            let serialized = layer.serialize()?.into_owned();
+            let layer = AwqLayer::deserialize(Cow::from(serialized), &dev)?;
+            let out = layer.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?;
+            assert!(
+                max_abs_diff(&out, &expected) < 1e-4 * tol,
+                "{version:?}, serde"
+            );
+
+            let layer =
+                layer.apply_isq(Some(IsqType::Q8_0), dev.clone(), &AtomicUsize::new(0), None)?;
+            let out = layer.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?;
+            assert!(
+                max_abs_diff(&out, &expected) < 2e-2 * tol,
+                "{version:?}, Q8_0"
+            );
+        }
+        Ok(())
+    }
+}
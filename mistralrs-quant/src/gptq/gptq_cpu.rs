@@ -1,4 +1,7 @@
-use crate::{DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizedConfig, QuantizedSerde};
+use crate::{
+    unquantized::{isq_cpu_threads, requantize},
+    DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizedConfig, QuantizedSerde,
+};
 use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::VarBuilder;
 use std::{
@@ -6,8 +9,40 @@ use std::{
     sync::{atomic::AtomicUsize, Arc},
 };
 
+use super::gptq_op::DequantGptq;
+
+macro_rules! pack_factor {
+    ($bits:expr) => {
+        32 / $bits
+    };
+}
+
+/// GPTQ layer for devices without the CUDA kernels. The weight is dequantized on the fly
+/// for each forward pass.
 #[derive(Debug)]
-pub struct GptqLayer;
+pub struct GptqLayer {
+    q_weight: Tensor,     // (in_dim / pack, out_dim), i32
+    scales_zeros: Tensor, // (2, groups, out_dim), unpacked zeros
+    g_idx: Tensor,        // (in_dim,), i32
+    bias: Option<Tensor>,
+    bits: usize,
+}
+
+impl GptqLayer {
+    /// Dequantize the weight to a `(in_dim, out_dim)` matrix.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let (packed_h, w) = self.q_weight.dims2()?;
+        let groups = self.scales_zeros.dim(1)?;
+        let op = DequantGptq {
+            bits: self.bits,
+            h: packed_h * pack_factor!(self.bits),
+            w,
+            groups,
+        };
+        self.q_weight
+            .apply_op3_no_bwd(&self.scales_zeros, &self.g_idx, &op)
+    }
+}
 
 impl QuantMethod for GptqLayer {
     fn new(method: QuantMethodConfig) -> Result<Self>
@@ -15,49 +50,154 @@ impl QuantMethod for GptqLayer {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::Gptq { .. } => candle_core::bail!("GPTQ is only supported on CUDA."),
+            QuantMethodConfig::Gptq {
+                bits,
+                use_exllama: _,
+                q_weight,
+                gptq_qzeros,
+                gptq_scales,
+                g_idx,
+                bias,
+                workspace: _,
+                is_marlin,
+            } => {
+                if is_marlin {
+                    candle_core::bail!("Marlin GPTQ checkpoints are only supported on CUDA.");
+                }
+                if !matches!(bits, 2 | 4 | 8) {
+                    candle_core::bail!("GPTQ only supports 2, 4 and 8 bits, got {bits}.");
+                }
+                let Some(qzeros) = gptq_qzeros else {
+                    candle_core::bail!("GPTQ requires `qzeros`.");
+                };
+                let bits = bits as usize;
+                let device = q_weight.device().clone();
+                let dtype = if device.is_cpu() {
+                    DType::F32
+                } else {
+                    DType::F16
+                };
+
+                let in_dim = q_weight.dim(0)? * pack_factor!(bits);
+                let (groups, out_dim) = gptq_scales.dims2()?;
+
+                // Zeros are packed along the output dim and stored minus one.
+                let mask = (1u32 << bits) - 1;
+                let zeros = qzeros
+                    .to_device(&Device::Cpu)?
+                    .to_vec2::<i32>()?
+                    .into_iter()
+                    .flat_map(|row| {
+                        (0..out_dim)
+                            .map(|n| {
+                                let packed = row[n / pack_factor!(bits)] as u32;
+                                let shift = bits * (n % pack_factor!(bits));
+                                (((packed >> shift) & mask) + 1) as f32
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                let zeros = Tensor::from_vec(zeros, (groups, out_dim), &device)?.to_dtype(dtype)?;
+                let scales_zeros = Tensor::stack(&[gptq_scales.to_dtype(dtype)?, zeros], 0)?;
+
+                let g_idx = match g_idx {
+                    Some(g_idx) => g_idx,
+                    None => {
+                        let group_size = in_dim / groups;
+                        let g_idx = (0..in_dim)
+                            .map(|k| (k / group_size) as i32)
+                            .collect::<Vec<_>>();
+                        Tensor::from_vec(g_idx, (in_dim,), &device)?
+                    }
+                };
+
+                Ok(Self {
+                    q_weight: q_weight.contiguous()?,
+                    scales_zeros: scales_zeros.contiguous()?,
+                    g_idx: g_idx.contiguous()?,
+                    bias: bias.map(|b| b.to_dtype(dtype)).transpose()?,
+                    bits,
+                })
+            }
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => {
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
         }
     }
 
-    fn forward(&self, _a: &Tensor) -> Result<Tensor> {
-        todo!()
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        let w = self.dequantize()?;
+        let w = match *a.dims() {
+            [b1, b2, _, _] => w.broadcast_left((b1, b2))?,
+            [bsize, _, _] => w.broadcast_left(bsize)?,
+            _ => w,
+        };
+        let res = a.matmul(&w)?;
+        if let Some(ref bias) = self.bias {
+            res.broadcast_add(bias)
+        } else {
+            Ok(res)
+        }
     }
 
     fn quantized_act_type(&self) -> Option<DType> {
-        todo!()
+        Some(self.scales_zeros.dtype())
     }
 
     fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        candle_core::bail!("GPTQ quantization does not support adding weight delta.")
     }
 
     fn dtype_and_device(&self) -> (DType, candle_core::Device) {
-        todo!()
+        (
+            self.scales_zeros.dtype(),
+            self.scales_zeros.device().clone(),
+        )
     }
 
     fn get_bias_mut(&mut self) -> Option<&mut Tensor> {
-        todo!()
+        self.bias.as_mut()
     }
 
     fn apply_isq(
         self: Arc<Self>,
-        _dtype: Option<IsqType>,
-        _device: Device,
-        _n_quantized: &AtomicUsize,
-        _imatrix_weight: Option<Vec<f32>>,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        let Some(dtype) = dtype else {
+            // Keep the GPTQ weights, only move them.
+            return Ok(Arc::new(Self {
+                q_weight: self.q_weight.to_device(&device)?,
+                scales_zeros: self.scales_zeros.to_device(&device)?,
+                g_idx: self.g_idx.to_device(&device)?,
+                bias: self
+                    .bias
+                    .as_ref()
+                    .map(|b| b.to_device(&device))
+                    .transpose()?,
+                bits: self.bits,
+            }));
+        };
+
+        requantize(
+            &self.dequantize()?,
+            self.bias.clone(),
+            dtype,
+            device,
+            n_quantized,
+            imatrix_weight,
+        )
     }
 
-    fn get_max_isq_cpu_threads(&self, _dtype: IsqType) -> Option<NonZeroUsize> {
-        todo!()
+    fn get_max_isq_cpu_threads(&self, dtype: IsqType) -> Option<NonZeroUsize> {
+        isq_cpu_threads(dtype)
     }
 }
 
@@ -67,12 +207,6 @@ impl QuantizedSerde for GptqLayer {
     }
 }
 
-macro_rules! pack_factor {
-    ($bits:expr) => {
-        32 / $bits
-    };
-}
-
 pub fn gptq_linear(
     in_dim: usize,
     out_dim: usize,
@@ -89,6 +223,14 @@ pub fn gptq_linear(
         return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
     }
 
+    let marlin_format = config
+        .checkpoint_format
+        .as_ref()
+        .is_some_and(|f| f.to_lowercase() == "marlin");
+    if marlin_format {
+        candle_core::bail!("Marlin GPTQ checkpoints are only supported on CUDA.");
+    }
+
     let qweight = vb.get_with_hints_dtype(
         (in_dim / pack_factor!(config.bits), out_dim),
         "qweight",
@@ -128,3 +270,143 @@ pub fn gptq_linear(
     };
     Ok(Arc::new(GptqLayer::new(config)?))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use crate::{
+        utils::testing::{
+            max_abs_diff, quantized_value, scale, zero_point, GROUP_SIZE, IN_DIM, OUT_DIM,
+        },
+        IsqType, QuantMethod, QuantMethodConfig,
+    };
+
+    use super::GptqLayer;
+
+    /// Build a packed act-order GPTQ layer and its dequantized `(in_dim, out_dim)` reference.
+    fn gptq_layer(bits: usize, dev: &Device) -> Result<(GptqLayer, Vec<f32>, Vec<f32>)> {
+        let pack = 32 / bits;
+        let maxq = (1 << bits) - 1;
+        let groups = IN_DIM / GROUP_SIZE;
+
+        // Act-order: the rows of each group are scattered over the input dim.
+        let g_idx = (0..IN_DIM)
+            .map(|k| ((k * 5) % IN_DIM / GROUP_SIZE) as i32)
+            .collect::<Vec<_>>();
+        let q = |k: usize, n: usize| quantized_value(k, n, bits);
+        let z = |g: usize, n: usize| zero_point(g, n, maxq);
+        let s = scale;
+
+        let mut qweight = vec![0u32; IN_DIM / pack * OUT_DIM];
+        for k in 0..IN_DIM {
+            for n in 0..OUT_DIM {
+                qweight[(k / pack) * OUT_DIM + n] |= q(k, n) << (bits * (k % pack));
+            }
+        }
+        let mut qzeros = vec![0u32; groups * OUT_DIM / pack];
+        let mut scales = Vec::new();
+        for g in 0..groups {
+            for n in 0..OUT_DIM {
+                qzeros[g * OUT_DIM / pack + n / pack] |= z(g, n) << (bits * (n % pack));
+                scales.push(s(g, n));
+            }
+        }
+        let mut reference = Vec::new();
+        for (k, g) in g_idx.iter().enumerate() {
+            let g = *g as usize;
+            for n in 0..OUT_DIM {
+                reference.push((q(k, n) as f32 - (z(g, n) + 1) as f32) * s(g, n));
+            }
+        }
+        let bias = (0..OUT_DIM).map(|n| n as f32 * 0.1).collect::<Vec<_>>();
+
+        let layer = GptqLayer::new(QuantMethodConfig::Gptq {
+            bits: bits as i32,
+            use_exllama: false,
+            q_weight: Tensor::from_vec(
+                qweight.into_iter().map(|x| x as i32).collect(),
+                (IN_DIM / pack, OUT_DIM),
+                dev,
+            )?,
+            gptq_qzeros: Some(Tensor::from_vec(
+                qzeros.into_iter().map(|x| x as i32).collect(),
+                (groups, OUT_DIM / pack),
+                dev,
+            )?),
+            gptq_scales: Tensor::from_vec(scales, (groups, OUT_DIM), dev)?.to_dtype(DType::F16)?,
+            g_idx: Some(Tensor::from_vec(g_idx, (IN_DIM,), dev)?),
+            bias: Some(Tensor::from_vec(bias.clone(), (OUT_DIM,), dev)?),
+            workspace: None,
+            is_marlin: false,
+        })?;
+        Ok((layer, reference, bias))
+    }
+
+    fn reference_matmul(xs: &[f32], w: &[f32], bias: &[f32]) -> Vec<f32> {
+        xs.chunks(IN_DIM)
+            .flat_map(|row| {
+                (0..OUT_DIM).map(move |n| {
+                    bias[n]
+                        + row
+                            .iter()
+                            .enumerate()
+                            .map(|(k, x)| x * w[k * OUT_DIM + n])
+                            .sum::<f32>()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dequantize_gptq_act_order() -> Result<()> {
+        let dev = Device::Cpu;
+        for bits in [2, 4, 8] {
+            let (layer, reference, _) = gptq_layer(bits, &dev)?;
+            let dequant = layer
+                .dequantize()?
+                .to_dtype(DType::F32)?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            assert!(max_abs_diff(&dequant, &reference) < 1e-3, "{bits} bits");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gptq_forward() -> Result<()> {
+        let dev = Device::Cpu;
+        let xs = (0..3 * IN_DIM)
+            .map(|i| ((i * 13) % 17) as f32 / 17. - 0.5)
+            .collect::<Vec<_>>();
+        for bits in [4, 8] {
+            let (layer, w, bias) = gptq_layer(bits, &dev)?;
+            let expected = reference_matmul(&xs, &w, &bias);
+
+            let out = layer
+                .forward(&Tensor::from_vec(xs.clone(), (1, 3, IN_DIM), &dev)?)?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            let tol = expected.iter().fold(0f32, |m, x| m.max(x.abs()));
+            assert!(max_abs_diff(&out, &expected) < 1e-4 * tol, "{bits} bits");
+
+            let layer = Arc::new(layer).apply_isq(
+                Some(IsqType::Q8_0),
+                dev.clone(),
+                &AtomicUsize::new(0),
+                None,
+            )?;
+            let out = layer
+                .forward(&Tensor::from_vec(xs.clone(), (1, 3, IN_DIM), &dev)?)?
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            assert!(
+                max_abs_diff(&out, &expected) < 2e-2 * tol,
+                "{bits} bits, Q8_0"
+            );
+        }
+        Ok(())
+    }
+}
@@ -261,7 +261,8 @@ impl QuantMethod for GptqLayer {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => {
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
         }
@@ -0,0 +1,132 @@
+#[cfg(feature = "metal")]
+use candle_core::{backend::BackendStorage, DType};
+use candle_core::{CpuStorage, CustomOp3, Layout, Result, Shape, WithDType};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+/// Dequantize GPTQ weights to a `(in_dim, out_dim)` matrix.
+///
+/// The inputs are the packed `q_weight` (i32, `(in_dim / (32 / bits), out_dim)`), the scales and
+/// the unpacked zeros stacked as `(2, groups, out_dim)`, and `g_idx` (i32, `(in_dim,)`), the group
+/// of each input row. With act-order, `g_idx` is not sorted.
+pub(crate) struct DequantGptq {
+    pub(crate) bits: usize,
+    pub(crate) h: usize,
+    pub(crate) w: usize,
+    pub(crate) groups: usize,
+}
+
+impl DequantGptq {
+    fn dequantize<T: WithDType>(&self, q: &[i32], sz: &[T], g_idx: &[i32]) -> Vec<T> {
+        let pack = 32 / self.bits;
+        let mask = (1u32 << self.bits) - 1;
+        let (scales, zeros) = sz.split_at(self.groups * self.w);
+        (0..self.h * self.w)
+            .into_par_iter()
+            .map(|i| {
+                let (k, n) = (i / self.w, i % self.w);
+                let packed = q[(k / pack) * self.w + n] as u32;
+                let v = (packed >> (self.bits * (k % pack))) & mask;
+                let g = g_idx[k] as usize * self.w + n;
+                (T::from_f64(v as f64) - zeros[g]) * scales[g]
+            })
+            .collect()
+    }
+}
+
+impl CustomOp3 for DequantGptq {
+    fn name(&self) -> &'static str {
+        "dequant-gptq"
+    }
+    fn cpu_fwd(
+        &self,
+        q: &CpuStorage,
+        l_q: &Layout,
+        sz: &CpuStorage,
+        l_sz: &Layout,
+        g_idx: &CpuStorage,
+        l_g_idx: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (CpuStorage::I32(q_slice), CpuStorage::I32(g_idx_slice)) = (q, g_idx) else {
+            candle_core::bail!("Weight and g_idx must be i32, GPTQ dequant");
+        };
+        let (Some((q_start, q_end)), Some((sz_start, sz_end)), Some((g_idx_start, g_idx_end))) = (
+            l_q.contiguous_offsets(),
+            l_sz.contiguous_offsets(),
+            l_g_idx.contiguous_offsets(),
+        ) else {
+            candle_core::bail!("All inputs must be contiguous");
+        };
+        let q_slice = &q_slice[q_start..q_end];
+        let g_idx_slice = &g_idx_slice[g_idx_start..g_idx_end];
+        let shape = Shape::from_dims(&[self.h, self.w]);
+        match sz {
+            CpuStorage::F32(sz_slice) => Ok((
+                CpuStorage::F32(self.dequantize(q_slice, &sz_slice[sz_start..sz_end], g_idx_slice)),
+                shape,
+            )),
+            CpuStorage::F16(sz_slice) => Ok((
+                CpuStorage::F16(self.dequantize(q_slice, &sz_slice[sz_start..sz_end], g_idx_slice)),
+                shape,
+            )),
+            CpuStorage::BF16(sz_slice) => Ok((
+                CpuStorage::BF16(self.dequantize(
+                    q_slice,
+                    &sz_slice[sz_start..sz_end],
+                    g_idx_slice,
+                )),
+                shape,
+            )),
+            _ => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
+        }
+    }
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        q: &candle_core::MetalStorage,
+        l_q: &Layout,
+        sz: &candle_core::MetalStorage,
+        l_sz: &Layout,
+        g_idx: &candle_core::MetalStorage,
+        l_g_idx: &Layout,
+    ) -> Result<(candle_core::MetalStorage, Shape)> {
+        if q.dtype() != DType::I32 || g_idx.dtype() != DType::I32 {
+            candle_core::bail!("Weight and g_idx must be i32, GPTQ dequant");
+        };
+        if !(l_q.is_contiguous() && l_sz.is_contiguous() && l_g_idx.is_contiguous()) {
+            candle_core::bail!("All inputs must be contiguous");
+        }
+
+        let command_buffer = q.device().command_buffer()?;
+        command_buffer.set_label("dequant-gptq");
+
+        let device = q.device();
+
+        let out_shape = Shape::from_dims(&[self.h, self.w]);
+
+        let output = device.new_buffer(out_shape.elem_count(), sz.dtype(), "dequant-gptq")?;
+
+        crate::metal_kernels::call_dequant_gptq(
+            device.device(),
+            &command_buffer,
+            &crate::metal_kernels::Kernels::new(),
+            sz.dtype(),
+            q.buffer(),
+            sz.buffer(),
+            g_idx.buffer(),
+            self.bits as u32,
+            self.groups as u32,
+            self.h as u32,
+            self.w as u32,
+            &output,
+        )
+        .map_err(candle_core::Error::wrap)?;
+
+        let newstorage = candle_core::MetalStorage::new(
+            output,
+            device.clone(),
+            out_shape.elem_count(),
+            sz.dtype(),
+        );
+        Ok((newstorage, out_shape))
+    }
+}
@@ -4,6 +4,8 @@ mod ffi;
 mod gptq_cpu;
 #[cfg(feature = "cuda")]
 mod gptq_cuda;
+#[cfg(not(feature = "cuda"))]
+mod gptq_op;
 #[cfg(feature = "cuda")]
 mod marlin_backend;
 #[cfg(feature = "cuda")]
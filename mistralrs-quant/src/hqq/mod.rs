@@ -527,7 +527,8 @@ impl QuantMethod for HqqLayer {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => {
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
             QuantMethodConfig::Hqq {
@@ -13,6 +13,7 @@ use candle_core::{
 #[cfg(feature = "metal")]
 mod metal_kernels;
 
+mod awq;
 mod cublaslt;
 mod dummy;
 mod fp8;
@@ -23,6 +24,8 @@ mod imatrix;
 mod unquantized;
 mod utils;
 
+use awq::awq_linear;
+pub use awq::{AwqLayer, AwqVersion};
 pub use dummy::DummyLayer;
 pub use fp8::FP8Linear;
 pub use gguf::GgufMatMul;
@@ -40,12 +43,15 @@ pub enum QuantMethodType {
     #[default]
     #[serde(rename = "gptq")]
     Gptq,
+    #[serde(rename = "awq")]
+    Awq,
 }
 
 impl Display for QuantMethodType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Gptq => write!(f, "GPTQ"),
+            Self::Awq => write!(f, "AWQ"),
         }
     }
 }
@@ -56,6 +62,8 @@ pub struct QuantizedConfig {
     pub quant_method: QuantMethodType,
     pub group_size: usize,
     pub checkpoint_format: Option<String>,
+    /// AWQ packing layout, `gemm` or `gemv`.
+    pub version: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -91,6 +99,14 @@ pub enum QuantMethodConfig {
         lin: Linear,
         dtype: DType,
     },
+    Awq {
+        qweight: Tensor,
+        qzeros: Tensor,
+        scales: Tensor,
+        bias: Option<Tensor>,
+        group_size: usize,
+        version: AwqVersion,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
@@ -161,6 +177,7 @@ pub enum QuantizedSerdeType {
     Unquant = 1,
     Hqq = 2,
     Fp8 = 3,
+    Awq = 4,
 }
 
 impl TryFrom<usize> for QuantizedSerdeType {
@@ -171,6 +188,7 @@ impl TryFrom<usize> for QuantizedSerdeType {
             1 => Ok(Self::Unquant),
             2 => Ok(Self::Hqq),
             3 => Ok(Self::Fp8),
+            4 => Ok(Self::Awq),
             other => candle_core::bail!("QuantizedSerdeType {other} is invalid."),
         }
     }
@@ -260,6 +278,7 @@ pub fn linear_no_bias(
     let layer = if let Some(quant_conf) = &config {
         match quant_conf.quant_method {
             QuantMethodType::Gptq => gptq_linear(in_dim, out_dim, quant_conf, vb)?,
+            QuantMethodType::Awq => awq_linear(in_dim, out_dim, quant_conf, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -285,6 +304,7 @@ pub fn linear(
     let layer = if let Some(quant_conf) = &config {
         match quant_conf.quant_method {
             QuantMethodType::Gptq => gptq_linear(in_dim, out_dim, quant_conf, vb)?,
+            QuantMethodType::Awq => awq_linear(in_dim, out_dim, quant_conf, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
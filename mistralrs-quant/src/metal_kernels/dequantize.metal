@@ -202,3 +202,50 @@ instantiate_dequantize_3bit(float)
 instantiate_dequantize_3bit(bfloat)
 #endif
 instantiate_dequantize_3bit(half)
+
+/*********************************/
+/************* GPTQ **************/
+//********************************/
+
+template <typename T>
+[[kernel]] void dequantize_gptq(
+    const device int* weight [[buffer(0)]],
+    const device T* scales_zeros [[buffer(1)]],
+    const device int* g_idx [[buffer(2)]],
+    device T* output [[buffer(3)]],
+    device const uint& bits,
+    device const uint& groups,
+    device const uint& h,
+    device const uint& w,
+    uint tid [[ thread_position_in_grid ]]
+) {
+    if (tid >= h * w) {
+        return;
+    }
+    uint pack = 32 / bits;
+    uint mask = (1u << bits) - 1;
+    uint k = tid / w;
+    uint n = tid % w;
+    uint q = (((uint)weight[(k / pack) * w + n]) >> (bits * (k % pack))) & mask;
+    uint g = (uint)g_idx[k] * w + n;
+    output[tid] = ((T)q - scales_zeros[groups * w + g]) * scales_zeros[g];
+}
+
+#define instantiate_dequantize_gptq(type)                           \
+  template [[host_name("dequantize_gptq_" #type)]]                  \
+  [[kernel]] void dequantize_gptq<type>(                            \
+    const device int* weight [[buffer(0)]],                         \
+    const device type* scales_zeros [[buffer(1)]],                  \
+    const device int* g_idx [[buffer(2)]],                          \
+    device type* output [[buffer(3)]],                              \
+    device const uint& bits,                                        \
+    device const uint& groups,                                      \
+    device const uint& h,                                           \
+    device const uint& w,                                           \
+    uint tid [[ thread_position_in_grid ]]);
+
+instantiate_dequantize_gptq(float)
+#if defined(__HAVE_BFLOAT__)
+instantiate_dequantize_gptq(bfloat)
+#endif
+instantiate_dequantize_gptq(half)
@@ -329,6 +329,50 @@ pub fn call_dequant_3bit(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn call_dequant_gptq(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    ty: DType,
+    weight: &Buffer,
+    scales_zeros: &Buffer,
+    g_idx: &Buffer,
+    bits: u32,
+    groups: u32,
+    h: u32,
+    w: u32,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let name = match ty {
+        DType::F32 => "dequantize_gptq_float",
+        DType::BF16 => "dequantize_gptq_bfloat",
+        DType::F16 => "dequantize_gptq_half",
+        other => {
+            return Err(MetalKernelError::DTypeMismatch {
+                expected: vec![DType::F32, DType::F16, DType::BF16],
+                got: other,
+            })
+        }
+    };
+    let pipeline = kernels.load_pipeline(device, Source::Dequant, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let length = h * w;
+
+    set_params!(
+        encoder,
+        (weight, scales_zeros, g_idx, output, bits, groups, h, w)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length as usize);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_bitwise_or(
     device: &Device,
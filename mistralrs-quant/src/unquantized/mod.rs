@@ -25,6 +25,57 @@ pub struct UnquantLinear {
     stats: Option<ImatrixLayerStats>,
 }
 
+impl UnquantLinear {
+    /// A layer running with a dequantized `(in_dim, out_dim)` weight.
+    pub(crate) fn from_dequantized(w: &Tensor, bias: Option<Tensor>) -> Result<Self> {
+        Self::new(QuantMethodConfig::Unquantized(Linear::new(
+            w.t()?.contiguous()?,
+            bias,
+        )))
+    }
+}
+
+/// Apply ISQ to a quantized layer through its dequantized `(in_dim, out_dim)` weight.
+pub(crate) fn requantize(
+    w: &Tensor,
+    bias: Option<Tensor>,
+    dtype: IsqType,
+    device: Device,
+    n_quantized: &AtomicUsize,
+    imatrix_weight: Option<Vec<f32>>,
+) -> Result<Arc<dyn QuantMethod>> {
+    Arc::new(UnquantLinear::from_dequantized(w, bias)?).apply_isq(
+        Some(dtype),
+        device,
+        n_quantized,
+        imatrix_weight,
+    )
+}
+
+/// The maximum number of threads to apply ISQ with on the CPU.
+pub(crate) fn isq_cpu_threads(dtype: IsqType) -> Option<NonZeroUsize> {
+    match dtype {
+        /*IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
+        IsqType::HQQ4 | IsqType::HQQ8 => {
+            // Use 1 because our HQQ quantizes on the GPU
+            Some(1.try_into().unwrap())
+        }
+        IsqType::F8E4M3 => None,
+        IsqType::Q2K
+        | IsqType::Q3K
+        | IsqType::Q4K
+        | IsqType::Q4_0
+        | IsqType::Q4_1
+        | IsqType::Q5K
+        | IsqType::Q5_0
+        | IsqType::Q5_1
+        | IsqType::Q6K
+        | IsqType::Q8K
+        | IsqType::Q8_0
+        | IsqType::Q8_1 => None,
+    }
+}
+
 impl QuantMethod for UnquantLinear {
     fn new(method: QuantMethodConfig) -> candle_core::Result<Self>
     where
@@ -35,7 +86,8 @@ impl QuantMethod for UnquantLinear {
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => unreachable!(),
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self {
                 w: l.weight().clone(),
                 b: l.bias().cloned(),
@@ -228,26 +280,7 @@ impl QuantMethod for UnquantLinear {
     }
 
     fn get_max_isq_cpu_threads(&self, dtype: IsqType) -> Option<NonZeroUsize> {
-        match dtype {
-            /*IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
-            IsqType::HQQ4 | IsqType::HQQ8 => {
-                // Use 1 because our HQQ quantizes on the GPU
-                Some(1.try_into().unwrap())
-            }
-            IsqType::F8E4M3 => None,
-            IsqType::Q2K
-            | IsqType::Q3K
-            | IsqType::Q4K
-            | IsqType::Q4_0
-            | IsqType::Q4_1
-            | IsqType::Q5K
-            | IsqType::Q5_0
-            | IsqType::Q5_1
-            | IsqType::Q6K
-            | IsqType::Q8K
-            | IsqType::Q8_0
-            | IsqType::Q8_1 => None,
-        }
+        isq_cpu_threads(dtype)
     }
 
     fn unquant_weight_bias(&self) -> Option<(Tensor, Option<Tensor>)> {
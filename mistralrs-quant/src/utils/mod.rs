@@ -2,7 +2,8 @@
 mod ffi;
 pub(crate) mod isq;
 mod ops;
-
+#[cfg(test)]
+pub(crate) mod testing;
 mod uqff;
 
 pub use ops::{BitWiseOp, LeftshiftOp};
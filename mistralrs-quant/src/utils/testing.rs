@@ -0,0 +1,27 @@
+//! Synthetic weights shared by the tests of the dequantizing layers.
+
+pub(crate) const IN_DIM: usize = 64;
+pub(crate) const OUT_DIM: usize = 16;
+pub(crate) const GROUP_SIZE: usize = 32;
+
+/// The quantized weight at input `k` and output `n`, with `bits` bits.
+pub(crate) fn quantized_value(k: usize, n: usize, bits: usize) -> u32 {
+    ((k * 7 + n * 3) % (1 << bits)) as u32
+}
+
+/// The zero point of group `g` and output `n`, below `max`.
+pub(crate) fn zero_point(g: usize, n: usize, max: usize) -> u32 {
+    ((g + n) % max) as u32
+}
+
+/// The scale of group `g` and output `n`.
+pub(crate) fn scale(g: usize, n: usize) -> f32 {
+    (1 + g + n) as f32 / 64.
+}
+
+pub(crate) fn max_abs_diff(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0., f32::max)
+}
@@ -12,13 +12,14 @@ use std::{
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
-    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens},
+    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, ResponseFormat, StopTokens},
+    registry::ModelRegistry,
     util,
 };
 use anyhow::{Context as _, Result};
 use axum::{
     extract::{Json, State},
-    http::{self, StatusCode},
+    http::{self, HeaderMap, StatusCode},
     response::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
@@ -27,8 +28,9 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, DrySamplingParams, ExtendedSamplers,
+    ExtendedSamplingFields, MistralRs, ModelCategory, NormalRequest, Request, RequestMessage,
+    Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -45,6 +47,18 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before generation finished, so free the sequences.
+        if !self.is_done {
+            if let Ok(sender) = self.state.get_sender() {
+                let _ = sender.try_send(Request::Cancel(self.request_id));
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -82,6 +96,7 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
                 Response::Raw { .. } => unreachable!(),
             },
             Err(_) => Poll::Pending,
@@ -157,10 +172,27 @@ async fn parse_request(
     oairequest: ChatCompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
+    request_id: usize,
+    tenant: Option<String>,
 ) -> Result<(Request, bool)> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
+    let constraint = match (oairequest.grammar, oairequest.response_format) {
+        (Some(_), Some(ResponseFormat::JsonObject | ResponseFormat::JsonSchema { .. })) => {
+            anyhow::bail!("Only one of `grammar` and `response_format` may be specified.")
+        }
+        (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+        (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+        (None, Some(ResponseFormat::JsonObject)) => {
+            Constraint::JsonSchema(serde_json::json!({ "type": "object" }))
+        }
+        (None, Some(ResponseFormat::JsonSchema { json_schema })) => {
+            Constraint::JsonSchema(json_schema.schema)
+        }
+        (None, Some(ResponseFormat::Text) | None) => Constraint::None,
+    };
+
     let stop_toks = match oairequest.stop_seqs {
         Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
         Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
@@ -170,6 +202,7 @@ async fn parse_request(
         Either::Left(req_messages) => {
             let mut messages = Vec::new();
             let mut image_urls = Vec::new();
+            let mut video_urls = Vec::new();
             for message in req_messages {
                 match message.content.deref() {
                     Either::Left(content) => {
@@ -185,12 +218,12 @@ async fn parse_request(
                     Either::Right(image_messages) => {
                         if image_messages.len() != 2 {
                             anyhow::bail!(
-                                "Expected 2 items for the content of a message with an image."
+                                "Expected 2 items for the content of a message with an image or video."
                             );
                         }
                         if message.role != "user" {
                             anyhow::bail!(
-                                "Role for an image message must be `user`, but it is {}",
+                                "Role for an image or video message must be `user`, but it is {}",
                                 message.role
                             );
                         }
@@ -198,7 +231,7 @@ async fn parse_request(
                         let mut items = Vec::new();
                         for image_message in image_messages {
                             if image_message.len() != 2 {
-                                anyhow::bail!("Expected 2 items for the sub-content of a message with an image.");
+                                anyhow::bail!("Expected 2 items for the sub-content of a message with an image or video.");
                             }
                             if !image_message.contains_key("type") {
                                 anyhow::bail!("Expected `type` key in input message.");
@@ -212,6 +245,7 @@ async fn parse_request(
                         fn get_content_and_url(
                             text_idx: usize,
                             url_idx: usize,
+                            url_key: &str,
                             image_messages: &[HashMap<String, MessageInnerContent>],
                         ) -> Result<(String, String)> {
                             if image_messages[text_idx]["text"].is_right() {
@@ -221,15 +255,16 @@ async fn parse_request(
                                 .as_ref()
                                 .unwrap_left()
                                 .clone();
-                            if image_messages[url_idx]["image_url"].is_left()
-                                || !image_messages[url_idx]["image_url"]
+                            if !image_messages[url_idx].contains_key(url_key)
+                                || image_messages[url_idx][url_key].is_left()
+                                || !image_messages[url_idx][url_key]
                                     .as_ref()
                                     .unwrap_right()
                                     .contains_key("url")
                             {
-                                anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}")
+                                anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `{url_key}`, `{url_key}`: {{`url`: ...}}}}")
                             }
-                            let url = image_messages[url_idx]["image_url"].as_ref().unwrap_right()
+                            let url = image_messages[url_idx][url_key].as_ref().unwrap_right()
                                 ["url"]
                                 .clone();
                             Ok((content, url))
@@ -239,16 +274,25 @@ async fn parse_request(
                             Either<String, Vec<IndexMap<String, Value>>>,
                         > = IndexMap::new();
                         message_map.insert("role".to_string(), Either::Left(message.role));
-                        let (content, url) = if items[0] == "text" {
-                            get_content_and_url(0, 1, image_messages)?
-                        } else {
-                            get_content_and_url(1, 0, image_messages)?
-                        };
+                        let (text_idx, url_idx) = if items[0] == "text" { (0, 1) } else { (1, 0) };
+                        let is_video = items[url_idx] == "video_url";
+                        let url_key = if is_video { "video_url" } else { "image_url" };
+                        let (mut content, url) =
+                            get_content_and_url(text_idx, url_idx, url_key, image_messages)?;
+                        if is_video {
+                            // The video placeholder is not in the chat template for all models.
+                            if let ModelCategory::Vision { prefixer, .. } = &state.config().category
+                            {
+                                content = prefixer.prefix_video(video_urls.len(), &content);
+                            }
+                        }
 
                         let mut content_map: Vec<IndexMap<String, Value>> = Vec::new();
                         let mut content_image_map = IndexMap::new();
-                        content_image_map
-                            .insert("type".to_string(), Value::String("image".to_string()));
+                        content_image_map.insert(
+                            "type".to_string(),
+                            Value::String(if is_video { "video" } else { "image" }.to_string()),
+                        );
                         content_map.push(content_image_map);
                         let mut content_text_map = IndexMap::new();
                         content_text_map
@@ -258,11 +302,15 @@ async fn parse_request(
 
                         message_map.insert("content".to_string(), Either::Right(content_map));
                         messages.push(message_map);
-                        image_urls.push(url);
+                        if is_video {
+                            video_urls.push(url);
+                        } else {
+                            image_urls.push(url);
+                        }
                     }
                 }
             }
-            if !image_urls.is_empty() {
+            if !image_urls.is_empty() || !video_urls.is_empty() {
                 let mut images = Vec::new();
                 for url_unparsed in image_urls {
                     let image = util::parse_image_url(&url_unparsed)
@@ -273,7 +321,21 @@ async fn parse_request(
 
                     images.push(image);
                 }
-                RequestMessage::VisionChat { messages, images }
+                let mut videos = Vec::new();
+                for url_unparsed in video_urls {
+                    let frames = util::parse_video_url(&url_unparsed)
+                        .await
+                        .with_context(|| {
+                            format!("Failed to parse video resource: {}", url_unparsed)
+                        })?;
+
+                    videos.push(frames);
+                }
+                RequestMessage::VisionChat {
+                    messages,
+                    images,
+                    videos,
+                }
             } else {
                 RequestMessage::Chat(messages)
             }
@@ -289,6 +351,23 @@ async fn parse_request(
         }
     };
 
+    let ExtendedSamplers {
+        mirostat,
+        xtc,
+        dynatemp,
+        penalty_range,
+    } = ExtendedSamplers::new(ExtendedSamplingFields {
+        mirostat: oairequest.mirostat,
+        mirostat_tau: oairequest.mirostat_tau,
+        mirostat_eta: oairequest.mirostat_eta,
+        xtc_probability: oairequest.xtc_probability,
+        xtc_threshold: oairequest.xtc_threshold,
+        dynatemp_range: oairequest.dynatemp_range,
+        dynatemp_exponent: oairequest.dynatemp_exponent,
+        penalty_last_n: oairequest.penalty_last_n,
+        penalty_exclude_prompt: oairequest.penalty_exclude_prompt,
+        penalty_whitelist: oairequest.penalty_whitelist.clone(),
+    })?;
     let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
         Some(DrySamplingParams::new_with_defaults(
             dry_multiplier,
@@ -303,7 +382,7 @@ async fn parse_request(
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
-            id: state.next_request_id(),
+            id: request_id,
             messages,
             sampling_params: SamplingParams {
                 temperature: oairequest.temperature,
@@ -318,21 +397,29 @@ async fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                beam_search: None,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                tfs_z: oairequest.tfs_z,
+                mirostat,
+                xtc,
+                dynatemp,
+                repetition_penalty: oairequest.repetition_penalty,
+                penalty_range,
+                seed: oairequest.seed,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
             return_raw_logits: false,
+            priority: oairequest.priority.unwrap_or_default(),
+            tenant,
         }),
         is_streaming,
     ))
@@ -346,11 +433,25 @@ async fn parse_request(
     responses((status = 200, description = "Chat completions"))
 )]
 pub async fn chatcompletions(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
+    headers: HeaderMap,
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
+    let state = match registry.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return ChatCompletionResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
+    let request_id = state.next_request_id();
+    let (request, is_streaming) = match parse_request(
+        oairequest,
+        state.clone(),
+        tx,
+        request_id,
+        util::get_tenant(&headers),
+    )
+    .await
+    {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
@@ -371,6 +472,7 @@ pub async fn chatcompletions(
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         ChatCompletionResponder::Sse(
@@ -414,6 +516,7 @@ pub async fn chatcompletions(
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
             Response::Raw { .. } => unreachable!(),
         }
     }
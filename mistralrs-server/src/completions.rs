@@ -9,18 +9,23 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::{
+    openai::{CompletionRequest, Grammar, StopTokens},
+    registry::ModelRegistry,
+    util,
+};
 use axum::{
     extract::{Json, State},
-    http::{self, StatusCode},
+    http::{self, HeaderMap, StatusCode},
     response::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
     },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    BeamSearchParams, CompletionResponse, Constraint, DrySamplingParams, ExtendedSamplers,
+    ExtendedSamplingFields, MistralRs, NormalRequest, Request, RequestMessage, Response,
+    SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 use tracing::warn;
@@ -38,6 +43,18 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before generation finished, so free the sequences.
+        if !self.is_done {
+            if let Ok(sender) = self.state.get_sender() {
+                let _ = sender.try_send(Request::Cancel(self.request_id));
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -75,6 +92,7 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
                 Response::Raw { .. } => unreachable!(),
             },
             Err(_) => Poll::Pending,
@@ -148,6 +166,8 @@ fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
+    request_id: usize,
+    tenant: Option<String>,
 ) -> Result<(Request, bool)> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
@@ -164,6 +184,23 @@ fn parse_request(
 
     let is_streaming = oairequest.stream.unwrap_or(false);
 
+    let ExtendedSamplers {
+        mirostat,
+        xtc,
+        dynatemp,
+        penalty_range,
+    } = ExtendedSamplers::new(ExtendedSamplingFields {
+        mirostat: oairequest.mirostat,
+        mirostat_tau: oairequest.mirostat_tau,
+        mirostat_eta: oairequest.mirostat_eta,
+        xtc_probability: oairequest.xtc_probability,
+        xtc_threshold: oairequest.xtc_threshold,
+        dynatemp_range: oairequest.dynatemp_range,
+        dynatemp_exponent: oairequest.dynatemp_exponent,
+        penalty_last_n: oairequest.penalty_last_n,
+        penalty_exclude_prompt: oairequest.penalty_exclude_prompt,
+        penalty_whitelist: oairequest.penalty_whitelist.clone(),
+    })?;
     let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
         Some(DrySamplingParams::new_with_defaults(
             dry_multiplier,
@@ -174,9 +211,17 @@ fn parse_request(
     } else {
         None
     };
+    let beam_search = oairequest.use_beam_search.then(|| {
+        let defaults = BeamSearchParams::new(oairequest.best_of);
+        BeamSearchParams {
+            length_penalty: oairequest.length_penalty.unwrap_or(defaults.length_penalty),
+            early_stopping: oairequest.early_stopping.unwrap_or(defaults.early_stopping),
+            ..defaults
+        }
+    });
     Ok((
         Request::Normal(NormalRequest {
-            id: state.next_request_id(),
+            id: request_id,
             messages: RequestMessage::Completion {
                 text: oairequest.prompt,
                 echo_prompt: oairequest.echo_prompt,
@@ -195,6 +240,16 @@ fn parse_request(
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                beam_search,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                tfs_z: oairequest.tfs_z,
+                mirostat,
+                xtc,
+                dynatemp,
+                repetition_penalty: oairequest.repetition_penalty,
+                penalty_range,
+                seed: oairequest.seed,
             },
             response: tx,
             return_logprobs: false,
@@ -210,6 +265,8 @@ fn parse_request(
             tools: oairequest.tools,
             logits_processors: None,
             return_raw_logits: false,
+            priority: oairequest.priority.unwrap_or_default(),
+            tenant,
         }),
         is_streaming,
     ))
@@ -224,17 +281,29 @@ fn parse_request(
 )]
 
 pub async fn completions(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
+    headers: HeaderMap,
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
+    let state = match registry.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return CompletionResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
+    let request_id = state.next_request_id();
     if oairequest.logprobs.is_some() {
         return CompletionResponder::ValidationError(
             "Completion requests do not support logprobs.".into(),
         );
     }
 
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
+    let (request, is_streaming) = match parse_request(
+        oairequest,
+        state.clone(),
+        tx,
+        request_id,
+        util::get_tenant(&headers),
+    ) {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
@@ -255,6 +324,7 @@ pub async fn completions(
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         CompletionResponder::Sse(
@@ -298,6 +368,7 @@ pub async fn completions(
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
             Response::Raw { .. } => unreachable!(),
         }
     }
@@ -0,0 +1,246 @@
+use anyhow::Result;
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::{channel, Sender};
+
+use crate::{
+    openai::{EmbeddingInput, EmbeddingRequest},
+    registry::ModelRegistry,
+};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    Constraint, EmbeddingResponse, MistralRs, NormalRequest, Request, RequestMessage, Response,
+    SamplingParams,
+};
+use serde::Serialize;
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+/// Validate the encoding format and flatten the input into a list of texts.
+fn embedding_inputs(oairequest: &EmbeddingRequest) -> Result<Vec<String>> {
+    if let Some(format) = &oairequest.encoding_format {
+        if format != "float" {
+            anyhow::bail!("Encoding format `{format}` is not supported, only `float` is.");
+        }
+    }
+    Ok(match &oairequest.input {
+        EmbeddingInput::Single(input) => vec![input.clone()],
+        EmbeddingInput::Multi(inputs) => inputs.clone(),
+    })
+}
+
+fn parse_request(
+    oairequest: EmbeddingRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<Request> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let inputs = embedding_inputs(&oairequest)?;
+
+    Ok(Request::Normal(NormalRequest {
+        id: state.next_request_id(),
+        messages: RequestMessage::Embedding {
+            inputs,
+            pooling: oairequest.pooling,
+        },
+        sampling_params: SamplingParams::deterministic(),
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        suffix: None,
+        constraint: Constraint::None,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        logits_processors: None,
+        return_raw_logits: false,
+        priority: 0,
+        tenant: None,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+
+pub async fn embeddings(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let state = match registry.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return EmbeddingResponder::ValidationError(e.into()),
+    };
+    let (tx, mut rx) = channel(10_000);
+
+    let request = match parse_request(oairequest, state.clone(), tx) {
+        Ok(x) => x,
+        Err(e) => return EmbeddingResponder::ValidationError(e.into()),
+    };
+    let sender = state.get_sender().unwrap();
+
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return EmbeddingResponder::InternalError(e.into());
+    }
+
+    let response = match rx.recv().await {
+        Some(response) => response,
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+    };
+
+    match response {
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e)
+        }
+        Response::ValidationError(e) => EmbeddingResponder::ValidationError(e),
+        Response::Embeddings(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            EmbeddingResponder::Json(response)
+        }
+        Response::CompletionModelError(m, _) => {
+            let e = anyhow::Error::msg(m.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e.into())
+        }
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Chunk(_) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::ModelError(_, _) => unreachable!(),
+        Response::ImageGeneration(_) => unreachable!(),
+        Response::Raw { .. } => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::{EmbeddingData, EmbeddingPooling, EmbeddingResponse, EmbeddingUsage};
+    use serde_json::json;
+
+    use super::embedding_inputs;
+    use crate::{openai::EmbeddingRequest, registry::DEFAULT_MODEL};
+
+    #[test]
+    fn single_input() {
+        let request: EmbeddingRequest = serde_json::from_value(json!({
+            "input": "The food was delicious.",
+        }))
+        .unwrap();
+        assert_eq!(request.model, DEFAULT_MODEL);
+        assert_eq!(request.pooling, EmbeddingPooling::Mean);
+        assert_eq!(
+            embedding_inputs(&request).unwrap(),
+            vec!["The food was delicious."]
+        );
+    }
+
+    #[test]
+    fn multi_input() {
+        let request: EmbeddingRequest = serde_json::from_value(json!({
+            "model": "bge",
+            "input": ["a", "b"],
+            "encoding_format": "float",
+            "pooling": "cls",
+        }))
+        .unwrap();
+        assert_eq!(request.model, "bge");
+        assert_eq!(request.pooling, EmbeddingPooling::Cls);
+        assert_eq!(embedding_inputs(&request).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let request: EmbeddingRequest = serde_json::from_value(json!({
+            "input": "a",
+            "encoding_format": "base64",
+        }))
+        .unwrap();
+        assert!(embedding_inputs(&request).is_err());
+        assert!(serde_json::from_value::<EmbeddingRequest>(json!({ "input": 1 })).is_err());
+        assert!(serde_json::from_value::<EmbeddingRequest>(json!({
+            "input": "a",
+            "pooling": "max",
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn response() {
+        let response = EmbeddingResponse {
+            object: "list".to_string(),
+            data: vec![EmbeddingData {
+                object: "embedding".to_string(),
+                embedding: vec![0.5, -0.5],
+                index: 0,
+            }],
+            model: "bge".to_string(),
+            usage: EmbeddingUsage {
+                prompt_tokens: 3,
+                total_tokens: 3,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "object": "list",
+                "data": [{ "object": "embedding", "embedding": [0.5, -0.5], "index": 0 }],
+                "model": "bge",
+                "usage": { "prompt_tokens": 3, "total_tokens": 3 },
+            })
+        );
+    }
+}
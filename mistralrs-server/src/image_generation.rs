@@ -1,18 +1,25 @@
 use anyhow::Result;
 use std::{error::Error, sync::Arc};
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::ImageGenerationRequest;
+use crate::{
+    openai::{ImageEditRequest, ImageGenerationRequest, ImageVariationRequest},
+    registry::ModelRegistry,
+    util,
+};
 use axum::{
-    extract::{Json, State},
-    http::{self, StatusCode},
+    async_trait,
+    extract::{FromRequest, Json, Multipart, State},
+    http::{self, header, StatusCode},
     response::IntoResponse,
 };
+use base64::{prelude::BASE64_STANDARD, Engine};
+use image::DynamicImage;
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, ImageGenerationResponse, MistralRs, NormalRequest,
-    Request, RequestMessage, Response, SamplingParams,
+    Request, RequestMessage, Response, SamplingParams, MAX_DIFFUSION_IMAGE_SIZE,
 };
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 
 pub enum ImageGenerationResponder {
     Json(ImageGenerationResponse),
@@ -54,6 +61,100 @@ impl IntoResponse for ImageGenerationResponder {
     }
 }
 
+/// The body of the image edit and variation endpoints. Like in the OpenAI API, this can be
+/// multipart/form-data, with the images uploaded as files. Otherwise, it is JSON.
+pub struct ImageForm<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for ImageForm<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = ImageGenerationResponder;
+
+    async fn from_request(req: axum::extract::Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_multipart = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("multipart/form-data"));
+        if !is_multipart {
+            let Json(request) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(|e| ImageGenerationResponder::ValidationError(e.into()))?;
+            return Ok(Self(request));
+        }
+
+        let mut multipart = Multipart::from_request(req, state)
+            .await
+            .map_err(|e| ImageGenerationResponder::ValidationError(e.into()))?;
+        let mut fields = Vec::new();
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| ImageGenerationResponder::ValidationError(e.into()))?
+        {
+            let Some(name) = field.name().map(ToString::to_string) else {
+                continue;
+            };
+            // Uploaded files are read like the images of JSON requests, as base64 encoded data.
+            let value = if field.file_name().is_some() {
+                field
+                    .bytes()
+                    .await
+                    .map(|bytes| BASE64_STANDARD.encode(bytes))
+            } else {
+                field.text().await
+            }
+            .map_err(|e| ImageGenerationResponder::ValidationError(e.into()))?;
+            fields.push((name, value));
+        }
+        // The fields are all text, which the URL encoded form deserializer parses into numbers.
+        serde_urlencoded::to_string(&fields)
+            .map_err(|e| ImageGenerationResponder::ValidationError(e.into()))
+            .and_then(|form| {
+                serde_urlencoded::from_str(&form)
+                    .map_err(|e| ImageGenerationResponder::ValidationError(e.into()))
+            })
+            .map(Self)
+    }
+}
+
+/// The size of an edit of `image`, where the request does not set it: the size of the image,
+/// scaled down to fit in the largest size which can be generated.
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn edit_size(image: &DynamicImage, height: Option<usize>, width: Option<usize>) -> (usize, usize) {
+    let (image_height, image_width) = (image.height() as usize, image.width() as usize);
+    let scale = (MAX_DIFFUSION_IMAGE_SIZE as f64 / image_height.max(image_width) as f64).min(1.0);
+    let scaled = |size: usize| ((size as f64 * scale).round() as usize).max(1);
+    (
+        height.unwrap_or(scaled(image_height)),
+        width.unwrap_or(scaled(image_width)),
+    )
+}
+
+/// The request of an image endpoint, which generates one image.
+fn image_request(state: &MistralRs, messages: RequestMessage, tx: Sender<Response>) -> Request {
+    Request::Normal(NormalRequest {
+        id: state.next_request_id(),
+        messages,
+        sampling_params: SamplingParams::deterministic(),
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        suffix: None,
+        constraint: Constraint::None,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        logits_processors: None,
+        return_raw_logits: false,
+        priority: 0,
+        tenant: None,
+    })
+}
+
 fn parse_request(
     oairequest: ImageGenerationRequest,
     state: Arc<MistralRs>,
@@ -62,28 +163,92 @@ fn parse_request(
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
-    Ok(Request::Normal(NormalRequest {
-        id: state.next_request_id(),
-        messages: RequestMessage::ImageGeneration {
+    Ok(image_request(
+        &state,
+        RequestMessage::ImageGeneration {
             prompt: oairequest.prompt,
             format: oairequest.response_format,
             generation_params: DiffusionGenerationParams {
                 height: oairequest.height,
                 width: oairequest.width,
+                num_steps: oairequest.num_steps,
+                guidance_scale: oairequest.guidance_scale,
+                seed: oairequest.seed,
+                negative_prompt: oairequest.negative_prompt,
+                negative_guidance_scale: oairequest.negative_guidance_scale,
+                strength: None,
             },
         },
-        sampling_params: SamplingParams::deterministic(),
-        response: tx,
-        return_logprobs: false,
-        is_streaming: false,
-        suffix: None,
-        constraint: Constraint::None,
-        adapters: None,
-        tool_choice: None,
-        tools: None,
-        logits_processors: None,
-        return_raw_logits: false,
-    }))
+        tx,
+    ))
+}
+
+async fn parse_edit_request(
+    oairequest: ImageEditRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<Request> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let image = util::parse_image_url(&oairequest.image).await?;
+    let mask = match &oairequest.mask {
+        Some(mask) => Some(util::parse_image_url(mask).await?),
+        None => None,
+    };
+    let (height, width) = edit_size(&image, oairequest.height, oairequest.width);
+    Ok(image_request(
+        &state,
+        RequestMessage::ImageEdit {
+            prompt: oairequest.prompt,
+            format: oairequest.response_format,
+            generation_params: DiffusionGenerationParams {
+                height,
+                width,
+                num_steps: oairequest.num_steps,
+                guidance_scale: oairequest.guidance_scale,
+                seed: oairequest.seed,
+                negative_prompt: oairequest.negative_prompt,
+                negative_guidance_scale: oairequest.negative_guidance_scale,
+                strength: oairequest.strength,
+            },
+            image,
+            mask,
+        },
+        tx,
+    ))
+}
+
+async fn parse_variation_request(
+    oairequest: ImageVariationRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<Request> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let image = util::parse_image_url(&oairequest.image).await?;
+    let (height, width) = edit_size(&image, oairequest.height, oairequest.width);
+    Ok(image_request(
+        &state,
+        RequestMessage::ImageEdit {
+            prompt: String::new(),
+            format: oairequest.response_format,
+            generation_params: DiffusionGenerationParams {
+                height,
+                width,
+                num_steps: oairequest.num_steps,
+                guidance_scale: oairequest.guidance_scale,
+                seed: oairequest.seed,
+                negative_prompt: None,
+                negative_guidance_scale: None,
+                strength: oairequest.strength,
+            },
+            image,
+            mask: None,
+        },
+        tx,
+    ))
 }
 
 #[utoipa::path(
@@ -95,12 +260,69 @@ fn parse_request(
 )]
 
 pub async fn image_generation(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(oairequest): Json<ImageGenerationRequest>,
 ) -> ImageGenerationResponder {
-    let (tx, mut rx) = channel(10_000);
+    let state = match registry.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return ImageGenerationResponder::ValidationError(e.into()),
+    };
+    let (tx, rx) = channel(10_000);
+
+    let request = parse_request(oairequest, state.clone(), tx);
+    send_request(state, request, rx).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/images/edits",
+    request_body = ImageEditRequest,
+    responses((status = 200, description = "Image edit"))
+)]
+
+pub async fn image_edit(
+    State(registry): State<Arc<ModelRegistry>>,
+    ImageForm(oairequest): ImageForm<ImageEditRequest>,
+) -> ImageGenerationResponder {
+    let state = match registry.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return ImageGenerationResponder::ValidationError(e.into()),
+    };
+    let (tx, rx) = channel(10_000);
+
+    let request = parse_edit_request(oairequest, state.clone(), tx).await;
+    send_request(state, request, rx).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/images/variations",
+    request_body = ImageVariationRequest,
+    responses((status = 200, description = "Image variation"))
+)]
+
+pub async fn image_variation(
+    State(registry): State<Arc<ModelRegistry>>,
+    ImageForm(oairequest): ImageForm<ImageVariationRequest>,
+) -> ImageGenerationResponder {
+    let state = match registry.get(&oairequest.model) {
+        Ok(state) => state,
+        Err(e) => return ImageGenerationResponder::ValidationError(e.into()),
+    };
+    let (tx, rx) = channel(10_000);
+
+    let request = parse_variation_request(oairequest, state.clone(), tx).await;
+    send_request(state, request, rx).await
+}
 
-    let request = match parse_request(oairequest, state.clone(), tx) {
+async fn send_request(
+    state: Arc<MistralRs>,
+    request: Result<Request>,
+    mut rx: Receiver<Response>,
+) -> ImageGenerationResponder {
+    let request = match request {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
@@ -145,6 +367,7 @@ pub async fn image_generation(
         Response::Chunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
+        Response::Embeddings(_) => unreachable!(),
         Response::Raw { .. } => unreachable!(),
     }
 }
@@ -34,6 +34,11 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         ModelCategory::Text => text_interactive_mode(mistralrs, throughput).await,
         ModelCategory::Vision { .. } => vision_interactive_mode(mistralrs, throughput).await,
         ModelCategory::Diffusion => diffusion_interactive_mode(mistralrs).await,
+        ModelCategory::Embedding => {
+            error!(
+                "Interactive mode is not supported for embedding models, use the server instead."
+            )
+        }
     }
 }
 
@@ -95,6 +100,16 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        xtc: None,
+        dynatemp: None,
+        repetition_penalty: None,
+        penalty_range: None,
+        seed: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -177,6 +192,8 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -216,6 +233,7 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
                 Response::Raw { .. } => unreachable!(),
             }
         }
@@ -265,7 +283,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
     let mut images = Vec::new();
 
     let prefixer = match &mistralrs.config().category {
-        ModelCategory::Text | ModelCategory::Diffusion => {
+        ModelCategory::Text | ModelCategory::Diffusion | ModelCategory::Embedding => {
             panic!("`add_image_message` expects a vision model.")
         }
         ModelCategory::Vision {
@@ -287,6 +305,16 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        xtc: None,
+        dynatemp: None,
+        repetition_penalty: None,
+        penalty_range: None,
+        seed: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -380,6 +408,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
 
         let request_messages = RequestMessage::VisionChat {
             images: images.clone(),
+            videos: Vec::new(),
             messages: messages.clone(),
         };
 
@@ -398,6 +427,8 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -437,6 +468,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
                 Response::Raw { .. } => unreachable!(),
             }
         }
@@ -521,6 +553,8 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         });
 
         let start = Instant::now();
@@ -2,41 +2,46 @@ use anyhow::Result;
 use axum::{
     extract::{DefaultBodyLimit, Json, State},
     http::{self, Method},
+    middleware,
     routing::{get, post},
     Router,
 };
 use candle_core::Device;
 use clap::Parser;
 use mistralrs_core::{
-    get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
-    parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType,
-    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    initialize_logging, paged_attn_supported, parse_isq_value, AdapterLoadRequest,
+    AdapterUnloadRequest, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType, KvCacheDType,
+    MemoryGpuConfig, MistralRs, ModelSelected, PagedAttentionConfig, PrefixCacheDiskConfig,
+    Request, SchedulingPolicy, TokenSource, ToolCallParser,
 };
 use openai::{
-    ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
-    StopTokens,
+    default_model, ChatCompletionRequest, CompletionRequest, EmbeddingRequest, ImageEditRequest,
+    ImageGenerationRequest, ImageVariationRequest, Message, ModelObjects, StopTokens,
 };
+use registry::{ModelEntry, ModelLoadOptions, ModelRegistry, ModelSource, ModelsConfig};
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{collections::HashMap, num::NonZeroUsize, path::PathBuf, sync::Arc};
 
 mod chat_completion;
 mod completions;
+mod embeddings;
 mod image_generation;
 mod interactive_mode;
 mod openai;
+mod registry;
 mod util;
 
 use crate::openai::ModelObject;
 use crate::{
     chat_completion::{__path_chatcompletions, chatcompletions},
     completions::completions,
-    image_generation::image_generation,
+    embeddings::embeddings,
+    image_generation::{image_edit, image_generation, image_variation},
 };
 
 use interactive_mode::interactive_mode;
 use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing::{info, warn};
+use tracing::info;
 use utoipa::{OpenApi, ToSchema};
 use utoipa_swagger_ui::SwaggerUi;
 
@@ -48,6 +53,30 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_scheduling_policy(
+    policy: &str,
+    tenant_weights: Option<Vec<String>>,
+) -> Result<SchedulingPolicy> {
+    match policy {
+        "fcfs" => Ok(SchedulingPolicy::Fcfs),
+        "priority" => Ok(SchedulingPolicy::StrictPriority),
+        "sjf" => Ok(SchedulingPolicy::ShortestJobFirst),
+        "fair" => {
+            let mut weights = HashMap::new();
+            for weight in tenant_weights.unwrap_or_default() {
+                let Some((tenant, weight)) = weight.rsplit_once('=') else {
+                    anyhow::bail!("Expected tenant weight to be of format KEY=WEIGHT, got {weight}");
+                };
+                weights.insert(util::tenant_id(tenant), weight.parse::<f64>()?);
+            }
+            Ok(SchedulingPolicy::WeightedFair { weights })
+        }
+        other => anyhow::bail!(
+            "Unknown scheduling policy `{other}`, expected one of `fcfs`, `priority`, `fair` or `sjf`."
+        ),
+    }
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -75,7 +104,19 @@ struct Args {
 
     /// Model selector
     #[clap(subcommand)]
-    model: ModelSelected,
+    model: Option<ModelSelected>,
+
+    /// TOML file listing several models to serve, instead of selecting one model. Requests are routed
+    /// by their `model` field. Each `[[models]]` entry has a `name` and the fields of a TOML selector file.
+    /// An optional top-level `default_model` names the model for requests to the `default` model.
+    #[arg(long, conflicts_with = "interactive_mode")]
+    models_config: Option<PathBuf>,
+
+    /// API key which enables the `/admin/models/load`, `/admin/models/unload`, `/load_adapter` and `/unload_adapter`
+    /// endpoints. Requests to them must pass it as a bearer token. By default, models and adapters cannot be loaded
+    /// or unloaded at runtime.
+    #[arg(long, conflicts_with = "interactive_mode")]
+    admin_api_key: Option<String>,
 
     /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1.
     #[arg(long, default_value_t = 16)]
@@ -104,6 +145,21 @@ struct Args {
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
 
+    /// Directory in which prefix caches evicted from the device, and all of them on shutdown, are persisted so
+    /// that they can be reloaded on demand and after a restart. By default, the caches are only kept in memory.
+    /// This is only supported for text models without PagedAttention or speculative decoding.
+    #[arg(long)]
+    prefix_cache_dir: Option<PathBuf>,
+
+    /// Maximum size of the persisted prefix caches in MB. The least recently used caches are deleted first.
+    #[arg(long, default_value_t = 8192)]
+    prefix_cache_disk_mb: u64,
+
+    /// Storage dtype of the KV cache: `auto` (the model dtype), `q8`, `fp8` or `q4`. Quantized KV caches reduce
+    /// memory usage at some cost in accuracy. This does not apply to PagedAttention.
+    #[arg(long, default_value_t = KvCacheDType::Auto)]
+    kv_cache_dtype: KvCacheDType,
+
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
     /// ORD:NUM;... Where ORD is a unique device ordinal and NUM is the number of layers for that device.
@@ -146,9 +202,35 @@ struct Args {
     #[arg(long = "throughput", default_value_t = false)]
     throughput_log: bool,
 
+    /// Order in which waiting requests are admitted: `fcfs`, `priority` (by the request `priority`),
+    /// `fair` (weighted fair queuing by API key) or `sjf` (shortest `max_tokens` first).
+    /// This does not apply when PagedAttention is used.
+    #[arg(long, default_value = "fcfs")]
+    scheduling_policy: String,
+
+    /// Weights for the `fair` scheduling policy, following the pattern KEY=WEIGHT;... where KEY is an API key.
+    /// API keys which are not listed have a weight of 1.
+    #[arg(long, value_parser, value_delimiter = ';')]
+    tenant_weights: Option<Vec<String>>,
+
+    /// Number of scheduling steps a request may wait before it is admitted regardless of the scheduling policy.
+    /// Set to 0 to disable starvation protection.
+    #[arg(long, default_value_t = 64)]
+    starvation_limit: usize,
+
+    /// Use speculative decoding without a draft model, drafting up to this many tokens per step by looking up
+    /// the last tokens of a sequence earlier in its prompt and output. TOML selectors configure this under `[speculative]`.
+    #[arg(long)]
+    ngram_lookup_gamma: Option<usize>,
+
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
+
+    /// Format of the tool calls the model generates: `json`, `hermes` (or `qwen`), `mistral` or `llama3`.
+    /// If this is not set, the format is detected from the chat template.
+    #[arg(long)]
+    tool_call_parser: Option<ToolCallParser>,
 }
 
 #[utoipa::path(
@@ -157,15 +239,19 @@ struct Args {
     path = "/v1/models",
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
-async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+async fn models(State(registry): State<Arc<ModelRegistry>>) -> Json<ModelObjects> {
     Json(ModelObjects {
         object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
+        data: registry
+            .list()
+            .into_iter()
+            .map(|(name, state)| ModelObject {
+                id: name,
+                object: "model",
+                created: state.get_creation_time(),
+                owned_by: "local",
+            })
+            .collect(),
     })
 }
 
@@ -183,6 +269,9 @@ async fn health() -> &'static str {
 struct AdapterActivationRequest {
     #[schema(example = json!(vec!["adapter_1","adapter_2"]))]
     adapter_names: Vec<String>,
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    model: String,
 }
 
 #[utoipa::path(
@@ -193,20 +282,108 @@ struct AdapterActivationRequest {
     responses((status = 200, description = "Activate a set of pre-loaded LoRA adapters"))
 )]
 async fn activate_adapters(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(request): Json<AdapterActivationRequest>,
-) -> String {
+) -> Result<String, String> {
+    let state = registry.get(&request.model).map_err(|e| e.to_string())?;
     let repr = format!("Adapter activation: {:?}", request.adapter_names);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ActivateAdapters(request.adapter_names);
     state.get_sender().unwrap().send(request).await.unwrap();
-    repr
+    Ok(repr)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct AdapterLoadHttpRequest {
+    /// Name which requests select the adapter by.
+    #[schema(example = "adapter_4")]
+    name: String,
+    /// Local directory of a PEFT adapter, with `adapter_config.json` and `adapter_model.safetensors`.
+    #[schema(example = "/path/to/adapter_4")]
+    path: PathBuf,
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    model: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/load_adapter",
+    request_body = AdapterLoadHttpRequest,
+    responses((status = 200, description = "Load a LoRA adapter into a model fine-tuned with LoRA"))
+)]
+async fn load_adapter(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<AdapterLoadHttpRequest>,
+) -> Result<String, String> {
+    let state = registry.get(&request.model).map_err(|e| e.to_string())?;
+    let repr = format!(
+        "Adapter load: {} from {}",
+        request.name,
+        request.path.display()
+    );
+    MistralRs::maybe_log_request(state.clone(), repr);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let name = request.name.clone();
+    let request = Request::LoadAdapter(AdapterLoadRequest {
+        name: request.name,
+        path: request.path,
+        response: tx,
+    });
+    state.get_sender().unwrap().send(request).await.unwrap();
+    let n_layers = rx
+        .recv()
+        .await
+        .ok_or("Engine did not respond.")?
+        .map_err(|e| e.to_string())?;
+    Ok(format!("Loaded adapter `{name}` for {n_layers} layers."))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct AdapterUnloadHttpRequest {
+    #[schema(example = "adapter_4")]
+    name: String,
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    model: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/unload_adapter",
+    request_body = AdapterUnloadHttpRequest,
+    responses((status = 200, description = "Unload a LoRA adapter which is not in use"))
+)]
+async fn unload_adapter(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<AdapterUnloadHttpRequest>,
+) -> Result<String, String> {
+    let state = registry.get(&request.model).map_err(|e| e.to_string())?;
+    let repr = format!("Adapter unload: {}", request.name);
+    MistralRs::maybe_log_request(state.clone(), repr);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let name = request.name.clone();
+    let request = Request::UnloadAdapter(AdapterUnloadRequest {
+        name: request.name,
+        response: tx,
+    });
+    state.get_sender().unwrap().send(request).await.unwrap();
+    rx.recv()
+        .await
+        .ok_or("Engine did not respond.")?
+        .map_err(|e| e.to_string())?;
+    Ok(format!("Unloaded adapter `{name}`."))
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
     ggml_type: String,
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    model: String,
 }
 
 #[utoipa::path(
@@ -217,9 +394,10 @@ struct ReIsqRequest {
     responses((status = 200, description = "Reapply ISQ to a non GGUF or GGML model."))
 )]
 async fn re_isq(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(request): Json<ReIsqRequest>,
 ) -> Result<String, String> {
+    let state = registry.get(&request.model).map_err(|e| e.to_string())?;
     let repr = format!("Re ISQ: {:?}", request.ggml_type);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ReIsq(parse_isq_value(&request.ggml_type)?);
@@ -227,12 +405,50 @@ async fn re_isq(
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/admin/models/load",
+    request_body = ModelEntry,
+    responses((status = 200, description = "Load a model, which is then served under the given name."))
+)]
+async fn load_model(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(entry): Json<ModelEntry>,
+) -> Result<String, String> {
+    let name = entry.name.clone();
+    info!("Loading model `{name}`.");
+    registry.load(entry).await.map_err(|e| e.to_string())?;
+    Ok(format!("Loaded model `{name}`."))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct UnloadModelRequest {
+    #[schema(example = "mistral")]
+    name: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/admin/models/unload",
+    request_body = UnloadModelRequest,
+    responses((status = 200, description = "Stop serving a model and free its memory."))
+)]
+async fn unload_model(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(request): Json<UnloadModelRequest>,
+) -> Result<String, String> {
+    registry.unload(&request.name).map_err(|e| e.to_string())?;
+    Ok(format!("Unloaded model `{}`.", request.name))
+}
+
+fn get_router(state: Arc<ModelRegistry>, admin_api_key: Option<String>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
         paths(models, health, chatcompletions),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, ImageEditRequest, ImageVariationRequest, EmbeddingRequest, StopTokens, Message)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -253,6 +469,21 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
         .allow_origin(allow_origin);
 
+    // Loading models and adapters reads arbitrary paths and downloads arbitrary repositories, so
+    // this is opt-in.
+    let admin_router = match admin_api_key {
+        Some(admin_api_key) => Router::new()
+            .route("/admin/models/load", post(load_model))
+            .route("/admin/models/unload", post(unload_model))
+            .route("/load_adapter", post(load_adapter))
+            .route("/unload_adapter", post(unload_adapter))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::new(admin_api_key),
+                util::require_admin_key,
+            )),
+        None => Router::new(),
+    };
+
     Router::new()
         .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
         .route("/v1/chat/completions", post(chatcompletions))
@@ -263,6 +494,10 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .route("/activate_adapters", post(activate_adapters))
         .route("/re_isq", post(re_isq))
         .route("/v1/images/generations", post(image_generation))
+        .route("/v1/images/edits", post(image_edit))
+        .route("/v1/images/variations", post(image_variation))
+        .route("/v1/embeddings", post(embeddings))
+        .merge(admin_router)
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
         .with_state(state)
@@ -270,7 +505,7 @@ fn get_router(state: Arc<MistralRs>) -> Router {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut args = Args::parse();
+    let args = Args::parse();
     initialize_logging();
 
     #[cfg(not(feature = "flash-attn"))]
@@ -278,13 +513,6 @@ async fn main() -> Result<()> {
     #[cfg(feature = "flash-attn")]
     let use_flash_attn = true;
 
-    let tgt_non_granular_index = get_tgt_non_granular_index(&args.model);
-    let dtype = get_model_dtype(&args.model)?;
-
-    if tgt_non_granular_index.is_some() {
-        args.max_seqs = 1;
-    }
-
     let prompt_batchsize = match args.prompt_batchsize {
         Some(0) => {
             anyhow::bail!("`prompt_batchsize` must be a strictly positive integer, got 0.",)
@@ -293,13 +521,6 @@ async fn main() -> Result<()> {
         None => None,
     };
 
-    let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
-        .with_no_kv_cache(args.no_kv_cache)
-        .with_chat_template(args.chat_template)
-        .with_use_flash_attn(use_flash_attn)
-        .with_prompt_batchsize(prompt_batchsize)
-        .build()?;
-
     #[cfg(feature = "metal")]
     let device = Device::new_metal(0)?;
     #[cfg(not(feature = "metal"))]
@@ -320,10 +541,6 @@ async fn main() -> Result<()> {
     if use_flash_attn {
         info!("Using flash attention.");
     }
-    if use_flash_attn && loader.get_kind().is_quantized() {
-        warn!("Using flash attention with a quantized model has no effect!")
-    }
-    info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
     let mapper = if let Some(device_layers) = args.num_device_layers {
@@ -419,58 +636,73 @@ async fn main() -> Result<()> {
         (_, _, _, _, _, _) => None,
     };
 
-    let pipeline = loader.load_model_from_hf(
-        None,
-        args.token_source,
-        &dtype,
-        &device,
-        false,
+    let options = ModelLoadOptions {
+        device,
+        token_source: args.token_source,
+        max_seqs: args.max_seqs,
+        no_kv_cache: args.no_kv_cache,
+        chat_template: args.chat_template,
+        use_flash_attn,
+        prompt_batchsize,
         mapper,
-        args.in_situ_quant,
-        cache_config,
-    )?;
-    info!("Model loaded.");
-
-    let scheduler_config = if cache_config.is_some() {
-        // Handle case where we may have device mapping
-        if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
-            SchedulerConfig::PagedAttentionMeta {
-                max_num_seqs: args.max_seqs,
-                config: cache_config.clone(),
+        in_situ_quant: args.in_situ_quant,
+        paged_attn_config: cache_config,
+        scheduling_policy: parse_scheduling_policy(&args.scheduling_policy, args.tenant_weights)?,
+        starvation_limit: args.starvation_limit,
+        log: args.log,
+        truncate_sequence: args.truncate_sequence,
+        prefix_cache_n: args.prefix_cache_n,
+        prefix_cache_disk: args.prefix_cache_dir.map(|dir| PrefixCacheDiskConfig {
+            dir,
+            max_bytes: args.prefix_cache_disk_mb * 1024 * 1024,
+            revision: None,
+            isq: args.in_situ_quant,
+        }),
+        kv_cache_dtype: args.kv_cache_dtype,
+        ngram_lookup_gamma: args.ngram_lookup_gamma,
+        // Interactive mode logs the throughput itself.
+        throughput_log: args.throughput_log && !args.interactive_mode,
+        tool_call_parser: args.tool_call_parser,
+    };
+
+    let registry = match (args.model, args.models_config) {
+        (Some(model), None) => {
+            let load_options = options.clone();
+            let mistralrs = tokio::task::spawn_blocking(move || {
+                load_options.load(ModelSource::Selected(model))
+            })
+            .await??;
+
+            if args.interactive_mode {
+                interactive_mode(mistralrs, args.throughput_log).await;
+                return Ok(());
             }
-        } else {
-            SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+
+            let registry = ModelRegistry::new(options, None);
+            registry.insert(mistralrs.get_id(), mistralrs)?;
+            registry
+        }
+        (None, Some(path)) => {
+            let ModelsConfig {
+                default_model,
+                models,
+            } = ModelsConfig::from_file(&path)?;
+            let registry = ModelRegistry::new(options, default_model);
+            for entry in models {
+                info!("Loading model `{}`.", entry.name);
+                registry.load(entry).await?;
             }
+            registry
         }
-    } else {
-        SchedulerConfig::DefaultScheduler {
-            method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+        (Some(_), Some(_)) => {
+            anyhow::bail!("Select either a model or `--models-config`, not both.")
         }
+        (None, None) => anyhow::bail!("Expected a model to be selected, or `--models-config`."),
     };
-    // Throughput logging in the server
-    let builder = MistralRsBuilder::new(pipeline, scheduler_config)
-        .with_opt_log(args.log)
-        .with_truncate_sequence(args.truncate_sequence)
-        .with_no_kv_cache(args.no_kv_cache)
-        .with_prefix_cache_n(args.prefix_cache_n);
-
-    if args.interactive_mode {
-        interactive_mode(builder.build(), args.throughput_log).await;
-        return Ok(());
-    }
-
-    let builder = if args.throughput_log {
-        builder.with_throughput_logging()
-    } else {
-        builder
-    };
-    let mistralrs = builder.build();
 
     let port = args.port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
 
-    let app = get_router(mistralrs);
-
+    let app = get_router(Arc::new(registry), args.admin_api_key);
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
     } else {
@@ -478,7 +710,12 @@ async fn main() -> Result<()> {
     };
     let listener = tokio::net::TcpListener::bind(format!("{ip}:{}", port)).await?;
     info!("Serving on http://{ip}:{}.", port);
-    axum::serve(listener, app).await?;
+    // Shut down gracefully so that the engines can persist their prefix caches.
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
 
     Ok(())
 }
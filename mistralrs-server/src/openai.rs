@@ -1,9 +1,12 @@
 use either::Either;
-use mistralrs_core::{ImageGenerationResponseFormat, Tool, ToolChoice};
+use mistralrs_core::{EmbeddingPooling, ImageGenerationResponseFormat, Tool, ToolChoice};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{collections::HashMap, ops::Deref};
 use utoipa::ToSchema;
 
+use crate::registry::DEFAULT_MODEL;
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct MessageInnerContent(
     #[serde(with = "either::serde_untagged")] Either<String, HashMap<String, String>>,
@@ -59,8 +62,8 @@ fn default_1280usize() -> usize {
     1280
 }
 
-fn default_model() -> String {
-    "default".to_string()
+pub fn default_model() -> String {
+    DEFAULT_MODEL.to_string()
 }
 
 fn default_response_format() -> ImageGenerationResponseFormat {
@@ -76,6 +79,26 @@ pub enum Grammar {
     Yacc(String),
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaResponseFormat {
+    pub name: String,
+    #[schema(value_type = Object)]
+    pub schema: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json_object")]
+    JsonObject,
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        json_schema: JsonSchemaResponseFormat,
+    },
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
     #[schema(example = json!(vec![Message{content:"Why did the crab cross the road?".to_string(), role:"user".to_string(), name: None}]))]
@@ -108,12 +131,16 @@ pub struct ChatCompletionRequest {
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -132,6 +159,41 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    /// Mirostat version, 1 or 2. 0 disables Mirostat.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub dynatemp_range: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub dynatemp_exponent: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    /// Only penalize the last `penalty_last_n` tokens.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub penalty_last_n: Option<usize>,
+    /// Do not penalize the tokens of the prompt.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub penalty_exclude_prompt: bool,
+    /// Tokens which are never penalized.
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub penalty_whitelist: Option<Vec<u32>>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -184,6 +246,8 @@ pub struct CompletionRequest {
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
     #[serde(rename = "user")]
@@ -210,6 +274,49 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    /// Mirostat version, 1 or 2. 0 disables Mirostat.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_probability: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub xtc_threshold: Option<f32>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub dynatemp_range: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub dynatemp_exponent: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    /// Only penalize the last `penalty_last_n` tokens.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub penalty_last_n: Option<usize>,
+    /// Do not penalize the tokens of the prompt.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub penalty_exclude_prompt: bool,
+    /// Tokens which are never penalized.
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub penalty_whitelist: Option<Vec<u32>>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Run beam search with `best_of` beams, returning the best `n` sequences.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub use_beam_search: bool,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -231,4 +338,108 @@ pub struct ImageGenerationRequest {
     #[serde(default = "default_1280usize")]
     #[schema(example = 1280)]
     pub width: usize,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_steps: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub guidance_scale: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub negative_guidance_scale: Option<f64>,
+}
+
+/// Edit an image. Like in the OpenAI API, the body can be multipart/form-data, with the images
+/// uploaded as files. In a JSON body, the images are given like in chat completions: as a URL, a
+/// path to a local file, or base64 encoded data.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ImageEditRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "https://www.garden-treasures.com/cdn/shop/products/IMG_6245.jpg")]
+    pub image: String,
+    /// The areas to change: the transparent areas of the mask, or its white areas if it is
+    /// opaque. Without a mask, the whole image is changed.
+    #[schema(example = json!(Option::None::<String>))]
+    pub mask: Option<String>,
+    #[schema(example = "Make the flower blue.")]
+    pub prompt: String,
+    #[serde(rename = "n")]
+    #[serde(default = "default_1usize")]
+    #[schema(example = 1)]
+    pub n_choices: usize,
+    #[serde(default = "default_response_format")]
+    pub response_format: ImageGenerationResponseFormat,
+    /// Defaults to the height of the image, scaled down to fit in the largest size.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub height: Option<usize>,
+    /// Defaults to the width of the image, scaled down to fit in the largest size.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub width: Option<usize>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_steps: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub guidance_scale: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub negative_guidance_scale: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub strength: Option<f64>,
+}
+
+/// Generate a variation of an image. This is an edit of the image without a prompt or a mask.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ImageVariationRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "https://www.garden-treasures.com/cdn/shop/products/IMG_6245.jpg")]
+    pub image: String,
+    #[serde(rename = "n")]
+    #[serde(default = "default_1usize")]
+    #[schema(example = 1)]
+    pub n_choices: usize,
+    #[serde(default = "default_response_format")]
+    pub response_format: ImageGenerationResponseFormat,
+    /// Defaults to the height of the image, scaled down to fit in the largest size.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub height: Option<usize>,
+    /// Defaults to the width of the image, scaled down to fit in the largest size.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub width: Option<usize>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_steps: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub guidance_scale: Option<f64>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub strength: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Multi(Vec<String>),
+    Single(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "The food was delicious and the waiter was friendly.")]
+    pub input: EmbeddingInput,
+    /// Only `float` is supported.
+    #[schema(example = json!(Option::None::<String>))]
+    pub encoding_format: Option<String>,
+    /// Not part of the OpenAI API: how the token hidden states are pooled, `mean`, `last` or `cls`.
+    #[serde(default)]
+    pub pooling: EmbeddingPooling,
 }
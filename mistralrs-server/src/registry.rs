@@ -0,0 +1,428 @@
+use std::{
+    collections::HashSet,
+    fs,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::{Context, Result};
+use candle_core::Device;
+use indexmap::IndexMap;
+use mistralrs_core::{
+    get_model_dtype, get_tgt_non_granular_index, get_toml_selected_model_dtype,
+    DefaultSchedulerMethod, DeviceMapMetadata, DraftLoader, IsqType, KvCacheDType, Loader,
+    LoaderBuilder, MistralRs, MistralRsBuilder, ModelSelected, NgramLookupConfig,
+    PagedAttentionConfig, PrefixCacheDiskConfig, SchedulerConfig, SchedulingPolicy,
+    SpeculativeConfig, SpeculativeLoader, TokenSource, TomlLoaderArgs, TomlSelector,
+    ToolCallParser,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+/// Model name which routes to the default model.
+pub const DEFAULT_MODEL: &str = "default";
+
+/// A model in the `--models-config` file, or the body of a `/admin/models/load` request.
+/// Apart from `name`, the fields are those of a TOML selector file.
+#[derive(Deserialize, ToSchema)]
+pub struct ModelEntry {
+    /// Name which requests select the model by, in their `model` field.
+    #[schema(example = "mistral")]
+    pub name: String,
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub selector: TomlSelector,
+}
+
+/// The file passed with `--models-config`.
+#[derive(Deserialize)]
+pub struct ModelsConfig {
+    /// Model which requests for the `default` model are routed to. Defaults to the first model.
+    pub default_model: Option<String>,
+    pub models: Vec<ModelEntry>,
+}
+
+impl ModelsConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read models config at {}", path.display()))?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+pub enum ModelSource {
+    Selected(ModelSelected),
+    Toml(TomlSelector),
+}
+
+/// Options which apply to every model the server loads.
+#[derive(Clone)]
+pub struct ModelLoadOptions {
+    pub device: Device,
+    pub token_source: TokenSource,
+    pub max_seqs: usize,
+    pub no_kv_cache: bool,
+    pub chat_template: Option<String>,
+    pub use_flash_attn: bool,
+    pub prompt_batchsize: Option<NonZeroUsize>,
+    pub mapper: DeviceMapMetadata,
+    pub in_situ_quant: Option<IsqType>,
+    pub paged_attn_config: Option<PagedAttentionConfig>,
+    pub scheduling_policy: SchedulingPolicy,
+    pub starvation_limit: usize,
+    pub log: Option<String>,
+    pub truncate_sequence: bool,
+    pub prefix_cache_n: usize,
+    pub prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    pub kv_cache_dtype: KvCacheDType,
+    pub ngram_lookup_gamma: Option<usize>,
+    pub throughput_log: bool,
+    pub tool_call_parser: Option<ToolCallParser>,
+}
+
+impl ModelLoadOptions {
+    /// Load a model and start its engine. This blocks until the weights are loaded, so it should
+    /// be run with `spawn_blocking`.
+    pub fn load(&self, source: ModelSource) -> Result<Arc<MistralRs>> {
+        let (loader, dtype, tgt_non_granular_index): (Box<dyn Loader>, _, _) = match source {
+            ModelSource::Selected(model) => {
+                let tgt_non_granular_index = get_tgt_non_granular_index(&model);
+                let dtype = get_model_dtype(&model)?;
+                let loader = LoaderBuilder::new(model)
+                    .with_no_kv_cache(self.no_kv_cache)
+                    .with_chat_template(self.chat_template.clone())
+                    .with_use_flash_attn(self.use_flash_attn)
+                    .with_prompt_batchsize(self.prompt_batchsize)
+                    .build()?;
+                let loader: Box<dyn Loader> = match self.ngram_lookup_gamma {
+                    Some(gamma) => Box::new(SpeculativeLoader {
+                        target: loader,
+                        draft: DraftLoader::NgramLookup(NgramLookupConfig::default()),
+                        config: SpeculativeConfig {
+                            gamma,
+                            adaptive_gamma: false,
+                        },
+                    }),
+                    None => loader,
+                };
+                (loader, dtype, tgt_non_granular_index)
+            }
+            ModelSource::Toml(selector) => {
+                let dtype = get_toml_selected_model_dtype(&selector);
+                let args = TomlLoaderArgs {
+                    use_flash_attn: self.use_flash_attn,
+                    chat_template: self.chat_template.clone(),
+                    no_kv_cache: self.no_kv_cache,
+                    prompt_batchsize: self.prompt_batchsize,
+                };
+                ((selector, args).try_into()?, dtype, None)
+            }
+        };
+
+        // X-LoRA models with a non-granular index can only run one sequence at a time.
+        let max_seqs = if tgt_non_granular_index.is_some() {
+            1
+        } else {
+            self.max_seqs
+        };
+
+        if self.use_flash_attn && loader.get_kind().is_quantized() {
+            warn!("Using flash attention with a quantized model has no effect!")
+        }
+        info!("Model kind is: {}", loader.get_kind().to_string());
+
+        let pipeline = loader.load_model_from_hf(
+            None,
+            self.token_source.clone(),
+            &dtype,
+            &self.device,
+            false,
+            self.mapper.clone(),
+            self.in_situ_quant,
+            self.paged_attn_config,
+        )?;
+        info!("Model loaded.");
+
+        let default_scheduler_config = SchedulerConfig::DefaultSchedulerWithPolicy {
+            method: DefaultSchedulerMethod::Fixed(max_seqs.try_into()?),
+            policy: self.scheduling_policy.clone(),
+            starvation_limit: self.starvation_limit,
+        };
+        let scheduler_config = if self.paged_attn_config.is_some() {
+            // Handle case where we may have device mapping
+            if let Some(ref cache_config) = pipeline.blocking_lock().get_metadata().cache_config {
+                SchedulerConfig::PagedAttentionMeta {
+                    max_num_seqs: max_seqs,
+                    config: cache_config.clone(),
+                }
+            } else {
+                default_scheduler_config
+            }
+        } else {
+            default_scheduler_config
+        };
+
+        let builder = MistralRsBuilder::new(pipeline, scheduler_config)
+            .with_opt_log(self.log.clone())
+            .with_truncate_sequence(self.truncate_sequence)
+            .with_no_kv_cache(self.no_kv_cache)
+            .with_prefix_cache_n(self.prefix_cache_n)
+            .with_kv_cache_dtype(self.kv_cache_dtype);
+        let builder = match &self.prefix_cache_disk {
+            Some(config) => builder.with_prefix_cache_disk(config.clone()),
+            None => builder,
+        };
+        let builder = if self.throughput_log {
+            builder.with_throughput_logging()
+        } else {
+            builder
+        };
+        let builder = match self.tool_call_parser {
+            Some(parser) => builder.with_tool_call_parser(parser),
+            None => builder,
+        };
+        Ok(builder.build())
+    }
+}
+
+type ModelLoader<M> = Arc<dyn Fn(TomlSelector) -> Result<Arc<M>> + Send + Sync>;
+
+/// The models served, by name. Each model has its own engine.
+pub struct ModelRegistry<M = MistralRs> {
+    models: RwLock<IndexMap<String, Arc<M>>>,
+    /// Models which are being loaded, whose names are reserved.
+    loading: Mutex<HashSet<String>>,
+    default_model: Option<String>,
+    loader: ModelLoader<M>,
+}
+
+impl ModelRegistry {
+    pub fn new(options: ModelLoadOptions, default_model: Option<String>) -> Self {
+        Self::with_loader(default_model, move |selector| {
+            options.load(ModelSource::Toml(selector))
+        })
+    }
+}
+
+impl<M: Send + Sync + 'static> ModelRegistry<M> {
+    /// Create a registry which loads models with `loader`.
+    pub fn with_loader(
+        default_model: Option<String>,
+        loader: impl Fn(TomlSelector) -> Result<Arc<M>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            models: RwLock::new(IndexMap::new()),
+            loading: Mutex::new(HashSet::new()),
+            default_model,
+            loader: Arc::new(loader),
+        }
+    }
+
+    /// Get the model a request is routed to:
+    /// - The model with exactly this name.
+    /// - For `default`, the configured default model if it is loaded, otherwise the first model.
+    /// - If only one model is loaded, that model, so that single model servers accept any name.
+    pub fn get(&self, name: &str) -> Result<Arc<M>> {
+        let models = self.models.read().unwrap();
+        if let Some(model) = models.get(name) {
+            return Ok(model.clone());
+        }
+        let fallback = if name == DEFAULT_MODEL {
+            self.default_model
+                .as_ref()
+                .and_then(|default| models.get(default))
+                .or_else(|| models.first().map(|(_, model)| model))
+        } else if models.len() == 1 {
+            models.first().map(|(_, model)| model)
+        } else {
+            None
+        };
+        fallback.cloned().with_context(|| {
+            format!(
+                "Model `{name}` is not loaded. Loaded models are: {}.",
+                models
+                    .keys()
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+
+    pub fn list(&self) -> Vec<(String, Arc<M>)> {
+        self.models
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, model)| (name.clone(), model.clone()))
+            .collect()
+    }
+
+    pub fn insert(&self, name: String, model: Arc<M>) -> Result<()> {
+        let mut models = self.models.write().unwrap();
+        if models.contains_key(&name) || self.loading.lock().unwrap().contains(&name) {
+            anyhow::bail!("Model `{name}` is already loaded.");
+        }
+        info!("Serving model `{name}`.");
+        models.insert(name, model);
+        Ok(())
+    }
+
+    /// Load a model at runtime, without blocking the server while the weights are loaded.
+    /// The name is reserved while loading, so concurrent loads of the same name fail.
+    pub async fn load(&self, entry: ModelEntry) -> Result<()> {
+        {
+            let models = self.models.write().unwrap();
+            let mut loading = self.loading.lock().unwrap();
+            if models.contains_key(&entry.name) || !loading.insert(entry.name.clone()) {
+                anyhow::bail!("Model `{}` is already loaded.", entry.name);
+            }
+        }
+        let loader = self.loader.clone();
+        let model = tokio::task::spawn_blocking(move || loader(entry.selector)).await;
+
+        let mut models = self.models.write().unwrap();
+        self.loading.lock().unwrap().remove(&entry.name);
+        let model = model??;
+        info!("Serving model `{}`.", entry.name);
+        models.insert(entry.name, model);
+        Ok(())
+    }
+
+    /// Stop serving a model. In-flight requests hold a reference to the model, so its engine is
+    /// only terminated once they finish.
+    pub fn unload(&self, name: &str) -> Result<()> {
+        if self.models.write().unwrap().shift_remove(name).is_none() {
+            anyhow::bail!("Model `{name}` is not loaded.");
+        }
+        info!("Unloaded model `{name}`.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{ModelEntry, ModelRegistry, ModelsConfig};
+
+    fn entry(name: &str) -> ModelEntry {
+        toml::from_str(&format!(
+            r#"
+            name = "{name}"
+            [model]
+            model_id = "mistralai/Mistral-7B-Instruct-v0.1"
+            arch = "mistral"
+            "#
+        ))
+        .unwrap()
+    }
+
+    /// A registry whose models are the number of the load which created them.
+    fn registry(default_model: Option<&str>) -> ModelRegistry<usize> {
+        let n_loads = AtomicUsize::new(0);
+        ModelRegistry::with_loader(default_model.map(str::to_string), move |_| {
+            thread::sleep(Duration::from_millis(50));
+            Ok(Arc::new(n_loads.fetch_add(1, Ordering::SeqCst)))
+        })
+    }
+
+    #[test]
+    fn parse_models_config() {
+        let config: ModelsConfig = toml::from_str(
+            r#"
+            default_model = "phi3"
+
+            [[models]]
+            name = "mistral"
+            [models.model]
+            model_id = "mistralai/Mistral-7B-Instruct-v0.1"
+            arch = "mistral"
+
+            [[models]]
+            name = "phi3"
+            tokenizer_json = "tokenizer.json"
+            [models.model]
+            model_id = "microsoft/Phi-3-mini-128k-instruct"
+            arch = "phi3"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default_model.as_deref(), Some("phi3"));
+        let names = config
+            .models
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["mistral", "phi3"]);
+    }
+
+    #[tokio::test]
+    async fn routes_by_name_and_default() {
+        let registry = registry(Some("b"));
+        registry.load(entry("a")).await.unwrap();
+        registry.load(entry("b")).await.unwrap();
+
+        assert_eq!(*registry.get("a").unwrap(), 0);
+        assert_eq!(*registry.get("b").unwrap(), 1);
+        assert_eq!(*registry.get("default").unwrap(), 1);
+        assert!(registry.get("c").is_err());
+
+        // Without the default model, `default` is the first model, and the only model is
+        // served under any name.
+        registry.unload("b").unwrap();
+        assert_eq!(*registry.get("default").unwrap(), 0);
+        assert_eq!(*registry.get("c").unwrap(), 0);
+
+        registry.unload("a").unwrap();
+        assert!(registry.get("default").is_err());
+        assert!(registry.unload("a").is_err());
+    }
+
+    #[tokio::test]
+    async fn loaded_models_are_not_replaced() {
+        let registry = registry(None);
+        registry.load(entry("a")).await.unwrap();
+        assert!(registry.load(entry("a")).await.is_err());
+        assert!(registry.insert("a".to_string(), Arc::new(5)).is_err());
+        assert_eq!(*registry.get("a").unwrap(), 0);
+
+        // Once unloaded, the name can be reused.
+        registry.unload("a").unwrap();
+        registry.load(entry("a")).await.unwrap();
+        assert_eq!(*registry.get("a").unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_loads_of_a_name_conflict() {
+        let registry = registry(None);
+        let (first, second) = tokio::join!(registry.load(entry("a")), registry.load(entry("a")));
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(registry.list().len(), 1);
+        assert_eq!(*registry.get("a").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_loads_release_the_name() {
+        let n_loads = AtomicUsize::new(0);
+        let registry = ModelRegistry::with_loader(None, move |_| {
+            if n_loads.fetch_add(1, Ordering::SeqCst) == 0 {
+                anyhow::bail!("Out of memory.");
+            }
+            Ok(Arc::new(()))
+        });
+        assert!(registry.load(entry("a")).await.is_err());
+        assert!(registry.get("a").is_err());
+        registry.load(entry("a")).await.unwrap();
+        assert!(registry.get("a").is_ok());
+    }
+}
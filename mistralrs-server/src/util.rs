@@ -1,18 +1,45 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
 use image::DynamicImage;
+use mistralrs_core::{load_video_from_memory, DEFAULT_MAX_VIDEO_FRAMES, DEFAULT_VIDEO_FPS};
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::{self, File},
     io::AsyncReadExt,
 };
 
 pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow::Error> {
+    Ok(image::load_from_memory(&load_url(url_unparsed).await?)?)
+}
+
+/// Load the video at a URL, path or in base64 and sample its frames.
+pub async fn parse_video_url(url_unparsed: &str) -> Result<Vec<DynamicImage>, anyhow::Error> {
+    let bytes = load_url(url_unparsed).await?;
+    tokio::task::spawn_blocking(move || {
+        load_video_from_memory(&bytes, DEFAULT_VIDEO_FPS, DEFAULT_MAX_VIDEO_FRAMES)
+    })
+    .await?
+}
+
+/// Read the contents of a URL, a path to a local file, or base64 encoded data.
+async fn load_url(url_unparsed: &str) -> Result<Vec<u8>, anyhow::Error> {
     let url = if let Ok(url) = url::Url::parse(url_unparsed) {
         url
     } else if File::open(url_unparsed).await.is_ok() {
         url::Url::from_file_path(std::path::absolute(url_unparsed)?)
             .map_err(|_| anyhow::anyhow!("Could not parse file path: {}", url_unparsed))?
     } else {
-        url::Url::parse(&format!("data:image/png;base64,{}", url_unparsed))
-            .map_err(|_| anyhow::anyhow!("Could not parse as base64 data: {}", url_unparsed))?
+        url::Url::parse(&format!(
+            "data:application/octet-stream;base64,{}",
+            url_unparsed
+        ))
+        .map_err(|_| anyhow::anyhow!("Could not parse as base64 data: {}", url_unparsed))?
     };
 
     let bytes = if url.scheme() == "http" || url.scheme() == "https" {
@@ -43,7 +70,44 @@ pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow:
         anyhow::bail!("Unsupported URL scheme: {}", url.scheme());
     };
 
-    Ok(image::load_from_memory(&bytes)?)
+    Ok(bytes)
+}
+
+/// Get the tenant of a request from its bearer token (API key), used for fair scheduling.
+/// The key is hashed with [`tenant_id`] so that it never reaches the scheduler or the logs.
+pub fn get_tenant(headers: &HeaderMap) -> Option<String> {
+    bearer_token(headers).map(tenant_id)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+}
+
+/// Middleware which only lets requests through whose bearer token is the admin API key.
+/// The hashes are compared, so that the comparison time does not depend on the key.
+pub async fn require_admin_key(
+    State(admin_api_key): State<Arc<String>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let authorized = bearer_token(&headers).is_some_and(|key| {
+        Sha256::digest(key.as_bytes()) == Sha256::digest(admin_api_key.as_bytes())
+    });
+    if !authorized {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
+
+/// The tenant ID of an API key: the hex encoded SHA-256 hash of the key.
+pub fn tenant_id(api_key: &str) -> String {
+    format!("{:x}", Sha256::digest(api_key.as_bytes()))
 }
 
 #[cfg(test)]
@@ -22,7 +22,10 @@ async fn main() -> Result<()> {
         .generate_image(
             "A vibrant sunset in the mountains, 4k, high quality.".to_string(),
             ImageGenerationResponseFormat::Url,
-            DiffusionGenerationParams::default(),
+            DiffusionGenerationParams {
+                seed: Some(42),
+                ..Default::default()
+            },
         )
         .await?;
 
@@ -53,6 +53,7 @@ fn main() -> anyhow::Result<()> {
     let request = Request::Normal(NormalRequest {
         messages: RequestMessage::VisionChat {
             images: vec![DynamicImage::new(1280, 720, ColorType::Rgb8)],
+            videos: Vec::new(),
             messages: vec![IndexMap::from([
                 ("role".to_string(), Either::Left("user".to_string())),
                 (
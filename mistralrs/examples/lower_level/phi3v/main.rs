@@ -72,6 +72,7 @@ fn main() -> anyhow::Result<()> {
     let request = Request::Normal(NormalRequest {
         messages: RequestMessage::VisionChat {
             images: vec![image],
+            videos: Vec::new(),
             messages: vec![IndexMap::from([
                 ("role".to_string(), Either::Left("user".to_string())),
                 (
@@ -74,6 +74,15 @@ impl AnyMoeModelBuilder {
             layers: self.layers,
         });
 
+        let prefix_cache_disk = self
+            .base
+            .prefix_cache_disk
+            .map(|config| PrefixCacheDiskConfig {
+                revision: self.base.hf_revision.clone(),
+                isq: self.base.isq,
+                ..config
+            });
+
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
             self.base.hf_revision,
@@ -112,11 +121,15 @@ impl AnyMoeModelBuilder {
         let mut runner = MistralRsBuilder::new(pipeline, scheduler_method)
             .with_no_kv_cache(self.base.no_kv_cache)
             .with_gemm_full_precision_f16(true)
-            .with_no_prefix_cache(self.base.prefix_cache_n.is_none());
+            .with_no_prefix_cache(self.base.prefix_cache_n.is_none())
+            .with_kv_cache_dtype(self.base.kv_cache_dtype);
 
         if let Some(n) = self.base.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(prefix_cache_disk) = prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(prefix_cache_disk)
+        }
 
         Ok(Model::new(runner.build()))
     }
@@ -0,0 +1,108 @@
+use mistralrs_core::*;
+
+use crate::{best_device, Model};
+
+/// Configure an encoder-only embedding model with the various parameters for loading and running.
+pub struct EmbeddingModelBuilder {
+    // Loading model
+    pub(crate) model_id: String,
+    pub(crate) token_source: TokenSource,
+    pub(crate) hf_revision: Option<String>,
+    pub(crate) tokenizer_json: Option<String>,
+
+    // Model running
+    pub(crate) loader_type: EmbeddingLoaderType,
+    pub(crate) dtype: ModelDType,
+    pub(crate) force_cpu: bool,
+
+    // Other things
+    pub(crate) with_logging: bool,
+}
+
+impl EmbeddingModelBuilder {
+    /// A few defaults are applied here:
+    /// - Token source is from the cache (.cache/huggingface/token)
+    pub fn new(model_id: impl ToString, loader_type: EmbeddingLoaderType) -> Self {
+        Self {
+            model_id: model_id.to_string(),
+            loader_type,
+            dtype: ModelDType::Auto,
+            force_cpu: false,
+            token_source: TokenSource::CacheToken,
+            hf_revision: None,
+            tokenizer_json: None,
+            with_logging: false,
+        }
+    }
+
+    /// Load the model in a certain dtype.
+    pub fn with_dtype(mut self, dtype: ModelDType) -> Self {
+        self.dtype = dtype;
+        self
+    }
+
+    /// Force usage of the CPU device.
+    pub fn with_force_cpu(mut self) -> Self {
+        self.force_cpu = true;
+        self
+    }
+
+    /// Source of the Hugging Face token.
+    pub fn with_token_source(mut self, token_source: TokenSource) -> Self {
+        self.token_source = token_source;
+        self
+    }
+
+    /// Set the revision to use for a Hugging Face remote model.
+    pub fn with_hf_revision(mut self, revision: impl ToString) -> Self {
+        self.hf_revision = Some(revision.to_string());
+        self
+    }
+
+    /// Path to a discrete `tokenizer.json` file.
+    pub fn with_tokenizer_json(mut self, tokenizer_json: impl ToString) -> Self {
+        self.tokenizer_json = Some(tokenizer_json.to_string());
+        self
+    }
+
+    /// Enable logging.
+    pub fn with_logging(mut self) -> Self {
+        self.with_logging = true;
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
+        let config = EmbeddingSpecificConfig {
+            tokenizer_json: self.tokenizer_json,
+        };
+
+        if self.with_logging {
+            initialize_logging();
+        }
+
+        let loader =
+            EmbeddingLoaderBuilder::new(config, Some(self.model_id)).build(self.loader_type);
+
+        // Load, into a Pipeline
+        let pipeline = loader.load_model_from_hf(
+            self.hf_revision,
+            self.token_source,
+            &self.dtype,
+            &best_device(self.force_cpu)?,
+            !self.with_logging,
+            DeviceMapMetadata::dummy(),
+            None,
+            None,
+        )?;
+
+        // Embedding requests are not scheduled, so this only bounds the (unused) batch size.
+        let scheduler_method = SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(1.try_into()?),
+        };
+
+        let runner =
+            MistralRsBuilder::new(pipeline, scheduler_method).with_gemm_full_precision_f16(true);
+
+        Ok(Model::new(runner.build()))
+    }
+}
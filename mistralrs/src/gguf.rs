@@ -1,5 +1,5 @@
 use mistralrs_core::*;
-use std::num::NonZeroUsize;
+use std::{num::NonZeroUsize, path::PathBuf};
 
 use crate::{best_device, Model};
 
@@ -26,6 +26,7 @@ pub struct GgufModelBuilder {
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) prefix_cache_disk: Option<PrefixCacheDiskConfig>,
 }
 
 impl GgufModelBuilder {
@@ -47,6 +48,7 @@ impl GgufModelBuilder {
             max_num_seqs: 32,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            prefix_cache_disk: None,
             with_logging: false,
             topology: None,
             tok_model_id: None,
@@ -138,6 +140,18 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Persist prefix caches evicted from the device in `dir`, using at most `max_bytes` of disk space.
+    /// These are reloaded on demand, also after a restart.
+    pub fn with_prefix_cache_disk(mut self, dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        self.prefix_cache_disk = Some(PrefixCacheDiskConfig {
+            dir: dir.into(),
+            max_bytes,
+            revision: None,
+            isq: None,
+        });
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -170,6 +184,11 @@ impl GgufModelBuilder {
         )
         .build();
 
+        let prefix_cache_disk = self.prefix_cache_disk.map(|config| PrefixCacheDiskConfig {
+            revision: self.hf_revision.clone(),
+            ..config
+        });
+
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
             self.hf_revision,
@@ -211,6 +230,9 @@ impl GgufModelBuilder {
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(prefix_cache_disk) = prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(prefix_cache_disk)
+        }
 
         Ok(Model::new(runner.build()))
     }
@@ -52,6 +52,7 @@
 
 mod anymoe;
 mod diffusion_model;
+mod embedding_model;
 mod gguf;
 mod gguf_lora_model;
 mod gguf_xlora_model;
@@ -66,6 +67,7 @@ mod xlora_model;
 pub mod v0_4_api {
     pub use super::anymoe::AnyMoeModelBuilder;
     pub use super::diffusion_model::DiffusionModelBuilder;
+    pub use super::embedding_model::EmbeddingModelBuilder;
     pub use super::gguf::GgufModelBuilder;
     pub use super::gguf_lora_model::GgufLoraModelBuilder;
     pub use super::gguf_xlora_model::GgufXLoraModelBuilder;
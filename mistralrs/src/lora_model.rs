@@ -48,6 +48,15 @@ impl LoraModelBuilder {
         .with_no_kv_cache(self.text_model.no_kv_cache)
         .build(self.text_model.loader_type)?;
 
+        let prefix_cache_disk =
+            self.text_model
+                .prefix_cache_disk
+                .map(|config| PrefixCacheDiskConfig {
+                    revision: self.text_model.hf_revision.clone(),
+                    isq: self.text_model.isq,
+                    ..config
+                });
+
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
             self.text_model.hf_revision,
@@ -84,11 +93,15 @@ impl LoraModelBuilder {
         let mut runner = MistralRsBuilder::new(pipeline, scheduler_method)
             .with_no_kv_cache(self.text_model.no_kv_cache)
             .with_gemm_full_precision_f16(true)
-            .with_no_prefix_cache(self.text_model.prefix_cache_n.is_none());
+            .with_no_prefix_cache(self.text_model.prefix_cache_n.is_none())
+            .with_kv_cache_dtype(self.text_model.kv_cache_dtype);
 
         if let Some(n) = self.text_model.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(prefix_cache_disk) = prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(prefix_cache_disk)
+        }
 
         Ok(Model::new(runner.build()))
     }
@@ -108,7 +108,7 @@ impl RequestLike for TextMessages {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-/// Text (chat) messages with images.
+/// Text (chat) messages with images and videos.
 ///
 /// No constraints, logits processors, logprobs, tools, or adapters.
 ///
@@ -116,6 +116,7 @@ impl RequestLike for TextMessages {
 pub struct VisionMessages {
     messages: Vec<IndexMap<String, MessageContent>>,
     images: Vec<DynamicImage>,
+    videos: Vec<Vec<DynamicImage>>,
 }
 
 impl Default for VisionMessages {
@@ -128,6 +129,7 @@ impl VisionMessages {
     pub fn new() -> Self {
         Self {
             images: Vec::new(),
+            videos: Vec::new(),
             messages: Vec::new(),
         }
     }
@@ -233,7 +235,7 @@ impl VisionMessages {
         model: &Model,
     ) -> anyhow::Result<Self> {
         let prefixer = match &model.config().category {
-            ModelCategory::Text | ModelCategory::Diffusion => {
+            ModelCategory::Text | ModelCategory::Diffusion | ModelCategory::Embedding => {
                 anyhow::bail!("`add_image_message` expects a vision model.")
             }
             ModelCategory::Vision {
@@ -263,9 +265,50 @@ impl VisionMessages {
         Ok(self)
     }
 
+    /// Add a message with a video, given as its frames. Only some models, such as Qwen2-VL,
+    /// support video input. [`load_video`] samples the frames of a video file.
+    pub fn add_video_message(
+        mut self,
+        role: TextMessageRole,
+        text: impl ToString,
+        frames: Vec<DynamicImage>,
+        model: &Model,
+    ) -> anyhow::Result<Self> {
+        let prefixer = match &model.config().category {
+            ModelCategory::Text | ModelCategory::Diffusion | ModelCategory::Embedding => {
+                anyhow::bail!("`add_video_message` expects a vision model.")
+            }
+            ModelCategory::Vision {
+                has_conv2d: _,
+                prefixer,
+            } => prefixer,
+        };
+        self.videos.push(frames);
+        self.messages.push(IndexMap::from([
+            ("role".to_string(), Either::Left(role.to_string())),
+            (
+                "content".to_string(),
+                Either::Right(vec![
+                    IndexMap::from([("type".to_string(), Value::String("video".to_string()))]),
+                    IndexMap::from([
+                        ("type".to_string(), Value::String("text".to_string())),
+                        (
+                            "text".to_string(),
+                            Value::String(
+                                prefixer.prefix_video(self.videos.len() - 1, &text.to_string()),
+                            ),
+                        ),
+                    ]),
+                ]),
+            ),
+        ]));
+        Ok(self)
+    }
+
     pub fn clear(mut self) -> Self {
         self.messages.clear();
         self.images.clear();
+        self.videos.clear();
 
         self
     }
@@ -280,8 +323,11 @@ impl RequestLike for VisionMessages {
         std::mem::swap(&mut other_messages, &mut self.messages);
         let mut other_images = Vec::new();
         std::mem::swap(&mut other_images, &mut self.images);
+        let mut other_videos = Vec::new();
+        std::mem::swap(&mut other_videos, &mut self.videos);
         RequestMessage::VisionChat {
             images: other_images,
+            videos: other_videos,
             messages: other_messages,
         }
     }
@@ -317,6 +363,7 @@ impl RequestLike for VisionMessages {
 pub struct RequestBuilder {
     messages: Vec<IndexMap<String, MessageContent>>,
     images: Vec<DynamicImage>,
+    videos: Vec<Vec<DynamicImage>>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     adapters: Vec<String>,
     return_logprobs: bool,
@@ -337,6 +384,7 @@ impl From<TextMessages> for RequestBuilder {
         Self {
             messages: value.0,
             images: Vec::new(),
+            videos: Vec::new(),
             logits_processors: Vec::new(),
             adapters: Vec::new(),
             return_logprobs: false,
@@ -353,6 +401,7 @@ impl From<VisionMessages> for RequestBuilder {
         Self {
             messages: value.messages,
             images: value.images,
+            videos: value.videos,
             logits_processors: Vec::new(),
             adapters: Vec::new(),
             return_logprobs: false,
@@ -369,6 +418,7 @@ impl RequestBuilder {
         Self {
             messages: Vec::new(),
             images: Vec::new(),
+            videos: Vec::new(),
             logits_processors: Vec::new(),
             adapters: Vec::new(),
             return_logprobs: false,
@@ -454,6 +504,22 @@ impl RequestBuilder {
         self
     }
 
+    /// Add a message with a video, given as its frames. The text should include the video
+    /// placeholder of the model, see [`VisionMessages::add_video_message`].
+    pub fn add_video_message(
+        mut self,
+        role: TextMessageRole,
+        text: impl ToString,
+        frames: Vec<DynamicImage>,
+    ) -> Self {
+        self.messages.push(IndexMap::from([
+            ("role".to_string(), Either::Left(role.to_string())),
+            ("content".to_string(), Either::Left(text.to_string())),
+        ]));
+        self.videos.push(frames);
+        self
+    }
+
     pub fn add_logits_processor(mut self, processor: Arc<dyn CustomLogitsProcessor>) -> Self {
         self.logits_processors.push(processor);
         self
@@ -536,6 +602,12 @@ impl RequestBuilder {
         self
     }
 
+    /// Seed the sampling of this request, making it reproducible whatever else runs with it.
+    pub fn set_sampler_seed(mut self, seed: u64) -> Self {
+        self.sampling_params.seed = Some(seed);
+        self
+    }
+
     pub fn set_sampler_stop_toks(mut self, stop_toks: StopTokens) -> Self {
         self.sampling_params.stop_toks = Some(stop_toks);
         self
@@ -560,6 +632,12 @@ impl RequestBuilder {
         self.sampling_params.dry_params = Some(dry_params);
         self
     }
+
+    /// Use beam search instead of sampling. The best `n_choices` beams are returned.
+    pub fn set_beam_search(mut self, beam_search: BeamSearchParams) -> Self {
+        self.sampling_params.beam_search = Some(beam_search);
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
@@ -568,7 +646,7 @@ impl RequestLike for RequestBuilder {
     }
 
     fn take_messages(&mut self) -> RequestMessage {
-        if self.images.is_empty() {
+        if self.images.is_empty() && self.videos.is_empty() {
             let mut other = Vec::new();
             std::mem::swap(&mut other, &mut self.messages);
             RequestMessage::Chat(other)
@@ -577,8 +655,11 @@ impl RequestLike for RequestBuilder {
             std::mem::swap(&mut other_messages, &mut self.messages);
             let mut other_images = Vec::new();
             std::mem::swap(&mut other_images, &mut self.images);
+            let mut other_videos = Vec::new();
+            std::mem::swap(&mut other_videos, &mut self.videos);
             RequestMessage::VisionChat {
                 images: other_images,
+                videos: other_videos,
                 messages: other_messages,
             }
         }
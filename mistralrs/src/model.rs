@@ -2,7 +2,7 @@ use anyhow::Context;
 use candle_core::{Device, Result, Tensor};
 use either::Either;
 use mistralrs_core::*;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 use tokio::sync::mpsc::channel;
 
 use crate::{RequestLike, TextMessages};
@@ -32,6 +32,7 @@ pub fn best_device(force_cpu: bool) -> Result<Device> {
 /// - [`GgufXLoraModelBuilder`]
 /// - [`VisionModelBuilder`]
 /// - [`AnyMoeModelBuilder`]
+/// - [`EmbeddingModelBuilder`]
 ///
 /// [`TextModelBuilder`]: crate::TextModelBuilder
 /// [`LoraModelBuilder`]: crate::LoraModelBuilder
@@ -42,6 +43,7 @@ pub fn best_device(force_cpu: bool) -> Result<Device> {
 /// [`GgufXLoraModelBuilder`]: crate::GgufXLoraModelBuilder
 /// [`VisionModelBuilder`]: crate::VisionModelBuilder
 /// [`AnyMoeModelBuilder`]: crate::AnyMoeModelBuilder
+/// [`EmbeddingModelBuilder`]: crate::EmbeddingModelBuilder
 ///
 pub struct Model {
     runner: Arc<MistralRs>,
@@ -55,6 +57,17 @@ impl Model {
     /// Generate with the model.
     pub async fn send_chat_request<R: RequestLike>(
         &self,
+        request: R,
+    ) -> anyhow::Result<ChatCompletionResponse> {
+        self.send_chat_request_with_id(self.next_request_id(), request)
+            .await
+    }
+
+    /// Generate with the model, using a request ID obtained from [`Model::next_request_id`].
+    /// The request may be canceled from another task with [`Model::cancel`].
+    pub async fn send_chat_request_with_id<R: RequestLike>(
+        &self,
+        request_id: usize,
         mut request: R,
     ) -> anyhow::Result<ChatCompletionResponse> {
         let (tx, mut rx) = channel(1);
@@ -70,7 +83,7 @@ impl Model {
             response: tx,
             return_logprobs: request.return_logprobs(),
             is_streaming: false,
-            id: 0,
+            id: request_id,
             constraint: request.take_constraint(),
             suffix: None,
             adapters: request.take_adapters(),
@@ -78,6 +91,8 @@ impl Model {
             tool_choice,
             logits_processors: request.take_logits_processors(),
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -85,7 +100,7 @@ impl Model {
         let ResponseOk::Done(response) = rx
             .recv()
             .await
-            .context("Channel was erroneously closed!")?
+            .context("Channel was closed, the request may have been canceled.")?
             .as_result()?
         else {
             anyhow::bail!("Got unexpected response type.")
@@ -114,7 +129,7 @@ impl Model {
             response: tx,
             return_logprobs: request.return_logprobs(),
             is_streaming: false,
-            id: 0,
+            id: self.next_request_id(),
             constraint: request.take_constraint(),
             suffix: None,
             adapters: request.take_adapters(),
@@ -122,6 +137,8 @@ impl Model {
             tool_choice,
             logits_processors: request.take_logits_processors(),
             return_raw_logits: true,
+            priority: 0,
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -150,7 +167,7 @@ impl Model {
         let (tx, mut rx) = channel(1);
 
         let request = Request::Normal(NormalRequest {
-            id: 0,
+            id: self.next_request_id(),
             messages: RequestMessage::ImageGeneration {
                 prompt: prompt.to_string(),
                 format: response_format,
@@ -167,6 +184,8 @@ impl Model {
             tools: None,
             logits_processors: None,
             return_raw_logits: false,
+            priority: 0,
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -183,6 +202,110 @@ impl Model {
         Ok(response)
     }
 
+    /// Generate an image starting from `image` instead of noise. If there is a `mask`, only the
+    /// areas it marks are changed: its transparent areas, or its white areas if it is opaque.
+    pub async fn edit_image(
+        &self,
+        prompt: impl ToString,
+        image: image::DynamicImage,
+        mask: Option<image::DynamicImage>,
+        response_format: ImageGenerationResponseFormat,
+        generation_params: DiffusionGenerationParams,
+    ) -> anyhow::Result<ImageGenerationResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            id: self.next_request_id(),
+            messages: RequestMessage::ImageEdit {
+                prompt: prompt.to_string(),
+                image,
+                mask,
+                format: response_format,
+                generation_params,
+            },
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            return_raw_logits: false,
+            priority: 0,
+            tenant: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::ImageGeneration(response) = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        Ok(response)
+    }
+
+    /// Compute one embedding per input, in order. This requires an embedding model, or a text
+    /// model whose architecture supports returning its hidden states.
+    pub async fn embed(
+        &self,
+        inputs: Vec<String>,
+        pooling: EmbeddingPooling,
+    ) -> anyhow::Result<EmbeddingResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            id: self.next_request_id(),
+            messages: RequestMessage::Embedding { inputs, pooling },
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            return_raw_logits: false,
+            priority: 0,
+            tenant: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::Embeddings(response) = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        Ok(response)
+    }
+
+    /// Get a fresh request ID, for use with [`Model::send_chat_request_with_id`] and [`Model::cancel`].
+    pub fn next_request_id(&self) -> usize {
+        self.runner.next_request_id()
+    }
+
+    /// Cancel an in-flight request. Its sequences are stopped and their KV cache is freed; the
+    /// pending call returns an error as the response channel is closed.
+    pub async fn cancel(&self, request_id: usize) -> anyhow::Result<()> {
+        let request = Request::Cancel(request_id);
+
+        Ok(self.runner.get_sender()?.send(request).await?)
+    }
+
     /// Activate certain adapters on the model, they will be used for requests which do not specify unique adapters.
     pub async fn activate_adapters<A: ToString>(&self, adapters: Vec<A>) -> anyhow::Result<()> {
         let request = Request::ActivateAdapters(
@@ -195,6 +318,40 @@ impl Model {
         Ok(self.runner.get_sender()?.send(request).await?)
     }
 
+    /// Load a PEFT adapter from a local directory with `adapter_config.json` and
+    /// `adapter_model.safetensors`, registering it as `name`. The model must be a LoRA model, and the
+    /// adapter can then be used by requests or activated. Returns the number of layers it applies to.
+    pub async fn load_adapter(
+        &self,
+        name: impl ToString,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<usize> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::LoadAdapter(AdapterLoadRequest {
+            name: name.to_string(),
+            path: path.into(),
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv().await.context("Channel was erroneously closed!")?
+    }
+
+    /// Unload an adapter. This fails if it is active or used by a running or waiting request.
+    pub async fn unload_adapter(&self, name: impl ToString) -> anyhow::Result<()> {
+        let (tx, mut rx) = channel(1);
+        let request = Request::UnloadAdapter(AdapterUnloadRequest {
+            name: name.to_string(),
+            response: tx,
+        });
+        self.runner.get_sender()?.send(request).await?;
+
+        rx.recv()
+            .await
+            .context("Channel was erroneously closed!")??;
+        Ok(())
+    }
+
     /// Reapply ISQ to the model. This will be done on whatever device the model is already on.
     pub async fn re_isq_model(&self, isq_type: IsqType) -> anyhow::Result<()> {
         let request = Request::ReIsq(isq_type);
@@ -33,6 +33,8 @@ pub struct TextModelBuilder {
     pub(crate) no_kv_cache: bool,
     pub(crate) with_logging: bool,
     pub(crate) prefix_cache_n: Option<usize>,
+    pub(crate) prefix_cache_disk: Option<PrefixCacheDiskConfig>,
+    pub(crate) kv_cache_dtype: KvCacheDType,
 }
 
 /// Builder for PagedAttention metadata.
@@ -95,6 +97,8 @@ impl TextModelBuilder {
             max_num_seqs: 32,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
+            prefix_cache_disk: None,
+            kv_cache_dtype: KvCacheDType::Auto,
             with_logging: false,
             device_mapping: None,
             imatrix: None,
@@ -215,6 +219,25 @@ impl TextModelBuilder {
         self
     }
 
+    /// Persist prefix caches evicted from the device in `dir`, using at most `max_bytes` of disk space.
+    /// These are reloaded on demand, also after a restart.
+    pub fn with_prefix_cache_disk(mut self, dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        self.prefix_cache_disk = Some(PrefixCacheDiskConfig {
+            dir: dir.into(),
+            max_bytes,
+            revision: None,
+            isq: None,
+        });
+        self
+    }
+
+    /// Set the storage dtype of the KV cache. Quantized KV caches reduce memory usage at some cost in accuracy.
+    /// This does not apply to PagedAttention.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KvCacheDType) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
+
     /// Enable logging.
     pub fn with_logging(mut self) -> Self {
         self.with_logging = true;
@@ -272,6 +295,12 @@ impl TextModelBuilder {
         .with_no_kv_cache(self.no_kv_cache)
         .build(self.loader_type)?;
 
+        let prefix_cache_disk = self.prefix_cache_disk.map(|config| PrefixCacheDiskConfig {
+            revision: self.hf_revision.clone(),
+            isq: self.isq,
+            ..config
+        });
+
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
             self.hf_revision,
@@ -308,11 +337,15 @@ impl TextModelBuilder {
         let mut runner = MistralRsBuilder::new(pipeline, scheduler_method)
             .with_no_kv_cache(self.no_kv_cache)
             .with_gemm_full_precision_f16(true)
-            .with_no_prefix_cache(self.prefix_cache_n.is_none());
+            .with_no_prefix_cache(self.prefix_cache_n.is_none())
+            .with_kv_cache_dtype(self.kv_cache_dtype);
 
         if let Some(n) = self.prefix_cache_n {
             runner = runner.with_prefix_cache_n(n)
         }
+        if let Some(prefix_cache_disk) = prefix_cache_disk {
+            runner = runner.with_prefix_cache_disk(prefix_cache_disk)
+        }
 
         Ok(Model::new(runner.build()))
     }